@@ -0,0 +1,101 @@
+// Rendering trees of three different shapes, each stressing a different part of the
+// `PostOrderIterator`:
+//
+//  - wide: a single directory of 100k files, the sizing and rendering of a single large block
+//  - deep: a chain of 1000 directories, the handling of the long paths
+//  - balanced: a fanout of 16 under the wrapping root, three levels of directories and the
+//    files, for a depth of 5 counting both the root and the files and 65536 files in total; the
+//    bookkeeping of the rendered children
+//
+// Only the rendering is measured, the builders are filled before and cloned for every
+// iteration. Baseline when the benchmark was added:
+//
+//      shape:          time:
+//
+//      wide            19.6 ms
+//      deep            0.62 ms
+//      balanced        16.3 ms
+
+use cid::Cid;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ipfs_unixfs::dir::builder::{BufferingTreeBuilder, TreeOptions};
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree-shapes");
+    group.sample_size(10);
+
+    let shapes = [("wide", wide()), ("deep", deep()), ("balanced", balanced())];
+
+    for (name, builder) in shapes.iter() {
+        group.bench_function(*name, |b| {
+            b.iter_batched(|| builder.clone(), render, BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
+
+fn render(builder: BufferingTreeBuilder) -> usize {
+    let mut iter = builder.build();
+    let mut blocks = 0;
+
+    while let Some(node) = iter.next_borrowed() {
+        node.unwrap();
+        blocks += 1;
+    }
+
+    blocks
+}
+
+fn options() -> TreeOptions {
+    let mut opts = TreeOptions::default();
+    opts.wrap_with_directory();
+    // the single directory of the wide tree is way over the default limit
+    opts.block_size_limit(None);
+    opts
+}
+
+fn wide() -> BufferingTreeBuilder {
+    let mut builder = BufferingTreeBuilder::new(options());
+
+    for i in 0..100_000 {
+        builder
+            .put_link(&format!("{:06}.txt", i), some_cid(i), 1)
+            .unwrap();
+    }
+
+    builder
+}
+
+fn deep() -> BufferingTreeBuilder {
+    let mut builder = BufferingTreeBuilder::new(options());
+    let path = vec!["d"; 1000].join("/");
+    builder.put_link(&path, some_cid(0), 1).unwrap();
+    builder
+}
+
+fn balanced() -> BufferingTreeBuilder {
+    let mut builder = BufferingTreeBuilder::new(options());
+
+    for i in 0..16usize.pow(4) {
+        let path = format!(
+            "{:x}/{:x}/{:x}/{:x}.txt",
+            i >> 12,
+            (i >> 8) & 0xf,
+            (i >> 4) & 0xf,
+            i & 0xf
+        );
+        builder.put_link(&path, some_cid(i), 1).unwrap();
+    }
+
+    builder
+}
+
+fn some_cid(number: usize) -> Cid {
+    use multihash::Sha2_256;
+    let mh = Sha2_256::digest(&number.to_le_bytes());
+    Cid::new_v0(mh).unwrap()
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -5,12 +5,23 @@ use core::convert::TryFrom;
 use core::fmt;
 
 mod sharded_lookup;
-pub use sharded_lookup::{Cache, LookupError, ShardError, ShardedLookup};
+pub use sharded_lookup::{
+    lookup_by_hash, Cache, HashLookupFailed, LookupError, ShardError, ShardedLookup,
+};
 
 mod directory;
 pub(crate) use directory::{check_directory_supported, UnexpectedDirectoryProperties};
 
+pub(crate) mod hamt;
+
 /// Directory tree builder.
+///
+/// Use `builder::BufferingTreeBuilder` to collect the paths and then iterate the blocks out of
+/// the `builder::PostOrderIterator`. When only the `Cid` of the resulting tree is needed, use
+/// `builder::root_cid`.
+///
+/// The builders, the options and the yielded nodes are all `Send` and `Sync`, so the tree can be
+/// built on another thread or held across `.await` points.
 pub mod builder;
 
 pub(crate) fn check_hamtshard_supported(
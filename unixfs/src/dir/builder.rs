@@ -1,11 +1,15 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use cid::Cid;
+use core::cmp::Ordering;
 use core::fmt;
+use core::time::Duration;
 
 mod dir_builder;
-use dir_builder::DirBuilder;
+pub use dir_builder::{DirBuilder, StorageEstimate};
 
 mod iter;
-pub use iter::{OwnedTreeNode, PostOrderIterator, TreeNode};
+pub use iter::{BuildSummary, OnlyDepth, OwnedTreeNode, PostOrderIterator, TreeNode};
 
 mod buffered;
 pub use buffered::BufferingTreeBuilder;
@@ -13,9 +17,88 @@ pub use buffered::BufferingTreeBuilder;
 mod custom_pb;
 use custom_pb::CustomFlatUnixFs;
 
+pub mod tags;
+
+mod parse;
+pub use parse::{parse_directory_block, DirectoryParsingFailed};
+
+mod sink;
+pub use sink::{BlockSink, DriveFailed, DriveReport, DriveStats};
+
+mod logging;
+pub use logging::{LoggingFailed, LoggingSink};
+
+mod pre_order;
+
+mod options;
+pub use options::{OptionsError, TreeOptionsBuilder};
+
+mod diff;
+pub use diff::{diff, PathChange};
+
+mod update;
+pub use update::{update_leaf, UpdateFailed};
+
+mod relayout;
+pub use relayout::{relayout, RelayoutFailed};
+
+mod append;
+pub use append::append_entries;
+
+mod verify;
+pub use verify::{verify_block, VerifyError};
+
+mod single_path;
+pub use single_path::{single_path_tree, SinglePathFailed};
+
+mod stash;
+
+#[cfg(feature = "compressed-blocks")]
+mod compressed;
+#[cfg(feature = "compressed-blocks")]
+pub use compressed::{decompress_block, Compression, COMPRESSED_BLOCK_MAGIC};
+
+#[cfg(feature = "fs")]
+mod fs;
+#[cfg(feature = "fs")]
+pub use fs::{import_dir, ImportFailed, ImportOptions, Imported};
+
+#[cfg(feature = "legacy-metadata")]
+mod legacy;
+#[cfg(feature = "legacy-metadata")]
+pub use legacy::wrap_with_mime_type;
+
+#[cfg(feature = "serde")]
+mod checkpoint;
+#[cfg(feature = "serde")]
+pub use checkpoint::Checkpoint;
+
+// the builders are moved to and shared between threads, for example in async servers, so a new
+// field must not make any of these !Send or !Sync
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<TreeOptions>();
+    assert_send_sync::<TreeOptionsBuilder>();
+    assert_send_sync::<DirBuilder>();
+    assert_send_sync::<BufferingTreeBuilder>();
+    assert_send_sync::<PostOrderIterator>();
+    assert_send_sync::<OwnedTreeNode>();
+    assert_send_sync::<TreeNode<'_>>();
+    assert_send_sync::<TreeConstructionFailed>();
+    assert_send_sync::<LinkOrder>();
+    assert_send_sync::<LinkRewriter>();
+    assert_send_sync::<EntryFilter>();
+    assert_send_sync::<RenderObserver>();
+};
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum Entry {
     Leaf(Leaf),
     Directory(DirBuilder),
+    /// Leaf without a known total size yet, see `BufferingTreeBuilder::put_link_deferred`.
+    Deferred(#[cfg_attr(feature = "serde", serde(with = "checkpoint::cid_bytes"))] Cid),
 }
 
 impl fmt::Debug for Entry {
@@ -25,11 +108,19 @@ impl fmt::Debug for Entry {
         match self {
             Leaf(leaf) => write!(fmt, "Leaf {{ {:?} }}", leaf),
             Directory(_) => write!(fmt, "DirBuilder {{ .. }}"),
+            Deferred(cid) => write!(fmt, "Deferred {{ {} }}", cid),
         }
     }
 }
 
 impl Entry {
+    fn kind(&self) -> EntryKind {
+        match self {
+            Entry::Leaf(_) | Entry::Deferred(_) => EntryKind::Leaf,
+            Entry::Directory(_) => EntryKind::Directory,
+        }
+    }
+
     fn as_dir_builder(&mut self) -> Result<&mut DirBuilder, ()> {
         use Entry::*;
         match self {
@@ -39,22 +130,356 @@ impl Entry {
     }
 }
 
-struct Leaf {
+/// The kind of an entry in `DirBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Link to a file, symlink or any other opaque target.
+    Leaf,
+    /// Directory which will be rendered as part of the tree.
+    Directory,
+}
+
+/// Link to a file, symlink or any other opaque target within a directory.
+///
+/// The link is only a reference: building the directories needs nothing but the `Cid` and the
+/// total size, and the blocks of the target are never read, so the target does not need to be
+/// available or even to exist. The total size is written as the `PBLink::Tsize` as given, and it
+/// is up to the caller to make it match the target; for a file added with
+/// `crate::file::adder::FileAdder` it is the sum of the lengths of all of the yielded blocks.
+/// The codec of the `Cid` is not looked at, so the single raw (0x55) blocks of files added with
+/// raw leaves are linked like any other target, with the length of the block as the total size.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Leaf {
+    #[cfg_attr(feature = "serde", serde(with = "checkpoint::cid_bytes"))]
     link: Cid,
     total_size: u64,
 }
 
+impl Leaf {
+    /// Creates a new link to the `Cid` with the cumulative size of the target, see
+    /// `BufferingTreeBuilder::put_link`.
+    pub fn new(link: Cid, total_size: u64) -> Self {
+        Leaf { link, total_size }
+    }
+
+    /// Returns the `Cid` of the link target.
+    pub fn cid(&self) -> &Cid {
+        &self.link
+    }
+
+    /// Returns the cumulative size of the link target, the `PBLink::Tsize`.
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+}
+
 impl fmt::Debug for Leaf {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(fmt, "{}, {}", self.link, self.total_size)
     }
 }
 
+/// The conventional maximum block size of 1 MiB used by go-ipfs and bitswap. Larger blocks might
+/// not be transferable between the nodes at all.
+pub const PROTOCOL_MAX_BLOCK_SIZE: u64 = 1 << 20;
+
 /// Configuration for customizing how the tree is built.
+///
+/// The options are `Send` and `Sync`, which is why the callbacks given to `LinkOrder`,
+/// `LinkRewriter`, `EntryFilter` and `RenderObserver` are required to be `Send + Sync` as well.
+/// Closures capturing non-thread safe state need to wrap it, for example in a `Mutex`.
 #[derive(Debug, Clone)]
 pub struct TreeOptions {
     block_size_limit: Option<u64>,
+    buffer_hard_cap: Option<u64>,
     wrap_with_directory: bool,
+    path_prefix: Option<String>,
+    cid_version: cid::Version,
+    hash: multihash::Code,
+    digest_len: Option<usize>,
+    link_order: Option<LinkOrder>,
+    sharding_threshold: Option<u64>,
+    shard_fanout: u64,
+    shard_hash_type: u64,
+    omit_tsize: bool,
+    link_rewriter: Option<LinkRewriter>,
+    entry_filter: Option<EntryFilter>,
+    whitespace_policy: WhitespacePolicy,
+    case_insensitive_collisions: CaseCollisionPolicy,
+    data_first: bool,
+    max_depth: Option<usize>,
+    max_name_len: Option<usize>,
+    reject_suspicious_names: bool,
+    max_total_size: Option<u64>,
+    max_total_nodes: Option<usize>,
+    block_cache_size: usize,
+    render_observer: Option<RenderObserver>,
+    prefix_buckets: Option<usize>,
+    root_data: RootData,
+    omit_directory_data: bool,
+    allow_non_unixfs_links: bool,
+    content_only_total_size: bool,
+    #[cfg(feature = "unicode-normalization")]
+    name_normalization: Option<UnicodeNormalization>,
+    #[cfg(feature = "spill")]
+    spill_budget: Option<usize>,
+}
+
+/// Comparator for the names of the links in a directory, see `TreeOptions::link_order`.
+#[derive(Clone)]
+pub struct LinkOrder(Arc<NameComparator>);
+
+type NameComparator = dyn Fn(&str, &str) -> Ordering + Send + Sync;
+
+impl LinkOrder {
+    /// Creates a new comparator for link names. The comparator must be a total order and
+    /// deterministic.
+    pub fn new<F>(cmp: F) -> Self
+    where
+        F: Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    {
+        LinkOrder(Arc::new(cmp))
+    }
+
+    /// Creates a comparator which orders the runs of ASCII digits within the names by their
+    /// numeric value, so that `"2"` comes before `"10"`, and everything else byte-wise as in the
+    /// default order. Names which are equal except for the leading zeros of the numbers, such as
+    /// `"01"` and `"1"`, are ordered byte-wise to keep the order total.
+    ///
+    /// Note: any directory with names whose order differs from the default order will get a
+    /// different `Cid` than go-ipfs would give it.
+    pub fn natural() -> Self {
+        LinkOrder::new(natural_cmp)
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        (self.0)(a, b)
+    }
+}
+
+/// Compares the names with the runs of digits compared by their numeric value, see
+/// `LinkOrder::natural`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+        let len = s.iter().take_while(|b| b.is_ascii_digit()).count();
+        s.split_at(len)
+    }
+
+    fn trim_zeroes(mut digits: &[u8]) -> &[u8] {
+        while let Some((b'0', rest)) = digits.split_first() {
+            digits = rest;
+        }
+        digits
+    }
+
+    let (mut x, mut y) = (a.as_bytes(), b.as_bytes());
+
+    loop {
+        match (x.first(), y.first()) {
+            (Some(first_x), Some(first_y))
+                if first_x.is_ascii_digit() && first_y.is_ascii_digit() =>
+            {
+                let (digits_x, rest_x) = split_digits(x);
+                let (digits_y, rest_y) = split_digits(y);
+                let (digits_x, digits_y) = (trim_zeroes(digits_x), trim_zeroes(digits_y));
+
+                // without the leading zeroes the longer number is the larger one
+                let ord = digits_x
+                    .len()
+                    .cmp(&digits_y.len())
+                    .then_with(|| digits_x.cmp(digits_y));
+
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+
+                x = rest_x;
+                y = rest_y;
+            }
+            (Some(first_x), Some(first_y)) => {
+                if first_x != first_y {
+                    return first_x.cmp(first_y);
+                }
+
+                x = &x[1..];
+                y = &y[1..];
+            }
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+        }
+    }
+}
+
+impl fmt::Debug for LinkOrder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "LinkOrder(..)")
+    }
+}
+
+/// How to handle leading and trailing whitespace in the path segments given to
+/// `BufferingTreeBuilder`, see `TreeOptions::whitespace_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhitespacePolicy {
+    /// The names are used as is.
+    Keep,
+    /// Paths with any segment having leading or trailing whitespace are denied.
+    Reject,
+    /// Leading and trailing whitespace is removed from all segments.
+    Trim,
+}
+
+impl WhitespacePolicy {
+    /// Returns the name to use for the path segment, or `None` if the segment is not allowed.
+    fn apply(self, segment: &str) -> Option<&str> {
+        use WhitespacePolicy::*;
+
+        match self {
+            Keep => Some(segment),
+            Reject if segment.trim() != segment => None,
+            Reject => Some(segment),
+            Trim => Some(segment.trim()).filter(|trimmed| !trimmed.is_empty()),
+        }
+    }
+}
+
+/// How to handle the names in a directory which are the same when compared case-insensitively,
+/// like `"File.txt"` and `"file.txt"`, see `TreeOptions::case_insensitive_collisions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseCollisionPolicy {
+    /// All of the entries are linked.
+    Allow,
+    /// Directories with such names fail as `TreeConstructionFailed::CaseCollision`.
+    Error,
+    /// Only the entry with the first name in the byte order is linked, the others are left out.
+    KeepFirst,
+}
+
+/// Unicode normalization form for the names given to `BufferingTreeBuilder`, see
+/// `TreeOptions::name_normalization`. Enabled with the `unicode-normalization` feature.
+#[cfg(feature = "unicode-normalization")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeNormalization {
+    /// Canonical composition (NFC), used by most systems.
+    Nfc,
+    /// Canonical decomposition (NFD), used for example by the macOS HFS+ filesystem.
+    Nfd,
+}
+
+#[cfg(feature = "unicode-normalization")]
+impl UnicodeNormalization {
+    fn apply(self, path: &str) -> String {
+        use unicode_normalization::UnicodeNormalization as _;
+
+        match self {
+            UnicodeNormalization::Nfc => path.nfc().collect(),
+            UnicodeNormalization::Nfd => path.nfd().collect(),
+        }
+    }
+}
+
+/// The `PBNode::Data` of the root directory, see `TreeOptions::root_data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootData {
+    /// The usual UnixFs message of a plain directory.
+    Directory,
+    /// The given bytes, written as is. Empty bytes still write the field.
+    Raw(Vec<u8>),
+    /// The `PBNode::Data` field is left out.
+    Absent,
+}
+
+/// Rewriter for the `Cid`s of the leaves, see `TreeOptions::link_rewriter`.
+#[derive(Clone)]
+pub struct LinkRewriter(Arc<CidRewriter>);
+
+type CidRewriter = dyn Fn(&Cid) -> Cid + Send + Sync;
+
+impl LinkRewriter {
+    /// Creates a new rewriter for the leaf `Cid`s. The rewriter must be deterministic.
+    pub fn new<F>(rewrite: F) -> Self
+    where
+        F: Fn(&Cid) -> Cid + Send + Sync + 'static,
+    {
+        LinkRewriter(Arc::new(rewrite))
+    }
+
+    fn rewrite(&self, cid: &Cid) -> Cid {
+        (self.0)(cid)
+    }
+}
+
+impl fmt::Debug for LinkRewriter {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "LinkRewriter(..)")
+    }
+}
+
+/// Filter for the entries of the directories, see `TreeOptions::entry_filter`.
+#[derive(Clone)]
+pub struct EntryFilter(Arc<EntryCallback>);
+
+type EntryCallback = dyn Fn(&str, &EntryKind) -> FilterDecision + Send + Sync;
+
+impl EntryFilter {
+    /// Creates a new filter called with the slash separated path of the entry, relative to the
+    /// root and without the `TreeOptions::path_prefix`, and the kind of the entry. The filter must
+    /// be deterministic.
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&str, &EntryKind) -> FilterDecision + Send + Sync + 'static,
+    {
+        EntryFilter(Arc::new(filter))
+    }
+
+    fn decide(&self, path: &str, kind: &EntryKind) -> FilterDecision {
+        (self.0)(path, kind)
+    }
+}
+
+impl fmt::Debug for EntryFilter {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "EntryFilter(..)")
+    }
+}
+
+/// What to do with an entry, returned by the `EntryFilter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// The entry is linked as is.
+    Keep,
+    /// The entry is not linked; for a directory nothing under it is rendered.
+    Skip,
+    /// The entry is linked with the given name instead.
+    Rename(String),
+}
+
+/// Callback for the timing of the rendered directories, see `TreeOptions::render_observer`.
+#[derive(Clone)]
+pub struct RenderObserver(Arc<RenderCallback>);
+
+type RenderCallback = dyn Fn(&str, usize, usize, Duration) + Send + Sync;
+
+impl RenderObserver {
+    /// Creates a new observer called with the path of the directory, the number of links, the
+    /// size of the rendered block in bytes and the time it took to render the block.
+    pub fn new<F>(observe: F) -> Self
+    where
+        F: Fn(&str, usize, usize, Duration) + Send + Sync + 'static,
+    {
+        RenderObserver(Arc::new(observe))
+    }
+
+    fn observe(&self, path: &str, links: usize, block_size: usize, elapsed: Duration) {
+        (self.0)(path, links, block_size, elapsed)
+    }
+}
+
+impl fmt::Debug for RenderObserver {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "RenderObserver(..)")
+    }
 }
 
 impl Default for TreeOptions {
@@ -62,7 +487,38 @@ impl Default for TreeOptions {
         TreeOptions {
             // this is just a guess; our bitswap message limit is a bit more
             block_size_limit: Some(512 * 1024),
+            buffer_hard_cap: None,
             wrap_with_directory: false,
+            path_prefix: None,
+            cid_version: cid::Version::V0,
+            hash: multihash::Code::Sha2_256,
+            digest_len: None,
+            link_order: None,
+            sharding_threshold: None,
+            shard_fanout: crate::dir::hamt::FANOUT,
+            shard_hash_type: crate::dir::hamt::MURMUR3_X64_64,
+            omit_tsize: false,
+            link_rewriter: None,
+            entry_filter: None,
+            whitespace_policy: WhitespacePolicy::Keep,
+            case_insensitive_collisions: CaseCollisionPolicy::Allow,
+            data_first: false,
+            max_depth: None,
+            max_name_len: None,
+            reject_suspicious_names: false,
+            max_total_size: None,
+            max_total_nodes: None,
+            block_cache_size: 0,
+            render_observer: None,
+            prefix_buckets: None,
+            root_data: RootData::Directory,
+            omit_directory_data: false,
+            allow_non_unixfs_links: false,
+            content_only_total_size: false,
+            #[cfg(feature = "unicode-normalization")]
+            name_normalization: None,
+            #[cfg(feature = "spill")]
+            spill_budget: None,
         }
     }
 }
@@ -74,11 +530,449 @@ impl TreeOptions {
         self.block_size_limit = limit;
     }
 
+    /// Sets the directory block size limit to `PROTOCOL_MAX_BLOCK_SIZE`, the largest size which
+    /// can still be expected to be fetchable over bitswap. Setting no limit at all with
+    /// `TreeOptions::block_size_limit` can produce blocks which other nodes will refuse to
+    /// transfer.
+    pub fn with_protocol_max(&mut self) {
+        self.block_size_limit = Some(PROTOCOL_MAX_BLOCK_SIZE);
+    }
+
+    /// Limits the size of the buffer the blocks are rendered into by `PostOrderIterator`, failing
+    /// with `TreeConstructionFailed::BufferCapExceeded` before allocating for a larger block. The
+    /// buffer is grown only as much as each block needs, so with a `TreeOptions::block_size_limit`
+    /// it never grows past the limit, but without one a single huge directory could require an
+    /// allocation of any size; the cap protects against that. Defaults to `None`.
+    pub fn buffer_hard_cap(&mut self, cap: Option<u64>) {
+        self.buffer_hard_cap = cap;
+    }
+
     /// When true, allow multiple top level entries, otherwise error on the second entry.
     /// Defaults to false.
+    ///
+    /// The root of the tree, see `PostOrderIterator::root`, depends on the single top level
+    /// entry when not wrapping:
+    ///
+    /// | top level     | wrapped                        | not wrapped                         |
+    /// |---------------|--------------------------------|-------------------------------------|
+    /// | directory `a` | directory with `a`, path `""`  | the directory `a`, path `"a"`       |
+    /// | file `a`      | directory with `a`, path `""`  | the file, no directory is yielded   |
+    ///
+    /// When nothing has been added the wrapped tree is an empty directory and the unwrapped tree
+    /// has no root.
     pub fn wrap_with_directory(&mut self) {
         self.wrap_with_directory = true;
     }
+
+    /// Prefix for all of the `TreeNode::path` values, for example when importing into a
+    /// subdirectory of an existing tree. The root will have the prefix as its path, others will
+    /// have the prefix followed by a slash and the usual path. Trailing slashes are removed from
+    /// the prefix. The prefix is only used for presentation and does not affect the Cids.
+    pub fn path_prefix(&mut self, prefix: Option<String>) {
+        self.path_prefix = prefix.map(|mut prefix| {
+            while prefix.ends_with('/') {
+                prefix.pop();
+            }
+            prefix
+        });
+    }
+
+    /// Overrides the default `Cid` version of the created directories, which is `V0`. CIDv1
+    /// directories will use the `dag-pb` codec.
+    pub fn cid_version(&mut self, version: cid::Version) {
+        self.cid_version = version;
+    }
+
+    /// Overrides the default multihash of the created directories, `Sha2_256`. When `digest_len`
+    /// is given, the digest will be truncated to the given number of bytes. Anything but an
+    /// untruncated `Sha2_256` requires the `Cid` version to be set to `V1` through
+    /// `TreeOptions::cid_version`, otherwise the rendering will fail with
+    /// `TreeConstructionFailed::InvalidCidOptions`.
+    pub fn hash(&mut self, code: multihash::Code, digest_len: Option<usize>) {
+        self.hash = code;
+        self.digest_len = digest_len;
+    }
+
+    /// Overrides the default byte-wise lexicographic order of the links within each directory.
+    /// As the order of the links is a part of the serialized directory, different orders will
+    /// produce different Cids. The comparator must be a total order and deterministic, and it
+    /// should not consider any two different names equal, otherwise the Cids depend on the
+    /// implementation details of the sorting.
+    pub fn link_order(&mut self, order: Option<LinkOrder>) {
+        self.link_order = order;
+    }
+
+    /// Enables HAMT sharding for directories which would be larger than `threshold` bytes when
+    /// serialized as plain directory. Sharded directories are split into buckets by the
+    /// `murmur3-x64-64` hash of the link names, compatible with go-ipfs, see
+    /// `TreeOptions::shard_fanout`. Defaults
+    /// to `None`, which disables sharding.
+    ///
+    /// The buckets are rendered in post order as well, and they are yielded as separate nodes
+    /// with the path of the sharded directory. The root bucket, which is the sharded directory, is
+    /// yielded last.
+    pub fn sharding_threshold(&mut self, threshold: Option<u64>) {
+        self.sharding_threshold = threshold;
+    }
+
+    /// Overrides how the leading and trailing whitespace in the path segments is handled when
+    /// adding paths to `BufferingTreeBuilder`. Defaults to `WhitespacePolicy::Keep`, which keeps
+    /// the names as given, like go-ipfs does.
+    ///
+    /// Note: `WhitespacePolicy::Trim` changes the `Cid`s of the directories with such names. Names
+    /// which are the same only after trimming, for example `"a "` and `"a"`, are not merged but
+    /// fail as `TreeBuildingFailed::NormalizedNameCollision`.
+    pub fn whitespace_policy(&mut self, policy: WhitespacePolicy) {
+        self.whitespace_policy = policy;
+    }
+
+    /// Overrides how the names in the same directory which differ only by their case are handled,
+    /// for example when the tree will be checked out to a case-insensitive filesystem where the
+    /// entries would overwrite each other. The names are compared when their directory is visited,
+    /// after `TreeOptions::entry_filter`, using the Unicode lowercase forms. Defaults to
+    /// `CaseCollisionPolicy::Allow`, which links all of the entries like go-ipfs does.
+    ///
+    /// Note: `CaseCollisionPolicy::KeepFirst` changes the `Cid`s of the directories with such
+    /// names, as the other entries are not linked at all.
+    pub fn case_insensitive_collisions(&mut self, policy: CaseCollisionPolicy) {
+        self.case_insensitive_collisions = policy;
+    }
+
+    /// Normalizes all of the paths given to `BufferingTreeBuilder` to the given Unicode
+    /// normalization form before adding them, so that the same names written in different forms
+    /// end up as the same link. Defaults to `None`, which uses the names as is.
+    ///
+    /// Note: normalizing changes the names and so the `Cid`s of the directories whenever any of
+    /// the names are not already in the chosen form. Names which differ only by their form are
+    /// not merged, instead adding the latter fails as
+    /// `TreeBuildingFailed::NormalizedNameCollision`, so that none of the entries are silently
+    /// lost. Available with the `unicode-normalization` feature.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn name_normalization(&mut self, normalization: Option<UnicodeNormalization>) {
+        self.name_normalization = normalization;
+    }
+
+    /// Sets a rewriter for the `Cid`s of the leaves, called once for every leaf before it is
+    /// linked into a directory. The directories rendered by the builder are linked as is. Defaults
+    /// to `None`.
+    ///
+    /// The rewritten `Cid`s are linked from the directories without any checks, so it is up to the
+    /// rewriter to return `Cid`s which are valid links to the same content, for example the same
+    /// content under a different `Cid` version. The `total_size` of the leaves is not changed.
+    pub fn link_rewriter(&mut self, rewriter: Option<LinkRewriter>) {
+        self.link_rewriter = rewriter;
+    }
+
+    /// Sets a filter consulted for every entry when its directory is visited, before any of the
+    /// entries are linked, allowing the entries to be skipped or renamed without going through
+    /// the whole `DirBuilder` beforehand. The paths given to the filter are the paths in the built
+    /// tree, so the entries under a renamed directory are given under its new name. The top level
+    /// entries are filtered as well, so skipping all of them without
+    /// `TreeOptions::wrap_with_directory` leaves the tree without a root. Defaults to `None`.
+    ///
+    /// Renaming into a name already in use in the same directory fails the building with
+    /// `TreeConstructionFailed::DuplicateName`, and renaming into an empty name, `.`, `..` or a
+    /// name containing a slash with `TreeConstructionFailed::InvalidRename`. The new names are
+    /// linked as they are, without applying `TreeOptions::whitespace_policy` or
+    /// `TreeOptions::name_normalization`, which only apply to the paths given to the builder.
+    ///
+    /// Note: filtering changes the `Cid`s of the directories, and as the `Cid`s must not depend on
+    /// anything else than the tree, the filter must be deterministic. `DirBuilder::count_directories`
+    /// does not take the filter into account.
+    pub fn entry_filter(&mut self, filter: Option<EntryFilter>) {
+        self.entry_filter = filter;
+    }
+
+    /// When called, the `Tsize` field is left out of all of the links, making the blocks slightly
+    /// smaller. The cumulative sizes are still reported through `TreeNode::total_size`.
+    ///
+    /// Note: this changes the `Cid`s of all directories and the resulting trees are not
+    /// compatible with go-ipfs, which always writes the `Tsize`. Defaults to false.
+    pub fn omit_tsize(&mut self) {
+        self.omit_tsize = true;
+    }
+
+    /// When called, the total size of a directory is the sum of the total sizes of its links,
+    /// leaving out the size of the directory block itself. By default the total size is the size
+    /// of the directory block plus the total sizes of its links, the same as the go-ipfs, so for a
+    /// tree with only directories and files it is the size of all of the blocks in the tree.
+    ///
+    /// The total size is written as the `Tsize` of the links to the directory, so this changes
+    /// the `Cid`s of all directories containing directories, and other implementations will
+    /// report different sizes for the trees. Defaults to false.
+    pub fn content_only_total_size(&mut self) {
+        self.content_only_total_size = true;
+    }
+
+    /// Limits the depth of the directories in the tree, counted as the number of path segments, so
+    /// that the directory `a/b` is at depth 2. Deeper directories will fail the building with
+    /// `TreeConstructionFailed::MaxDepthExceeded`, which can be used to avoid creating trees which
+    /// the readers would refuse to walk. The HAMT shard buckets are not counted. Defaults to
+    /// `None`, which allows any depth.
+    pub fn max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    /// Limits the length of the names of the entries in bytes. Longer names will fail the
+    /// building with `TreeConstructionFailed::NameTooLong`, which is easier to act on than a
+    /// `TreeConstructionFailed::TooLargeBlock` caused by a single enormous name, and can be used
+    /// to keep the names within the limits of the filesystems the tree might be exported to.
+    /// Defaults to `None`, which allows names of any length.
+    pub fn max_name_len(&mut self, limit: Option<usize>) {
+        self.max_name_len = limit;
+    }
+
+    /// When called, the names containing control characters, bidirectional text overrides or
+    /// invisible characters such as the zero width spaces fail the building with
+    /// `TreeConstructionFailed::SuspiciousName`. Such names are valid, but can be used to make
+    /// the entries look like others in the directory listings, so this is useful when building
+    /// trees out of untrusted input. The combining characters are allowed as they are common in
+    /// the decomposed forms of the names, see `TreeOptions::name_normalization` instead. By
+    /// default all names are allowed.
+    pub fn reject_suspicious_names(&mut self) {
+        self.reject_suspicious_names = true;
+    }
+
+    /// Limits the total size of the tree, stopping the building with
+    /// `TreeConstructionFailed::BudgetExceeded` as soon as a rendered directory has a larger
+    /// total size, without rendering the rest of the tree. Defaults to `None`, which allows trees
+    /// of any size.
+    ///
+    /// The budget is in logical bytes, the same as the `Tsize` of the links: the subtrees and
+    /// files linked multiple times are counted every time, even though their blocks are stored
+    /// only once. With `TreeOptions::content_only_total_size` the sizes of the directory blocks are
+    /// not counted.
+    pub fn max_total_size(&mut self, limit: Option<u64>) {
+        self.max_total_size = limit;
+    }
+
+    /// Limits the number of nodes in the tree, stopping the building with
+    /// `TreeConstructionFailed::TooManyNodes` instead of rendering the node which would exceed the
+    /// limit, and any nodes after it. This protects against being made to build huge trees out of
+    /// untrusted descriptions of them. Both the directories and the HAMT shard buckets are
+    /// counted. Defaults to `None`, which allows any number of nodes.
+    pub fn max_total_nodes(&mut self, limit: Option<usize>) {
+        self.max_total_nodes = limit;
+    }
+
+    /// Keeps the blocks of the given number of the most recently rendered directories and HAMT
+    /// shard buckets in memory, to be looked up with `PostOrderIterator::get_cached_block` while
+    /// iterating, for example to inspect the block of a child again after its parent has been
+    /// yielded. The least recently rendered block is dropped when a new one does not fit, where
+    /// rendering an identical directory again counts as rendering its block. Defaults to zero,
+    /// which disables the cache.
+    pub fn block_cache_size(&mut self, blocks: usize) {
+        self.block_cache_size = blocks;
+    }
+
+    /// Limits the memory used for the links of the directories waiting for their subdirectories
+    /// to be rendered to about the given number of bytes. Once the links go over the budget, the
+    /// links of the least recently visited directories are written to a temporary file and read
+    /// back when their next subdirectory has been rendered. Defaults to `None`, which keeps all of
+    /// the links in memory. Available with the `spill` feature.
+    ///
+    /// The rendered tree is the same regardless of the spilling. The links are spilled only for
+    /// the trees whose links go over the budget; each spill writes the links of the directory and
+    /// reads them back once, so the build slows down by the disk I/O for the spilled links, and
+    /// the temporary file grows by every spill until the iterator is dropped. Failing to write or
+    /// read the file fails the iteration with `TreeConstructionFailed::Spill`.
+    #[cfg(feature = "spill")]
+    pub fn spill_budget(&mut self, bytes: Option<usize>) {
+        self.spill_budget = bytes;
+    }
+
+    /// Sets an observer called after every directory and HAMT shard bucket has been rendered by
+    /// `PostOrderIterator`, for example to find the directories which are slow to render and
+    /// could use `TreeOptions::sharding_threshold`. The buckets are reported with the path of the
+    /// sharded directory. Nothing is measured when no observer has been set. Defaults to `None`.
+    ///
+    /// On `wasm32-unknown-unknown` there is no clock, and the durations are always zero.
+    pub fn render_observer(&mut self, observer: Option<RenderObserver>) {
+        self.render_observer = observer;
+    }
+
+    /// When called, the `Data` field is written before the links in the rendered dag-pb nodes. By
+    /// default the links are written first and the `Data` last, which is the canonical dag-pb
+    /// order also used by go-ipfs.
+    ///
+    /// The field order does not change how the nodes are read, but it changes the bytes and so
+    /// the `Cid`s of all directories. This should only be needed to reproduce the `Cid`s of
+    /// encoders using the other order. Defaults to false.
+    pub fn data_first(&mut self) {
+        self.data_first = true;
+    }
+
+    /// Splits the directories with more than `max_links` entries into plain subdirectories, or
+    /// buckets, as an alternative to `TreeOptions::sharding_threshold` for the readers which do
+    /// not support HAMT sharding. The entries are divided in the order of their names into at most
+    /// `max_links` buckets of nearly equal size, and the buckets are split again until every
+    /// directory has at most `max_links` entries. Each bucket is named by the shortest prefix of
+    /// its first entry which sorts after the last entry of the previous bucket, so an entry can
+    /// be found from the last bucket with a name sorting before or equal to it. Values less than
+    /// two are treated as two. Defaults to `None`, which does not split any directories.
+    ///
+    /// Note: this changes the paths of the entries by adding the bucket levels, and so the `Cid`s
+    /// of the directories. The buckets are counted towards `TreeOptions::max_depth`.
+    pub fn prefix_buckets(&mut self, max_links: Option<usize>) {
+        self.prefix_buckets = max_links;
+    }
+
+    /// Overrides the `PBNode::Data` of the root directory, for example to reproduce the exact bytes
+    /// of roots written by other implementations such as the go-ipfs MFS. The override only
+    /// applies to a plain root directory; the data of a HAMT sharded root is not changed.
+    /// Defaults to `RootData::Directory`.
+    ///
+    /// Note: the data is not checked, and anything else than a UnixFs directory message will
+    /// make the root unreadable as a UnixFs directory.
+    pub fn root_data(&mut self, data: RootData) {
+        self.root_data = data;
+    }
+
+    /// When called, the `PBNode::Data` field is left out of all of the plain directories, as in
+    /// some legacy directories written before the UnixFs directory message was used, for example
+    /// to verify the `Cid`s of historical datasets. The `Cid`s differ from the directories with
+    /// the field. A `TreeOptions::root_data` other than `RootData::Directory` still applies to the
+    /// root, and the HAMT shard buckets always have their data. Defaults to writing the field.
+    ///
+    /// Note: such directories are read as plain dag-pb nodes and not as UnixFs directories.
+    pub fn omit_directory_data(&mut self) {
+        self.omit_directory_data = true;
+    }
+
+    /// When called, the directories can link to any `Cid`, for example to a `dag-cbor` document.
+    /// By default only `dag-pb` and `raw` links are accepted, as the other codecs are not UnixFs
+    /// and linking to them fails the building with `TreeConstructionFailed::UnsupportedLinkCodec`.
+    /// The codecs are checked after `TreeOptions::link_rewriter`.
+    ///
+    /// Note: go-ipfs does not create such links with `ipfs files`, but follows them with
+    /// `ipfs refs`. Other implementations might not be able to read the linked nodes at all.
+    pub fn allow_non_unixfs_links(&mut self) {
+        self.allow_non_unixfs_links = true;
+    }
+
+    /// Overrides the fanout of the HAMT sharded directories, see `TreeOptions::sharding_threshold`.
+    /// The fanout must be a power of two between 2 and 256, inclusive. Defaults to 256, which is
+    /// the only fanout supported by the go-ipfs and by this crate when reading.
+    pub fn shard_fanout(&mut self, fanout: u64) {
+        self.shard_fanout = fanout;
+    }
+
+    /// Overrides the multicodec code of the hash function used to bucket the names in HAMT sharded
+    /// directories. Only the default `murmur3-x64-64` (0x22) is supported, as required by the
+    /// UnixFS specification.
+    pub fn shard_hash_type(&mut self, hash_type: u64) {
+        self.shard_hash_type = hash_type;
+    }
+
+    /// Checks the HAMT sharding options, returning the layout of the buckets.
+    fn shard_layout(&self) -> Result<crate::dir::hamt::Layout, TreeConstructionFailed> {
+        use crate::dir::hamt::{Layout, MURMUR3_X64_64};
+        use TreeConstructionFailed::InvalidShardingOptions;
+
+        if self.shard_hash_type != MURMUR3_X64_64 {
+            return Err(InvalidShardingOptions(
+                "only murmur3-x64-64 hash is supported",
+            ));
+        }
+
+        Layout::new(self.shard_fanout).ok_or(InvalidShardingOptions(
+            "fanout must be a power of two between 2 and 256",
+        ))
+    }
+
+    /// Checks the name against `TreeOptions::max_name_len` and
+    /// `TreeOptions::reject_suspicious_names`.
+    fn check_name(&self, name: &str) -> Result<(), TreeConstructionFailed> {
+        match self.max_name_len {
+            Some(limit) if name.len() > limit => {
+                return Err(TreeConstructionFailed::NameTooLong {
+                    name_len: name.len(),
+                    limit,
+                })
+            }
+            _ => {}
+        }
+
+        if self.reject_suspicious_names {
+            if let Some(character) = name.chars().find(|ch| is_suspicious(*ch)) {
+                return Err(TreeConstructionFailed::SuspiciousName {
+                    name: name.to_owned(),
+                    character,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the total size of a rendered directory against `TreeOptions::max_total_size`.
+    fn check_total_size(&self, total_size: u64) -> Result<(), TreeConstructionFailed> {
+        match self.max_total_size {
+            Some(limit) if total_size > limit => {
+                Err(TreeConstructionFailed::BudgetExceeded(total_size))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the number of already rendered nodes against `TreeOptions::max_total_nodes` before
+    /// rendering the next one.
+    fn check_node_count(&self, rendered: u64) -> Result<(), TreeConstructionFailed> {
+        match self.max_total_nodes {
+            Some(limit) if rendered >= limit as u64 => {
+                Err(TreeConstructionFailed::TooManyNodes(limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the configured hash and `Cid` version can be used together.
+    fn validate_cid_options(&self) -> Result<(), TreeConstructionFailed> {
+        use TreeConstructionFailed::InvalidCidOptions;
+
+        if self.digest_len == Some(0) {
+            return Err(InvalidCidOptions(
+                "digest cannot be truncated to zero length",
+            ));
+        }
+
+        if self.cid_version == cid::Version::V0 {
+            if self.hash != multihash::Code::Sha2_256 {
+                return Err(InvalidCidOptions("CIDv0 requires sha2-256 multihash"));
+            }
+
+            if self.digest_len.map(|len| len != 32).unwrap_or(false) {
+                return Err(InvalidCidOptions("CIDv0 cannot have a truncated digest"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates the `Cid` for the rendered directory `block`.
+    fn cid_for(&self, block: &[u8]) -> Result<Cid, TreeConstructionFailed> {
+        self.validate_cid_options()?;
+        self.cid_for_multihash(self.hash.digest(block))
+    }
+
+    /// Creates the `Cid` out of the full multihash of a rendered directory, once the options have
+    /// been validated.
+    fn cid_for_multihash(&self, mh: multihash::Multihash) -> Result<Cid, TreeConstructionFailed> {
+        let mh = match self.digest_len {
+            Some(len) if len < mh.digest().len() => multihash::wrap(self.hash, &mh.digest()[..len]),
+            Some(len) if len > mh.digest().len() => {
+                return Err(TreeConstructionFailed::InvalidCidOptions(
+                    "digest cannot be truncated to be longer than the hash output",
+                ))
+            }
+            _ => mh,
+        };
+
+        Ok(match self.cid_version {
+            cid::Version::V0 => Cid::new_v0(mh).expect("validated sha2-256 multihash for cidv0"),
+            cid::Version::V1 => Cid::new_v1(cid::Codec::DagProtobuf, mh),
+        })
+    }
 }
 
 /// Tree building failure cases.
@@ -97,6 +991,27 @@ pub enum TreeBuildingFailed {
     DuplicatePath(String),
     /// The given full path had already been added as a link to an opaque entry.
     LeafAsDirectory(String),
+    /// The given full path was empty.
+    EmptyPath,
+    /// A segment of the given full path had leading or trailing whitespace, which was denied by
+    /// `WhitespacePolicy::Reject`, or consisted only of whitespace with `WhitespacePolicy::Trim`.
+    SurroundingWhitespace(String),
+    /// A name of the given full path, or of one of its directories, was the same as an already
+    /// added name only after `TreeOptions::name_normalization` or `WhitespacePolicy::Trim`. The
+    /// paths are up to the colliding name.
+    NormalizedNameCollision {
+        /// The already added path in its original form.
+        original_a: String,
+        /// The path being added in its original form.
+        original_b: String,
+        /// The normalized path of both.
+        normalized: String,
+    },
+    /// The given full path was not added with `BufferingTreeBuilder::put_link_deferred`, or its
+    /// size had already been set.
+    NoDeferredLeaf(String),
+    /// The name of a link given for a single directory contained a slash.
+    SlashInName(String),
 }
 
 impl fmt::Display for TreeBuildingFailed {
@@ -118,12 +1033,179 @@ impl fmt::Display for TreeBuildingFailed {
                 "attempted to use already added leaf as a subdirectory: {:?}",
                 s
             ),
+            EmptyPath => write!(fmt, "path is empty"),
+            SurroundingWhitespace(s) => write!(
+                fmt,
+                "path segment has leading or trailing whitespace: {:?}",
+                s
+            ),
+            NormalizedNameCollision {
+                original_a,
+                original_b,
+                normalized,
+            } => write!(
+                fmt,
+                "paths {:?} and {:?} are both normalized to {:?}",
+                original_a, original_b, normalized
+            ),
+            NoDeferredLeaf(s) => write!(fmt, "no leaf awaiting its size at {:?}", s),
+            SlashInName(s) => write!(fmt, "link name contains a slash: {:?}", s),
         }
     }
 }
 
 impl std::error::Error for TreeBuildingFailed {}
 
+/// Returns the `Cid` the tree would have when built with the `opts`, without keeping any of the
+/// blocks. This is the simplest way to find out the `Cid` of a tree, for example to check if
+/// it already exists before storing the blocks of the `PostOrderIterator`.
+///
+/// The whole tree is rendered, so this costs as much as building it. Returns
+/// `TreeConstructionFailed::EmptyTree` if there were no entries and
+/// `TreeOptions::wrap_with_directory` was not enabled.
+pub fn root_cid(builder: DirBuilder, opts: TreeOptions) -> Result<Cid, TreeConstructionFailed> {
+    let mut iter = builder.build(opts);
+
+    while let Some(node) = iter.next_borrowed() {
+        node?;
+    }
+
+    iter.root()
+        .map(|(cid, _)| cid.to_owned())
+        .ok_or(TreeConstructionFailed::EmptyTree)
+}
+
+/// Renders a single plain directory node out of the `(name, cid, total_size)` entries, returning
+/// the `Cid` and the block. The links are ordered by name, or by `TreeOptions::link_order` if one
+/// has been set, and the `Cid`s are rewritten by `TreeOptions::link_rewriter`, if one has been
+/// set. The `TreeOptions::sharding_threshold` and the options only related to building
+/// whole trees are ignored.
+///
+/// Returns `TreeConstructionFailed::TooLargeBlock` if the rendered block would be larger than the
+/// `TreeOptions::block_size_limit`, and `TreeConstructionFailed::DuplicateName` if the same name is
+/// used by multiple entries.
+pub fn encode_directory(
+    entries: impl IntoIterator<Item = (String, Cid, u64)>,
+    opts: &TreeOptions,
+) -> Result<(Cid, Vec<u8>), TreeConstructionFailed> {
+    let mut block = Vec::new();
+    let (cid, _) = encode_directory_to(entries, opts, &mut block)?;
+    Ok((cid, block))
+}
+
+/// Renders a single plain directory node like `encode_directory`, but writes the block into the
+/// `writer` while hashing it, returning the `Cid` and the total size of the directory. The block
+/// is never held in memory as a whole, only the links are, which bounds the memory needed for
+/// directories near the size limit. Nothing is written when the block would be too large.
+///
+/// The block is written in small pieces, so a buffered `writer` should be used when the writes
+/// are expensive. Failing writes are returned as `TreeConstructionFailed::Protobuf`.
+pub fn encode_directory_to<W: std::io::Write>(
+    entries: impl IntoIterator<Item = (String, Cid, u64)>,
+    opts: &TreeOptions,
+    writer: W,
+) -> Result<(Cid, u64), TreeConstructionFailed> {
+    let links = ordered_links(entries, opts)?
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    let leaf = PostOrderIterator::render_directory_to(&links, writer, opts)?;
+
+    Ok((leaf.link, leaf.total_size))
+}
+
+/// Returns the `(name, cid, tsize)` of the links exactly as `encode_directory` would write them
+/// with the same arguments, in the serialized order, without rendering the directory. The `Cid`s
+/// are rewritten and the `tsize` is `None` when `TreeOptions::omit_tsize` is enabled. Useful for
+/// comparing the links field by field against the directories written by other
+/// implementations.
+///
+/// Fails the same as `encode_directory` on invalid names, but not on the size of the block.
+pub fn preview_directory_links(
+    entries: impl IntoIterator<Item = (String, Cid, u64)>,
+    opts: &TreeOptions,
+) -> Result<Vec<(String, Cid, Option<u64>)>, TreeConstructionFailed> {
+    let links = ordered_links(entries, opts)?
+        .into_iter()
+        .map(|NamedLeaf(name, cid, total_size)| {
+            (name, cid, Some(total_size).filter(|_| !opts.omit_tsize))
+        })
+        .collect();
+
+    Ok(links)
+}
+
+/// Rewrites, checks and orders the entries of `encode_directory` into the links to write.
+fn ordered_links(
+    entries: impl IntoIterator<Item = (String, Cid, u64)>,
+    opts: &TreeOptions,
+) -> Result<Vec<NamedLeaf>, TreeConstructionFailed> {
+    let mut entries = entries
+        .into_iter()
+        .map(|(name, cid, total_size)| match &opts.link_rewriter {
+            Some(rewriter) => NamedLeaf(name, rewriter.rewrite(&cid), total_size),
+            None => NamedLeaf(name, cid, total_size),
+        })
+        .collect::<Vec<_>>();
+
+    entries
+        .iter()
+        .try_for_each(|NamedLeaf(name, ..)| opts.check_name(name))?;
+
+    match &opts.link_order {
+        Some(order) => entries.sort_by(|a, b| order.compare(&a.0, &b.0)),
+        None => entries.sort_by(|a, b| a.0.cmp(&b.0)),
+    }
+
+    // with any total order the same names end up next to each other
+    if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(TreeConstructionFailed::DuplicateName(pair[0].0.clone()));
+    }
+
+    Ok(entries)
+}
+
+/// Returns `true` for the characters denied by `TreeOptions::reject_suspicious_names`: the
+/// control characters, the bidirectional formatting characters and the invisible characters.
+fn is_suspicious(ch: char) -> bool {
+    ch.is_control()
+        || matches!(ch,
+            // arabic letter mark, left-to-right and right-to-left marks
+            '\u{061C}' | '\u{200E}' | '\u{200F}'
+            // bidirectional embeddings, overrides and isolates
+            | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+            // soft hyphen, combining grapheme joiner, mongolian vowel separator
+            | '\u{00AD}' | '\u{034F}' | '\u{180E}'
+            // zero width space, non-joiner and joiner
+            | '\u{200B}'..='\u{200D}'
+            // word joiner, invisible operators and deprecated formatting characters
+            | '\u{2060}'..='\u{2064}' | '\u{206A}'..='\u{206F}'
+            // hangul fillers
+            | '\u{115F}' | '\u{1160}' | '\u{3164}' | '\u{FFA0}'
+            // zero width no-break space and interlinear annotations
+            | '\u{FEFF}' | '\u{FFF9}'..='\u{FFFB}'
+            // tags
+            | '\u{E0000}'..='\u{E007F}')
+}
+
+/// Returns the exact size of the plain directory block with the given links, as rendered with the
+/// default `TreeOptions`, without rendering it. The order of the links does not change the size.
+/// Useful for planning the block layout, for example in respect to
+/// `TreeOptions::block_size_limit`.
+pub fn directory_block_size(links: &BTreeMap<String, Leaf>) -> usize {
+    use quick_protobuf::sizeofs::sizeof_len;
+
+    let links = links
+        .iter()
+        .map(|(name, leaf)| custom_pb::link_size(name, &leaf.link, Some(leaf.total_size)))
+        .map(|size| 1 + sizeof_len(size))
+        .sum::<usize>();
+
+    // the Data is always UnixFs { Type: Directory }
+    links + 1 + sizeof_len(2)
+}
+
 /// Failure cases for `PostOrderIterator` creating the tree dag-pb nodes.
 #[derive(Debug)]
 pub enum TreeConstructionFailed {
@@ -132,6 +1214,94 @@ pub enum TreeConstructionFailed {
     /// The resulting directory would be too large and HAMT sharding is yet to be implemented or
     /// denied.
     TooLargeBlock(u64),
+    /// The configured hash and `Cid` version of `TreeOptions` cannot be used together.
+    InvalidCidOptions(&'static str),
+    /// There were multiple entries at the root level, but `TreeOptions::wrap_with_directory` was
+    /// not enabled. Without the wrapping directory there is no single root `Cid` for the tree;
+    /// either enable the wrapping or add the entries under a common top level directory.
+    MultipleRoots(usize),
+    /// The two names within a HAMT sharded directory have identical hashes, and cannot be placed
+    /// into different buckets.
+    HashCollision(String, String),
+    /// The cumulative size of the directory would not fit in `u64`.
+    SizeOverflow,
+    /// The same name was given for multiple entries of `encode_directory`, or an entry was renamed
+    /// by the `TreeOptions::entry_filter` into a name already in use.
+    DuplicateName(String),
+    /// The directory at the depth was deeper than allowed by `TreeOptions::max_depth`.
+    MaxDepthExceeded(usize),
+    /// The depth of a subdirectory would not fit in `usize`.
+    DepthOverflow,
+    /// The HAMT sharding options cannot be used, see `TreeOptions::shard_fanout` and
+    /// `TreeOptions::shard_hash_type`.
+    InvalidShardingOptions(&'static str),
+    /// Nothing was added and `TreeOptions::wrap_with_directory` was not enabled, so there was no
+    /// root, see `PostOrderIterator::into_block_map` and `root_cid`.
+    EmptyTree,
+    /// The directory with the given internal id reused the id of itself or one of its ancestors,
+    /// which would make the directory appear as its own ancestor. Such trees cannot be created
+    /// with `BufferingTreeBuilder` but can be deserialized.
+    CycleDetected(u64),
+    /// The rendered block had a different size than computed before rendering it, which is a bug
+    /// in this crate.
+    SizeMismatch {
+        /// The computed size in bytes.
+        expected: usize,
+        /// The number of bytes written.
+        written: usize,
+    },
+    /// The name of an entry was longer than allowed by `TreeOptions::max_name_len`.
+    NameTooLong {
+        /// The length of the name in bytes.
+        name_len: usize,
+        /// The configured limit.
+        limit: usize,
+    },
+    /// The total size of a directory was larger than allowed by `TreeOptions::max_total_size`.
+    BudgetExceeded(u64),
+    /// The tree had more nodes than allowed by `TreeOptions::max_total_nodes`, which is the
+    /// value.
+    TooManyNodes(usize),
+    /// The size of the leaf at the path added with `BufferingTreeBuilder::put_link_deferred` was
+    /// not set with `BufferingTreeBuilder::set_leaf_size` before its directory was visited.
+    UnfilledLeafSize(String),
+    /// The block was larger than allowed for the rendering buffer by
+    /// `TreeOptions::buffer_hard_cap`.
+    BufferCapExceeded {
+        /// The size of the block in bytes.
+        size: u64,
+        /// The configured cap.
+        cap: u64,
+    },
+    /// The names in the same directory were the same when compared case-insensitively, which
+    /// was denied by `TreeOptions::case_insensitive_collisions`.
+    CaseCollision(String, String),
+    /// The name contained a character denied by `TreeOptions::reject_suspicious_names`.
+    SuspiciousName {
+        /// The name of the entry.
+        name: String,
+        /// The first denied character in the name.
+        character: char,
+    },
+    /// The entry linked to a `Cid` of other codec than `dag-pb` or `raw`, which requires
+    /// `TreeOptions::allow_non_unixfs_links`.
+    UnsupportedLinkCodec {
+        /// The name of the entry.
+        name: String,
+        /// The codec of the linked `Cid`.
+        codec: cid::Codec,
+    },
+    /// The `TreeOptions::entry_filter` renamed the entry at the path into a name which cannot be
+    /// linked: an empty name, `.`, `..` or a name containing a slash.
+    InvalidRename {
+        /// The path of the entry before renaming.
+        path: String,
+        /// The new name.
+        name: String,
+    },
+    /// Writing or reading the links spilled to disk with `TreeOptions::spill_budget` failed.
+    #[cfg(feature = "spill")]
+    Spill(std::io::Error),
 }
 
 impl fmt::Display for TreeConstructionFailed {
@@ -141,11 +1311,501 @@ impl fmt::Display for TreeConstructionFailed {
         match self {
             Protobuf(e) => write!(fmt, "serialization failed: {}", e),
             TooLargeBlock(size) => write!(fmt, "attempted to create block of {} bytes", size),
+            InvalidCidOptions(reason) => write!(fmt, "invalid cid options: {}", reason),
+            MultipleRoots(count) => write!(
+                fmt,
+                "{} root level entries while configured wrap_with_directory = false, enable it to get a single root",
+                count
+            ),
+            HashCollision(a, b) => write!(
+                fmt,
+                "names {:?} and {:?} cannot be sharded due to hash collision",
+                a, b
+            ),
+            SizeOverflow => write!(fmt, "cumulative size of the directory overflows u64"),
+            DuplicateName(name) => write!(fmt, "multiple entries named {:?}", name),
+            InvalidShardingOptions(reason) => write!(fmt, "invalid sharding options: {}", reason),
+            MaxDepthExceeded(depth) => write!(fmt, "directory at depth {} is too deep", depth),
+            DepthOverflow => write!(fmt, "depth of the subdirectory overflows usize"),
+            EmptyTree => write!(fmt, "the tree was empty"),
+            CycleDetected(id) => write!(fmt, "directory id {} is already used by an ancestor", id),
+            SizeMismatch { expected, written } => write!(
+                fmt,
+                "rendered block was {} bytes instead of the computed {} bytes",
+                written, expected
+            ),
+            NameTooLong { name_len, limit } => write!(
+                fmt,
+                "name of {} bytes is longer than the limit of {} bytes",
+                name_len, limit
+            ),
+            BudgetExceeded(total) => write!(
+                fmt,
+                "total size of {} bytes exceeds the configured maximum",
+                total
+            ),
+            TooManyNodes(limit) => write!(fmt, "tree has more than the maximum of {} nodes", limit),
+            UnfilledLeafSize(path) => write!(fmt, "size of the leaf at {:?} was not set", path),
+            BufferCapExceeded { size, cap } => write!(
+                fmt,
+                "block of {} bytes does not fit the buffer capped to {} bytes",
+                size, cap
+            ),
+            CaseCollision(a, b) => write!(
+                fmt,
+                "names {:?} and {:?} differ only by their case",
+                a, b
+            ),
+            SuspiciousName { name, character } => write!(
+                fmt,
+                "name {:?} contains a suspicious character {:?}",
+                name, character
+            ),
+            UnsupportedLinkCodec { name, codec } => write!(
+                fmt,
+                "entry {:?} links to a non-UnixFs codec {:?}",
+                name, codec
+            ),
+            InvalidRename { path, name } => write!(
+                fmt,
+                "entry at {:?} cannot be renamed to {:?}",
+                path, name
+            ),
+            #[cfg(feature = "spill")]
+            Spill(e) => write!(fmt, "spilling the links to disk failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TreeConstructionFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use TreeConstructionFailed::*;
+
+        match self {
+            Protobuf(e) => Some(e),
+            #[cfg(feature = "spill")]
+            Spill(e) => Some(e),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for TreeConstructionFailed {}
+impl Clone for TreeConstructionFailed {
+    fn clone(&self) -> Self {
+        use TreeConstructionFailed::*;
+
+        match self {
+            Protobuf(e) => Protobuf(clone_protobuf_error(e)),
+            TooLargeBlock(size) => TooLargeBlock(*size),
+            InvalidCidOptions(reason) => InvalidCidOptions(reason),
+            MultipleRoots(count) => MultipleRoots(*count),
+            HashCollision(a, b) => HashCollision(a.clone(), b.clone()),
+            SizeOverflow => SizeOverflow,
+            DuplicateName(name) => DuplicateName(name.clone()),
+            InvalidShardingOptions(reason) => InvalidShardingOptions(reason),
+            MaxDepthExceeded(depth) => MaxDepthExceeded(*depth),
+            DepthOverflow => DepthOverflow,
+            EmptyTree => EmptyTree,
+            CycleDetected(id) => CycleDetected(*id),
+            SizeMismatch { expected, written } => SizeMismatch {
+                expected: *expected,
+                written: *written,
+            },
+            NameTooLong { name_len, limit } => NameTooLong {
+                name_len: *name_len,
+                limit: *limit,
+            },
+            BudgetExceeded(total) => BudgetExceeded(*total),
+            TooManyNodes(limit) => TooManyNodes(*limit),
+            UnfilledLeafSize(path) => UnfilledLeafSize(path.clone()),
+            BufferCapExceeded { size, cap } => BufferCapExceeded {
+                size: *size,
+                cap: *cap,
+            },
+            CaseCollision(a, b) => CaseCollision(a.clone(), b.clone()),
+            SuspiciousName { name, character } => SuspiciousName {
+                name: name.clone(),
+                character: *character,
+            },
+            UnsupportedLinkCodec { name, codec } => UnsupportedLinkCodec {
+                name: name.clone(),
+                codec: *codec,
+            },
+            InvalidRename { path, name } => InvalidRename {
+                path: path.clone(),
+                name: name.clone(),
+            },
+            #[cfg(feature = "spill")]
+            Spill(e) => Spill(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+/// `quick_protobuf::Error` is not `Clone` because of the `std::io::Error` variant. The io errors
+/// are cloned by their kind and the stringified message, all other variants are cloned as is.
+fn clone_protobuf_error(e: &quick_protobuf::Error) -> quick_protobuf::Error {
+    use quick_protobuf::Error::*;
+
+    match e {
+        Io(e) => Io(std::io::Error::new(e.kind(), e.to_string())),
+        Utf8(e) => Utf8(*e),
+        Deprecated(feature) => Deprecated(feature),
+        UnknownWireType(t) => UnknownWireType(*t),
+        Varint => Varint,
+        Message(msg) => Message(msg.clone()),
+        Map(tag) => Map(*tag),
+        UnexpectedEndOfBuffer => UnexpectedEndOfBuffer,
+        OutputBufferTooSmall => OutputBufferTooSmall,
+    }
+}
 
 #[derive(Debug)]
-struct NamedLeaf(String, Cid, u64);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct NamedLeaf(
+    String,
+    #[cfg_attr(feature = "serde", serde(with = "checkpoint::cid_bytes"))] Cid,
+    u64,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        directory_block_size, encode_directory, encode_directory_to, root_cid,
+        BufferingTreeBuilder, DirBuilder, Leaf, TreeConstructionFailed, TreeOptions,
+        PROTOCOL_MAX_BLOCK_SIZE,
+    };
+    use crate::test_support::some_cid;
+    use cid::Cid;
+    use std::error::Error;
+
+    #[test]
+    fn protocol_max_block_size() {
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(None);
+        opts.with_protocol_max();
+        assert_eq!(opts.block_size_limit, Some(1024 * 1024));
+        assert_eq!(opts.block_size_limit, Some(PROTOCOL_MAX_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn construction_failure_source_and_clone() {
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, "disk on fire");
+        let e = TreeConstructionFailed::Protobuf(quick_protobuf::Error::Io(io));
+
+        assert!(e.source().is_some());
+
+        let cloned = e.clone();
+        assert_eq!(e.to_string(), cloned.to_string());
+
+        match cloned {
+            TreeConstructionFailed::Protobuf(quick_protobuf::Error::Io(io)) => {
+                assert_eq!(io.kind(), std::io::ErrorKind::InvalidData)
+            }
+            x => unreachable!("{:?}", x),
+        }
+
+        let e = TreeConstructionFailed::TooLargeBlock(1024);
+        assert!(e.source().is_none());
+        assert_eq!(e.to_string(), e.clone().to_string());
+    }
+
+    #[test]
+    fn encode_single_directory() {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/c.txt", some_cid(1), 2).unwrap();
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        let expected = builder.build().next().unwrap().unwrap();
+
+        let entries = vec![
+            ("c.txt".to_string(), some_cid(1), 2),
+            ("b.txt".to_string(), some_cid(0), 1),
+        ];
+
+        let (cid, block) = encode_directory(entries, &TreeOptions::default()).unwrap();
+
+        assert_eq!(cid, expected.cid);
+        assert_eq!(&block[..], &expected.block[..]);
+    }
+
+    #[test]
+    fn root_cid_of_tree() {
+        let leaf = |i| Leaf {
+            link: some_cid(i),
+            total_size: 1,
+        };
+        let paths = || {
+            vec![
+                ("a/b.txt".to_string(), leaf(0)),
+                ("a/c/d.txt".to_string(), leaf(1)),
+            ]
+        };
+
+        let last = DirBuilder::from_paths(paths())
+            .unwrap()
+            .build(TreeOptions::default())
+            .last()
+            .unwrap()
+            .unwrap();
+
+        let root = root_cid(
+            DirBuilder::from_paths(paths()).unwrap(),
+            TreeOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(root, last.cid);
+
+        let single_file = DirBuilder::from_paths(vec![("a.txt".to_string(), leaf(0))]).unwrap();
+        assert_eq!(
+            root_cid(single_file, TreeOptions::default()).unwrap(),
+            some_cid(0)
+        );
+
+        match root_cid(DirBuilder::root(0), TreeOptions::default()) {
+            Err(TreeConstructionFailed::EmptyTree) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn natural_link_order() {
+        use super::{natural_cmp, LinkOrder};
+        use crate::pb::PBNode;
+        use core::cmp::Ordering;
+        use core::convert::TryFrom;
+
+        let mut names = vec![
+            "10",
+            "2",
+            "1",
+            "01",
+            "a10b",
+            "a2b",
+            "a2a",
+            "b",
+            "a",
+            "img12.png",
+            "img9.png",
+            "",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            &[
+                "",
+                "01",
+                "1",
+                "2",
+                "10",
+                "a",
+                "a2a",
+                "a2b",
+                "a10b",
+                "b",
+                "img9.png",
+                "img12.png"
+            ]
+        );
+
+        assert_eq!(natural_cmp("007", "7"), Ordering::Less);
+        assert_eq!(natural_cmp("x", "x"), Ordering::Equal);
+
+        let entries = || {
+            (0..12)
+                .map(|i| (i.to_string(), some_cid(i), 1))
+                .collect::<Vec<_>>()
+        };
+
+        let mut opts = TreeOptions::default();
+        let (default_cid, _) = encode_directory(entries(), &opts).unwrap();
+
+        opts.link_order(Some(LinkOrder::natural()));
+        let (natural_cid, block) = encode_directory(entries(), &opts).unwrap();
+        assert_ne!(natural_cid, default_cid);
+
+        let names = PBNode::try_from(&block[..])
+            .unwrap()
+            .Links
+            .into_iter()
+            .map(|link| link.Name.unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        let expected = (0..12).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn previewed_links_match_the_block() {
+        use super::{preview_directory_links, LinkOrder, LinkRewriter};
+        use crate::pb::PBNode;
+        use core::convert::TryFrom;
+
+        let entries = || {
+            (0..12)
+                .map(|i| (i.to_string(), some_cid(i), 10 * i as u64))
+                .collect::<Vec<_>>()
+        };
+
+        let mut reordered = TreeOptions::default();
+        reordered.link_order(Some(LinkOrder::natural()));
+        reordered.link_rewriter(Some(LinkRewriter::new(|cid| {
+            Cid::new_v1(cid.codec(), cid.hash().to_owned())
+        })));
+
+        let mut omitted = TreeOptions::default();
+        omitted.omit_tsize();
+
+        for opts in [TreeOptions::default(), reordered, omitted].iter() {
+            let preview = preview_directory_links(entries(), opts).unwrap();
+            let (_, block) = encode_directory(entries(), opts).unwrap();
+
+            let written = PBNode::try_from(&block[..])
+                .unwrap()
+                .Links
+                .into_iter()
+                .map(|link| {
+                    (
+                        link.Name.unwrap().into_owned(),
+                        Cid::try_from(link.Hash.as_deref().unwrap()).unwrap(),
+                        link.Tsize,
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(preview, written);
+        }
+
+        let duplicate = vec![
+            ("a".to_string(), some_cid(0), 1),
+            ("a".to_string(), some_cid(1), 1),
+        ];
+
+        match preview_directory_links(duplicate, &TreeOptions::default()) {
+            Err(TreeConstructionFailed::DuplicateName(name)) if name == "a" => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn exact_directory_block_size() {
+        let mut links = std::collections::BTreeMap::new();
+        assert_eq!(
+            directory_block_size(&links),
+            encode_directory(vec![], &TreeOptions::default())
+                .unwrap()
+                .1
+                .len()
+        );
+
+        for i in 0..300 {
+            // names and sizes with different lengths of varints
+            let name = "x".repeat(i);
+            links.insert(name, Leaf::new(some_cid(i), (i as u64) << (i % 48)));
+
+            let entries = links
+                .iter()
+                .map(|(name, leaf)| (name.clone(), leaf.link.clone(), leaf.total_size))
+                .collect::<Vec<_>>();
+
+            let (_, block) = encode_directory(entries, &TreeOptions::default()).unwrap();
+
+            assert_eq!(directory_block_size(&links), block.len(), "{}", i);
+        }
+    }
+
+    #[test]
+    fn encode_duplicate_names() {
+        let entries = vec![
+            ("b.txt".to_string(), some_cid(0), 1),
+            ("b.txt".to_string(), some_cid(1), 1),
+        ];
+
+        match encode_directory(entries, &TreeOptions::default()) {
+            Err(TreeConstructionFailed::DuplicateName(name)) => assert_eq!(name, "b.txt"),
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn encode_too_large_directory() {
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(Some(64));
+
+        let entries = (0..2).map(|i| (format!("{}.txt", i), some_cid(i), 1));
+
+        match encode_directory(entries, &opts) {
+            Err(TreeConstructionFailed::TooLargeBlock(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn encode_streamed_directory() {
+        let mut truncated = TreeOptions::default();
+        truncated.cid_version(cid::Version::V1);
+        truncated.hash(multihash::Code::Blake2b512, Some(20));
+
+        for opts in [TreeOptions::default(), truncated].iter() {
+            let entries = || (0..100).map(|i| (format!("{}.txt", i), some_cid(i), i as u64));
+
+            let (expected_cid, expected_block) = encode_directory(entries(), opts).unwrap();
+
+            // writing one byte at a time
+            struct Trickle(Vec<u8>);
+
+            impl std::io::Write for Trickle {
+                fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                    self.0.extend(buf.iter().take(1));
+                    Ok(buf.len().min(1))
+                }
+
+                fn flush(&mut self) -> std::io::Result<()> {
+                    Ok(())
+                }
+            }
+
+            let mut out = Trickle(Vec::new());
+            let (cid, total_size) = encode_directory_to(entries(), opts, &mut out).unwrap();
+
+            assert_eq!(cid, expected_cid);
+            assert_eq!(out.0, expected_block);
+            assert_eq!(total_size, out.0.len() as u64 + (0..100).sum::<u64>());
+        }
+    }
+
+    #[test]
+    fn encode_streamed_directory_failures() {
+        struct Full;
+
+        impl std::io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::WriteZero.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let entries = || (0..2).map(|i| (format!("{}.txt", i), some_cid(i), 1));
+
+        match encode_directory_to(entries(), &TreeOptions::default(), Full) {
+            Err(TreeConstructionFailed::Protobuf(quick_protobuf::Error::Io(e)))
+                if e.kind() == std::io::ErrorKind::WriteZero => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(Some(64));
+
+        let mut out = Vec::new();
+
+        match encode_directory_to(entries(), &opts, &mut out) {
+            Err(TreeConstructionFailed::TooLargeBlock(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        assert!(out.is_empty());
+    }
+}
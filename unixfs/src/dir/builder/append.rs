@@ -0,0 +1,186 @@
+use super::relayout::{is_directory, read_directory};
+use super::{BufferingTreeBuilder, Leaf, PostOrderIterator, RelayoutFailed, TreeOptions};
+use crate::Metadata;
+use cid::Cid;
+use std::collections::HashSet;
+
+/// Reads the existing directory tree starting at `root` and returns a `PostOrderIterator` for
+/// rendering it again with the new `entries` added, for example when appending to a log which is
+/// published as a new version of the same tree.
+///
+/// Only the directories which are on the path to one of the new entries are fetched and rendered
+/// again; all of the other directories and leaves are linked to as they were, so the new version
+/// shares every other block with the previous one and the iterator yields only the changed
+/// directories, ending with the new root. For the blocks to be shared, the `opts` should be the
+/// same as the ones the previous version was built with.
+///
+/// The blocks are requested from `fetch`, as with `relayout`, but within the fetched directories
+/// only the `dag-pb` links on the path to the new entries are fetched. The new directories are
+/// created as needed, and adding an entry over an existing path fails with
+/// `TreeBuildingFailed::DuplicatePath`.
+///
+/// The root is always the wrapping directory of the new tree, so
+/// `TreeOptions::wrap_with_directory` is enabled. As with `relayout`, the metadata of the
+/// fetched directories is not kept.
+pub fn append_entries<F, E>(
+    root: &Cid,
+    entries: impl IntoIterator<Item = (String, Leaf)>,
+    mut fetch: F,
+    mut opts: TreeOptions,
+) -> Result<PostOrderIterator, RelayoutFailed<E>>
+where
+    F: FnMut(&Cid) -> Result<Vec<u8>, E>,
+{
+    opts.wrap_with_directory();
+    let mut builder = BufferingTreeBuilder::new(opts);
+
+    let entries = entries.into_iter().collect::<Vec<_>>();
+
+    // the existing directories which need to be read, as the new entries are added into them
+    let mut expanded = HashSet::new();
+    for (path, _) in &entries {
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            end += pos;
+            expanded.insert(&path[..end]);
+            end += 1;
+        }
+    }
+
+    let block = fetch(root).map_err(RelayoutFailed::Fetch)?;
+    if !is_directory(&block) {
+        return Err(RelayoutFailed::NotADirectory(root.to_owned()));
+    }
+
+    // the directories read but not yet visited, along with their blocks
+    let mut pending = vec![(String::new(), block)];
+
+    while let Some((path, block)) = pending.pop() {
+        for (name, leaf) in read_directory(&block, &path, &mut fetch)? {
+            if name.contains('/') {
+                return Err(RelayoutFailed::InvalidName(path, name));
+            }
+
+            let entry_path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+
+            let block = if expanded.contains(entry_path.as_str())
+                && leaf.link.codec() == cid::Codec::DagProtobuf
+            {
+                Some(fetch(&leaf.link).map_err(RelayoutFailed::Fetch)?)
+            } else {
+                None
+            };
+
+            match block {
+                Some(block) if is_directory(&block) => {
+                    builder
+                        .set_metadata(&entry_path, Metadata::default())
+                        .map_err(RelayoutFailed::Building)?;
+                    pending.push((entry_path, block));
+                }
+                _ => builder
+                    .put_link(&entry_path, leaf.link, leaf.total_size)
+                    .map_err(RelayoutFailed::Building)?,
+            }
+        }
+    }
+
+    for (path, leaf) in entries {
+        builder
+            .put_link(&path, leaf.link, leaf.total_size)
+            .map_err(RelayoutFailed::Building)?;
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::append_entries;
+    use crate::dir::builder::{
+        BufferingTreeBuilder, Leaf, RelayoutFailed, TreeBuildingFailed, TreeOptions,
+    };
+    use crate::test_support::raw_cid;
+    use cid::Cid;
+    use std::collections::HashMap;
+
+    const LOGS: &[&str] = &[
+        "2020/01/a.log",
+        "2020/01/b.log",
+        "2020/02/c.log",
+        "2021/01/d.log",
+    ];
+
+    /// Builds the tree of the `paths`, storing all of the blocks into `blocks` and returning the
+    /// root.
+    fn build(paths: &[&str], blocks: &mut HashMap<Cid, Vec<u8>>) -> Cid {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        for (i, path) in paths.iter().enumerate() {
+            builder.put_link(path, raw_cid(i), 1).unwrap();
+        }
+
+        let mut root = None;
+        for node in builder.build() {
+            let node = node.unwrap();
+            root = Some(node.cid.clone());
+            blocks.insert(node.cid, node.block.into_vec());
+        }
+        root.unwrap()
+    }
+
+    #[test]
+    fn only_appended_directories_are_rendered() {
+        let mut blocks = HashMap::new();
+        let previous = build(LOGS, &mut blocks);
+
+        let mut all = LOGS.to_vec();
+        all.extend_from_slice(&["2021/01/e.log", "2021/02/f.log"]);
+        let expected = build(&all, &mut blocks);
+
+        let entries = vec![
+            ("2021/01/e.log".to_owned(), Leaf::new(raw_cid(4), 1)),
+            ("2021/02/f.log".to_owned(), Leaf::new(raw_cid(5), 1)),
+        ];
+
+        let mut fetched = Vec::new();
+        let fetch = |cid: &Cid| {
+            fetched.push(cid.to_owned());
+            blocks.get(cid).cloned().ok_or("missing")
+        };
+
+        let nodes = append_entries(&previous, entries, fetch, TreeOptions::default())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut paths = nodes.iter().map(|n| n.path.as_str()).collect::<Vec<_>>();
+        paths.sort_unstable();
+        assert_eq!(paths, &["", "2021", "2021/01", "2021/02"]);
+        assert_eq!(nodes.last().unwrap().cid, expected);
+
+        // the root, "2021" and "2021/01"; nothing under "2020" is read
+        assert_eq!(fetched.len(), 3);
+        assert_eq!(fetched[0], previous);
+    }
+
+    #[test]
+    fn appending_over_existing_path() {
+        let mut blocks = HashMap::new();
+        let previous = build(LOGS, &mut blocks);
+
+        let entries = vec![("2020/01/a.log".to_owned(), Leaf::new(raw_cid(9), 1))];
+        let fetch = |cid: &Cid| blocks.get(cid).cloned().ok_or("missing");
+
+        match append_entries(&previous, entries, fetch, TreeOptions::default()) {
+            Err(RelayoutFailed::Building(TreeBuildingFailed::DuplicatePath(_))) => {}
+            x => unreachable!("{:?}", x.map(|_| ())),
+        }
+    }
+}
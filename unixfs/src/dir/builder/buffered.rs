@@ -1,10 +1,16 @@
-use super::{DirBuilder, Entry, Leaf, PostOrderIterator, TreeBuildingFailed, TreeOptions};
+use super::{
+    DirBuilder, Entry, Leaf, OwnedTreeNode, PostOrderIterator, TreeBuildingFailed, TreeOptions,
+    WhitespacePolicy,
+};
 use crate::Metadata;
 use alloc::collections::btree_map::Entry::*;
+use alloc::collections::BTreeMap;
 use cid::Cid;
 
 /// UnixFs directory tree builder which buffers entries until `build()` is called.
-#[derive(Debug)]
+///
+/// Cloning allows building variants of the same tree, without adding the common paths again.
+#[derive(Debug, Clone)]
 pub struct BufferingTreeBuilder {
     /// At the root there can be only one element, unless an option was given to create a new
     /// directory surrounding the root elements.
@@ -14,6 +20,9 @@ pub struct BufferingTreeBuilder {
     // recover all children's rendered Cids
     counter: u64,
     opts: TreeOptions,
+    // the original form of each added path and its ancestors by their normalized form, only
+    // when the names are normalized
+    originals: BTreeMap<String, String>,
 }
 
 impl Default for BufferingTreeBuilder {
@@ -30,12 +39,13 @@ impl BufferingTreeBuilder {
             longest_path: 0,
             counter: 1,
             opts,
+            originals: BTreeMap::new(),
         }
     }
 
     /// Registers the given path to be a link to the cid that follows. The target leaf should be
     /// either a file, directory or symlink but could of course be anything. It will be treated as
-    /// an opaque link.
+    /// an opaque link, and the target blocks are not needed, see `Leaf`.
     pub fn put_link(
         &mut self,
         full_path: &str,
@@ -54,8 +64,75 @@ impl BufferingTreeBuilder {
         })
     }
 
+    /// Registers the given path to be a link to the cid whose total size is not known yet, for
+    /// example a file still being added while the directory structure is already known. The size
+    /// must be set with `set_leaf_size` before the directory of the link is visited, otherwise
+    /// building fails with `TreeConstructionFailed::UnfilledLeafSize`.
+    pub fn put_link_deferred(
+        &mut self,
+        full_path: &str,
+        target: Cid,
+    ) -> Result<(), TreeBuildingFailed> {
+        self.modify_with(full_path, |parent, basename, _| {
+            parent
+                .put_leaf_deferred(basename, target)
+                .map_err(|_| TreeBuildingFailed::DuplicatePath(full_path.to_string()))
+        })
+    }
+
+    /// Sets the total size of the link added with `put_link_deferred`. The path is looked up as
+    /// it was given to `put_link_deferred`. Fails with `TreeBuildingFailed::NoDeferredLeaf` if
+    /// there is no such link waiting for its size.
+    pub fn set_leaf_size(
+        &mut self,
+        full_path: &str,
+        total_size: u64,
+    ) -> Result<(), TreeBuildingFailed> {
+        #[cfg(feature = "unicode-normalization")]
+        let normalized = self
+            .opts
+            .name_normalization
+            .map(|form| form.apply(full_path));
+        #[cfg(feature = "unicode-normalization")]
+        let normalized_path = normalized.as_deref().unwrap_or(full_path);
+        #[cfg(not(feature = "unicode-normalization"))]
+        let normalized_path = full_path;
+
+        let policy = self.opts.whitespace_policy;
+        let segments = normalized_path
+            .split('/')
+            .map(|segment| policy.apply(segment).unwrap_or(segment))
+            .collect::<Vec<_>>();
+
+        self.root_builder
+            .set_leaf_size(&segments, total_size)
+            .map_err(|_| TreeBuildingFailed::NoDeferredLeaf(full_path.to_string()))
+    }
+
+    /// Registers the given path to be a link to the root of an already built tree, for example
+    /// one built in parallel with another `BufferingTreeBuilder`. The subtree will not be
+    /// rendered again, only the directories above it. The blocks of the subtree are not part of
+    /// the `PostOrderIterator` returned from `build` and must be persisted separately.
+    ///
+    /// The subtrees can be linked at any level, mixed with the paths which are built, so a tree
+    /// can be published sparsely by building only some of its subtrees and linking the others by
+    /// their `Cid`s, see `put_link`.
+    ///
+    /// As with `put_link`, no further paths can be added under the given path.
+    pub fn put_subtree(
+        &mut self,
+        full_path: &str,
+        root: &OwnedTreeNode,
+    ) -> Result<(), TreeBuildingFailed> {
+        self.put_link(full_path, root.cid.clone(), root.total_size)
+    }
+
     /// Directories get "put" implicitly through the put files, and directories need to be adjusted
     /// only when wanting them to have metadata.
+    ///
+    /// The mode and the mtime of the `metadata` are written into the rendered directory, or into
+    /// the root bucket of a HAMT sharded directory, unless `TreeOptions::omit_directory_data` is
+    /// used.
     pub fn set_metadata(
         &mut self,
         full_path: &str,
@@ -81,6 +158,17 @@ impl BufferingTreeBuilder {
         //
         // assuming it's ok to split at '/' since that cannot be escaped in linux at least
 
+        let original_path = full_path;
+
+        // the slashes are never changed by the normalization
+        #[cfg(feature = "unicode-normalization")]
+        let normalized = self
+            .opts
+            .name_normalization
+            .map(|form| form.apply(full_path));
+        #[cfg(feature = "unicode-normalization")]
+        let full_path = normalized.as_deref().unwrap_or(full_path);
+
         self.longest_path = full_path.len().max(self.longest_path);
         let mut remaining = full_path.split('/').enumerate().peekable();
         let mut dir_builder = &mut self.root_builder;
@@ -97,11 +185,30 @@ impl BufferingTreeBuilder {
             ));
         }
 
+        let policy = self.opts.whitespace_policy;
+
+        if full_path
+            .split('/')
+            .any(|segment| !segment.is_empty() && policy.apply(segment).is_none())
+        {
+            return Err(TreeBuildingFailed::SurroundingWhitespace(
+                full_path.to_string(),
+            ));
+        }
+
+        let new_originals = if normalizes_names(&self.opts) {
+            check_originals(&self.originals, original_path, full_path, policy)?
+        } else {
+            Vec::new()
+        };
+
         // needed to avoid borrowing into the DirBuilder::new calling closure
         let counter = &mut self.counter;
 
         while let Some((depth, next)) = remaining.next() {
             let last = remaining.peek().is_none();
+            // already validated: only the empty segments are not allowed by the policy
+            let next = policy.apply(next).unwrap_or(next);
 
             match (depth, next, last) {
                 // this might need to be accepted in case there is just a single file
@@ -140,6 +247,8 @@ impl BufferingTreeBuilder {
                 if ret.is_err() {
                     // FIXME: there might be a case where we have now stale nodes in our tree but
                     // cannot figure out an example for that.
+                } else {
+                    self.originals.extend(new_originals);
                 }
 
                 return ret;
@@ -170,6 +279,15 @@ impl BufferingTreeBuilder {
         );
     }
 
+    /// Returns the buffered root directory for inspecting the tree before building it.
+    pub fn dir_builder(&self) -> &DirBuilder {
+        &self.root_builder
+    }
+
+    pub(super) fn into_dir_builder(self) -> DirBuilder {
+        self.root_builder
+    }
+
     /// Called to build the tree. The built tree will have the added files and their implied
     /// directory structure, along with the directory entries which were created using
     /// `set_metadata`. To build the whole hierarchy, one must iterate the returned iterator to
@@ -179,8 +297,72 @@ impl BufferingTreeBuilder {
     /// its data during the walk. `PostOrderIterator` implements `Iterator` while also allowing
     /// borrowed access via `next_borrowed`.
     pub fn build(self) -> PostOrderIterator {
-        PostOrderIterator::new(self.root_builder, self.opts, self.longest_path)
+        PostOrderIterator::new(
+            self.root_builder,
+            self.opts,
+            self.longest_path,
+            self.counter,
+        )
+    }
+
+    /// Same as `BufferingTreeBuilder::build` but renders the blocks into the given `buffer`,
+    /// which can be reclaimed with `PostOrderIterator::into_buffer` once the tree has been built.
+    /// This allows reusing the allocation when building many trees. The contents of the
+    /// buffer are discarded.
+    pub fn build_with_buffer(self, buffer: Vec<u8>) -> PostOrderIterator {
+        PostOrderIterator::new_with_buffer(
+            self.root_builder,
+            self.opts,
+            self.longest_path,
+            self.counter,
+            buffer,
+        )
+    }
+}
+
+fn normalizes_names(opts: &TreeOptions) -> bool {
+    #[cfg(feature = "unicode-normalization")]
+    let normalizes = opts.name_normalization.is_some();
+    #[cfg(not(feature = "unicode-normalization"))]
+    let normalizes = false;
+
+    normalizes || opts.whitespace_policy == WhitespacePolicy::Trim
+}
+
+/// Returns the normalized and original forms of the `normalized` path and its ancestors not yet
+/// in `originals`, or the first of them which was already added in another original form.
+fn check_originals(
+    originals: &BTreeMap<String, String>,
+    original: &str,
+    normalized: &str,
+    policy: WhitespacePolicy,
+) -> Result<Vec<(String, String)>, TreeBuildingFailed> {
+    let mut new_originals = Vec::new();
+    let mut original_prefix = String::new();
+    let mut normalized_prefix = String::new();
+
+    for (original_segment, segment) in original.split('/').zip(normalized.split('/')) {
+        if !normalized_prefix.is_empty() {
+            original_prefix.push('/');
+            normalized_prefix.push('/');
+        }
+        original_prefix.push_str(original_segment);
+        normalized_prefix.push_str(policy.apply(segment).unwrap_or(segment));
+
+        match originals.get(&normalized_prefix) {
+            Some(existing) if existing != &original_prefix => {
+                return Err(TreeBuildingFailed::NormalizedNameCollision {
+                    original_a: existing.to_owned(),
+                    original_b: original_prefix,
+                    normalized: normalized_prefix,
+                })
+            }
+            Some(_) => {}
+            None => new_originals.push((normalized_prefix.clone(), original_prefix.clone())),
+        }
     }
+
+    Ok(new_originals)
 }
 
 #[cfg(test)]
@@ -188,6 +370,7 @@ mod tests {
     use super::{
         super::OwnedTreeNode, BufferingTreeBuilder, Metadata, TreeBuildingFailed, TreeOptions,
     };
+    use crate::test_support::some_cid;
     use cid::Cid;
     use core::convert::TryFrom;
 
@@ -294,152 +477,2487 @@ mod tests {
     }
 
     #[test]
-    fn single_wrapped_root() {
-        // foobar\n
+    fn path_prefix_does_not_change_cids() {
         let five_block_foobar =
             Cid::try_from("QmRJHYTNvC3hmd9gJQARxLR1QMEincccBV53bBw524yyq6").unwrap();
 
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder
+                .put_link("a/b/c.txt", five_block_foobar.clone(), 221)
+                .unwrap();
+            builder
+                .put_link("a/d/e.txt", five_block_foobar.clone(), 221)
+                .unwrap();
+            builder
+                .put_link("f.txt", five_block_foobar.clone(), 221)
+                .unwrap();
+
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
         let mut opts = TreeOptions::default();
         opts.wrap_with_directory();
-        let mut builder = BufferingTreeBuilder::new(opts);
-        builder.put_link("a", five_block_foobar, 221).unwrap();
 
-        let actual = builder
-            .build()
-            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid.to_string())))
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
+        let plain = build(opts.clone());
+
+        opts.path_prefix(Some("/data/import/".into()));
+        let prefixed = build(opts);
+
+        assert_eq!(plain.len(), prefixed.len());
+
+        for ((path, cid), (prefixed_path, prefixed_cid)) in plain.iter().zip(prefixed.iter()) {
+            assert_eq!(cid, prefixed_cid);
+
+            if path.is_empty() {
+                assert_eq!(prefixed_path, "/data/import");
+            } else {
+                assert_eq!(prefixed_path, &format!("/data/import/{}", path));
+            }
+        }
+
+        let paths = prefixed
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
 
         assert_eq!(
-            actual,
-            &[(
-                "".to_string(),
-                "QmQBseoi3b2FBrYhjM2E4mCF4Q7C8MgCUbzAbGNfyVwgNk".to_string()
-            )]
+            paths,
+            &[
+                "/data/import/a/d",
+                "/data/import/a/b",
+                "/data/import/a",
+                "/data/import"
+            ]
         );
     }
 
     #[test]
-    #[should_panic]
-    fn denied_multiple_root_dirs() {
-        let mut builder = BufferingTreeBuilder::default();
-        builder.put_link("a/c.txt", some_cid(0), 1).unwrap();
-        builder.put_link("b/d.txt", some_cid(1), 1).unwrap();
+    fn build_summary() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/b/d.txt", some_cid(1), 1).unwrap();
+        builder.put_link("a/b/e.txt", some_cid(2), 1).unwrap();
+        builder.put_link("a/f.txt", some_cid(3), 1).unwrap();
+        builder.put_link("g.txt", some_cid(4), 1).unwrap();
+
+        let mut iter = builder.build();
+        let mut largest = 0;
+
+        while let Some(node) = iter.next_borrowed() {
+            largest = largest.max(node.unwrap().block.len());
+        }
+
+        let summary = iter.summary();
+
+        assert_eq!(summary.directories, 3);
+        assert_eq!(summary.leaf_links, 5);
+        assert_eq!(summary.max_fanout, 3);
+        assert_eq!(summary.largest_block, largest);
     }
 
     #[test]
-    #[should_panic]
-    fn denied_multiple_root_files() {
-        let mut builder = BufferingTreeBuilder::default();
-        builder.put_link("a.txt", some_cid(0), 1).unwrap();
-        builder.put_link("b.txt", some_cid(1), 1).unwrap();
+    fn truncated_hash_directory() {
+        use sha2::{Digest, Sha256};
+
+        let mut opts = TreeOptions::default();
+        opts.hash(multihash::Code::Sha2_256, Some(20));
+        opts.cid_version(cid::Version::V1);
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(nodes.len(), 1);
+
+        let OwnedTreeNode { cid, block, .. } = &nodes[0];
+
+        assert_eq!(cid.version(), cid::Version::V1);
+        assert_eq!(cid.codec(), cid::Codec::DagProtobuf);
+        assert_eq!(cid.hash().algorithm(), multihash::Code::Sha2_256);
+        assert_eq!(cid.hash().digest(), &Sha256::digest(block)[..20]);
+
+        assert_eq!(cid.to_string(), "bafybefhoadi5n4z54le2dzqcbwqblcdbwdcb4iy");
     }
 
     #[test]
-    #[should_panic]
-    fn using_leaf_as_node() {
-        let mut builder = BufferingTreeBuilder::default();
-        builder.put_link("a.txt", some_cid(0), 1).unwrap();
-        builder.put_link("a.txt/b.txt", some_cid(1), 1).unwrap();
+    fn truncated_hash_denied_for_cidv0() {
+        use super::super::TreeConstructionFailed;
+
+        let mut opts = TreeOptions::default();
+        opts.hash(multihash::Code::Sha2_256, Some(20));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+        let err = builder
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_err();
+
+        assert!(
+            matches!(err, TreeConstructionFailed::InvalidCidOptions(_)),
+            "{:?}",
+            err
+        );
     }
 
     #[test]
-    fn set_metadata_before_files() {
-        let mut builder = BufferingTreeBuilder::default();
-        builder
-            .set_metadata("a/b/c/d", Metadata::default())
+    fn inspect_entries() {
+        use super::super::EntryKind::{self, *};
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/d.txt", some_cid(1), 1).unwrap();
+        builder.put_link("e.txt", some_cid(2), 1).unwrap();
+
+        let root = builder.dir_builder();
+
+        assert_eq!(root.len(), 2);
+        assert_eq!(
+            root.entries().collect::<Vec<_>>(),
+            &[("a", Directory), ("e.txt", Leaf)]
+        );
+
+        let walked = root.walk().collect::<Vec<_>>();
+        let expected: &[(&str, EntryKind)] = &[
+            ("a", Directory),
+            ("a/b", Directory),
+            ("a/b/c.txt", Leaf),
+            ("a/d.txt", Leaf),
+            ("e.txt", Leaf),
+        ];
+
+        assert_eq!(
+            walked
+                .iter()
+                .map(|(path, kind)| (path.as_str(), *kind))
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        assert_eq!(
+            root.leaf_paths().collect::<Vec<_>>(),
+            &["a/b/c.txt", "a/d.txt", "e.txt"]
+        );
+    }
+
+    #[test]
+    fn merge_built_subtree() {
+        let mut subtree = BufferingTreeBuilder::default();
+        subtree.put_link("b/c.txt", some_cid(0), 1).unwrap();
+        subtree.put_link("b/d/e.txt", some_cid(1), 1).unwrap();
+
+        let subtree_nodes = subtree.build().collect::<Result<Vec<_>, _>>().unwrap();
+        let subtree_root = subtree_nodes.last().unwrap();
+        assert_eq!(subtree_root.path, "b");
+
+        let mut merged = BufferingTreeBuilder::default();
+        merged.put_subtree("a/b", subtree_root).unwrap();
+        merged.put_link("a/f.txt", some_cid(2), 1).unwrap();
+
+        let merged = merged
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+            .collect::<Result<Vec<_>, _>>()
             .unwrap();
-        builder.put_link("a/b/c/d/e.txt", some_cid(1), 1).unwrap();
-        builder.put_link("a/b/c/d/f.txt", some_cid(2), 1).unwrap();
 
-        let actual = builder
+        // only the new parent got rendered
+        assert_eq!(merged.len(), 1);
+
+        let mut whole = BufferingTreeBuilder::default();
+        whole.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        whole.put_link("a/b/d/e.txt", some_cid(1), 1).unwrap();
+        whole.put_link("a/f.txt", some_cid(2), 1).unwrap();
+
+        let whole = whole
             .build()
-            .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
             .collect::<Result<Vec<_>, _>>()
             .unwrap();
 
-        assert_eq!(actual, &["a/b/c/d", "a/b/c", "a/b", "a",])
+        assert_eq!(merged.last(), whole.last());
     }
 
     #[test]
-    fn set_metadata_on_file() {
-        let mut builder = BufferingTreeBuilder::default();
-        builder.put_link("a/a.txt", some_cid(0), 1).unwrap();
-        let err = builder
-            .set_metadata("a/a.txt", Metadata::default())
-            .unwrap_err();
+    fn stubbed_subdirectories() {
+        use super::super::parse_directory_block;
+        use std::collections::HashSet;
+
+        // the precomputed subtrees which are only linked to
+        let mut stubs = BufferingTreeBuilder::default();
+        stubs.put_link("stub/a.txt", some_cid(0), 1).unwrap();
+        stubs.put_link("stub/b/c.txt", some_cid(1), 1).unwrap();
+        let stubs = stubs.build().collect::<Result<Vec<_>, _>>().unwrap();
+        let stub = stubs.last().unwrap();
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("expanded/d.txt", some_cid(2), 1).unwrap();
+        builder.put_subtree("stub", stub).unwrap();
+        // stubs can be mixed in at any level
+        builder.put_subtree("expanded/e/stub", stub).unwrap();
+        builder
+            .put_link("expanded/e/f.txt", some_cid(3), 1)
+            .unwrap();
+
+        match builder.put_link("stub/g.txt", some_cid(4), 1) {
+            Err(TreeBuildingFailed::LeafAsDirectory(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let paths = nodes
+            .iter()
+            .map(|node| node.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &["expanded/e", "expanded", ""]);
+
+        // none of the blocks of the stubbed subtree were emitted again
+        let emitted = nodes.iter().map(|node| &node.cid).collect::<HashSet<_>>();
+        assert!(stubs.iter().all(|node| !emitted.contains(&node.cid)));
+
+        let root = parse_directory_block(&nodes[2].block).unwrap();
+        assert_eq!(root["expanded"].cid(), &nodes[1].cid);
+        assert_eq!(root["expanded"].total_size(), nodes[1].total_size);
+        assert_eq!(root["stub"].cid(), &stub.cid);
+        assert_eq!(root["stub"].total_size(), stub.total_size);
+
+        let nested = parse_directory_block(&nodes[0].block).unwrap();
+        assert_eq!(nested["stub"].cid(), &stub.cid);
+
+        // the same root as when building everything
+        let mut whole = TreeOptions::default();
+        whole.wrap_with_directory();
+        let mut whole = BufferingTreeBuilder::new(whole);
+        for (path, n) in &[
+            ("expanded/d.txt", 2),
+            ("stub/a.txt", 0),
+            ("stub/b/c.txt", 1),
+            ("expanded/e/stub/a.txt", 0),
+            ("expanded/e/stub/b/c.txt", 1),
+            ("expanded/e/f.txt", 3),
+        ] {
+            whole.put_link(path, some_cid(*n), 1).unwrap();
+        }
+        let whole = whole.build().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(whole.last().unwrap().cid, nodes[2].cid);
+    }
+
+    #[test]
+    fn custom_link_order() {
+        use super::super::LinkOrder;
+        use crate::pb::FlatUnixFs;
+
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/A.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/b.txt", some_cid(1), 1).unwrap();
+            builder.put_link("a/C/d.txt", some_cid(2), 1).unwrap();
+
+            let mut nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+            let root = nodes.pop().unwrap();
+
+            let names = FlatUnixFs::try_parse(&root.block)
+                .unwrap()
+                .links
+                .into_iter()
+                .map(|link| link.Name.unwrap().into_owned())
+                .collect::<Vec<_>>();
+
+            (root.cid, names)
+        };
+
+        let (default_cid, default_names) = build(TreeOptions::default());
+        assert_eq!(default_names, &["A.txt", "C", "b.txt"]);
+
+        let mut opts = TreeOptions::default();
+        opts.link_order(Some(LinkOrder::new(|a, b| {
+            a.to_lowercase()
+                .cmp(&b.to_lowercase())
+                .then_with(|| a.cmp(b))
+        })));
+
+        let (cid, names) = build(opts);
+        assert_eq!(names, &["A.txt", "b.txt", "C"]);
+        assert_ne!(cid, default_cid);
+    }
+
+    #[test]
+    fn reused_ancestor_id_is_a_cycle() {
+        use super::super::{DirBuilder, Entry, Leaf, PostOrderIterator, TreeConstructionFailed};
+
+        let leaf = |i| {
+            Entry::Leaf(Leaf {
+                link: some_cid(i),
+                total_size: 1,
+            })
+        };
+
+        // a/b/c where c has the same id as a, making c its own ancestor
+        let mut d = DirBuilder::new(1, 3);
+        d.nodes.insert("e.txt".into(), leaf(0));
+
+        let mut c = DirBuilder::new(2, 1);
+        c.nodes.insert("d".into(), Entry::Directory(d));
+
+        let mut b = DirBuilder::new(1, 2);
+        b.nodes.insert("c".into(), Entry::Directory(c));
+        b.nodes.insert("f.txt".into(), leaf(1));
+
+        let mut a = DirBuilder::new(0, 1);
+        a.nodes.insert("b".into(), Entry::Directory(b));
+        a.nodes.insert("g.txt".into(), leaf(2));
+
+        let mut root = DirBuilder::root(0);
+        root.nodes.insert("a".into(), Entry::Directory(a));
+
+        let mut iter = PostOrderIterator::new(root, TreeOptions::default(), 0, 4);
+
+        match iter.next() {
+            Some(Err(TreeConstructionFailed::CycleDetected(1))) => {}
+            x => unreachable!("{:?}", x.map(|res| res.map(|node| node.path))),
+        }
+    }
 
+    #[test]
+    fn multiple_top_level_directories_without_wrapping() {
+        use super::super::{DirBuilder, Leaf, TreeConstructionFailed};
+
+        let leaf = |i| Leaf {
+            link: some_cid(i),
+            total_size: 1,
+        };
+        let root = DirBuilder::from_paths(vec![
+            ("a/b.txt".to_string(), leaf(0)),
+            ("c/d.txt".to_string(), leaf(1)),
+            ("e.txt".to_string(), leaf(2)),
+        ])
+        .unwrap();
+
+        let mut iter = root.build(TreeOptions::default());
+
+        // no directories are rendered before the failure
+        match iter.next() {
+            Some(Err(TreeConstructionFailed::MultipleRoots(3))) => {}
+            x => unreachable!("{:?}", x.map(|res| res.map(|node| node.path))),
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn multiple_roots_without_wrapping() {
+        use super::super::{DirBuilder, Leaf, PostOrderIterator, TreeConstructionFailed};
+
+        let mut root = DirBuilder::root(0);
+        for (i, name) in ["a", "b"].iter().enumerate() {
+            let leaf = Leaf {
+                link: some_cid(i),
+                total_size: 1,
+            };
+            assert!(root.put_leaf(name.to_string(), leaf).is_ok());
+        }
+
+        let mut iter = PostOrderIterator::new(root, TreeOptions::default(), 1, 1);
+
+        let err = iter.next().unwrap().map(|node| node.path).unwrap_err();
         assert!(
-            matches!(err, TreeBuildingFailed::LeafAsDirectory(_)),
+            matches!(err, TreeConstructionFailed::MultipleRoots(2)),
             "{:?}",
             err
         );
+        assert!(iter.next().is_none());
     }
 
     #[test]
-    fn dir_with_cidv1_link() {
-        // this is `echo '{ "name": "hello" }` | ./ipfs dag put`
-        let target =
-            Cid::try_from("bafyreihakpd7te5nbmlhdk5ntvcvhf2hmfgrvcwna2sddq5zz5342mcbli").unwrap();
-
+    fn single_root_without_wrapping() {
         let mut builder = BufferingTreeBuilder::default();
-        builder.put_link("a/b", target, 12).unwrap();
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
 
         let actual = builder
             .build()
-            .map(|res| res.map(|n| (n.path, n.cid, n.block)))
+            .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
             .collect::<Result<Vec<_>, _>>()
             .unwrap();
 
-        let expected = vec![("a", "QmPMDMPG8dbHDC9GuvqWr9pfruLnp4GZCAWrskwCmenVQa")];
-
-        verify_results(expected, actual);
+        assert_eq!(actual, &["a/c", "a"]);
     }
 
-    fn verify_results(
-        mut expected: Vec<(
-            impl AsRef<str> + core::fmt::Debug,
-            impl AsRef<str> + core::fmt::Debug,
-        )>,
-        mut actual: Vec<(String, Cid, Box<[u8]>)>,
-    ) {
-        use core::fmt;
+    #[test]
+    fn sharded_directory() {
+        use crate::dir::{resolve, MaybeResolved};
+        use crate::pb::{FlatUnixFs, UnixFsType};
+        use std::collections::HashMap;
 
-        struct Hex<'a>(&'a [u8]);
+        let mut opts = TreeOptions::default();
+        opts.sharding_threshold(Some(4096));
 
-        impl<'a> fmt::Debug for Hex<'a> {
-            fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-                for b in self.0 {
-                    write!(fmt, "{:02x}", b)?;
-                }
-                Ok(())
-            }
+        let mut builder = BufferingTreeBuilder::new(opts);
+        for i in 0..2000 {
+            let name = format!("a/big/{}.txt", i);
+            builder.put_link(&name, some_cid(i), 1).unwrap();
         }
+        builder.put_link("a/small.txt", some_cid(0), 1).unwrap();
+
+        let mut iter = builder.build();
+        let mut nodes = (&mut iter).collect::<Result<Vec<_>, _>>().unwrap();
+
+        let summary = iter.summary();
+        assert_eq!(summary.directories, 1);
+        // 2000 names will have collisions at the first level
+        assert!(summary.hamt_buckets > 1, "{:?}", summary);
+        assert_eq!(summary.hamt_buckets as usize + 1, nodes.len());
+        assert_eq!(summary.hamt_fanout, Some(256));
+
+        let root = nodes.pop().unwrap();
+        assert_eq!(root.path, "a");
+        let root = FlatUnixFs::try_parse(&root.block).unwrap();
+        assert_eq!(root.data.Type, UnixFsType::Directory);
+
+        // the buckets are yielded before the sharded directory, which comes last
+        let shard_root = nodes.last().unwrap().cid.clone();
+        assert!(nodes.iter().all(|node| node.path == "a/big"));
+        assert_eq!(
+            root.links[0].Hash.as_deref(),
+            Some(&shard_root.to_bytes()[..])
+        );
 
-        // hopefully this way the errors will be easier to hunt down
-
-        actual.reverse();
-        expected.reverse();
+        let blocks = nodes
+            .into_iter()
+            .map(|node| {
+                let parsed = FlatUnixFs::try_parse(&node.block).unwrap();
+                assert_eq!(parsed.data.Type, UnixFsType::HAMTShard);
+                (node.cid, node.block)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut cache = None;
+
+        for i in 0..2000 {
+            let name = format!("{}.txt", i);
+            let mut resolved = resolve(&blocks[&shard_root], &name, &mut cache).unwrap();
+
+            let found = loop {
+                match resolved {
+                    MaybeResolved::Found(cid) => break cid,
+                    MaybeResolved::NeedToLoadMore(walker) => {
+                        let next = &blocks[walker.pending_links().0];
+                        resolved = walker.continue_walk(next, &mut cache).unwrap();
+                    }
+                    MaybeResolved::NotFound => panic!("{} not found", name),
+                }
+            };
 
-        while let Some(actual) = actual.pop() {
-            let expected = expected.pop().expect("size mismatch");
-            assert_eq!(actual.0, expected.0.as_ref());
-            assert_eq!(
-                actual.1.to_string(),
-                expected.1.as_ref(),
-                "{:?}: {:?}",
-                actual.0,
-                Hex(&actual.2)
-            );
+            assert_eq!(found, some_cid(i));
         }
-
-        assert_eq!(expected.len(), 0, "size mismatch: {:?}", actual);
     }
 
-    /// Returns a quick and dirty sha2-256 of the given number as a Cidv0
-    fn some_cid(number: usize) -> Cid {
-        use multihash::Sha2_256;
-        let mh = Sha2_256::digest(&number.to_le_bytes());
-        Cid::new_v0(mh).unwrap()
+    #[test]
+    fn sharded_directory_with_custom_fanout() {
+        use super::super::TreeConstructionFailed;
+        use crate::pb::FlatUnixFs;
+
+        let build = |fanout: u64| {
+            let mut opts = TreeOptions::default();
+            opts.sharding_threshold(Some(1024));
+            opts.shard_fanout(fanout);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            for i in 0..100 {
+                builder
+                    .put_link(&format!("a/{}.txt", i), some_cid(i), 1)
+                    .unwrap();
+            }
+
+            let mut iter = builder.build();
+            let nodes = (&mut iter).collect::<Result<Vec<_>, _>>();
+            (nodes, iter.summary())
+        };
+
+        let (nodes, summary) = build(16);
+        let nodes = nodes.unwrap();
+        assert_eq!(summary.hamt_fanout, Some(16));
+
+        let root = FlatUnixFs::try_parse(&nodes.last().unwrap().block).unwrap();
+        assert_eq!(root.data.fanout, Some(16));
+        assert_eq!(root.data.hashType, Some(0x22));
+        assert!(root.links.len() <= 16);
+
+        for link in &root.links {
+            let name = link.Name.as_deref().unwrap();
+            // single hex digit prefix, followed by the name in case the bucket has only one link
+            assert!(name.len() == 1 || name.ends_with(".txt"), "{}", name);
+            assert!(name[..1].chars().all(|c| c.is_ascii_hexdigit()), "{}", name);
+        }
+
+        match build(100).0 {
+            Err(TreeConstructionFailed::InvalidShardingOptions(_)) => {}
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+    }
+
+    #[test]
+    fn sharding_threshold_not_exceeded() {
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
+
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let mut opts = TreeOptions::default();
+        opts.sharding_threshold(Some(4096));
+
+        assert_eq!(build(opts), build(TreeOptions::default()));
+    }
+
+    #[test]
+    fn two_level_total_sizes() {
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 10).unwrap();
+            builder.put_link("a/c/d.txt", some_cid(1), 20).unwrap();
+            builder
+                .build()
+                .map(|res| res.map(|node| (node.path, node.block.len(), node.total_size)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        // own block and the links: a/c = 51 + 20, a = 94 + 10 + 71
+        assert_eq!(
+            build(TreeOptions::default()),
+            &[("a/c".into(), 51, 71), ("a".into(), 94, 175)]
+        );
+
+        let mut opts = TreeOptions::default();
+        opts.content_only_total_size();
+
+        // only the links: a/c = 20, a = 10 + 20
+        assert_eq!(build(opts), &[("a/c".into(), 51, 20), ("a".into(), 94, 30)]);
+    }
+
+    #[test]
+    fn total_size_overflow() {
+        use super::super::TreeConstructionFailed;
+
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder
+            .put_link("a/b.txt", some_cid(0), u64::MAX - 1)
+            .unwrap();
+        builder.put_link("a/c.txt", some_cid(1), 1).unwrap();
+
+        match builder.build().next() {
+            Some(Err(TreeConstructionFailed::SizeOverflow)) => {}
+            x => unreachable!("{:?}", x.map(|res| res.map(|node| node.path))),
+        }
+
+        // the sizes alone fit, but not with the size of the directory block itself
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder
+            .put_link("a/b.txt", some_cid(0), u64::MAX - 10)
+            .unwrap();
+
+        match builder.build().next() {
+            Some(Err(TreeConstructionFailed::SizeOverflow)) => {}
+            x => unreachable!("{:?}", x.map(|res| res.map(|node| node.path))),
+        }
+    }
+
+    #[test]
+    fn omitted_tsize() {
+        use crate::pb::FlatUnixFs;
+
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 10).unwrap();
+            builder.put_link("a/c/d.txt", some_cid(1), 20).unwrap();
+            builder.build().collect::<Result<Vec<_>, _>>().unwrap()
+        };
+
+        let mut opts = TreeOptions::default();
+        opts.omit_tsize();
+
+        let with_tsize = build(TreeOptions::default());
+        let without_tsize = build(opts);
+
+        for (with, without) in with_tsize.iter().zip(without_tsize.iter()) {
+            assert!(without.block.len() < with.block.len());
+            assert_ne!(without.cid, with.cid);
+
+            let parsed = FlatUnixFs::try_parse(&without.block).unwrap();
+            assert!(!parsed.links.is_empty());
+            assert!(parsed.links.iter().all(|link| link.Tsize.is_none()));
+            assert!(parsed.links.iter().all(|link| link.Name.is_some()));
+        }
+
+        // the reported sizes are still cumulative, but over the smaller blocks
+        let root = without_tsize.last().unwrap();
+        assert_eq!(
+            root.total_size,
+            30 + without_tsize
+                .iter()
+                .map(|node| node.block.len() as u64)
+                .sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn rewritten_leaf_links() {
+        use super::super::LinkRewriter;
+        use crate::pb::FlatUnixFs;
+        use core::convert::TryFrom;
+
+        let to_v1 = |cid: &Cid| Cid::new_v1(cid::Codec::DagProtobuf, cid.hash().to_owned());
+
+        let mut opts = TreeOptions::default();
+        opts.link_rewriter(Some(LinkRewriter::new(to_v1)));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
+
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let links = |block: &[u8]| {
+            FlatUnixFs::try_parse(block)
+                .unwrap()
+                .links
+                .into_iter()
+                .map(|link| Cid::try_from(&*link.Hash.unwrap()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(nodes[0].path, "a/c");
+        assert_eq!(links(&nodes[0].block), &[to_v1(&some_cid(1))]);
+
+        // the directory links are not rewritten
+        assert_eq!(nodes[1].path, "a");
+        assert_eq!(
+            links(&nodes[1].block),
+            &[to_v1(&some_cid(0)), nodes[0].cid.clone()]
+        );
+    }
+
+    #[test]
+    fn observed_renders() {
+        use super::super::RenderObserver;
+        use std::sync::{Arc, Mutex};
+
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        opts.render_observer(Some(RenderObserver::new({
+            let observed = Arc::clone(&observed);
+            move |path, links, block_size, _| {
+                observed
+                    .lock()
+                    .unwrap()
+                    .push((path.to_owned(), links, block_size))
+            }
+        })));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
+        builder.put_link("a/c/e.txt", some_cid(2), 1).unwrap();
+
+        let expected = builder
+            .build()
+            .map(|res| res.map(|node| (node.path, node.block.len())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let observed = observed.lock().unwrap();
+        let links = observed
+            .iter()
+            .map(|(_, links, _)| *links)
+            .collect::<Vec<_>>();
+        let observed = observed
+            .iter()
+            .map(|(path, _, block_size)| (path.clone(), *block_size))
+            .collect::<Vec<_>>();
+
+        assert_eq!(observed, expected);
+        assert_eq!(links, &[2, 2, 1]);
+    }
+
+    #[test]
+    #[cfg(feature = "unicode-normalization")]
+    fn normalized_names() {
+        use super::super::UnicodeNormalization;
+
+        // "e" with the combining acute accent, and the precomposed "é"
+        let (nfd, nfc) = ("cafe\u{301}/a.txt", "caf\u{e9}/a.txt");
+
+        let build = |path: &str, form: Option<UnicodeNormalization>| {
+            let mut opts = TreeOptions::default();
+            opts.name_normalization(form);
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link(path, some_cid(0), 1).unwrap();
+            builder
+                .build()
+                .map(|res| res.map(|node| (node.path, node.cid)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        assert_ne!(build(nfd, None), build(nfc, None));
+        assert_eq!(
+            build(nfd, None),
+            build(nfc, Some(UnicodeNormalization::Nfd))
+        );
+        assert_eq!(
+            build(nfd, Some(UnicodeNormalization::Nfc)),
+            build(nfc, None)
+        );
+        assert_eq!(
+            build(nfd, Some(UnicodeNormalization::Nfc))[0].0,
+            "caf\u{e9}"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "unicode-normalization")]
+    fn normalized_name_collisions() {
+        use super::super::UnicodeNormalization;
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        opts.name_normalization(Some(UnicodeNormalization::Nfc));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("caf\u{e9}/a.txt", some_cid(0), 1).unwrap();
+        // the same original form is the same directory
+        builder.put_link("caf\u{e9}/b.txt", some_cid(1), 1).unwrap();
+
+        for path in &["cafe\u{301}/c.txt", "cafe\u{301}"] {
+            match builder.put_link(path, some_cid(2), 1) {
+                Err(TreeBuildingFailed::NormalizedNameCollision {
+                    original_a,
+                    original_b,
+                    normalized,
+                }) => {
+                    assert_eq!(original_a, "caf\u{e9}");
+                    assert_eq!(original_b, "cafe\u{301}");
+                    assert_eq!(normalized, "caf\u{e9}");
+                }
+                x => unreachable!("{:?}", x),
+            }
+        }
+
+        // a collision of the file names in the same directory
+        builder.put_link("d/e\u{301}.txt", some_cid(3), 1).unwrap();
+        match builder.put_link("d/\u{e9}.txt", some_cid(4), 1) {
+            Err(TreeBuildingFailed::NormalizedNameCollision {
+                original_a,
+                original_b,
+                ..
+            }) => assert_eq!(
+                (original_a, original_b),
+                ("d/e\u{301}.txt".into(), "d/\u{e9}.txt".into())
+            ),
+            x => unreachable!("{:?}", x),
+        }
+
+        let paths = builder
+            .build()
+            .map(|res| res.map(|node| node.path))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(paths, &["d", "caf\u{e9}", ""]);
+    }
+
+    #[test]
+    fn prefix_bucketed_directories() {
+        use super::super::parse_directory_block;
+        use std::collections::BTreeSet;
+
+        let build = |count: usize| {
+            let mut opts = TreeOptions::default();
+            opts.prefix_buckets(Some(10));
+            let mut builder = BufferingTreeBuilder::new(opts);
+            for i in 0..count {
+                builder
+                    .put_link(&format!("a/{:04}.txt", i), some_cid(i), 1)
+                    .unwrap();
+            }
+            builder.put_link("a/b/c.txt", some_cid(count), 1).unwrap();
+            builder.build().collect::<Result<Vec<_>, _>>().unwrap()
+        };
+
+        for &count in &[9, 10, 99, 1000] {
+            let nodes = build(count);
+            let directories = nodes
+                .iter()
+                .map(|node| node.cid.clone())
+                .collect::<BTreeSet<_>>();
+
+            let mut leaves = BTreeSet::new();
+            for node in &nodes {
+                let links = parse_directory_block(&node.block).unwrap();
+                assert!(links.len() <= 10, "{}: {}", node.path, links.len());
+
+                leaves.extend(
+                    links
+                        .values()
+                        .map(|leaf| leaf.cid().clone())
+                        .filter(|cid| !directories.contains(cid)),
+                );
+            }
+
+            assert_eq!(leaves, (0..=count).map(some_cid).collect::<BTreeSet<_>>());
+        }
+
+        // the 101 entries of "a" are split into ten buckets, the first one split again
+        let paths = build(100)
+            .into_iter()
+            .map(|node| node.path)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            paths,
+            &[
+                "a/0091/b", "a/0091", "a/0081", "a/0071", "a/0061", "a/0051", "a/0041", "a/0031",
+                "a/0021", "a/0011", "a/0/0006", "a/0/0", "a/0", "a",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_last_node_is_root() {
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            for i in 0..100 {
+                builder
+                    .put_link(&format!("a/b/{}.txt", i), some_cid(i), 1)
+                    .unwrap();
+            }
+            builder.put_link("a/c.txt", some_cid(0), 1).unwrap();
+
+            builder
+                .build()
+                .map(|res| res.map(|node| node.is_root))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let mut wrapped = TreeOptions::default();
+        wrapped.wrap_with_directory();
+
+        let mut sharded = TreeOptions::default();
+        sharded.sharding_threshold(Some(1024));
+
+        let mut prefixed = TreeOptions::default();
+        prefixed.path_prefix(Some("prefix".into()));
+
+        for opts in &[TreeOptions::default(), wrapped, sharded, prefixed] {
+            let roots = build(opts.clone());
+            assert!(roots.len() > 1, "{:?}", opts);
+            assert_eq!(roots.last(), Some(&true), "{:?}", opts);
+            assert_eq!(roots.iter().filter(|x| **x).count(), 1, "{:?}", opts);
+        }
+    }
+
+    #[test]
+    fn whitespace_policies() {
+        use super::super::WhitespacePolicy;
+
+        let build = |policy: WhitespacePolicy| {
+            let mut opts = TreeOptions::default();
+            opts.whitespace_policy(policy);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a /b.txt ", some_cid(0), 1)?;
+            builder.put_link("a/c.txt", some_cid(1), 1)?;
+
+            Ok(builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap())
+        };
+
+        // "a " and "a" are different top level entries
+        match build(WhitespacePolicy::Keep) {
+            Err(TreeBuildingFailed::TooManyRootLevelEntries) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        match build(WhitespacePolicy::Reject) {
+            Err(TreeBuildingFailed::SurroundingWhitespace(path)) => assert_eq!(path, "a /b.txt "),
+            x => unreachable!("{:?}", x),
+        }
+
+        // "a " and "a" are the same directory only after trimming
+        match build(WhitespacePolicy::Trim) {
+            Err(TreeBuildingFailed::NormalizedNameCollision {
+                original_a,
+                original_b,
+                normalized,
+            }) => assert_eq!(
+                (
+                    original_a.as_str(),
+                    original_b.as_str(),
+                    normalized.as_str()
+                ),
+                ("a ", "a", "a")
+            ),
+            x => unreachable!("{:?}", x),
+        }
+
+        // whitespace only names cannot be trimmed
+        let mut opts = TreeOptions::default();
+        opts.whitespace_policy(WhitespacePolicy::Trim);
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        match builder.put_link("a/ /b.txt", some_cid(0), 1) {
+            Err(TreeBuildingFailed::SurroundingWhitespace(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        // nothing was created for the failed path
+        assert!(builder.dir_builder().is_empty());
+    }
+
+    #[test]
+    fn data_before_links() {
+        use crate::pb::FlatUnixFs;
+
+        let build = |opts: TreeOptions| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/c.txt", some_cid(1), 1).unwrap();
+            builder.build().next().unwrap().unwrap()
+        };
+
+        let mut opts = TreeOptions::default();
+        opts.data_first();
+
+        let links_first = build(TreeOptions::default());
+        let data_first = build(opts);
+
+        assert_ne!(links_first.cid, data_first.cid);
+        assert_eq!(links_first.block.len(), data_first.block.len());
+        // Data { Type: Directory } is the first field
+        assert_eq!(&data_first.block[..4], &[0x0a, 0x02, 0x08, 0x01]);
+        assert_eq!(
+            &links_first.block[links_first.block.len() - 4..],
+            &[0x0a, 0x02, 0x08, 0x01]
+        );
+
+        let links_first = FlatUnixFs::try_parse(&links_first.block).unwrap();
+        let data_first = FlatUnixFs::try_parse(&data_first.block).unwrap();
+
+        assert_eq!(links_first.links, data_first.links);
+        assert_eq!(links_first.data, data_first.data);
+    }
+
+    #[test]
+    fn counted_directories() {
+        for &wrap in &[false, true] {
+            let mut opts = TreeOptions::default();
+            if wrap {
+                opts.wrap_with_directory();
+            }
+
+            let mut builder = BufferingTreeBuilder::new(opts.clone());
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
+            builder.put_link("a/c/e/f.txt", some_cid(2), 1).unwrap();
+            builder.put_link("a/g/h.txt", some_cid(3), 1).unwrap();
+
+            let counted = builder.dir_builder().count_directories(&opts);
+            let built = builder.build().count();
+
+            assert_eq!(counted, built);
+            assert_eq!(counted, if wrap { 5 } else { 4 });
+        }
+
+        let empty = BufferingTreeBuilder::default();
+        assert_eq!(
+            empty
+                .dir_builder()
+                .count_directories(&TreeOptions::default()),
+            0
+        );
+    }
+
+    #[test]
+    fn dir_builder_from_paths() {
+        use super::super::{DirBuilder, Leaf};
+
+        let paths = [
+            ("a/b.txt", 0),
+            ("a/c/d.txt", 1),
+            ("a/c/e.txt", 2),
+            ("f.txt", 3),
+        ];
+
+        let leaves = || {
+            paths
+                .iter()
+                .map(|(path, i)| (path.to_string(), Leaf::new(some_cid(*i), 1)))
+        };
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts.clone());
+        for (path, leaf) in leaves() {
+            builder.put_link(&path, leaf.link, leaf.total_size).unwrap();
+        }
+
+        let expected = builder
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let actual = DirBuilder::from_paths(leaves())
+            .unwrap()
+            .build(opts)
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn dir_builder_from_invalid_paths() {
+        use super::super::{DirBuilder, Leaf};
+
+        let from_paths = |paths: &[&str]| {
+            DirBuilder::from_paths(
+                paths
+                    .iter()
+                    .map(|path| (path.to_string(), Leaf::new(some_cid(0), 1))),
+            )
+        };
+
+        match from_paths(&["a/b", "a/b/c"]) {
+            Err(TreeBuildingFailed::LeafAsDirectory(path)) => assert_eq!(path, "a/b/c"),
+            x => unreachable!("{:?}", x),
+        }
+
+        match from_paths(&["a/b", "a/b"]) {
+            Err(TreeBuildingFailed::DuplicatePath(path)) => assert_eq!(path, "a/b"),
+            x => unreachable!("{:?}", x),
+        }
+
+        match from_paths(&["a//b"]) {
+            Err(TreeBuildingFailed::RepeatSlashesInPath(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        match from_paths(&[""]) {
+            Err(TreeBuildingFailed::EmptyPath) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn max_depth() {
+        use super::super::TreeConstructionFailed;
+
+        let build = |max_depth: Option<usize>| {
+            let mut opts = TreeOptions::default();
+            opts.max_depth(max_depth);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b/c/d.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/e.txt", some_cid(1), 1).unwrap();
+
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(build(None).unwrap(), &["a/b/c", "a/b", "a"]);
+        assert_eq!(build(Some(3)).unwrap(), &["a/b/c", "a/b", "a"]);
+
+        match build(Some(2)) {
+            Err(TreeConstructionFailed::MaxDepthExceeded(3)) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn suspicious_names() {
+        use super::super::TreeConstructionFailed;
+
+        let build = |name: &str, reject: bool| {
+            let mut opts = TreeOptions::default();
+            if reject {
+                opts.reject_suspicious_names();
+            }
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder
+                .put_link(&format!("a/{}", name), some_cid(0), 1)
+                .unwrap();
+            builder.build().collect::<Result<Vec<_>, _>>().map(|_| ())
+        };
+
+        let suspicious = [
+            // right-to-left override making "exe.txt" look like "txt.exe"
+            ("\u{202E}txt.exe", '\u{202E}'),
+            ("first\u{2066}isolate", '\u{2066}'),
+            ("zero\u{200B}width", '\u{200B}'),
+            ("joined\u{200D}", '\u{200D}'),
+            ("\u{FEFF}bom", '\u{FEFF}'),
+            ("soft\u{00AD}hyphen", '\u{00AD}'),
+            ("bell\u{0007}", '\u{0007}'),
+            ("line\nbreak", '\n'),
+            ("tag\u{E0041}", '\u{E0041}'),
+            ("filler\u{3164}", '\u{3164}'),
+        ];
+
+        for (name, ch) in suspicious.iter() {
+            build(name, false).unwrap();
+
+            match build(name, true) {
+                Err(TreeConstructionFailed::SuspiciousName { name: n, character })
+                    if n == *name && character == *ch => {}
+                x => unreachable!("{:?}: {:?}", name, x),
+            }
+        }
+
+        // combining characters, other scripts and emoji are fine
+        for name in &[
+            "cafe\u{0301}.txt",
+            "\u{05E9}\u{05DC}\u{05D5}\u{05DD}",
+            "\u{1F600}",
+        ] {
+            build(name, true).unwrap();
+        }
+    }
+
+    #[test]
+    fn max_name_len() {
+        use super::super::{encode_directory, TreeConstructionFailed};
+
+        let long_name = "x".repeat(10 * 1024);
+
+        let build = |max_name_len: Option<usize>| {
+            let mut opts = TreeOptions::default();
+            opts.max_name_len(max_name_len);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder
+                .put_link(&format!("a/{}", long_name), some_cid(0), 1)
+                .unwrap();
+            builder.put_link("a/b.txt", some_cid(1), 1).unwrap();
+
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(build(None).unwrap(), &["a"]);
+        assert_eq!(build(Some(10 * 1024)).unwrap(), &["a"]);
+
+        match build(Some(255)) {
+            Err(TreeConstructionFailed::NameTooLong {
+                name_len: 10240,
+                limit: 255,
+            }) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        let mut opts = TreeOptions::default();
+        opts.max_name_len(Some(255));
+
+        match encode_directory(vec![(long_name, some_cid(0), 1)], &opts) {
+            Err(TreeConstructionFailed::NameTooLong {
+                name_len: 10240,
+                limit: 255,
+            }) => {}
+            x => unreachable!("{:?}", x.map(|(cid, _)| cid)),
+        }
+    }
+
+    #[test]
+    fn reused_buffer() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
+        builder.put_link("e.txt", some_cid(2), 1).unwrap();
+
+        let expected = builder
+            .clone()
+            .build()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        // pre-warmed with garbage larger than any of the blocks
+        let mut buffer = vec![0xff; 4096];
+
+        for _ in 0..2 {
+            let mut iter = builder.clone().build_with_buffer(buffer);
+            let mut nodes = Vec::new();
+            while let Some(node) = iter.next_borrowed() {
+                nodes.push(node.unwrap().into_owned());
+            }
+
+            assert_eq!(nodes.len(), expected.len());
+            for (node, expected) in nodes.iter().zip(expected.iter()) {
+                assert_eq!(node.path, expected.path);
+                assert_eq!(node.cid, expected.cid);
+                assert_eq!(node.block, expected.block);
+            }
+
+            buffer = iter.into_buffer();
+            assert!(buffer.is_empty());
+            assert!(buffer.capacity() >= 4096);
+        }
+    }
+
+    #[test]
+    fn links_to_content_never_materialized() {
+        use crate::pb::PBNode;
+
+        // nothing has been stored for this cid, nor for the size
+        let mh = multihash::Sha2_256::digest(b"content which only exists elsewhere");
+        let target = Cid::new_v1(cid::Codec::Raw, mh);
+
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder
+            .put_link("a/remote.bin", target.clone(), 1_000_000_000)
+            .unwrap();
+
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(nodes.len(), 1);
+
+        let node = PBNode::try_from(&nodes[0].block[..]).unwrap();
+        assert_eq!(node.Links.len(), 1);
+
+        let link = &node.Links[0];
+        assert_eq!(link.Name.as_deref(), Some("remote.bin"));
+        assert_eq!(
+            Cid::try_from(link.Hash.as_deref().unwrap()).unwrap(),
+            target
+        );
+        assert_eq!(link.Tsize, Some(1_000_000_000));
+    }
+
+    #[test]
+    fn raw_leaf_links() {
+        use super::super::encode_directory;
+
+        // a small file added with raw leaves is a single raw block, linked with its length as the
+        // total size
+        let content = b"foobar\n";
+        let raw_leaf = Cid::new_v1(cid::Codec::Raw, multihash::Sha2_256::digest(content));
+        let total_size = content.len() as u64;
+
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder
+            .put_link("a/foobar.txt", raw_leaf.clone(), total_size)
+            .unwrap();
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let cid_bytes = raw_leaf.to_bytes();
+        // the CIDv1 is written as is, with the version and the raw codec before the multihash
+        assert_eq!(&cid_bytes[..4], &[0x01, 0x55, 0x12, 0x20]);
+
+        let mut link = vec![0x0a, cid_bytes.len() as u8];
+        link.extend_from_slice(&cid_bytes);
+        link.extend_from_slice(&[0x12, 10]);
+        link.extend_from_slice(b"foobar.txt");
+        link.extend_from_slice(&[0x18, total_size as u8]);
+
+        let mut expected = vec![0x12, link.len() as u8];
+        expected.extend_from_slice(&link);
+        // UnixFs { Type: Directory }
+        expected.extend_from_slice(&[0x0a, 0x02, 0x08, 0x01]);
+
+        assert_eq!(&nodes[0].block[..], &expected[..]);
+        assert_eq!(nodes[0].total_size, expected.len() as u64 + total_size);
+
+        let (cid, _) = encode_directory(
+            vec![("foobar.txt".to_string(), raw_leaf, total_size)],
+            &TreeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(cid, nodes[0].cid);
+    }
+
+    #[test]
+    fn mixed_link_versions_in_v1_directory() {
+        use crate::pb::PBNode;
+
+        let old_file = some_cid(0);
+        let new_file = Cid::new_v1(cid::Codec::Raw, some_cid(1).hash().to_owned());
+        let new_dir = Cid::new_v1(cid::Codec::DagProtobuf, some_cid(2).hash().to_owned());
+
+        let build = |version| {
+            let mut opts = TreeOptions::default();
+            opts.cid_version(version);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/old.txt", old_file.clone(), 1).unwrap();
+            builder.put_link("a/new.txt", new_file.clone(), 1).unwrap();
+            builder.put_link("a/new_dir", new_dir.clone(), 1).unwrap();
+            builder.build().collect::<Result<Vec<_>, _>>().unwrap()
+        };
+
+        let v1 = build(cid::Version::V1);
+        assert_eq!(v1.len(), 1);
+        assert_eq!(v1[0].cid.version(), cid::Version::V1);
+        assert_eq!(v1[0].cid.codec(), cid::Codec::DagProtobuf);
+
+        // every link keeps its own version and codec
+        let links = PBNode::try_from(&v1[0].block[..])
+            .unwrap()
+            .Links
+            .into_iter()
+            .map(|link| Cid::try_from(link.Hash.as_deref().unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            &[new_file.clone(), new_dir.clone(), old_file.clone()]
+        );
+
+        // the version of the directory itself does not change the block
+        let v0 = build(cid::Version::V0);
+        assert_eq!(v0[0].block, v1[0].block);
+        assert_eq!(v0[0].cid.hash(), v1[0].cid.hash());
+    }
+
+    #[test]
+    fn filtered_entries() {
+        use super::super::{EntryFilter, EntryKind, FilterDecision};
+
+        let build = |paths: &[&str], filter: Option<EntryFilter>| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.entry_filter(filter);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            for (i, path) in paths.iter().enumerate() {
+                builder.put_link(path, some_cid(i), 1).unwrap();
+            }
+
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let filter = EntryFilter::new(|path, kind| match (path, kind) {
+            (".git", EntryKind::Directory) => FilterDecision::Skip,
+            (path, EntryKind::Leaf) if path.ends_with(".tmp") => FilterDecision::Skip,
+            ("src", _) => FilterDecision::Rename("source".into()),
+            ("source/lib.rs", _) => FilterDecision::Rename("main.rs".into()),
+            _ => FilterDecision::Keep,
+        });
+
+        let filtered = build(
+            &[
+                ".git/HEAD",
+                ".git/objects/00",
+                "a.tmp",
+                "src/lib.rs",
+                "src/b.tmp",
+                "README",
+            ],
+            Some(filter),
+        )
+        .unwrap();
+
+        // the leaves with the same cids as above
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut expected = BufferingTreeBuilder::new(opts);
+        expected.put_link("source/main.rs", some_cid(3), 1).unwrap();
+        expected.put_link("README", some_cid(5), 1).unwrap();
+        let expected = expected
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(filtered, expected);
+        assert_eq!(filtered[0].0, "source");
+
+        let colliding = EntryFilter::new(|path, _| match path {
+            "a/b.txt" => FilterDecision::Rename("c.txt".into()),
+            _ => FilterDecision::Keep,
+        });
+
+        match build(&["a/b.txt", "a/c.txt"], Some(colliding)) {
+            Err(super::super::TreeConstructionFailed::DuplicateName(name)) if name == "c.txt" => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        for invalid in &["", ".", "..", "d/e.txt"] {
+            let renaming = EntryFilter::new(move |path, _| match path {
+                "a/b.txt" => FilterDecision::Rename(invalid.to_string()),
+                _ => FilterDecision::Keep,
+            });
+
+            match build(&["a/b.txt"], Some(renaming)) {
+                Err(super::super::TreeConstructionFailed::InvalidRename { path, name })
+                    if path == "a/b.txt" && &name == invalid => {}
+                x => unreachable!("{:?}: {:?}", invalid, x),
+            }
+        }
+    }
+
+    #[test]
+    fn yielded_sibling_order() {
+        use super::super::LinkOrder;
+
+        let paths = |opts| {
+            let mut builder = BufferingTreeBuilder::new(opts);
+            for path in &[
+                "a/b/c/d.txt",
+                "a/B/e.txt",
+                "a/f.txt",
+                "g/h.txt",
+                "a/b/i/j.txt",
+            ] {
+                builder.put_link(path, some_cid(0), 1).unwrap();
+            }
+            builder
+                .build()
+                .map(|res| res.map(|node| node.path))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        assert_eq!(
+            paths(opts.clone()),
+            &["g", "a/b/i", "a/b/c", "a/b", "a/B", "a", ""]
+        );
+
+        opts.link_order(Some(LinkOrder::new(|a, b| b.cmp(a))));
+
+        assert_eq!(paths(opts), &["a/B", "a/b/c", "a/b/i", "a/b", "a", "g", ""]);
+    }
+
+    #[test]
+    fn max_total_size() {
+        use super::super::TreeConstructionFailed;
+
+        let build = |max_total_size: Option<u64>| {
+            let mut opts = TreeOptions::default();
+            opts.max_total_size(max_total_size);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b/c.txt", some_cid(0), 1000).unwrap();
+            // counted twice, as the budget is in logical bytes
+            builder.put_link("a/d/c.txt", some_cid(0), 1000).unwrap();
+
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(build(None).unwrap(), &["a/d", "a/b", "a"]);
+        assert_eq!(build(Some(3000)).unwrap(), &["a/d", "a/b", "a"]);
+
+        match build(Some(1500)) {
+            Err(TreeConstructionFailed::BudgetExceeded(total)) if total > 2000 => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        match build(Some(1000)) {
+            Err(TreeConstructionFailed::BudgetExceeded(total)) if total < 1100 => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn max_total_nodes() {
+        use super::super::TreeConstructionFailed;
+
+        let build = |max_total_nodes: Option<usize>| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.max_total_nodes(max_total_nodes);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/d/e.txt", some_cid(1), 1).unwrap();
+            builder.put_link("f/g.txt", some_cid(2), 1).unwrap();
+
+            // keep iterating past the failure to see that no further nodes are yielded
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+                .collect::<Vec<_>>()
+        };
+
+        let all = build(None);
+        assert_eq!(all.len(), 5);
+        assert!(all.iter().all(Result::is_ok));
+        assert!(build(Some(5)).iter().all(Result::is_ok));
+
+        let limited = build(Some(2));
+        let yielded = limited
+            .iter()
+            .take_while(|res| res.is_ok())
+            .map(|res| res.as_ref().unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(yielded, &["f", "a/d"]);
+
+        assert!(!limited[2..].is_empty());
+        for res in &limited[2..] {
+            match res {
+                Err(TreeConstructionFailed::TooManyNodes(2)) => {}
+                x => unreachable!("{:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn cached_blocks() {
+        let build = |cache_size| {
+            let mut opts = TreeOptions::default();
+            opts.block_cache_size(cache_size);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/d/e.txt", some_cid(1), 1).unwrap();
+            builder.put_link("a/f/e.txt", some_cid(1), 1).unwrap();
+            builder.build()
+        };
+
+        let mut iter = build(2);
+        let mut nodes = Vec::new();
+
+        while let Some(node) = iter.next_borrowed() {
+            let node = node.unwrap();
+            nodes.push((node.cid.to_owned(), node.block.to_vec()));
+            assert_eq!(
+                iter.get_cached_block(&nodes.last().unwrap().0),
+                Some(&nodes.last().unwrap().1[..])
+            );
+        }
+
+        // a/f, a/d (the same as a/f), a/b and a
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(nodes[0], nodes[1]);
+        assert_eq!(iter.get_cached_block(&nodes[0].0), None);
+        assert_eq!(iter.get_cached_block(&nodes[2].0), Some(&nodes[2].1[..]));
+        assert_eq!(iter.get_cached_block(&nodes[3].0), Some(&nodes[3].1[..]));
+
+        let mut iter = build(0);
+        while let Some(node) = iter.next() {
+            assert!(iter.get_cached_block(&node.unwrap().cid).is_none());
+        }
+    }
+
+    #[test]
+    fn rerendered_blocks_stay_cached() {
+        use std::collections::HashMap;
+
+        let mut opts = TreeOptions::default();
+        opts.block_cache_size(2);
+
+        // visited in the order r/d, r/c, r/b and r/a, where r/b is the same as r/d
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("r/a/z.txt", some_cid(2), 1).unwrap();
+        builder.put_link("r/b/x.txt", some_cid(0), 1).unwrap();
+        builder.put_link("r/c/y.txt", some_cid(1), 1).unwrap();
+        builder.put_link("r/d/x.txt", some_cid(0), 1).unwrap();
+
+        let mut iter = builder.build();
+        let mut cids = HashMap::new();
+
+        while let Some(node) = iter.next_borrowed() {
+            let node = node.unwrap();
+            cids.insert(node.path.to_owned(), node.cid.to_owned());
+
+            if node.path == "r/a" {
+                // r/c was rendered before the second r/d, so it was dropped to make room for r/a
+                assert!(iter.get_cached_block(&cids["r/d"]).is_some());
+                assert!(iter.get_cached_block(&cids["r/c"]).is_none());
+            }
+        }
+
+        assert_eq!(cids["r/b"], cids["r/d"]);
+    }
+
+    #[test]
+    fn displayed_nodes() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+        let mut iter = builder.build();
+
+        let node = iter.next_borrowed().unwrap().unwrap();
+        let expected = format!(
+            "a ({}, {} bytes, total {})",
+            node.cid,
+            node.block.len(),
+            node.total_size
+        );
+        assert_eq!(node.to_string(), expected);
+        assert_eq!(node.into_owned().to_string(), expected);
+
+        let root = iter.next().unwrap().unwrap();
+        assert!(root.to_string().starts_with(&format!(". ({}, ", root.cid)));
+        assert!(!root.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn only_depth() {
+        let paths_at = |depth, prefix: Option<&str>| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.path_prefix(prefix.map(String::from));
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/d/e.txt", some_cid(1), 1).unwrap();
+            builder.put_link("f/g.txt", some_cid(2), 1).unwrap();
+
+            builder
+                .build()
+                .only_depth(depth)
+                .map(|node| node.unwrap().path)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(paths_at(0, None), vec![""]);
+        assert_eq!(paths_at(1, None), vec!["f", "a"]);
+        assert_eq!(paths_at(2, None), vec!["a/d", "a/b"]);
+        assert!(paths_at(3, None).is_empty());
+
+        assert_eq!(paths_at(1, Some("pfx")), vec!["pfx/f", "pfx/a"]);
+    }
+
+    #[test]
+    fn estimated_storage() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts.clone());
+        // the a/x and b/x are identical, as are the a and b
+        builder.put_link("a/x/1.txt", some_cid(0), 1).unwrap();
+        builder.put_link("b/x/1.txt", some_cid(0), 1).unwrap();
+        builder.put_link("c/2.txt", some_cid(1), 1).unwrap();
+
+        let estimate = builder.dir_builder().estimate_storage(&opts).unwrap();
+
+        let (_, blocks) = builder.build().into_block_map().unwrap();
+        let physical = blocks.values().map(|block| block.len() as u64).sum::<u64>();
+
+        assert_eq!((estimate.blocks, estimate.unique_blocks), (6, 4));
+        assert_eq!(estimate.unique_blocks, blocks.len() as u64);
+        assert_eq!(estimate.physical_bytes, physical);
+        assert!(estimate.physical_bytes < estimate.logical_bytes);
+    }
+
+    #[test]
+    fn fingerprints() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let build = |links: &[(&str, usize, u64)]| {
+            let mut builder = BufferingTreeBuilder::new(opts.clone());
+            for (path, n, size) in links {
+                builder.put_link(path, some_cid(*n), *size).unwrap();
+            }
+            builder
+        };
+
+        let links = [("a/b.txt", 0, 1), ("a/c/d.txt", 1, 1), ("e.txt", 2, 1)];
+        let builder = build(&links);
+        let fingerprint = builder.dir_builder().fingerprint();
+
+        let mut reversed = links;
+        reversed.reverse();
+        assert_eq!(build(&reversed).dir_builder().fingerprint(), fingerprint);
+
+        let changed = [
+            // other cid
+            [("a/b.txt", 3, 1), ("a/c/d.txt", 1, 1), ("e.txt", 2, 1)],
+            // other size
+            [("a/b.txt", 0, 2), ("a/c/d.txt", 1, 1), ("e.txt", 2, 1)],
+            // other name
+            [("a/B.txt", 0, 1), ("a/c/d.txt", 1, 1), ("e.txt", 2, 1)],
+            // moved to another directory
+            [("a/b.txt", 0, 1), ("a/c/d.txt", 1, 1), ("a/e.txt", 2, 1)],
+        ];
+
+        for links in &changed {
+            assert_ne!(build(links).dir_builder().fingerprint(), fingerprint);
+        }
+
+        let mut with_metadata = build(&links);
+        let metadata = Metadata {
+            mode: Some(0o755),
+            ..Default::default()
+        };
+        with_metadata.set_metadata("a/c", metadata).unwrap();
+        assert_ne!(with_metadata.dir_builder().fingerprint(), fingerprint);
+
+        // equal fingerprints build into equal roots
+        let root = |builder: BufferingTreeBuilder| builder.build().last().unwrap().unwrap().cid;
+        assert_eq!(root(builder), root(build(&reversed)));
+    }
+
+    #[test]
+    fn case_insensitive_collisions() {
+        use super::super::{parse_directory_block, CaseCollisionPolicy, TreeConstructionFailed};
+
+        let build = |policy| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.case_insensitive_collisions(policy);
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("file.txt", some_cid(0), 1).unwrap();
+            builder.put_link("File.txt", some_cid(1), 1).unwrap();
+            builder.put_link("Dir/a.txt", some_cid(2), 1).unwrap();
+            builder.put_link("dir/b.txt", some_cid(3), 1).unwrap();
+            builder.put_link("other.txt", some_cid(4), 1).unwrap();
+
+            builder.build().collect::<Result<Vec<_>, _>>()
+        };
+
+        let names = |nodes: &[OwnedTreeNode]| {
+            let root = nodes.last().unwrap();
+            parse_directory_block(&root.block)
+                .unwrap()
+                .into_iter()
+                .map(|(name, leaf)| (name, leaf.cid().to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        let allowed = build(CaseCollisionPolicy::Allow).unwrap();
+        let allowed_names = names(&allowed);
+        assert_eq!(allowed_names.len(), 5);
+        assert_eq!(allowed.len(), 3);
+
+        // the first names in the byte order are kept, which the uppercase letters come first in
+        let kept = build(CaseCollisionPolicy::KeepFirst).unwrap();
+        let paths = kept
+            .iter()
+            .map(|node| node.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &["Dir", ""]);
+
+        let kept_names = names(&kept);
+        let kept_names = kept_names
+            .iter()
+            .map(|(name, cid)| (name.as_str(), cid))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kept_names,
+            &[
+                ("Dir", &kept[0].cid),
+                ("File.txt", &some_cid(1)),
+                ("other.txt", &some_cid(4))
+            ]
+        );
+
+        match build(CaseCollisionPolicy::Error) {
+            Err(TreeConstructionFailed::CaseCollision(a, b)) => {
+                assert_eq!((a.as_str(), b.as_str()), ("Dir", "dir"));
+            }
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+    }
+
+    #[test]
+    fn deferred_leaf_sizes() {
+        use super::super::TreeConstructionFailed;
+
+        let mut deferred = BufferingTreeBuilder::default();
+        deferred.put_link_deferred("a/b.txt", some_cid(0)).unwrap();
+        deferred
+            .put_link_deferred("a/c/d.txt", some_cid(1))
+            .unwrap();
+        deferred.put_link("a/e.txt", some_cid(2), 3).unwrap();
+
+        match deferred.put_link_deferred("a/b.txt", some_cid(3)) {
+            Err(TreeBuildingFailed::DuplicatePath(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        deferred.set_leaf_size("a/c/d.txt", 2).unwrap();
+
+        // only the sizes of the deferred links can be set, and only once
+        for path in &["a/c/d.txt", "a/e.txt", "a/f.txt", "a/c", "a/c/d.txt/g"] {
+            match deferred.set_leaf_size(path, 1) {
+                Err(TreeBuildingFailed::NoDeferredLeaf(p)) => assert_eq!(&p, path),
+                x => unreachable!("{}: {:?}", path, x),
+            }
+        }
+
+        let mut unfilled = deferred.clone().build();
+        match unfilled.next() {
+            Some(Err(TreeConstructionFailed::UnfilledLeafSize(path))) => {
+                assert_eq!(path, "a/b.txt")
+            }
+            x => unreachable!("{:?}", x.map(|res| res.map(|node| node.path))),
+        }
+
+        deferred.set_leaf_size("a/b.txt", 1).unwrap();
+        let filled = deferred.build().last().unwrap().unwrap();
+
+        let mut known = BufferingTreeBuilder::default();
+        known.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        known.put_link("a/c/d.txt", some_cid(1), 2).unwrap();
+        known.put_link("a/e.txt", some_cid(2), 3).unwrap();
+        let known = known.build().last().unwrap().unwrap();
+
+        assert_eq!(filled.cid, known.cid);
+        assert_eq!(filled.total_size, known.total_size);
+    }
+
+    #[test]
+    fn from_object_links() {
+        use super::super::{encode_directory, DirBuilder};
+
+        // in the shape listed by `ipfs object links --enc=json`, with the links of the symlink and
+        // the file of `symlinks_in_trees_rooted`
+        let json = r#"{
+            "Hash": "QmYpyny3fZ8YVmqtDxUytWZGXYAWiwMKTsaG2GjBTWZ6S7",
+            "Links": [
+                {"Name": "a", "Hash": "QmfLJN6HLyREnWr7QQNmgmuNziUhcbwUopkHQ8gD3pMfp6", "Size": 7, "Type": 4},
+                {"Name": "b", "Hash": "QmNYVgoDXh3dqC1jjCuYqQ9w4XfiocehPZjEPiQiCVYv33", "Size": 12, "Type": 2}
+            ]
+        }"#;
+
+        let object = serde_json::from_str::<serde_json::Value>(json).unwrap();
+        let links = object["Links"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|link| {
+                (
+                    link["Name"].as_str().unwrap().to_owned(),
+                    Cid::try_from(link["Hash"].as_str().unwrap()).unwrap(),
+                    link["Size"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let dir = DirBuilder::from_object_links(&links).unwrap();
+        assert_eq!(dir.len(), 2);
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let (cid, block) = encode_directory(links.clone(), &opts).unwrap();
+
+        let root = dir.build(opts).last().unwrap().unwrap();
+        assert_eq!(root.cid, cid);
+        assert_eq!(&root.block[..], &block[..]);
+        assert_eq!(object["Hash"].as_str(), Some(cid.to_string().as_str()));
+
+        let mut nested = links;
+        nested[0].0 = "c/a".to_owned();
+        match DirBuilder::from_object_links(&nested) {
+            Err(TreeBuildingFailed::SlashInName(name)) => assert_eq!(name, "c/a"),
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn cloned_builders() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut original = BufferingTreeBuilder::new(opts);
+        original.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        original.put_link("a/c/d.txt", some_cid(1), 1).unwrap();
+
+        let build = |builder: BufferingTreeBuilder| {
+            builder
+                .build()
+                .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        let cloned = original.clone();
+        let from_dir_builder = original.dir_builder().clone();
+
+        let mut extended = original.clone();
+        extended.put_link("a/c/e.txt", some_cid(2), 1).unwrap();
+        extended.put_link("f.txt", some_cid(3), 1).unwrap();
+
+        let expected = build(original);
+        assert_eq!(build(cloned), expected);
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let rebuilt = from_dir_builder
+            .build(opts)
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rebuilt, expected);
+
+        let extended = build(extended);
+        assert_eq!(extended.len(), expected.len());
+        assert_ne!(extended.last(), expected.last());
+    }
+
+    #[test]
+    fn single_top_level_entry_roots() {
+        let build = |path: &str, wrap: bool| {
+            let mut opts = TreeOptions::default();
+            if wrap {
+                opts.wrap_with_directory();
+            }
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link(path, some_cid(0), 1).unwrap();
+
+            let mut iter = builder.build();
+            let nodes = (&mut iter)
+                .map(|res| res.map(|node| (node.path, node.cid, node.is_root)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let root = iter
+                .root()
+                .map(|(cid, total_size)| (cid.to_owned(), total_size));
+            (nodes, root.unwrap())
+        };
+
+        // file, not wrapped: the file is the root without any directory nodes
+        let (nodes, root) = build("a", false);
+        assert!(nodes.is_empty());
+        assert_eq!(root, (some_cid(0), 1));
+
+        // file, wrapped
+        let (nodes, root) = build("a", true);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].0, "");
+        assert!(nodes[0].2);
+        assert_eq!(root.0, nodes[0].1);
+
+        // directory, not wrapped
+        let (nodes, root) = build("a/b", false);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].0, "a");
+        assert!(nodes[0].2);
+        assert_eq!(root.0, nodes[0].1);
+
+        // directory, wrapped
+        let (nodes, root) = build("a/b", true);
+        let paths = nodes
+            .iter()
+            .map(|(path, ..)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &["a", ""]);
+        assert_eq!(
+            nodes
+                .iter()
+                .map(|(.., is_root)| *is_root)
+                .collect::<Vec<_>>(),
+            &[false, true]
+        );
+        assert_eq!(root.0, nodes[1].1);
+    }
+
+    #[test]
+    fn empty_unwrapped_tree_has_no_root() {
+        let mut iter = BufferingTreeBuilder::default().build();
+        assert!(iter.root().is_none());
+        assert!(iter.next().is_none());
+        assert!(iter.root().is_none());
+    }
+
+    #[test]
+    fn single_wrapped_root() {
+        // foobar\n
+        let five_block_foobar =
+            Cid::try_from("QmRJHYTNvC3hmd9gJQARxLR1QMEincccBV53bBw524yyq6").unwrap();
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a", five_block_foobar, 221).unwrap();
+
+        let actual = builder
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid.to_string())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            actual,
+            &[(
+                "".to_string(),
+                "QmQBseoi3b2FBrYhjM2E4mCF4Q7C8MgCUbzAbGNfyVwgNk".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn denied_multiple_root_dirs() {
+        let mut builder = BufferingTreeBuilder::default();
+        builder.put_link("a/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("b/d.txt", some_cid(1), 1).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn denied_multiple_root_files() {
+        let mut builder = BufferingTreeBuilder::default();
+        builder.put_link("a.txt", some_cid(0), 1).unwrap();
+        builder.put_link("b.txt", some_cid(1), 1).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn using_leaf_as_node() {
+        let mut builder = BufferingTreeBuilder::default();
+        builder.put_link("a.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a.txt/b.txt", some_cid(1), 1).unwrap();
+    }
+
+    #[test]
+    fn set_metadata_before_files() {
+        let mut builder = BufferingTreeBuilder::default();
+        builder
+            .set_metadata("a/b/c/d", Metadata::default())
+            .unwrap();
+        builder.put_link("a/b/c/d/e.txt", some_cid(1), 1).unwrap();
+        builder.put_link("a/b/c/d/f.txt", some_cid(2), 1).unwrap();
+
+        let actual = builder
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, .. }| path))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(actual, &["a/b/c/d", "a/b/c", "a/b", "a",])
+    }
+
+    #[test]
+    fn rendered_metadata() {
+        use crate::pb::FlatUnixFs;
+
+        let metadata = Metadata {
+            mode: Some(0o750),
+            mtime: Some((1_600_000_000, 5)),
+        };
+
+        for &sharded in &[false, true] {
+            let mut opts = TreeOptions::default();
+            if sharded {
+                opts.sharding_threshold(Some(0));
+            }
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.set_metadata("a", metadata.clone()).unwrap();
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+            let root = builder.build().last().unwrap().unwrap();
+            let flat = FlatUnixFs::try_parse(&root.block).unwrap();
+            assert_eq!(Metadata::from(&flat.data), metadata, "sharded: {}", sharded);
+        }
+    }
+
+    #[test]
+    fn set_metadata_on_file() {
+        let mut builder = BufferingTreeBuilder::default();
+        builder.put_link("a/a.txt", some_cid(0), 1).unwrap();
+        let err = builder
+            .set_metadata("a/a.txt", Metadata::default())
+            .unwrap_err();
+
+        assert!(
+            matches!(err, TreeBuildingFailed::LeafAsDirectory(_)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn dir_with_cidv1_link() {
+        // this is `echo '{ "name": "hello" }` | ./ipfs dag put`
+        let target =
+            Cid::try_from("bafyreihakpd7te5nbmlhdk5ntvcvhf2hmfgrvcwna2sddq5zz5342mcbli").unwrap();
+
+        let mut opts = TreeOptions::default();
+        opts.allow_non_unixfs_links();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b", target, 12).unwrap();
+
+        let actual = builder
+            .build()
+            .map(|res| res.map(|n| (n.path, n.cid, n.block)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let expected = vec![("a", "QmPMDMPG8dbHDC9GuvqWr9pfruLnp4GZCAWrskwCmenVQa")];
+
+        verify_results(expected, actual);
+    }
+
+    #[test]
+    fn non_unixfs_link_denied_by_default() {
+        use super::super::{encode_directory, TreeConstructionFailed};
+
+        let target =
+            Cid::try_from("bafyreihakpd7te5nbmlhdk5ntvcvhf2hmfgrvcwna2sddq5zz5342mcbli").unwrap();
+
+        let mut builder = BufferingTreeBuilder::default();
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/c", target.clone(), 12).unwrap();
+
+        match builder.build().collect::<Result<Vec<_>, _>>() {
+            Err(TreeConstructionFailed::UnsupportedLinkCodec {
+                name,
+                codec: cid::Codec::DagCBOR,
+            }) if name == "c" => {}
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+
+        match encode_directory(vec![("c".into(), target, 12)], &TreeOptions::default()) {
+            Err(TreeConstructionFailed::UnsupportedLinkCodec { .. }) => {}
+            x => unreachable!("{:?}", x.map(|(cid, _)| cid)),
+        }
+    }
+
+    /// Builds the tree and compares the rendered blocks to the go-ipfs created blocks in
+    /// `FakeBlockstore::with_fixtures`.
+    fn assert_go_ipfs_fixture(builder: BufferingTreeBuilder, expected: &[(&str, &str)]) {
+        use crate::test_support::FakeBlockstore;
+
+        let blocks = FakeBlockstore::with_fixtures();
+
+        let actual = builder
+            .build()
+            .map(|res| res.map(|n| (n.path, n.cid, n.block)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        for ((path, cid, block), (expected_path, expected_cid)) in actual.iter().zip(expected) {
+            assert_eq!(path, expected_path);
+            assert_eq!(cid.to_string(), *expected_cid, "{:?}", path);
+            assert_eq!(&block[..], blocks.get_by_cid(cid), "{:?}", path);
+        }
+
+        assert_eq!(actual.len(), expected.len());
+    }
+
+    #[test]
+    fn go_ipfs_empty_directory() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let actual = BufferingTreeBuilder::new(opts)
+            .build()
+            .map(|res| res.map(|OwnedTreeNode { path, cid, .. }| (path, cid.to_string())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        // ipfs object new unixfs-dir
+        assert_eq!(
+            actual,
+            &[(
+                "".to_string(),
+                "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn go_ipfs_mfs_root_data() {
+        use super::super::RootData;
+        use hex_literal::hex;
+
+        let build = |data: RootData| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.root_data(data);
+
+            BufferingTreeBuilder::new(opts)
+                .build()
+                .map(|res| res.map(|node| (node.cid.to_string(), node.block)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .pop()
+                .unwrap()
+        };
+
+        // the empty go-ipfs MFS root is the same as `ipfs object new unixfs-dir`
+        let mfs_root = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn";
+
+        assert_eq!(build(RootData::Directory).0, mfs_root);
+        assert_eq!(build(RootData::Raw(hex!("0801").to_vec())).0, mfs_root);
+
+        // the empty and the missing data are different
+        assert_eq!(&build(RootData::Raw(Vec::new())).1[..], &hex!("0a00")[..]);
+        assert!(build(RootData::Absent).1.is_empty());
+
+        // only the root is affected
+        let mut opts = TreeOptions::default();
+        opts.root_data(RootData::Absent);
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+
+        let blocks = builder
+            .build()
+            .map(|res| res.map(|node| node.block))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert!(blocks[0].ends_with(&hex!("0a020801")));
+        assert!(!blocks[1].ends_with(&hex!("0a020801")));
+    }
+
+    #[test]
+    fn omitted_directory_data() {
+        use crate::pb::PBNode;
+
+        let build = |omit: bool, paths: &[&str]| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            if omit {
+                opts.omit_directory_data();
+            }
+
+            let mut builder = BufferingTreeBuilder::new(opts);
+            for path in paths {
+                builder.put_link(path, some_cid(0), 1).unwrap();
+            }
+            builder.build().collect::<Result<Vec<_>, _>>().unwrap()
+        };
+
+        // the empty dag-pb node, `ipfs object new`
+        assert_eq!(
+            build(true, &[]).pop().unwrap().cid.to_string(),
+            "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n"
+        );
+
+        let with_data = build(false, &["a/b.txt"]);
+        let without_data = build(true, &["a/b.txt"]);
+
+        for (with, without) in with_data.iter().zip(&without_data) {
+            assert_ne!(with.cid, without.cid, "{:?}", with.path);
+            assert_eq!(with.block.len() - without.block.len(), 4);
+
+            let node = PBNode::try_from(&without.block[..]).unwrap();
+            assert!(node.Data.is_none());
+            assert_eq!(node.Links.len(), 1);
+        }
+    }
+
+    #[test]
+    fn go_ipfs_nested_directories() {
+        // the same "foobar\n" file added with balanced and trickle layouts
+        let balanced = Cid::try_from("QmRJHYTNvC3hmd9gJQARxLR1QMEincccBV53bBw524yyq6").unwrap();
+        let trickle = Cid::try_from("QmWfQ48ChJUj4vWKFsUDe4646xCBmXgdmNfhjz9T7crywd").unwrap();
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        // the inner directory is named after its own Cid; the trickle file is added first but
+        // needs to be sorted last
+        let dir = "QmVkvLsSEm2uJx1h5Fqukje8mMPYg393o5C2kMCkF2bBTA";
+        builder
+            .put_link(&format!("{}/foobar.trickle", dir), trickle, 221)
+            .unwrap();
+        builder
+            .put_link(&format!("{}/foobar.balanced", dir), balanced, 221)
+            .unwrap();
+
+        assert_go_ipfs_fixture(
+            builder,
+            &[
+                (dir, dir),
+                ("", "QmPTotyhVnnfCu9R4qwR4cdhpi5ENaiP8ZJfdqsm8Dw2jB"),
+            ],
+        );
+    }
+
+    #[test]
+    fn go_ipfs_total_sizes() {
+        use crate::pb::PBNode;
+        use crate::test_support::FakeBlockstore;
+
+        /// The names, `Cid`s and `Tsize`s of the links of the go-ipfs directory.
+        fn links(blocks: &FakeBlockstore, cid: &Cid) -> Vec<(String, Cid, u64)> {
+            PBNode::try_from(blocks.get_by_cid(cid))
+                .unwrap()
+                .Links
+                .iter()
+                .map(|link| {
+                    let name = link.Name.as_deref().unwrap().to_owned();
+                    let cid = Cid::try_from(link.Hash.as_deref().unwrap()).unwrap();
+                    (name, cid, link.Tsize.unwrap())
+                })
+                .collect()
+        }
+
+        let blocks = FakeBlockstore::with_fixtures();
+        let dir = Cid::try_from("QmVkvLsSEm2uJx1h5Fqukje8mMPYg393o5C2kMCkF2bBTA").unwrap();
+        let root = Cid::try_from("QmPTotyhVnnfCu9R4qwR4cdhpi5ENaiP8ZJfdqsm8Dw2jB").unwrap();
+
+        let files = links(&blocks, &dir);
+        let parent_links = links(&blocks, &root);
+        assert_eq!(files.len(), 2);
+        assert_eq!(parent_links.len(), 1);
+
+        let (dir_name, linked_dir, dir_tsize) = &parent_links[0];
+        assert_eq!(linked_dir, &dir);
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        // the sizes of the files are the Tsizes go-ipfs gave them
+        for (name, cid, tsize) in &files {
+            builder
+                .put_link(&format!("{}/{}", dir_name, name), cid.clone(), *tsize)
+                .unwrap();
+        }
+
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(nodes.len(), 2);
+
+        // the Tsize of the go-ipfs link to the directory
+        assert_eq!(nodes[0].cid, dir);
+        assert_eq!(nodes[0].total_size, *dir_tsize);
+
+        // there is no link to the root, but its Tsize would be the same sum over its block
+        assert_eq!(nodes[1].cid, root);
+        let root_block = blocks.get_by_cid(&root);
+        assert_eq!(nodes[1].total_size, root_block.len() as u64 + dir_tsize);
+    }
+
+    #[test]
+    fn go_ipfs_sharded_directory() {
+        let empty = Cid::try_from("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        opts.sharding_threshold(Some(0));
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        // names picked so that every bucket of the root has two links, each with a nested bucket
+        for i in &[38, 48, 50, 58, 9, 33, 4, 34, 17, 37, 40, 16, 41, 3, 25, 49] {
+            let name = format!("long-named-file-{:03}", i);
+            builder.put_link(&name, empty.clone(), 6).unwrap();
+        }
+
+        let blocks = crate::test_support::FakeBlockstore::with_fixtures();
+        let mut iter = builder.build();
+        let mut nodes = (&mut iter).collect::<Result<Vec<_>, _>>().unwrap();
+
+        // all of the buckets are the same as created by go-ipfs
+        for node in &nodes {
+            assert_eq!(&node.block[..], blocks.get_by_cid(&node.cid));
+        }
+
+        let root = nodes.pop().unwrap();
+        assert_eq!(
+            root.cid.to_string(),
+            "QmZbFPTnDBMWbQ6iBxQAhuhLz8Nu9XptYS96e7cuf5wvbk"
+        );
+        assert_eq!(iter.summary().hamt_buckets, 9);
+    }
+
+    #[test]
+    fn go_ipfs_sharded_directory_with_plain_subdirectory() {
+        // single block "foobar\n"
+        let foobar = Cid::try_from("QmRgutAxd8t7oGkSm4wmeuByG6M51wcTso6cubDdQtuEfL").unwrap();
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        // as plain directories the subdirectory would be 52 bytes and the root 62 bytes
+        opts.sharding_threshold(Some(55));
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        builder
+            .put_link("non_sharded_dir/foobar", foobar, 15)
+            .unwrap();
+
+        assert_go_ipfs_fixture(
+            builder,
+            &[
+                (
+                    "non_sharded_dir",
+                    "QmYmmkD3dGZjuozuqSzDYjU4ZyhAgc4T4P4SUgY6qjzBi8",
+                ),
+                ("", "QmQXUANxYGpkwMTWQUdZBPx9jqfFP7acNgL4FHRWkndKCe"),
+            ],
+        );
+    }
+
+    fn verify_results(
+        mut expected: Vec<(
+            impl AsRef<str> + core::fmt::Debug,
+            impl AsRef<str> + core::fmt::Debug,
+        )>,
+        mut actual: Vec<(String, Cid, Box<[u8]>)>,
+    ) {
+        use core::fmt;
+
+        struct Hex<'a>(&'a [u8]);
+
+        impl<'a> fmt::Debug for Hex<'a> {
+            fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                for b in self.0 {
+                    write!(fmt, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+
+        // hopefully this way the errors will be easier to hunt down
+
+        actual.reverse();
+        expected.reverse();
+
+        while let Some(actual) = actual.pop() {
+            let expected = expected.pop().expect("size mismatch");
+            assert_eq!(actual.0, expected.0.as_ref());
+            assert_eq!(
+                actual.1.to_string(),
+                expected.1.as_ref(),
+                "{:?}: {:?}",
+                actual.0,
+                Hex(&actual.2)
+            );
+        }
+
+        assert_eq!(expected.len(), 0, "size mismatch: {:?}", actual);
     }
 }
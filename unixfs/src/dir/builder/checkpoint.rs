@@ -0,0 +1,219 @@
+//! Serializable state of a `PostOrderIterator`, enabled with the `serde` feature.
+
+use super::iter::Visited;
+use super::{BuildSummary, NamedLeaf};
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Resumable state of an interrupted `PostOrderIterator`, created with
+/// `PostOrderIterator::into_checkpoint`. The checkpoint contains the yet to be rendered parts of
+/// the tree along with the already rendered child links, so the iteration can be continued with
+/// `PostOrderIterator::from_checkpoint` exactly where it stopped.
+///
+/// The checkpoint does not contain the `TreeOptions`, which must be given again when resuming.
+/// Resuming with different options than the iterator was originally created with will most
+/// likely produce an inconsistent tree or panic.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The path of the latest yielded node, without the `TreeOptions::path_prefix`.
+    pub(super) full_path: String,
+    pub(super) old_depth: usize,
+    pub(super) pending: Vec<Visited>,
+    pub(super) persisted_cids: HashMap<u64, Vec<Option<NamedLeaf>>>,
+    #[serde(with = "cid_bytes::option")]
+    pub(super) cid: Option<Cid>,
+    pub(super) total_size: u64,
+    pub(super) summary: BuildSummary,
+    pub(super) next_id: u64,
+}
+
+/// Serializes `Cid` values in their binary representation.
+pub(super) mod cid_bytes {
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&cid.to_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Cid, D::Error> {
+        let bytes = <Vec<u8>>::deserialize(deserializer)?;
+        Cid::try_from(bytes).map_err(D::Error::custom)
+    }
+
+    pub mod option {
+        use cid::Cid;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        #[derive(Serialize, Deserialize)]
+        struct Wrapper(#[serde(with = "super")] Cid);
+
+        pub fn serialize<S: Serializer>(
+            cid: &Option<Cid>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            cid.as_ref()
+                .map(|cid| Wrapper(cid.to_owned()))
+                .serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Cid>, D::Error> {
+            Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(cid)| cid))
+        }
+    }
+}
+
+/// Serializes `Cid` values in their canonical string representation.
+pub(super) mod cid_string {
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(cid: &Cid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(cid)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Cid, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Cid::try_from(s.as_str()).map_err(D::Error::custom)
+    }
+}
+
+/// Serializes the blocks as bytes instead of a sequence of numbers.
+pub(super) mod boxed_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<[u8]>, D::Error> {
+        Ok(<Vec<u8>>::deserialize(deserializer)?.into_boxed_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{PostOrderIterator, TreeOptions};
+    use super::Checkpoint;
+    use crate::test_support::builder_with;
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let paths = ["a/b/c.txt", "a/b/d.txt", "a/e/f.txt", "a/g.txt", "h/i.txt"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+
+        let opts = || {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts
+        };
+
+        let len = assert_resumes_at_every_node(opts, &paths);
+        assert_eq!(len, 5);
+    }
+
+    #[test]
+    fn resume_sharded_from_checkpoint() {
+        let paths = (0..300).map(|i| format!("a/{}.txt", i)).collect::<Vec<_>>();
+
+        let opts = || {
+            let mut opts = TreeOptions::default();
+            opts.sharding_threshold(Some(1024));
+            opts
+        };
+
+        let len = assert_resumes_at_every_node(opts, &paths);
+        assert!(len > 2, "{}", len);
+    }
+
+    #[test]
+    #[cfg(feature = "spill")]
+    fn resume_spilled_from_checkpoint() {
+        let paths = (0..30)
+            .map(|i| format!("{}/{}/{}.txt", i % 3, i % 5, i))
+            .collect::<Vec<_>>();
+
+        let opts = || {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.spill_budget(Some(0));
+            opts
+        };
+
+        let len = assert_resumes_at_every_node(opts, &paths);
+        assert_eq!(len, 19);
+    }
+
+    #[test]
+    fn owned_tree_node_round_trip() {
+        use super::super::OwnedTreeNode;
+
+        let paths = ["a/b.txt".to_string(), "a/c/d.txt".to_string()];
+        let nodes = builder_with(TreeOptions::default(), &paths)
+            .build()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let json = serde_json::to_string(&nodes).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[1]["path"], "a");
+        assert_eq!(value[1]["cid"], nodes[1].cid.to_string());
+        assert_eq!(value[1]["is_root"], true);
+
+        let parsed: Vec<OwnedTreeNode> = serde_json::from_str(&json).unwrap();
+
+        let fields = |nodes: &[OwnedTreeNode]| {
+            nodes
+                .iter()
+                .map(|n| {
+                    let fields = (n.path.clone(), n.cid.clone(), n.total_size);
+                    (fields, n.block.clone(), n.is_root)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(fields(&parsed), fields(&nodes));
+    }
+
+    /// Returns the number of nodes in the tree.
+    fn assert_resumes_at_every_node(opts: impl Fn() -> TreeOptions, paths: &[String]) -> usize {
+        let expected = builder_with(opts(), paths)
+            .build()
+            .map(|res| res.map(|n| (n.path, n.cid)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        for stop_after in 0..expected.len() {
+            let mut iter = builder_with(opts(), paths).build();
+
+            let mut actual = (&mut iter)
+                .take(stop_after)
+                .map(|res| res.map(|n| (n.path, n.cid)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            let json = serde_json::to_string(&iter.into_checkpoint()).unwrap();
+            let checkpoint: Checkpoint = serde_json::from_str(&json).unwrap();
+
+            let resumed = PostOrderIterator::from_checkpoint(checkpoint, opts());
+
+            actual.extend(
+                resumed
+                    .map(|res| res.map(|n| (n.path, n.cid)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap(),
+            );
+
+            assert_eq!(actual, expected, "stopped after {}", stop_after);
+        }
+
+        expected.len()
+    }
+}
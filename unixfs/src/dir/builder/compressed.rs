@@ -0,0 +1,215 @@
+use super::sink::encode_varint;
+use super::PostOrderIterator;
+use cid::Cid;
+use std::io;
+
+/// The magic bytes starting the header of every block written by
+/// `PostOrderIterator::write_compressed_blocks`.
+pub const COMPRESSED_BLOCK_MAGIC: [u8; 4] = *b"UFSC";
+
+/// The compression algorithm recorded in the header of the blocks written by
+/// `PostOrderIterator::write_compressed_blocks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard, recorded as the tag `1`.
+    Zstd,
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl PostOrderIterator {
+    /// Same as `PostOrderIterator::write_encoded_blocks` but each block is written compressed
+    /// after a small header: the `COMPRESSED_BLOCK_MAGIC`, the tag of the `compression` and the
+    /// varint encoded length of the uncompressed block. The `compress` is given the block and the
+    /// buffer to append the compressed bytes to, for example with
+    /// `zstd::stream::copy_encode(block, out, 0)` for `Compression::Zstd`.
+    ///
+    /// The `Cid`s are always those of the uncompressed blocks; `decompress_block` reads a block
+    /// back from the returned offset and length.
+    ///
+    /// Note: the output is not a standard format and cannot be read as a CAR file or by any other
+    /// implementation.
+    pub fn write_compressed_blocks<W, F>(
+        self,
+        w: W,
+        compression: Compression,
+        mut compress: F,
+    ) -> io::Result<Vec<(Cid, u64, u64)>>
+    where
+        W: io::Write,
+        F: FnMut(&[u8], &mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut len_buffer = [0u8; 10];
+
+        self.write_encoded_blocks(w, |block, out| {
+            out.extend_from_slice(&COMPRESSED_BLOCK_MAGIC);
+            out.push(compression.tag());
+            out.extend_from_slice(encode_varint(block.len() as u64, &mut len_buffer));
+            compress(block, out)
+        })
+    }
+}
+
+/// Reads back a block written by `PostOrderIterator::write_compressed_blocks`, where the `encoded`
+/// are the bytes at the offset and length of the block in the returned index. The `decompress` is
+/// given the compression of the block, the compressed bytes and the buffer to append the
+/// uncompressed block to.
+///
+/// Fails with an `io::Error` of kind `InvalidData` if the header is not valid or the uncompressed
+/// block is not of the length recorded in the header. The returned block should still be checked
+/// with `verify_block` against its `Cid`.
+pub fn decompress_block<F>(encoded: &[u8], mut decompress: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(Compression, &[u8], &mut Vec<u8>) -> io::Result<()>,
+{
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let rest = encoded
+        .strip_prefix(&COMPRESSED_BLOCK_MAGIC[..])
+        .ok_or_else(|| invalid("missing compressed block header"))?;
+
+    let (&tag, rest) = rest
+        .split_first()
+        .ok_or_else(|| invalid("truncated compressed block header"))?;
+
+    let compression = Compression::from_tag(tag).ok_or_else(|| invalid("unknown compression"))?;
+
+    let (len, rest) = decode_varint(rest).ok_or_else(|| invalid("invalid uncompressed length"))?;
+
+    let mut block = Vec::new();
+    decompress(compression, rest, &mut block)?;
+
+    if block.len() as u64 != len {
+        return Err(invalid("uncompressed length does not match the header"));
+    }
+
+    Ok(block)
+}
+
+/// Decodes an unsigned LEB128 varint from the start of `bytes`, returning it and the rest of the
+/// bytes.
+fn decode_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_varint, decompress_block, Compression, COMPRESSED_BLOCK_MAGIC};
+    use crate::dir::builder::sink::encode_varint;
+    use crate::dir::builder::{verify_block, TreeOptions};
+    use crate::test_support::small_tree;
+
+    // stand-ins for a compressor; the bytes are stored reversed
+    fn compress(block: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+        out.extend(block.iter().rev());
+        Ok(())
+    }
+
+    fn decompress(
+        compression: Compression,
+        compressed: &[u8],
+        out: &mut Vec<u8>,
+    ) -> std::io::Result<()> {
+        assert_eq!(compression, Compression::Zstd);
+        out.extend(compressed.iter().rev());
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_blocks_round_trip() {
+        let mut out = Vec::new();
+        let index = small_tree(TreeOptions::default())
+            .build()
+            .write_compressed_blocks(&mut out, Compression::Zstd, compress)
+            .unwrap();
+
+        let expected = small_tree(TreeOptions::default())
+            .build()
+            .map(|res| res.map(|node| (node.cid, node.block)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(index.len(), expected.len());
+
+        for ((cid, offset, len), (expected_cid, expected_block)) in
+            index.iter().zip(expected.iter())
+        {
+            let encoded = &out[*offset as usize..][..*len as usize];
+            assert_eq!(&encoded[..4], &COMPRESSED_BLOCK_MAGIC);
+            assert_eq!(encoded[4], 1);
+
+            let block = decompress_block(encoded, decompress).unwrap();
+            assert_eq!(&block[..], &expected_block[..]);
+
+            // the cids are of the uncompressed blocks
+            assert_eq!(cid, expected_cid);
+            verify_block(cid, &block).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_headers_fail() {
+        let mut out = Vec::new();
+        let index = small_tree(TreeOptions::default())
+            .build()
+            .write_compressed_blocks(&mut out, Compression::Zstd, compress)
+            .unwrap();
+
+        let (_, offset, len) = index[0];
+        let encoded = &out[offset as usize..][..len as usize];
+
+        let mut unknown = encoded.to_vec();
+        unknown[4] = 0xff;
+
+        let truncated = |_: Compression, compressed: &[u8], out: &mut Vec<u8>| {
+            out.extend(compressed.iter().rev().skip(1));
+            Ok(())
+        };
+
+        let failures = [
+            decompress_block(&encoded[1..], decompress),
+            decompress_block(&encoded[..4], decompress),
+            decompress_block(&unknown, decompress),
+            decompress_block(encoded, truncated),
+        ];
+
+        for failure in &failures {
+            match failure {
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {}
+                x => unreachable!("{:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut buffer = [0u8; 10];
+        for &value in &[0, 127, 128, 300, u64::MAX] {
+            let encoded = encode_varint(value, &mut buffer);
+            assert_eq!(decode_varint(encoded), Some((value, &[][..])));
+        }
+
+        assert_eq!(decode_varint(&[0x80]), None);
+        assert_eq!(decode_varint(&[0xff; 11]), None);
+    }
+}
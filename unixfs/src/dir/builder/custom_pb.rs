@@ -1,5 +1,6 @@
 //! Custom protobuf types which are used in encoding directorys.
 
+use super::tags::*;
 use super::NamedLeaf;
 use crate::pb::UnixFs;
 use cid::Cid;
@@ -8,14 +9,52 @@ use quick_protobuf::{MessageWrite, Writer, WriterBackend};
 /// Newtype which uses the &[Option<(NamedLeaf)>] as Vec<PBLink>.
 pub(super) struct CustomFlatUnixFs<'a> {
     pub(super) links: &'a [Option<NamedLeaf>],
-    pub(super) data: UnixFs<'a>,
+    pub(super) data: NodeData<'a>,
+    /// When true, the `PBLink::Tsize` is not written.
+    pub(super) omit_tsize: bool,
+    /// When true, the `PBNode::Data` is written before the links.
+    pub(super) data_first: bool,
+}
+
+/// The `PBNode::Data` of the rendered node.
+pub(super) enum NodeData<'a> {
+    /// The usual UnixFs message.
+    UnixFs(UnixFs<'a>),
+    /// Already serialized bytes, written as is.
+    Raw(&'a [u8]),
+    /// No `PBNode::Data` field at all.
+    Absent,
+}
+
+impl<'a> NodeData<'a> {
+    fn get_size(&self) -> usize {
+        use quick_protobuf::sizeofs::*;
+
+        match self {
+            NodeData::UnixFs(data) => 1 + sizeof_len(data.get_size()),
+            NodeData::Raw(bytes) => 1 + sizeof_len(bytes.len()),
+            NodeData::Absent => 0,
+        }
+    }
+
+    fn write<W: WriterBackend>(&self, w: &mut Writer<W>) -> quick_protobuf::Result<()> {
+        match self {
+            NodeData::UnixFs(data) => w.write_with_tag(PBNODE_DATA_TAG, |w| w.write_message(data)),
+            NodeData::Raw(bytes) => w.write_with_tag(PBNODE_DATA_TAG, |w| w.write_bytes(bytes)),
+            NodeData::Absent => Ok(()),
+        }
+    }
 }
 
 impl<'a> CustomFlatUnixFs<'a> {
     fn mapped(&self) -> impl Iterator<Item = NamedLeafAsPBLink<'_>> + '_ {
-        self.links
-            .iter()
-            .map(|triple| triple.as_ref().map(|l| NamedLeafAsPBLink(l)).unwrap())
+        let omit_tsize = self.omit_tsize;
+        self.links.iter().map(move |triple| {
+            triple
+                .as_ref()
+                .map(|l| NamedLeafAsPBLink(l, omit_tsize))
+                .unwrap()
+        })
     }
 }
 
@@ -28,41 +67,56 @@ impl<'a> MessageWrite for CustomFlatUnixFs<'a> {
             .map(|link| 1 + sizeof_len(link.get_size()))
             .sum::<usize>();
 
-        links + 1 + sizeof_len(self.data.get_size())
+        links + self.data.get_size()
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> quick_protobuf::Result<()> {
+        // dag-pb canonical form, as written by go-ipfs, has the links first and data last
+        if self.data_first {
+            self.data.write(w)?;
+        }
         self.mapped()
-            .try_for_each(|l| w.write_with_tag(18, |w| w.write_message(&l)))?;
-        w.write_with_tag(10, |w| w.write_message(&self.data))
+            .try_for_each(|l| w.write_with_tag(PBNODE_LINKS_TAG, |w| w.write_message(&l)))?;
+        if !self.data_first {
+            self.data.write(w)?;
+        }
+        Ok(())
     }
 }
 
-/// Custom NamedLeaf as PBLink "adapter."
-struct NamedLeafAsPBLink<'a>(&'a NamedLeaf);
+/// Custom NamedLeaf as PBLink "adapter," optionally without the Tsize.
+struct NamedLeafAsPBLink<'a>(&'a NamedLeaf, bool);
 
 impl<'a> MessageWrite for NamedLeafAsPBLink<'a> {
     fn get_size(&self) -> usize {
-        use quick_protobuf::sizeofs::*;
-
-        // ones are the tags
-        1 + sizeof_len((self.0).0.len())
-            + 1
-            + sizeof_len(WriteableCid(&(self.0).1).get_size())
-            //+ sizeof_len(self.1.link.to_bytes().len())
-            + 1
-            + sizeof_varint((self.0).2)
+        let NamedLeaf(name, cid, total_size) = self.0;
+        link_size(name, cid, Some(*total_size).filter(|_| !self.1))
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> quick_protobuf::Result<()> {
-        w.write_with_tag(10, |w| w.write_message(&WriteableCid(&(self.0).1)))?;
-        //w.write_with_tag(10, |w| w.write_bytes(&self.1.link.to_bytes()))?;
-        w.write_with_tag(18, |w| w.write_string((self.0).0.as_str()))?;
-        w.write_with_tag(24, |w| w.write_uint64((self.0).2))?;
+        w.write_with_tag(PBLINK_HASH_TAG, |w| {
+            w.write_message(&WriteableCid(&(self.0).1))
+        })?;
+        w.write_with_tag(PBLINK_NAME_TAG, |w| w.write_string((self.0).0.as_str()))?;
+        if !self.1 {
+            w.write_with_tag(PBLINK_TSIZE_TAG, |w| w.write_uint64((self.0).2))?;
+        }
         Ok(())
     }
 }
 
+/// Returns the serialized size of the `PBLink` without the tag and the length prefix, with
+/// `total_size` of `None` leaving out the `Tsize`.
+pub(super) fn link_size(name: &str, cid: &Cid, total_size: Option<u64>) -> usize {
+    use quick_protobuf::sizeofs::*;
+
+    // ones are the tags
+    1 + sizeof_len(name.len())
+        + 1
+        + sizeof_len(WriteableCid(cid).get_size())
+        + total_size.map(|size| 1 + sizeof_varint(size)).unwrap_or(0)
+}
+
 /// Newtype around Cid to allow embedding it as PBLink::Hash without allocating a vector.
 struct WriteableCid<'a>(&'a Cid);
 
@@ -0,0 +1,157 @@
+use super::{DirBuilder, Entry};
+use core::cmp::Ordering;
+
+/// Change to a single path between two trees, see `diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathChange {
+    /// The path exists only in the new tree.
+    Added(String),
+    /// The path exists only in the old tree.
+    Removed(String),
+    /// The path is a leaf in both trees but with a different `Cid` or total size, or it changed
+    /// between a leaf and a directory.
+    Modified(String),
+}
+
+impl PathChange {
+    /// Returns the slash separated path of the changed entry.
+    pub fn path(&self) -> &str {
+        match self {
+            PathChange::Added(path) | PathChange::Removed(path) | PathChange::Modified(path) => {
+                path
+            }
+        }
+    }
+}
+
+/// Compares the trees starting from the two root directories, returning the changed paths in the
+/// order of the paths, relative to the roots. Only the topmost added or removed path is reported,
+/// so a directory added with all of its contents is a single `PathChange::Added`. The directories
+/// which are in both trees are not reported, but any of their changes imply that they, and all
+/// of their ancestors, need to be rendered again. The metadata of the directories is not
+/// compared, as it is not rendered.
+pub fn diff(old: &DirBuilder, new: &DirBuilder) -> Vec<PathChange> {
+    let mut changes = Vec::new();
+    diff_into(old, new, "", &mut changes);
+    changes
+}
+
+fn diff_into(old: &DirBuilder, new: &DirBuilder, prefix: &str, changes: &mut Vec<PathChange>) {
+    let path = |name: &str| {
+        if prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{}", prefix, name)
+        }
+    };
+
+    let mut old_entries = old.nodes.iter().peekable();
+    let mut new_entries = new.nodes.iter().peekable();
+
+    loop {
+        let ordering = match (old_entries.peek(), new_entries.peek()) {
+            (Some((old_name, _)), Some((new_name, _))) => old_name.cmp(new_name),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return,
+        };
+
+        match ordering {
+            Ordering::Less => {
+                let (name, _) = old_entries.next().unwrap();
+                changes.push(PathChange::Removed(path(name)));
+            }
+            Ordering::Greater => {
+                let (name, _) = new_entries.next().unwrap();
+                changes.push(PathChange::Added(path(name)));
+            }
+            Ordering::Equal => {
+                let (name, old_entry) = old_entries.next().unwrap();
+                let (_, new_entry) = new_entries.next().unwrap();
+
+                match (old_entry, new_entry) {
+                    (Entry::Directory(old), Entry::Directory(new)) => {
+                        diff_into(old, new, &path(name), changes)
+                    }
+                    (Entry::Leaf(old), Entry::Leaf(new))
+                        if old.link == new.link && old.total_size == new.total_size => {}
+                    (Entry::Deferred(old), Entry::Deferred(new)) if old == new => {}
+                    _ => changes.push(PathChange::Modified(path(name))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, PathChange};
+    use crate::dir::builder::{BufferingTreeBuilder, DirBuilder, TreeOptions};
+    use crate::test_support::some_cid;
+
+    fn tree(links: &[(&str, usize)]) -> DirBuilder {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        for (path, n) in links {
+            builder.put_link(path, some_cid(*n), 1).unwrap();
+        }
+        builder.dir_builder().clone()
+    }
+
+    #[test]
+    fn identical_trees() {
+        let links = [("a/b.txt", 0), ("a/c/d.txt", 1)];
+        assert_eq!(diff(&tree(&links), &tree(&links)), &[]);
+    }
+
+    #[test]
+    fn added_removed_and_modified() {
+        let old = tree(&[
+            ("a/b.txt", 0),
+            ("a/c/d.txt", 1),
+            ("a/c/e.txt", 2),
+            ("a/f/g.txt", 3),
+            ("a/h", 4),
+        ]);
+        let new = tree(&[
+            ("a/b.txt", 0),
+            ("a/c/d.txt", 5),
+            ("a/c/x.txt", 2),
+            ("a/h/i.txt", 4),
+            ("a/j/k/l.txt", 6),
+        ]);
+
+        assert_eq!(
+            diff(&old, &new),
+            &[
+                PathChange::Modified("a/c/d.txt".into()),
+                PathChange::Removed("a/c/e.txt".into()),
+                PathChange::Added("a/c/x.txt".into()),
+                PathChange::Removed("a/f".into()),
+                // leaf turned into a directory
+                PathChange::Modified("a/h".into()),
+                PathChange::Added("a/j".into()),
+            ]
+        );
+
+        let reversed = diff(&new, &old)
+            .iter()
+            .map(|change| change.path().to_owned())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            reversed,
+            &["a/c/d.txt", "a/c/e.txt", "a/c/x.txt", "a/f", "a/h", "a/j"]
+        );
+    }
+
+    #[test]
+    fn changed_total_size_is_modified() {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a.txt", some_cid(0), 2).unwrap();
+
+        assert_eq!(
+            diff(&tree(&[("a.txt", 0)]), builder.dir_builder()),
+            &[PathChange::Modified("a.txt".into())]
+        );
+    }
+}
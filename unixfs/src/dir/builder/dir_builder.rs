@@ -1,27 +1,35 @@
-use super::{Entry, Leaf};
+use super::{
+    BufferingTreeBuilder, Entry, EntryKind, Leaf, PostOrderIterator, TreeBuildingFailed,
+    TreeConstructionFailed, TreeOptions,
+};
 use crate::Metadata;
 use alloc::collections::btree_map::Entry::*;
 use alloc::collections::BTreeMap;
+use cid::Cid;
+use std::collections::HashSet;
 
 pub(super) struct DuplicateName;
 pub(super) struct FoundLeaf;
 
 /// Node in a directory tree.
-#[derive(Debug)]
-pub(super) struct DirBuilder {
+///
+/// Cloning creates a deep copy of the tree, which builds into the same `Cid`s as the original.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirBuilder {
     /// Immediate files, symlinks or directories in this directory
-    pub nodes: BTreeMap<String, Entry>,
+    pub(super) nodes: BTreeMap<String, Entry>,
     /// Metadata for this directory
-    metadata: Metadata,
+    pub(super) metadata: Metadata,
     /// Id of the parent; None for the root node
-    pub parent_id: Option<u64>,
+    pub(super) parent_id: Option<u64>,
     /// Internal id, used for propagating Cids back from children during post order visit.
-    pub id: u64,
+    pub(super) id: u64,
 }
 
 impl DirBuilder {
-    pub fn new(parent_id: u64, id: u64) -> Self {
-        assert_ne!(parent_id, id);
+    pub(super) fn new(parent_id: u64, id: u64) -> Self {
+        debug_assert_ne!(parent_id, id);
         DirBuilder {
             nodes: Default::default(),
             metadata: Default::default(),
@@ -30,7 +38,7 @@ impl DirBuilder {
         }
     }
 
-    pub fn root(id: u64) -> Self {
+    pub(super) fn root(id: u64) -> Self {
         DirBuilder {
             nodes: Default::default(),
             metadata: Default::default(),
@@ -39,7 +47,124 @@ impl DirBuilder {
         }
     }
 
-    pub fn put_leaf(&mut self, key: String, leaf: Leaf) -> Result<(), DuplicateName> {
+    /// Creates a new tree out of the slash separated full paths and their leaves, creating all of
+    /// the directories along the way. The root may have multiple entries, see
+    /// `DirBuilder::build`.
+    ///
+    /// Fails on the same paths as `BufferingTreeBuilder::put_link`, for example on duplicate
+    /// paths, on paths going through a leaf and on empty components, including empty paths.
+    pub fn from_paths(
+        entries: impl IntoIterator<Item = (String, Leaf)>,
+    ) -> Result<DirBuilder, TreeBuildingFailed> {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        for (path, leaf) in entries {
+            if path.is_empty() {
+                return Err(TreeBuildingFailed::EmptyPath);
+            }
+            builder.put_link(&path, leaf.link, leaf.total_size)?;
+        }
+
+        Ok(builder.into_dir_builder())
+    }
+
+    /// Creates a single directory out of the `(name, cid, total_size)` links, as listed by
+    /// go-ipfs' `ipfs object links`. The directory can then be built with
+    /// `TreeOptions::wrap_with_directory` to get the same block as with `encode_directory`, or be
+    /// inspected before, for example with `DirBuilder::walk`.
+    ///
+    /// With `--enc=json` the links are listed as the `Links` of the object, each with the `Name`,
+    /// the `Hash` as a string and the cumulative `Size`:
+    ///
+    /// ```text
+    /// {"Hash":"Qm...","Links":[{"Name":"a.txt","Hash":"Qm...","Size":12,"Type":2}]}
+    /// ```
+    ///
+    /// The `Type` is not needed. Fails with `TreeBuildingFailed::SlashInName` for the names with a
+    /// slash, and otherwise on the same names as `DirBuilder::from_paths`, for example on
+    /// duplicate or empty names.
+    pub fn from_object_links(
+        links: &[(String, Cid, u64)],
+    ) -> Result<DirBuilder, TreeBuildingFailed> {
+        if let Some((name, ..)) = links.iter().find(|(name, ..)| name.contains('/')) {
+            return Err(TreeBuildingFailed::SlashInName(name.clone()));
+        }
+
+        Self::from_paths(
+            links
+                .iter()
+                .map(|(name, cid, total_size)| (name.clone(), Leaf::new(cid.clone(), *total_size))),
+        )
+    }
+
+    /// Builds the tree starting from this root directory, see `BufferingTreeBuilder::build`. More
+    /// than one entry at the root requires `TreeOptions::wrap_with_directory`, otherwise the
+    /// returned iterator will fail with `TreeConstructionFailed::MultipleRoots`.
+    pub fn build(self, opts: TreeOptions) -> PostOrderIterator {
+        let next_id = self.max_id() + 1;
+        PostOrderIterator::new(self, opts, 0, next_id)
+    }
+
+    /// Same as `DirBuilder::build` but renders the blocks into the given `buffer`, see
+    /// `BufferingTreeBuilder::build_with_buffer`.
+    pub fn build_with_buffer(self, opts: TreeOptions, buffer: Vec<u8>) -> PostOrderIterator {
+        let next_id = self.max_id() + 1;
+        PostOrderIterator::new_with_buffer(self, opts, 0, next_id, buffer)
+    }
+
+    fn max_id(&self) -> u64 {
+        self.nodes
+            .values()
+            .filter_map(|entry| match entry {
+                Entry::Directory(node) => Some(node.max_id()),
+                Entry::Leaf(_) | Entry::Deferred(_) => None,
+            })
+            .fold(self.id, u64::max)
+    }
+
+    /// Moves the entries of the directories with more than `max_links` entries into buckets,
+    /// see `TreeOptions::prefix_buckets`. The new buckets get their ids starting from `next_id`.
+    pub(super) fn split_into_buckets(&mut self, max_links: usize, next_id: &mut u64) {
+        debug_assert!(max_links >= 2);
+
+        if self.nodes.len() > max_links {
+            let entries = core::mem::take(&mut self.nodes);
+            let buckets = entries.len().div_ceil(max_links).min(max_links);
+            let (size, extra) = (entries.len() / buckets, entries.len() % buckets);
+            let mut entries = entries.into_iter();
+            let mut previous_last = None;
+
+            for i in 0..buckets {
+                let mut bucket = DirBuilder::new(self.id, *next_id);
+                *next_id += 1;
+
+                let size = if i < extra { size + 1 } else { size };
+
+                for (name, mut entry) in (&mut entries).take(size) {
+                    if let Entry::Directory(node) = &mut entry {
+                        node.parent_id = Some(bucket.id);
+                    }
+                    bucket.nodes.insert(name, entry);
+                }
+
+                let first = bucket.nodes.keys().next().expect("buckets are never empty");
+                let name = shortest_prefix_after(first, previous_last.as_deref());
+                previous_last = bucket.nodes.keys().next_back().cloned();
+
+                self.nodes.insert(name, Entry::Directory(bucket));
+            }
+        }
+
+        for entry in self.nodes.values_mut() {
+            if let Entry::Directory(node) = entry {
+                node.split_into_buckets(max_links, next_id);
+            }
+        }
+    }
+
+    pub(super) fn put_leaf(&mut self, key: String, leaf: Leaf) -> Result<(), DuplicateName> {
         match self.nodes.entry(key) {
             Occupied(_) => Err(DuplicateName),
             Vacant(ve) => {
@@ -49,7 +174,38 @@ impl DirBuilder {
         }
     }
 
-    pub fn add_or_get_node(
+    pub(super) fn put_leaf_deferred(&mut self, key: String, cid: Cid) -> Result<(), DuplicateName> {
+        match self.nodes.entry(key) {
+            Occupied(_) => Err(DuplicateName),
+            Vacant(ve) => {
+                ve.insert(Entry::Deferred(cid));
+                Ok(())
+            }
+        }
+    }
+
+    /// Replaces the deferred leaf at the `path` of the normalized names with a complete one.
+    pub(super) fn set_leaf_size(&mut self, path: &[&str], total_size: u64) -> Result<(), ()> {
+        match path {
+            [name] => match self.nodes.get_mut(*name) {
+                Some(entry) => match entry {
+                    Entry::Deferred(cid) => {
+                        *entry = Entry::Leaf(Leaf::new(cid.clone(), total_size));
+                        Ok(())
+                    }
+                    _ => Err(()),
+                },
+                None => Err(()),
+            },
+            [name, rest @ ..] => match self.nodes.get_mut(*name) {
+                Some(Entry::Directory(node)) => node.set_leaf_size(rest, total_size),
+                _ => Err(()),
+            },
+            [] => Err(()),
+        }
+    }
+
+    pub(super) fn add_or_get_node(
         &mut self,
         key: String,
         id: &mut Option<u64>,
@@ -64,15 +220,209 @@ impl DirBuilder {
         }
     }
 
+    /// Returns the number of immediate entries in this directory.
     pub fn len(&self) -> usize {
         self.nodes.len()
     }
 
+    /// Returns `true` if this directory has no entries.
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
-    pub fn set_metadata(&mut self, metadata: Metadata) {
+    pub(super) fn set_metadata(&mut self, metadata: Metadata) {
         self.metadata = metadata;
     }
+
+    /// Returns the number of directory nodes which building this tree with the `opts` will
+    /// yield, which is the number of directories under this root, and the root itself when
+    /// `TreeOptions::wrap_with_directory` is enabled. HAMT shard buckets and the buckets of
+    /// `TreeOptions::prefix_buckets` are not counted.
+    pub fn count_directories(&self, opts: &TreeOptions) -> usize {
+        fn count(node: &DirBuilder) -> usize {
+            node.nodes
+                .values()
+                .map(|entry| match entry {
+                    Entry::Directory(node) => 1 + count(node),
+                    Entry::Leaf(_) | Entry::Deferred(_) => 0,
+                })
+                .sum()
+        }
+
+        count(self) + if opts.wrap_with_directory { 1 } else { 0 }
+    }
+
+    /// Renders a copy of the tree with the `opts` to find out how much storage its blocks need.
+    /// The identical directories are rendered into identical blocks, which need to be stored
+    /// only once, as with `PostOrderIterator::into_block_map`, so the physical size is less than
+    /// the logical size whenever the tree has repeated subtrees. The blocks of the leaves are not
+    /// included.
+    ///
+    /// The whole tree is rendered, so this costs as much as building it, but none of the blocks
+    /// are kept.
+    pub fn estimate_storage(
+        &self,
+        opts: &TreeOptions,
+    ) -> Result<StorageEstimate, TreeConstructionFailed> {
+        let mut iter = self.clone().build(opts.clone());
+        let mut seen = HashSet::new();
+        let mut estimate = StorageEstimate::default();
+
+        while let Some(node) = iter.next_borrowed() {
+            let node = node?;
+            let len = node.block.len() as u64;
+
+            estimate.blocks += 1;
+            estimate.logical_bytes += len;
+
+            if seen.insert(node.cid.to_owned()) {
+                estimate.unique_blocks += 1;
+                estimate.physical_bytes += len;
+            }
+        }
+
+        Ok(estimate)
+    }
+
+    /// Returns a structural fingerprint of the tree starting from this directory, computed out of
+    /// the names, the metadata of the directories and the `Cid`s and sizes of the leaves without
+    /// rendering any of the directories. The fingerprint can be used as a key for caching the
+    /// root `Cid` of a previous build, skipping the rebuilds of the same tree:
+    /// building trees with equal fingerprints using the same `TreeOptions` results in equal root
+    /// `Cid`s. The fingerprint is a truncated SHA-256 digest, so the chance of different trees
+    /// having the same fingerprint is negligible.
+    ///
+    /// The fingerprint is independent of the order the entries were added in, but it does not
+    /// cover the `TreeOptions`, including the `TreeOptions::entry_filter` and
+    /// `TreeOptions::link_rewriter`, which need to be the same between the builds.
+    pub fn fingerprint(&self) -> u64 {
+        use sha2::{Digest, Sha256};
+
+        fn feed(sha: &mut Sha256, node: &DirBuilder) {
+            feed_metadata(sha, &node.metadata);
+            sha.update((node.nodes.len() as u64).to_le_bytes());
+
+            for (name, entry) in &node.nodes {
+                feed_bytes(sha, name.as_bytes());
+
+                match entry {
+                    Entry::Leaf(leaf) => {
+                        sha.update([0]);
+                        feed_bytes(sha, &leaf.link.to_bytes());
+                        sha.update(leaf.total_size.to_le_bytes());
+                    }
+                    Entry::Directory(node) => {
+                        sha.update([1]);
+                        feed(sha, node);
+                    }
+                    Entry::Deferred(cid) => {
+                        sha.update([2]);
+                        feed_bytes(sha, &cid.to_bytes());
+                    }
+                }
+            }
+        }
+
+        // the lengths are included to keep the boundaries of the variable length fields apart
+        fn feed_bytes(sha: &mut Sha256, bytes: &[u8]) {
+            sha.update((bytes.len() as u64).to_le_bytes());
+            sha.update(bytes);
+        }
+
+        fn feed_metadata(sha: &mut Sha256, metadata: &Metadata) {
+            match metadata.mode() {
+                Some(mode) => {
+                    sha.update([1]);
+                    sha.update(mode.to_le_bytes());
+                }
+                None => sha.update([0]),
+            }
+
+            match metadata.mtime() {
+                Some((secs, nanos)) => {
+                    sha.update([1]);
+                    sha.update(secs.to_le_bytes());
+                    sha.update(nanos.to_le_bytes());
+                }
+                None => sha.update([0]),
+            }
+        }
+
+        let mut sha = Sha256::new();
+        feed(&mut sha, self);
+
+        let digest = sha.finalize();
+        let mut fingerprint = [0u8; 8];
+        fingerprint.copy_from_slice(&digest[..8]);
+        u64::from_le_bytes(fingerprint)
+    }
+
+    /// Returns the immediate entries of this directory in the order they will be linked, unless
+    /// `TreeOptions::link_order` is used.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, EntryKind)> + '_ {
+        self.nodes
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.kind()))
+    }
+
+    /// Returns all of the entries in the tree starting from this directory in pre-order, directories
+    /// before their contents. The paths are slash separated and relative to this directory.
+    pub fn walk(&self) -> impl Iterator<Item = (String, EntryKind)> + '_ {
+        let mut stack = vec![(String::new(), self.nodes.iter())];
+
+        core::iter::from_fn(move || loop {
+            let (prefix, entries) = stack.last_mut()?;
+
+            match entries.next() {
+                Some((name, entry)) => {
+                    let path = if prefix.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}/{}", prefix, name)
+                    };
+
+                    if let Entry::Directory(node) = entry {
+                        stack.push((path.clone(), node.nodes.iter()));
+                    }
+
+                    return Some((path, entry.kind()));
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        })
+    }
+
+    /// Returns the paths of all of the leaves (files, symlinks or opaque links) in the tree
+    /// starting from this directory, in the same order as `DirBuilder::walk`. Useful for checking
+    /// that all of the expected files were added before building.
+    pub fn leaf_paths(&self) -> impl Iterator<Item = String> + '_ {
+        self.walk()
+            .filter(|(_, kind)| *kind == EntryKind::Leaf)
+            .map(|(path, _)| path)
+    }
+}
+
+/// Storage needed by the directory blocks of a tree, see `DirBuilder::estimate_storage`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StorageEstimate {
+    /// Number of the rendered blocks, including the HAMT shard buckets.
+    pub blocks: u64,
+    /// Number of the blocks with distinct `Cid`s.
+    pub unique_blocks: u64,
+    /// Total length of all of the rendered blocks.
+    pub logical_bytes: u64,
+    /// Total length of the blocks with distinct `Cid`s, which is what needs to be stored.
+    pub physical_bytes: u64,
+}
+
+/// Returns the shortest prefix of `name` which sorts after `previous`. The `name` must sort after
+/// `previous`.
+fn shortest_prefix_after(name: &str, previous: Option<&str>) -> String {
+    name.char_indices()
+        .map(|(at, ch)| &name[..at + ch.len_utf8()])
+        .find(|prefix| previous.map(|previous| *prefix > previous).unwrap_or(true))
+        .unwrap_or(name)
+        .to_owned()
 }
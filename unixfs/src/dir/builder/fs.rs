@@ -0,0 +1,649 @@
+//! Importing a directory from the filesystem, enabled with the `fs` feature.
+
+use super::{BlockSink, BufferingTreeBuilder, PostOrderIterator, TreeBuildingFailed, TreeOptions};
+use crate::file::adder::FileAdder;
+use crate::Metadata;
+use cid::Cid;
+use core::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Options for `import_dir`.
+#[derive(Debug, Default, Clone)]
+pub struct ImportOptions {
+    follow_symlinks: bool,
+    skip_hidden: bool,
+    continue_on_errors: bool,
+    discard_metadata: bool,
+}
+
+impl ImportOptions {
+    /// Imports the targets of the symlinks instead of the symlinks themselves, so that a symlink
+    /// to a file is imported as a copy of the file and a symlink to a directory as a copy of the
+    /// directory. By default the symlinks are imported as UnixFs symlinks to the same target
+    /// path. A symlink to one of its own ancestor directories fails as an `io::Error` of kind
+    /// `Other`.
+    pub fn follow_symlinks(&mut self) {
+        self.follow_symlinks = true;
+    }
+
+    /// Leaves out all of the files and directories with a name starting with a dot. The root
+    /// directory is always imported.
+    pub fn skip_hidden(&mut self) {
+        self.skip_hidden = true;
+    }
+
+    /// Skips the entries which cannot be read, for example because of their permissions,
+    /// returning them in `Imported::skipped` instead of failing the import. A failure to read the
+    /// root directory or to store a block always fails the import.
+    pub fn continue_on_errors(&mut self) {
+        self.continue_on_errors = true;
+    }
+
+    /// Leaves out the mode and the mtime of the imported files, directories and symlinks, which
+    /// makes the `Cid`s depend only on the names and the contents, the same as with
+    /// `BufferingTreeBuilder`.
+    pub fn discard_metadata(&mut self) {
+        self.discard_metadata = true;
+    }
+}
+
+/// The result of `import_dir`.
+pub struct Imported {
+    /// Renders the directories of the imported tree, with the root as the last node.
+    pub tree: PostOrderIterator,
+    /// The entries which were skipped as they could not be read, only with
+    /// `ImportOptions::continue_on_errors`.
+    pub skipped: Vec<(PathBuf, io::Error)>,
+}
+
+/// Imports the directory at `root` and everything under it, storing the blocks of the files and
+/// the symlinks into the `sink`; the returned `Imported::tree` renders the directories, which
+/// remain to be stored, for example with `PostOrderIterator::drive`. The `root` is the wrapping
+/// directory of the tree, so `TreeOptions::wrap_with_directory` is enabled. The files are added
+/// with the default `FileAdder`.
+///
+/// All of the names must be valid UTF-8; the other names fail as an `io::Error` of kind
+/// `InvalidData`, or with `ImportOptions::continue_on_errors` are skipped without affecting their
+/// siblings. The empty directories are kept.
+///
+/// The mode and the mtime of the files, directories and symlinks are preserved, unless
+/// `ImportOptions::discard_metadata` is used: they are written into the root blocks of the files
+/// and the symlinks, and the directories get them through `BufferingTreeBuilder::set_metadata`.
+/// Only the permission bits of the mode, the lowest 12 bits, are kept, and only on unix; the mtime
+/// is kept on all platforms. The metadata of the `root` itself is not imported, as the wrapping
+/// directory stands for the import and not the directory on the disk.
+pub fn import_dir<S: BlockSink>(
+    root: &Path,
+    import: &ImportOptions,
+    mut opts: TreeOptions,
+    sink: &mut S,
+) -> Result<Imported, ImportFailed<S::Error>> {
+    opts.wrap_with_directory();
+
+    let mut importer = Importer {
+        import,
+        builder: BufferingTreeBuilder::new(opts),
+        sink,
+        skipped: Vec::new(),
+        ancestors: Vec::new(),
+        buffer: Vec::new(),
+    };
+
+    let canonical = fs::canonicalize(root).map_err(|e| ImportFailed::Io(root.to_owned(), e))?;
+    let entries = read_dir(root).map_err(|e| ImportFailed::Io(root.to_owned(), e))?;
+
+    importer.ancestors.push(canonical);
+    importer.import_entries("", entries)?;
+
+    Ok(Imported {
+        tree: importer.builder.build(),
+        skipped: importer.skipped,
+    })
+}
+
+struct Importer<'a, S> {
+    import: &'a ImportOptions,
+    builder: BufferingTreeBuilder,
+    sink: &'a mut S,
+    skipped: Vec<(PathBuf, io::Error)>,
+    // the canonical paths of the directories being imported, for finding the symlink cycles
+    ancestors: Vec<PathBuf>,
+    buffer: Vec<u8>,
+}
+
+enum Kind {
+    File(Metadata),
+    Directory(PathBuf, Metadata),
+    Symlink(String, Metadata),
+}
+
+impl<S: BlockSink> Importer<'_, S> {
+    fn import_entries(
+        &mut self,
+        prefix: &str,
+        (entries, failed): DirEntries,
+    ) -> Result<(), ImportFailed<S::Error>> {
+        for (path, e) in failed {
+            self.skip_or_fail(path, e)?;
+        }
+
+        for (name, path) in entries {
+            if self.import.skip_hidden && name.starts_with('.') {
+                continue;
+            }
+
+            let tree_path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+
+            let kind = match self.kind(&path) {
+                Ok(kind) => kind,
+                Err(e) => {
+                    self.skip_or_fail(path, e)?;
+                    continue;
+                }
+            };
+
+            match kind {
+                Kind::File(metadata) => match self.import_file(&path, &metadata) {
+                    Ok((cid, total_size)) => self
+                        .builder
+                        .put_link(&tree_path, cid, total_size)
+                        .map_err(ImportFailed::Building)?,
+                    Err(ImportFailed::Io(path, e)) => self.skip_or_fail(path, e)?,
+                    Err(e) => return Err(e),
+                },
+                Kind::Symlink(target, metadata) => {
+                    let (cid, total_size) = self.import_symlink(&target, &metadata)?;
+                    self.builder
+                        .put_link(&tree_path, cid, total_size)
+                        .map_err(ImportFailed::Building)?;
+                }
+                Kind::Directory(canonical, metadata) => {
+                    let entries = match read_dir(&path) {
+                        Ok(entries) => entries,
+                        Err(e) => {
+                            self.skip_or_fail(path, e)?;
+                            continue;
+                        }
+                    };
+
+                    // creates the directory even when it is empty
+                    self.builder
+                        .set_metadata(&tree_path, metadata)
+                        .map_err(ImportFailed::Building)?;
+
+                    self.ancestors.push(canonical);
+                    self.import_entries(&tree_path, entries)?;
+                    self.ancestors.pop();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn kind(&self, path: &Path) -> io::Result<Kind> {
+        let metadata = fs::symlink_metadata(path)?;
+
+        if metadata.file_type().is_symlink() && !self.import.follow_symlinks {
+            let target = fs::read_link(path)?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| invalid_data("symlink target is not valid UTF-8"))?;
+            let metadata = self.metadata(&metadata);
+            return Ok(Kind::Symlink(target.to_owned(), metadata));
+        }
+
+        // follows the symlink, if any
+        let metadata = fs::metadata(path)?;
+
+        if metadata.is_dir() {
+            let canonical = fs::canonicalize(path)?;
+            if self.ancestors.contains(&canonical) {
+                return Err(io::Error::other("symlink to an ancestor directory"));
+            }
+            Ok(Kind::Directory(canonical, self.metadata(&metadata)))
+        } else {
+            Ok(Kind::File(self.metadata(&metadata)))
+        }
+    }
+
+    /// Returns the preserved parts of the `metadata`, see `import_dir`.
+    fn metadata(&self, metadata: &fs::Metadata) -> Metadata {
+        if self.import.discard_metadata {
+            return Metadata::default();
+        }
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        let mtime = metadata.modified().ok().map(|modified| {
+            match modified.duration_since(std::time::UNIX_EPOCH) {
+                Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+                Err(e) => {
+                    // before the epoch, with the nanoseconds still counting forward
+                    let before = e.duration();
+                    let secs = -(before.as_secs() as i64);
+                    match before.subsec_nanos() {
+                        0 => (secs, 0),
+                        nanos => (secs - 1, 1_000_000_000 - nanos),
+                    }
+                }
+            }
+        });
+
+        Metadata { mode, mtime }
+    }
+
+    fn import_file(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> Result<(Cid, u64), ImportFailed<S::Error>> {
+        let io_failed = |e| ImportFailed::Io(path.to_owned(), e);
+
+        let mut file = fs::File::open(path).map_err(io_failed)?;
+        let mut adder = FileAdder::default();
+        let mut total_size = 0u64;
+
+        self.buffer.resize(adder.size_hint(), 0);
+
+        loop {
+            let read = match file.read(&mut self.buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(io_failed(e)),
+            };
+
+            let mut consumed = 0;
+            while consumed < read {
+                let (blocks, pushed) = adder.push(&self.buffer[consumed..read]);
+                for (cid, block) in blocks {
+                    total_size += block.len() as u64;
+                    self.sink.put(&cid, &block).map_err(ImportFailed::Sink)?;
+                }
+                consumed += pushed;
+            }
+        }
+
+        let mut blocks = adder.finish().peekable();
+        while let Some((cid, block)) = blocks.next() {
+            let (cid, block) = if blocks.peek().is_none() {
+                // the root block carries the metadata of the file
+                with_metadata(cid, block, metadata)
+            } else {
+                (cid, block)
+            };
+
+            total_size += block.len() as u64;
+            self.sink.put(&cid, &block).map_err(ImportFailed::Sink)?;
+
+            if blocks.peek().is_none() {
+                return Ok((cid, total_size));
+            }
+        }
+
+        unreachable!("finishing always creates the root block")
+    }
+
+    fn import_symlink(
+        &mut self,
+        target: &str,
+        metadata: &Metadata,
+    ) -> Result<(Cid, u64), ImportFailed<S::Error>> {
+        let mut block = Vec::new();
+        crate::symlink::serialize_symlink_block(target, &mut block);
+
+        let (cid, block) = with_metadata(cid_v0(&block), block, metadata);
+
+        self.sink.put(&cid, &block).map_err(ImportFailed::Sink)?;
+
+        Ok((cid, block.len() as u64))
+    }
+
+    fn skip_or_fail(&mut self, path: PathBuf, e: io::Error) -> Result<(), ImportFailed<S::Error>> {
+        if self.import.continue_on_errors {
+            self.skipped.push((path, e));
+            Ok(())
+        } else {
+            Err(ImportFailed::Io(path, e))
+        }
+    }
+}
+
+/// The entries of a directory sorted by their names, and the entries which could not be read.
+type DirEntries = (Vec<(String, PathBuf)>, Vec<(PathBuf, io::Error)>);
+
+/// Returns the entries of the directory sorted by their names, along with the entries which
+/// could not be read, each with its own error, so that a single unreadable entry does not fail
+/// all of its siblings.
+fn read_dir(dir: &Path) -> io::Result<DirEntries> {
+    let mut entries = Vec::new();
+    let mut failed = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                failed.push((dir.to_owned(), e));
+                continue;
+            }
+        };
+
+        match entry.file_name().into_string() {
+            Ok(name) => entries.push((name, entry.path())),
+            Err(_) => failed.push((entry.path(), invalid_data("name is not valid UTF-8"))),
+        }
+    }
+
+    entries.sort();
+    Ok((entries, failed))
+}
+
+/// Returns the UnixFs root `block` rendered again with the mode and the mtime of the `metadata`,
+/// and its `Cid`, or the `block` and the `cid` as they were when there is no metadata.
+fn with_metadata(cid: Cid, block: Vec<u8>, metadata: &Metadata) -> (Cid, Vec<u8>) {
+    use crate::pb::FlatUnixFs;
+    use quick_protobuf::{MessageWrite, Writer};
+
+    if metadata == &Metadata::default() {
+        return (cid, block);
+    }
+
+    let mut flat = FlatUnixFs::try_parse(&block).expect("the block was just rendered");
+    flat.data.mode = metadata.mode();
+    flat.data.mtime = super::iter::unix_time(metadata);
+
+    let mut rendered = Vec::with_capacity(flat.get_size());
+    flat.write_message(&mut Writer::new(&mut rendered))
+        .expect("writing to a Vec cannot fail");
+
+    (cid_v0(&rendered), rendered)
+}
+
+fn cid_v0(block: &[u8]) -> Cid {
+    use sha2::{Digest, Sha256};
+
+    let mh = multihash::wrap(multihash::Code::Sha2_256, &Sha256::digest(block));
+    Cid::new_v0(mh).expect("sha2_256 is the correct multihash for cidv0")
+}
+
+fn invalid_data(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Failure cases for `import_dir`.
+#[derive(Debug)]
+pub enum ImportFailed<E> {
+    /// Reading the file, directory or symlink at the path failed.
+    Io(PathBuf, io::Error),
+    /// Storing a block failed.
+    Sink(E),
+    /// Adding an entry to the tree failed.
+    Building(TreeBuildingFailed),
+}
+
+impl<E: fmt::Display> fmt::Display for ImportFailed<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ImportFailed::*;
+        match self {
+            Io(path, e) => write!(fmt, "failed to read {:?}: {}", path, e),
+            Sink(e) => write!(fmt, "failed to store a block: {}", e),
+            Building(e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for ImportFailed<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ImportFailed::*;
+        match self {
+            Io(_, e) => Some(e),
+            Sink(e) => Some(e),
+            Building(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import_dir, ImportFailed, ImportOptions};
+    use crate::dir::builder::{BufferingTreeBuilder, TreeOptions};
+    use crate::file::adder::FileAdder;
+    use cid::Cid;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Directory removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("ipfs-unixfs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("sub/empty")).unwrap();
+            fs::write(path.join("a.txt"), b"foobar\n").unwrap();
+            fs::write(path.join("sub/b.txt"), vec![7u8; 300_000]).unwrap();
+            fs::write(path.join(".hidden"), b"").unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn import(dir: &TempDir, import: &ImportOptions) -> (Cid, HashMap<Cid, Box<[u8]>>) {
+        let mut blocks = HashMap::new();
+        let imported = import_dir(&dir.0, import, TreeOptions::default(), &mut blocks).unwrap();
+        assert!(imported.skipped.is_empty(), "{:?}", imported.skipped);
+        let root = imported.tree.drive(&mut blocks).unwrap();
+        (root, blocks)
+    }
+
+    fn add_file(content: &[u8]) -> (Cid, u64) {
+        let mut adder = FileAdder::default();
+        let mut total_size = 0;
+        let mut consumed = 0;
+        while consumed < content.len() {
+            let (blocks, pushed) = adder.push(&content[consumed..]);
+            total_size += blocks.map(|(_, block)| block.len() as u64).sum::<u64>();
+            consumed += pushed;
+        }
+        let blocks = adder.finish().collect::<Vec<_>>();
+        total_size += blocks
+            .iter()
+            .map(|(_, block)| block.len() as u64)
+            .sum::<u64>();
+        (blocks.last().unwrap().0.clone(), total_size)
+    }
+
+    #[test]
+    fn imported_tree_matches_built() {
+        let dir = TempDir::new("matches-built");
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+        let (a, a_size) = add_file(b"foobar\n");
+        let (b, b_size) = add_file(&vec![7u8; 300_000]);
+        let (hidden, hidden_size) = add_file(b"");
+        builder.put_link("a.txt", a.clone(), a_size).unwrap();
+        builder.put_link("sub/b.txt", b.clone(), b_size).unwrap();
+        builder
+            .set_metadata("sub/empty", crate::Metadata::default())
+            .unwrap();
+        let without_hidden = builder.clone().build().drive(&mut Vec::new()).unwrap();
+        builder.put_link(".hidden", hidden, hidden_size).unwrap();
+        let with_hidden = builder.build().drive(&mut Vec::new()).unwrap();
+
+        let mut discarding = ImportOptions::default();
+        discarding.discard_metadata();
+
+        let (root, blocks) = import(&dir, &discarding);
+        assert_eq!(root, with_hidden);
+        // the file blocks are stored as well
+        assert!(blocks.contains_key(&a) && blocks.contains_key(&b));
+
+        let mut skip_hidden = discarding;
+        skip_hidden.skip_hidden();
+        assert_eq!(import(&dir, &skip_hidden).0, without_hidden);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks_linked_or_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("symlinks");
+        symlink("a.txt", dir.0.join("link")).unwrap();
+
+        let mut linking = ImportOptions::default();
+        linking.discard_metadata();
+        let mut follow = linking.clone();
+        follow.follow_symlinks();
+
+        let (linked, linked_blocks) = import(&dir, &linking);
+        let (followed, _) = import(&dir, &follow);
+        assert_ne!(linked, followed);
+
+        let mut symlink_block = Vec::new();
+        crate::symlink::serialize_symlink_block("a.txt", &mut symlink_block);
+        assert!(linked_blocks
+            .values()
+            .any(|block| **block == symlink_block[..]));
+
+        // a cycle cannot be followed, but can be linked
+        symlink("..", dir.0.join("sub/parent")).unwrap();
+        import(&dir, &linking);
+
+        let mut blocks = HashMap::new();
+        match import_dir(&dir.0, &follow, TreeOptions::default(), &mut blocks) {
+            Err(ImportFailed::Io(path, e)) => {
+                assert_eq!(path, dir.0.join("sub/parent"));
+                assert_eq!(e.kind(), std::io::ErrorKind::Other);
+            }
+            x => unreachable!("{:?}", x.map(|imported| imported.skipped)),
+        }
+
+        follow.continue_on_errors();
+        let imported = import_dir(&dir.0, &follow, TreeOptions::default(), &mut blocks).unwrap();
+        assert_eq!(imported.skipped.len(), 1);
+        assert_eq!(imported.skipped[0].0, dir.0.join("sub/parent"));
+        imported.tree.drive(&mut blocks).unwrap();
+    }
+
+    #[test]
+    fn metadata_preserved() {
+        use crate::pb::FlatUnixFs;
+        use crate::Metadata;
+
+        let dir = TempDir::new("metadata");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |mode| fs::Permissions::from_mode(mode);
+            fs::set_permissions(dir.0.join("a.txt"), mode(0o640)).unwrap();
+            fs::set_permissions(dir.0.join("sub"), mode(0o750)).unwrap();
+        }
+
+        let (root, blocks) = import(&dir, &ImportOptions::default());
+
+        let metadata_of = |cid: &Cid| {
+            let flat = FlatUnixFs::try_parse(&blocks[cid]).unwrap();
+            Metadata::from(&flat.data)
+        };
+        let link = |name: &str| {
+            let entries = crate::dir::builder::parse_directory_block(&blocks[&root]).unwrap();
+            entries[name].link.clone()
+        };
+
+        let mtime = |path: &str| {
+            let modified = fs::metadata(dir.0.join(path)).unwrap().modified().unwrap();
+            let since = modified.duration_since(std::time::UNIX_EPOCH).unwrap();
+            Some((since.as_secs() as i64, since.subsec_nanos()))
+        };
+
+        let file = metadata_of(&link("a.txt"));
+        let sub = metadata_of(&link("sub"));
+        assert_eq!(file.mtime(), mtime("a.txt"));
+        assert_eq!(sub.mtime(), mtime("sub"));
+
+        #[cfg(unix)]
+        {
+            assert_eq!(file.mode(), Some(0o640));
+            assert_eq!(sub.mode(), Some(0o750));
+        }
+
+        // the root of the 300k file is a link block, which carries the metadata
+        let sub_entries =
+            crate::dir::builder::parse_directory_block(&blocks[&link("sub")]).unwrap();
+        let large = metadata_of(&sub_entries["b.txt"].link);
+        assert_eq!(large.mtime(), mtime("sub/b.txt"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_name_skipped_alone() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TempDir::new("non-utf8");
+        let mut discarding = ImportOptions::default();
+        discarding.discard_metadata();
+        let (expected, _) = import(&dir, &discarding);
+
+        let invalid = dir.0.join("sub").join(OsStr::from_bytes(b"invalid-\xff"));
+        fs::write(&invalid, b"").unwrap();
+
+        match import_dir(&dir.0, &discarding, TreeOptions::default(), &mut Vec::new()) {
+            Err(ImportFailed::Io(path, e)) => {
+                assert_eq!(path, invalid);
+                assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            }
+            x => unreachable!("{:?}", x.map(|imported| imported.skipped)),
+        }
+
+        let mut continuing = discarding;
+        continuing.continue_on_errors();
+
+        let mut blocks = HashMap::new();
+        let imported =
+            import_dir(&dir.0, &continuing, TreeOptions::default(), &mut blocks).unwrap();
+        assert_eq!(imported.skipped.len(), 1);
+        assert_eq!(imported.skipped[0].0, invalid);
+
+        // the siblings of the skipped entry are imported as before
+        assert_eq!(imported.tree.drive(&mut blocks).unwrap(), expected);
+    }
+
+    #[test]
+    fn missing_root_fails() {
+        let dir = TempDir::new("missing-root");
+        let missing = dir.0.join("missing");
+
+        let mut import = ImportOptions::default();
+        import.continue_on_errors();
+
+        match import_dir(&missing, &import, TreeOptions::default(), &mut Vec::new()) {
+            Err(ImportFailed::Io(path, _)) => assert_eq!(path, missing),
+            x => unreachable!("{:?}", x.map(|imported| imported.skipped)),
+        }
+    }
+}
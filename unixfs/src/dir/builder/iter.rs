@@ -1,35 +1,111 @@
+use super::custom_pb::NodeData;
+use super::stash::LeafStash;
 use super::{
-    CustomFlatUnixFs, DirBuilder, Entry, Leaf, NamedLeaf, TreeConstructionFailed, TreeOptions,
+    CaseCollisionPolicy, CustomFlatUnixFs, DirBuilder, Entry, EntryFilter, Leaf, LinkOrder,
+    LinkRewriter, NamedLeaf, RootData, TreeConstructionFailed, TreeOptions,
 };
+use crate::dir::hamt::{self, Bitfield};
+use crate::pb::{unixfs::UnixTime, UnixFs, UnixFsType};
+use crate::Metadata;
+use alloc::collections::BTreeMap;
 use cid::Cid;
 use core::fmt;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(super) use std::time::Instant;
 
 /// Constructs the directory nodes required for a tree.
 ///
-/// Implements the Iterator interface for owned values and the borrowed version, `next_borrowed`.
+/// Implements the Iterator interface for owned values, also available as `next_owned`, and the
+/// borrowed version, `next_borrowed`.
 /// The tree is fully constructed once this has been exhausted.
+///
+/// # Order
+///
+/// The directories are yielded in the post order: every directory after all of its
+/// subdirectories, with the root last. The subdirectories of a directory are visited in the
+/// reverse of the order of their links in the rendered directory, which is the name order or
+/// `TreeOptions::link_order`, each subtree completely before the next one. For example `a/b/c`,
+/// `a/d` and `e` are yielded as `e`, `a/d`, `a/b/c`, `a/b`, `a`. The buckets of a HAMT sharded
+/// directory are yielded after its subdirectories, as the last nodes of its subtree.
+///
+/// The order only depends on the tree and the options, and is the same on every build. Changing
+/// it would be a breaking change.
+///
+/// # Memory usage
+///
+/// The whole tree of names is buffered up front in the `DirBuilder`, and the iterator consumes it
+/// while walking. During the walk, the links of a directory are kept only until the directory
+/// has been rendered. Because all children are rendered before their parent, the directories
+/// waiting for their subdirectories to be rendered are always the ancestors of the latest
+/// rendered node, so at most one set of links per level of the tree is kept at a time. For a wide
+/// directory all of its links need to be held until it is rendered, which is no more than what
+/// the rendered block needs. HAMT sharded directories do the same for their buckets.
+///
+/// With the `spill` feature, the links waiting for the subdirectories can be written to a
+/// temporary file once they go over `TreeOptions::spill_budget`; see there for the cost. This
+/// bounds the memory of the wide and deep trees, but the bulk of the memory is often the not yet
+/// visited part of the `DirBuilder`. To import trees which do not fit in memory, build the large
+/// subtrees separately, each with their own `BufferingTreeBuilder`, storing the blocks as they are
+/// yielded, and link their roots with `BufferingTreeBuilder::put_subtree`. This produces the same
+/// `Cid`s as building the whole tree at once.
 pub struct PostOrderIterator {
     full_path: String,
     old_depth: usize,
+    // length of the `TreeOptions::path_prefix` at the start of `full_path`
+    prefix_len: usize,
     block_buffer: Vec<u8>,
     // our stack of pending work
     pending: Vec<Visited>,
     // "communication channel" from nested entries back to their parents; this hashmap is only used
-    // in the event of mixed child nodes (leaves and nodes).
-    persisted_cids: HashMap<u64, Vec<Option<NamedLeaf>>>,
+    // in the event of mixed child nodes (leaves and nodes). holds at most one entry per ancestor
+    // of the latest rendered node.
+    //
+    // invariant: the entry is keyed by the id of the directory (or bucket) itself, created with
+    // all of its leaves and a `None` slot for every child before any of the children are visited,
+    // and removed when the directory is rendered. every child knows its parent_id and the index
+    // of its slot, so the slots are filled exactly once regardless of the order of the siblings.
+    persisted_cids: LeafStash,
     reused_children: Vec<Visited>,
     cid: Option<Cid>,
     total_size: u64,
     // from TreeOptions
     opts: TreeOptions,
+    summary: BuildSummary,
+    // next free id for the HAMT buckets, which need their own slots in persisted_cids
+    next_id: u64,
+    block_cache: BlockCache,
+    // entered for every step of the iteration, with the spans of the renders nested in it
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Statistics on the shape of the tree, accumulated by `PostOrderIterator` while the directory
+/// nodes are being rendered. See `PostOrderIterator::summary`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BuildSummary {
+    /// Number of directory nodes rendered so far.
+    pub directories: u64,
+    /// Number of links to leaves (files, symlinks or opaque links) within the directories which
+    /// have been or will be rendered.
+    pub leaf_links: u64,
+    /// The largest number of links in any of the rendered directories.
+    pub max_fanout: usize,
+    /// The size of the largest rendered directory block in bytes.
+    pub largest_block: usize,
+    /// Number of HAMT shard bucket nodes rendered so far, including the root bucket of each
+    /// sharded directory. These are not included in `directories`.
+    pub hamt_buckets: u64,
+    /// The fanout of the rendered HAMT shard buckets, if any were rendered.
+    pub hamt_fanout: Option<u64>,
 }
 
 /// The link list used to create the directory node. This list is created from a the BTreeMap
 /// inside DirBuilder, and initially it will have `Some` values only for the initial leaves and
 /// `None` values for subnodes which are not yet ready. At the time of use, this list is expected
 /// to have only `Some` values.
-type Leaves = Vec<Option<NamedLeaf>>;
+pub(super) type Leaves = Vec<Option<NamedLeaf>>;
 
 /// The nodes in the visit. We need to do a post-order visit, which starts from a single
 /// `DescentRoot`, followed by N `Descents` where N is the deepest directory in the tree. On each
@@ -37,7 +113,8 @@ type Leaves = Vec<Option<NamedLeaf>>;
 /// of the node. Directories are rendered when all of their direct and indirect descendants have
 /// been serialized into NamedLeafs.
 #[derive(Debug)]
-enum Visited {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(super) enum Visited {
     // handle root differently not to infect with the Option<String> and Option<usize>
     DescentRoot(DirBuilder),
     Descent {
@@ -55,62 +132,386 @@ enum Visited {
         /// Leaves will be stored directly in this field when there are no DirBuilder descendants,
         /// in the `PostOrderIterator::persisted_cids` otherwise.
         leaves: LeafStorage,
+        #[cfg_attr(feature = "serde", serde(default))]
+        metadata: Metadata,
     },
     PostRoot {
         leaves: LeafStorage,
+        #[cfg_attr(feature = "serde", serde(default))]
+        metadata: Metadata,
+    },
+    /// A bucket of HAMT sharded directory, which needs to be split into the immediate leaves and
+    /// nested buckets by the `level` of the hashes.
+    ShardDescent {
+        links: Vec<(u64, NamedLeaf)>,
+        level: u32,
+        id: u64,
+        target: ShardTarget,
+        /// The metadata of the sharded directory for its root bucket, the default for the nested
+        /// buckets.
+        #[cfg_attr(feature = "serde", serde(default))]
+        metadata: Metadata,
+    },
+    /// A bucket of HAMT sharded directory to be rendered once all of the nested buckets have been
+    /// rendered.
+    ShardPost {
+        leaves: LeafStorage,
+        bitfield: Bitfield,
+        target: ShardTarget,
+        #[cfg_attr(feature = "serde", serde(default))]
+        metadata: Metadata,
+    },
+}
+
+/// Where the rendered HAMT bucket should be linked from.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(super) enum ShardTarget {
+    /// The root bucket of the sharded wrapping root directory.
+    Root,
+    /// The root bucket which takes the place of the sharded directory in its parent.
+    Directory {
+        parent_id: u64,
+        name: String,
+        index: usize,
+    },
+    /// Nested bucket in the parent bucket, linked with the hex `name` of the bucket.
+    Bucket {
+        parent_id: u64,
+        index: usize,
+        name: String,
     },
 }
 
 impl PostOrderIterator {
-    pub(super) fn new(root: DirBuilder, opts: TreeOptions, longest_path: usize) -> Self {
+    /// Creates a new iterator over the given tree; `next_id` must be larger than any of the ids
+    /// in the tree.
+    pub(super) fn new(
+        root: DirBuilder,
+        opts: TreeOptions,
+        longest_path: usize,
+        next_id: u64,
+    ) -> Self {
+        Self::new_with_buffer(root, opts, longest_path, next_id, Vec::new())
+    }
+
+    /// Same as `PostOrderIterator::new` but renders the blocks into the given `buffer`, which
+    /// can be reclaimed with `PostOrderIterator::into_buffer`.
+    pub(super) fn new_with_buffer(
+        mut root: DirBuilder,
+        opts: TreeOptions,
+        longest_path: usize,
+        mut next_id: u64,
+        mut buffer: Vec<u8>,
+    ) -> Self {
+        buffer.clear();
+
+        if let Some(max_links) = opts.prefix_buckets {
+            root.split_into_buckets(max_links.max(2), &mut next_id);
+        }
+
         let root = Visited::DescentRoot(root);
+        let prefix = opts.path_prefix.as_deref().unwrap_or_default();
+        let mut full_path = String::with_capacity(prefix.len() + 1 + longest_path);
+        full_path.push_str(prefix);
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("build_tree", prefix = prefix);
+
         PostOrderIterator {
-            full_path: String::with_capacity(longest_path),
+            prefix_len: full_path.len(),
+            full_path,
             old_depth: 0,
-            block_buffer: Default::default(),
+            block_buffer: buffer,
             pending: vec![root],
-            persisted_cids: Default::default(),
+            persisted_cids: LeafStash::new(&opts),
             reused_children: Vec::new(),
             cid: None,
             total_size: 0,
             opts,
+            summary: Default::default(),
+            next_id,
+            block_cache: Default::default(),
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+
+    /// Stops the iteration, returning the emptied block buffer so that its allocation can be
+    /// reused for building another tree, see `BufferingTreeBuilder::build_with_buffer` and
+    /// `DirBuilder::build_with_buffer`. The buffer will have the capacity needed for the largest
+    /// block rendered so far.
+    pub fn into_buffer(self) -> Vec<u8> {
+        let mut buffer = self.block_buffer;
+        buffer.clear();
+        buffer
+    }
+
+    /// Stops the iteration, returning the state required to continue it later with
+    /// `PostOrderIterator::from_checkpoint`. The checkpoint is taken in between the yielded nodes,
+    /// so the latest node returned by `next` or `next_borrowed` must have been stored before.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the leaves spilled to disk with `TreeOptions::spill_budget` cannot be read back.
+    #[cfg(feature = "serde")]
+    pub fn into_checkpoint(self) -> super::Checkpoint {
+        super::Checkpoint {
+            full_path: self.full_path[self.prefix_len..]
+                .trim_start_matches('/')
+                .to_owned(),
+            old_depth: self.old_depth,
+            pending: self.pending,
+            persisted_cids: self
+                .persisted_cids
+                .into_map()
+                .expect("reading back the spilled leaves failed"),
+            cid: self.cid,
+            total_size: self.total_size,
+            summary: self.summary,
+            next_id: self.next_id,
+        }
+    }
+
+    /// Continues an iteration stopped with `PostOrderIterator::into_checkpoint`. The `opts` must
+    /// be the same as the ones used to create the original iterator.
+    #[cfg(feature = "serde")]
+    pub fn from_checkpoint(checkpoint: super::Checkpoint, opts: TreeOptions) -> Self {
+        let super::Checkpoint {
+            full_path: path,
+            old_depth,
+            pending,
+            persisted_cids,
+            cid,
+            total_size,
+            summary,
+            next_id,
+        } = checkpoint;
+
+        let mut iter = Self::new(DirBuilder::root(0), opts, path.len(), next_id);
+        if !iter.full_path.is_empty() && !path.is_empty() {
+            iter.full_path.push('/');
+        }
+        iter.full_path.push_str(&path);
+        iter.old_depth = old_depth;
+        iter.pending = pending;
+        iter.persisted_cids.restore(persisted_cids);
+        iter.cid = cid;
+        iter.total_size = total_size;
+        iter.summary = summary;
+        iter
+    }
+
+    /// Returns a snapshot of the statistics gathered so far. Once the iterator has been exhausted
+    /// the summary covers the whole tree.
+    pub fn summary(&self) -> BuildSummary {
+        self.summary
+    }
+
+    /// Returns the block of a recently rendered directory or HAMT shard bucket, if it is still
+    /// kept in the cache enabled with `TreeOptions::block_cache_size`. The cache is not part of
+    /// the checkpoints, and starts empty when continuing from one.
+    pub fn get_cached_block(&self, cid: &Cid) -> Option<&[u8]> {
+        self.block_cache.blocks.get(cid).map(|block| &block[..])
+    }
+
+    /// Returns the start time of a render when it needs to be reported to the
+    /// `TreeOptions::render_observer`.
+    fn render_started(&self) -> Option<Instant> {
+        self.opts.render_observer.as_ref().map(|_| Instant::now())
+    }
+
+    /// Returns the span for rendering the directory or the HAMT shard bucket with the `links` at
+    /// the current path.
+    #[cfg(feature = "tracing")]
+    fn render_span(&self, links: usize, bucket: bool) -> tracing::Span {
+        tracing::debug_span!(
+            "render_directory",
+            path = self.full_path.as_str(),
+            link_count = links,
+            bucket = bucket,
+        )
+    }
+
+    fn check_node_count(&self) -> Result<(), TreeConstructionFailed> {
+        let rendered = self.summary.directories + self.summary.hamt_buckets;
+        self.opts.check_node_count(rendered)
+    }
+
+    fn record_rendered(&mut self, links: usize, bucket: bool, started: Option<Instant>) {
+        if let (Some(observer), Some(started)) = (&self.opts.render_observer, started) {
+            let elapsed = started.elapsed();
+            observer.observe(&self.full_path, links, self.block_buffer.len(), elapsed);
+        }
+
+        let summary = &mut self.summary;
+        if bucket {
+            summary.hamt_buckets += 1;
+            summary.hamt_fanout = Some(self.opts.shard_fanout);
+        } else {
+            summary.directories += 1;
+        }
+        summary.max_fanout = summary.max_fanout.max(links);
+        summary.largest_block = summary.largest_block.max(self.block_buffer.len());
+
+        if let Some(cid) = self.cid.as_ref() {
+            let capacity = self.opts.block_cache_size;
+            self.block_cache.insert(capacity, cid, &self.block_buffer);
         }
     }
 
+    /// Renders the plain directory with the mode and the mtime of the `metadata`, if any.
     fn render_directory(
         links: &[Option<NamedLeaf>],
+        metadata: &Metadata,
+        buffer: &mut Vec<u8>,
+        opts: &TreeOptions,
+    ) -> Result<Leaf, TreeConstructionFailed> {
+        Self::render(links, directory_data(opts, metadata), buffer, opts)
+    }
+
+    /// Renders the plain directory at the root of the tree, with the `TreeOptions::root_data`.
+    fn render_root_directory(
+        links: &[Option<NamedLeaf>],
+        metadata: &Metadata,
+        buffer: &mut Vec<u8>,
+        opts: &TreeOptions,
+    ) -> Result<Leaf, TreeConstructionFailed> {
+        let data = match &opts.root_data {
+            RootData::Directory => return Self::render_directory(links, metadata, buffer, opts),
+            RootData::Raw(bytes) => NodeData::Raw(bytes),
+            RootData::Absent => NodeData::Absent,
+        };
+
+        Self::render(links, data, buffer, opts)
+    }
+
+    fn render_bucket(
+        links: &[Option<NamedLeaf>],
+        bitfield: &Bitfield,
+        metadata: &Metadata,
+        buffer: &mut Vec<u8>,
+        opts: &TreeOptions,
+    ) -> Result<Leaf, TreeConstructionFailed> {
+        use alloc::borrow::Cow;
+
+        let data = UnixFs {
+            Type: UnixFsType::HAMTShard,
+            Data: Some(Cow::Borrowed(bitfield.as_bytes())),
+            hashType: Some(opts.shard_hash_type),
+            fanout: Some(opts.shard_fanout),
+            mode: metadata.mode(),
+            mtime: unix_time(metadata),
+            ..Default::default()
+        };
+
+        Self::render(links, NodeData::UnixFs(data), buffer, opts)
+    }
+
+    fn render(
+        links: &[Option<NamedLeaf>],
+        data: NodeData<'_>,
         buffer: &mut Vec<u8>,
-        block_size_limit: &Option<u64>,
+        opts: &TreeOptions,
+    ) -> Result<Leaf, TreeConstructionFailed> {
+        use quick_protobuf::{MessageWrite, Writer};
+
+        let (node, size) = Self::checked_node(links, data, opts)?;
+
+        if let Some(cap) = opts.buffer_hard_cap {
+            if size as u64 > cap {
+                return Err(TreeConstructionFailed::BufferCapExceeded {
+                    size: size as u64,
+                    cap,
+                });
+            }
+        }
+
+        // the amortized growth of `reserve` could double the buffer past the block size limit
+        buffer.clear();
+        buffer.reserve_exact(size);
+
+        node.write_message(&mut Writer::new(&mut *buffer))
+            .map_err(TreeConstructionFailed::Protobuf)?;
+
+        check_written(size, buffer.len())?;
+
+        let cid = opts.cid_for(buffer)?;
+        let total_size = Self::total_size(links, size, opts)?;
+
+        Ok(Leaf {
+            link: cid,
+            total_size,
+        })
+    }
+
+    /// Renders the plain directory into the `writer` while hashing it, without buffering the
+    /// block. The size limit is checked before anything is written.
+    pub(super) fn render_directory_to<W: std::io::Write>(
+        links: &[Option<NamedLeaf>],
+        writer: W,
+        opts: &TreeOptions,
     ) -> Result<Leaf, TreeConstructionFailed> {
-        use crate::pb::{UnixFs, UnixFsType};
-        use quick_protobuf::{BytesWriter, MessageWrite, Writer};
-        use sha2::{Digest, Sha256};
-
-        // FIXME: ideas on how to turn this into a HAMT sharding on some heuristic. we probably
-        // need to introduce states in to the "iterator":
-        //
-        // 1. bucketization
-        // 2. another post order visit of the buckets?
-        //
-        // the nested post order visit should probably re-use the existing infra ("message
-        // passing") and new ids can be generated by giving this iterator the counter from
-        // BufferedTreeBuilder.
-        //
-        // could also be that the HAMT shard building should start earlier, since the same
-        // heuristic can be detected *at* bufferedtreewriter. there the split would be easier, and
-        // this would "just" be a single node rendering, and not need any additional states..
+        use quick_protobuf::{MessageWrite, Writer};
+
+        opts.validate_cid_options()?;
+
+        let (node, size) =
+            Self::checked_node(links, directory_data(opts, &Metadata::default()), opts)?;
+
+        let mut writer = HashingWriter {
+            inner: writer,
+            hasher: opts.hash.into(),
+            written: 0,
+        };
+
+        node.write_message(&mut Writer::new(&mut writer))
+            .map_err(TreeConstructionFailed::Protobuf)?;
+
+        check_written(size, writer.written)?;
+
+        let cid = opts.cid_for_multihash(writer.hasher.result_reset())?;
+        let total_size = Self::total_size(links, size, opts)?;
+
+        Ok(Leaf {
+            link: cid,
+            total_size,
+        })
+    }
+
+    /// Returns the node to be rendered and its size, checked against the
+    /// `TreeOptions::block_size_limit`.
+    fn checked_node<'a>(
+        links: &'a [Option<NamedLeaf>],
+        data: NodeData<'a>,
+        opts: &TreeOptions,
+    ) -> Result<(CustomFlatUnixFs<'a>, usize), TreeConstructionFailed> {
+        use quick_protobuf::MessageWrite;
+
+        if !opts.allow_non_unixfs_links {
+            let unsupported = links
+                .iter()
+                .flatten()
+                .find(|NamedLeaf(_, cid, _)| !is_unixfs_codec(cid.codec()));
+
+            if let Some(NamedLeaf(name, cid, _)) = unsupported {
+                return Err(TreeConstructionFailed::UnsupportedLinkCodec {
+                    name: name.clone(),
+                    codec: cid.codec(),
+                });
+            }
+        }
 
         let node = CustomFlatUnixFs {
             links,
-            data: UnixFs {
-                Type: UnixFsType::Directory,
-                ..Default::default()
-            },
+            data,
+            omit_tsize: opts.omit_tsize,
+            data_first: opts.data_first,
         };
 
         let size = node.get_size();
 
-        if let Some(limit) = block_size_limit {
+        if let Some(limit) = &opts.block_size_limit {
             let size = size as u64;
             if *limit < size {
                 // FIXME: this could probably be detected at builder
@@ -118,90 +519,312 @@ impl PostOrderIterator {
             }
         }
 
-        let cap = buffer.capacity();
+        Ok((node, size))
+    }
+
+    /// Returns the total size of the rendered block of `block_size` bytes with the `links`.
+    fn total_size(
+        links: &[Option<NamedLeaf>],
+        block_size: usize,
+        opts: &TreeOptions,
+    ) -> Result<u64, TreeConstructionFailed> {
+        let own_size = if opts.content_only_total_size {
+            0
+        } else {
+            block_size as u64
+        };
 
-        if let Some(additional) = size.checked_sub(cap) {
-            buffer.reserve(additional);
-        }
+        // the sizes could be anything given to BufferingTreeBuilder::put_link
+        links
+            .iter()
+            .map(|opt| {
+                opt.as_ref()
+                    .map(|NamedLeaf(_, _, total_size)| *total_size)
+                    .unwrap()
+            })
+            .try_fold(own_size, u64::checked_add)
+            .ok_or(TreeConstructionFailed::SizeOverflow)
+    }
 
-        if let Some(mut needed_zeroes) = size.checked_sub(buffer.len()) {
-            let zeroes = [0; 8];
+    /// Returns `true` if the directory with the given links should be rendered as a HAMT sharded
+    /// directory.
+    fn should_shard(&self, leaves: &[Option<NamedLeaf>], metadata: &Metadata) -> bool {
+        use quick_protobuf::MessageWrite;
 
-            while needed_zeroes > 8 {
-                buffer.extend_from_slice(&zeroes[..]);
-                needed_zeroes -= zeroes.len();
+        match self.opts.sharding_threshold {
+            Some(threshold) => {
+                let node = CustomFlatUnixFs {
+                    links: leaves,
+                    data: directory_data(&self.opts, metadata),
+                    omit_tsize: self.opts.omit_tsize,
+                    data_first: self.opts.data_first,
+                };
+                node.get_size() as u64 > threshold
             }
+            None => false,
+        }
+    }
 
-            buffer.extend(core::iter::repeat(0).take(needed_zeroes));
+    /// Schedules the rendering of the directory with the completed `leaves` as HAMT buckets. The
+    /// root bucket will be linked to the `target`.
+    fn start_sharding(&mut self, leaves: Leaves, target: ShardTarget, metadata: Metadata) {
+        let links = leaves
+            .into_iter()
+            .map(|leaf| leaf.expect("all links have been completed before rendering"))
+            .map(|leaf| (hamt::hash_name(&leaf.0), leaf))
+            .collect();
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.pending.push(Visited::ShardDescent {
+            links,
+            level: 0,
+            id,
+            target,
+            metadata,
+        });
+    }
+
+    /// Fills the slot of the rendered child in the stashed leaves of its parent. The parent must
+    /// still be waiting for its children, which is guaranteed by the post order.
+    fn complete_child(
+        &mut self,
+        parent_id: u64,
+        index: usize,
+        child: NamedLeaf,
+    ) -> Result<(), TreeConstructionFailed> {
+        if !self.persisted_cids.fill(parent_id, index, child)? {
+            panic!(
+                "leaves not found for parent_id = {} and index = {}",
+                parent_id, index
+            );
         }
+        Ok(())
+    }
 
-        let mut writer = Writer::new(BytesWriter::new(&mut buffer[..]));
-        node.write_message(&mut writer)
-            .map_err(TreeConstructionFailed::Protobuf)?;
+    /// Returns the `Cid` and the total size of the root of the tree once the iteration has been
+    /// completed, or `None` if it is still in progress or there is no root. The root is the last
+    /// yielded node, except for a single top level file added without
+    /// `TreeOptions::wrap_with_directory`, which is the root by itself without any node being
+    /// yielded.
+    pub fn root(&self) -> Option<(&Cid, u64)> {
+        if self.pending.is_empty() {
+            self.cid.as_ref().map(|cid| (cid, self.total_size))
+        } else {
+            None
+        }
+    }
 
-        buffer.truncate(size);
+    /// Returns the path of the latest visited node, which is the path of the directory which
+    /// failed when an error was just returned.
+    pub(super) fn current_path(&self) -> &str {
+        &self.full_path
+    }
 
-        let mh = multihash::wrap(multihash::Code::Sha2_256, &Sha256::digest(&buffer));
-        let cid = Cid::new_v0(mh).expect("sha2_256 is the correct multihash for cidv0");
+    /// Returns an iterator of only the nodes at the given depth, for example the top level
+    /// directories at depth one, counted in the segments of the path after the
+    /// `TreeOptions::path_prefix`. The wrapping root directory is at depth zero. The HAMT shard
+    /// buckets have the depth of their directory.
+    ///
+    /// All of the nodes are still rendered, as the `Cid`s of the directories depend on their
+    /// subdirectories, but only the nodes at the depth are copied into `OwnedTreeNode`s. The
+    /// errors are always returned.
+    pub fn only_depth(self, depth: usize) -> OnlyDepth {
+        OnlyDepth { iter: self, depth }
+    }
 
-        let combined_from_links = links
-            .iter()
-            .map(|opt| {
-                opt.as_ref()
-                    .map(|NamedLeaf(_, _, total_size)| total_size)
-                    .unwrap()
-            })
-            .sum::<u64>();
+    /// Returns the depth of the latest yielded node, see `PostOrderIterator::only_depth`.
+    fn current_depth(&self) -> usize {
+        let path = self.full_path[self.prefix_len..].trim_start_matches('/');
+        if path.is_empty() {
+            0
+        } else {
+            path.split('/').count()
+        }
+    }
 
-        Ok(Leaf {
-            link: cid,
-            total_size: buffer.len() as u64 + combined_from_links,
-        })
+    /// Returns `true` if the node about to be yielded is the root of the tree, which is always the
+    /// last node.
+    fn is_root_next(&self) -> bool {
+        match self.pending.as_slice() {
+            [] => true,
+            // the single top level directory is the root when not wrapping
+            [Visited::PostRoot { .. }] => !self.opts.wrap_with_directory,
+            _ => false,
+        }
+    }
+
+    /// Construct the next dag-pb node, if any, returning it as an `OwnedTreeNode` which holds its
+    /// own copy of the path and the block.
+    ///
+    /// This is the same as `Iterator::next` and the recommended way of walking the tree; see
+    /// `next_borrowed` for avoiding the copies.
+    pub fn next_owned(&mut self) -> Option<Result<OwnedTreeNode, TreeConstructionFailed>> {
+        self.next_borrowed()
+            .map(|res| res.map(TreeNode::into_owned))
     }
 
-    /// Construct the next dag-pb node, if any.
+    /// Construct the next dag-pb node, if any, without copying it.
     ///
-    /// Returns a `TreeNode` of the latest constructed tree node.
+    /// Returns a `TreeNode` of the latest constructed tree node, borrowing the path and the block
+    /// from the buffers of the iterator, which are reused for the next node. The borrow checker
+    /// does not allow calling this again while the `TreeNode` is alive, so the block must be
+    /// stored or copied before advancing; use `next_owned` or the `Iterator` implementation when
+    /// the nodes need to be kept.
     pub fn next_borrowed(&mut self) -> Option<Result<TreeNode<'_>, TreeConstructionFailed>> {
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+
         while let Some(visited) = self.pending.pop() {
+            // only the node rendered last has a cid, so that there is no root after a failure
+            self.cid = None;
+
             let (name, depth) = match &visited {
                 Visited::DescentRoot(_) => (None, 0),
                 Visited::Descent { name, depth, .. } => (Some(name.as_ref()), *depth),
                 Visited::Post { name, depth, .. } => (Some(name.as_ref()), *depth),
                 Visited::PostRoot { .. } => (None, 0),
+                // the buckets are visited right after the sharded directory and share its path
+                Visited::ShardDescent { .. } | Visited::ShardPost { .. } => (None, self.old_depth),
             };
 
-            update_full_path((&mut self.full_path, &mut self.old_depth), name, depth);
+            if !matches!(
+                visited,
+                Visited::ShardDescent { .. } | Visited::ShardPost { .. }
+            ) {
+                update_full_path(
+                    (&mut self.full_path, &mut self.old_depth),
+                    self.prefix_len,
+                    name,
+                    depth,
+                );
+            }
 
             match visited {
-                Visited::DescentRoot(node) => {
+                Visited::DescentRoot(mut node) => {
+                    let dir_path = self.full_path[self.prefix_len..].trim_start_matches('/');
+                    let filter = self.opts.entry_filter.as_ref();
+                    node.nodes = match filter_entries(node.nodes, dir_path, filter) {
+                        Ok(nodes) => nodes,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    let policy = self.opts.case_insensitive_collisions;
+                    node.nodes = match resolve_case_collisions(node.nodes, policy) {
+                        Ok(nodes) => nodes,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if let Err(e) = check_deferred_sizes(&node.nodes, dir_path) {
+                        return Some(Err(e));
+                    }
+
+                    if !self.opts.wrap_with_directory && node.nodes.len() > 1 {
+                        // fail before rendering any of the subtrees which would have to be
+                        // discarded anyway
+                        return Some(Err(TreeConstructionFailed::MultipleRoots(node.nodes.len())));
+                    }
+
+                    if let Err(e) = node
+                        .nodes
+                        .keys()
+                        .try_for_each(|name| self.opts.check_name(name))
+                    {
+                        return Some(Err(e));
+                    }
+
                     let children = &mut self.reused_children;
-                    let leaves = partition_children_leaves(depth, node.nodes.into_iter(), children);
+                    let entries = ordered_entries(node.nodes, self.opts.link_order.as_ref());
+                    let rewriter = self.opts.link_rewriter.as_ref();
+                    let leaves = match partition_children_leaves(depth, entries, children, rewriter)
+                    {
+                        Ok(leaves) => leaves,
+                        Err(e) => return Some(Err(e)),
+                    };
                     let any_children = !children.is_empty();
 
+                    if self.opts.wrap_with_directory {
+                        self.summary.leaf_links += count_leaves(&leaves);
+                    }
+
                     let leaves = if any_children {
-                        self.persisted_cids.insert(node.id, leaves);
+                        if let Err(e) = self.persisted_cids.insert(node.id, leaves) {
+                            return Some(Err(e));
+                        }
                         LeafStorage::from(node.id)
                     } else {
                         leaves.into()
                     };
 
-                    self.pending.push(Visited::PostRoot { leaves });
+                    self.pending.push(Visited::PostRoot {
+                        leaves,
+                        metadata: node.metadata,
+                    });
                     self.pending.extend(children.drain(..));
                 }
                 Visited::Descent {
-                    node,
+                    mut node,
                     name,
                     depth,
                     index,
                 } => {
+                    if self.opts.max_depth.map(|max| depth > max).unwrap_or(false) {
+                        return Some(Err(TreeConstructionFailed::MaxDepthExceeded(depth)));
+                    }
+
+                    // the ids of the ancestors are in use as the keys of their stashed leaves; a
+                    // directory reusing one would overwrite them, for example after deserializing
+                    // a hand crafted `DirBuilder`
+                    if node.parent_id == Some(node.id) || self.persisted_cids.contains_key(node.id)
+                    {
+                        return Some(Err(TreeConstructionFailed::CycleDetected(node.id)));
+                    }
+
+                    let dir_path = self.full_path[self.prefix_len..].trim_start_matches('/');
+                    let filter = self.opts.entry_filter.as_ref();
+                    node.nodes = match filter_entries(node.nodes, dir_path, filter) {
+                        Ok(nodes) => nodes,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    let policy = self.opts.case_insensitive_collisions;
+                    node.nodes = match resolve_case_collisions(node.nodes, policy) {
+                        Ok(nodes) => nodes,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if let Err(e) = check_deferred_sizes(&node.nodes, dir_path) {
+                        return Some(Err(e));
+                    }
+
+                    if let Err(e) = node
+                        .nodes
+                        .keys()
+                        .try_for_each(|name| self.opts.check_name(name))
+                    {
+                        return Some(Err(e));
+                    }
+
                     let children = &mut self.reused_children;
-                    let leaves = partition_children_leaves(depth, node.nodes.into_iter(), children);
+                    let entries = ordered_entries(node.nodes, self.opts.link_order.as_ref());
+                    let rewriter = self.opts.link_rewriter.as_ref();
+                    let leaves = match partition_children_leaves(depth, entries, children, rewriter)
+                    {
+                        Ok(leaves) => leaves,
+                        Err(e) => return Some(Err(e)),
+                    };
                     let any_children = !children.is_empty();
                     let parent_id = node.parent_id.expect("only roots parent_id is None");
 
+                    self.summary.leaf_links += count_leaves(&leaves);
+
                     let leaves = if any_children {
-                        self.persisted_cids.insert(node.id, leaves);
+                        if let Err(e) = self.persisted_cids.insert(node.id, leaves) {
+                            return Some(Err(e));
+                        }
                         node.id.into()
                     } else {
                         leaves.into()
@@ -213,6 +836,7 @@ impl PostOrderIterator {
                         depth,
                         leaves,
                         index,
+                        metadata: node.metadata,
                     });
 
                     self.pending.extend(children.drain(..));
@@ -222,40 +846,63 @@ impl PostOrderIterator {
                     name,
                     leaves,
                     index,
+                    metadata,
                     ..
                 } => {
-                    let leaves = leaves.into_inner(&mut self.persisted_cids);
+                    let leaves = match leaves.into_inner(&mut self.persisted_cids) {
+                        Ok(leaves) => leaves,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if has_failed_child(&leaves) {
+                        // a subdirectory failed earlier and the iteration was continued, which
+                        // makes this directory unbuildable as well, see `drive_lenient`
+                        continue;
+                    }
+
+                    if self.should_shard(&leaves, &metadata) {
+                        let target = ShardTarget::Directory {
+                            parent_id,
+                            name,
+                            index,
+                        };
+                        self.start_sharding(leaves, target, metadata);
+                        continue;
+                    }
+
+                    if let Err(e) = self.check_node_count() {
+                        return Some(Err(e));
+                    }
+
+                    let render = if self.is_root_next() {
+                        Self::render_root_directory
+                    } else {
+                        Self::render_directory
+                    };
+                    #[cfg(feature = "tracing")]
+                    let span = self.render_span(leaves.len(), false);
+                    #[cfg(feature = "tracing")]
+                    let _entered = span.enter();
+
+                    let started = self.render_started();
                     let buffer = &mut self.block_buffer;
 
-                    let leaf = match Self::render_directory(
-                        &leaves,
-                        buffer,
-                        &self.opts.block_size_limit,
-                    ) {
+                    let leaf = match render(&leaves, &metadata, buffer, &self.opts) {
                         Ok(leaf) => leaf,
                         Err(e) => return Some(Err(e)),
                     };
 
+                    if let Err(e) = self.opts.check_total_size(leaf.total_size) {
+                        return Some(Err(e));
+                    }
+
                     self.cid = Some(leaf.link.clone());
                     self.total_size = leaf.total_size;
+                    self.record_rendered(leaves.len(), false, started);
 
-                    {
-                        // name is None only for wrap_with_directory, which cannot really be
-                        // propagated up but still the parent_id is allowed to be None
-                        let parent_leaves = self.persisted_cids.get_mut(&parent_id);
-
-                        match (parent_id, parent_leaves, index) {
-                            (pid, None, index) => panic!(
-                                "leaves not found for parent_id = {} and index = {}",
-                                pid, index
-                            ),
-                            (_, Some(vec), index) => {
-                                let cell = &mut vec[index];
-                                // all
-                                assert!(cell.is_none());
-                                *cell = Some(NamedLeaf(name, leaf.link, leaf.total_size));
-                            }
-                        }
+                    let child = NamedLeaf(name, leaf.link, leaf.total_size);
+                    if let Err(e) = self.complete_child(parent_id, index, child) {
+                        return Some(Err(e));
                     }
 
                     return Some(Ok(TreeNode {
@@ -263,50 +910,271 @@ impl PostOrderIterator {
                         cid: self.cid.as_ref().unwrap(),
                         total_size: self.total_size,
                         block: &self.block_buffer,
+                        is_root: self.is_root_next(),
                     }));
                 }
-                Visited::PostRoot { leaves } => {
-                    let leaves = leaves.into_inner(&mut self.persisted_cids);
+                Visited::PostRoot { leaves, metadata } => {
+                    let leaves = match leaves.into_inner(&mut self.persisted_cids) {
+                        Ok(leaves) => leaves,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if has_failed_child(&leaves) {
+                        // there is no root as some of the subdirectories failed
+                        break;
+                    }
 
                     if !self.opts.wrap_with_directory {
+                        // there is at most one top level entry, as checked in `DescentRoot`, and it
+                        // is the root; for a directory this is the latest yielded node, but a file
+                        // has no block to be yielded
+                        if let Some(Some(NamedLeaf(_, cid, total_size))) = leaves.into_iter().next()
+                        {
+                            self.cid = Some(cid);
+                            self.total_size = total_size;
+                        }
                         break;
                     }
 
+                    if self.should_shard(&leaves, &metadata) {
+                        self.start_sharding(leaves, ShardTarget::Root, metadata);
+                        continue;
+                    }
+
+                    if let Err(e) = self.check_node_count() {
+                        return Some(Err(e));
+                    }
+
+                    #[cfg(feature = "tracing")]
+                    let span = self.render_span(leaves.len(), false);
+                    #[cfg(feature = "tracing")]
+                    let _entered = span.enter();
+
+                    let started = self.render_started();
                     let buffer = &mut self.block_buffer;
 
-                    let leaf = match Self::render_directory(
-                        &leaves,
-                        buffer,
-                        &self.opts.block_size_limit,
-                    ) {
-                        Ok(leaf) => leaf,
-                        Err(e) => return Some(Err(e)),
-                    };
+                    let leaf =
+                        match Self::render_root_directory(&leaves, &metadata, buffer, &self.opts) {
+                            Ok(leaf) => leaf,
+                            Err(e) => return Some(Err(e)),
+                        };
+
+                    if let Err(e) = self.opts.check_total_size(leaf.total_size) {
+                        return Some(Err(e));
+                    }
 
                     self.cid = Some(leaf.link.clone());
                     self.total_size = leaf.total_size;
+                    self.record_rendered(leaves.len(), false, started);
 
                     return Some(Ok(TreeNode {
                         path: self.full_path.as_str(),
                         cid: self.cid.as_ref().unwrap(),
                         total_size: self.total_size,
                         block: &self.block_buffer,
+                        is_root: self.is_root_next(),
                     }));
                 }
-            }
-        }
-        None
-    }
-}
+                Visited::ShardDescent {
+                    mut links,
+                    level,
+                    id,
+                    target,
+                    metadata,
+                } => {
+                    let layout = match self.opts.shard_layout() {
+                        Ok(layout) => layout,
+                        Err(e) => return Some(Err(e)),
+                    };
 
-impl Iterator for PostOrderIterator {
-    type Item = Result<OwnedTreeNode, TreeConstructionFailed>;
+                    if level >= layout.max_levels() {
+                        let mut names = links.into_iter().map(|(_, NamedLeaf(name, ..))| name);
+                        let first = names.next().unwrap_or_default();
+                        let second = names.next().unwrap_or_default();
+                        return Some(Err(TreeConstructionFailed::HashCollision(first, second)));
+                    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.next_borrowed()
-            .map(|res| res.map(TreeNode::into_owned))
-    }
-}
+                    // the order within a bucket does not matter, it will be sorted on the next
+                    // level if needed
+                    links.sort_by_key(|(hash, _)| layout.bucket_index(*hash, level));
+
+                    let children = &mut self.reused_children;
+                    let mut leaves = Vec::new();
+                    let mut bitfield = Bitfield::default();
+                    let mut links = links.into_iter().peekable();
+
+                    while let Some((hash, first)) = links.next() {
+                        let bucket = layout.bucket_index(hash, level);
+                        bitfield.set(bucket);
+
+                        let mut same_bucket = Vec::new();
+                        while let Some((next_hash, _)) = links.peek() {
+                            if layout.bucket_index(*next_hash, level) != bucket {
+                                break;
+                            }
+                            same_bucket.push(links.next().unwrap());
+                        }
+
+                        if same_bucket.is_empty() {
+                            let NamedLeaf(name, cid, total_size) = first;
+                            let name = layout.bucket_name(bucket) + &name;
+                            leaves.push(Some(NamedLeaf(name, cid, total_size)));
+                        } else {
+                            same_bucket.push((hash, first));
+
+                            let child_id = self.next_id;
+                            self.next_id += 1;
+
+                            children.push(Visited::ShardDescent {
+                                links: same_bucket,
+                                level: level + 1,
+                                id: child_id,
+                                target: ShardTarget::Bucket {
+                                    parent_id: id,
+                                    index: leaves.len(),
+                                    name: layout.bucket_name(bucket),
+                                },
+                                metadata: Metadata::default(),
+                            });
+                            leaves.push(None);
+                        }
+                    }
+
+                    let leaves = if children.is_empty() {
+                        leaves.into()
+                    } else {
+                        if let Err(e) = self.persisted_cids.insert(id, leaves) {
+                            return Some(Err(e));
+                        }
+                        id.into()
+                    };
+
+                    self.pending.push(Visited::ShardPost {
+                        leaves,
+                        bitfield,
+                        target,
+                        metadata,
+                    });
+                    self.pending.append(children);
+                }
+                Visited::ShardPost {
+                    leaves,
+                    bitfield,
+                    target,
+                    metadata,
+                } => {
+                    let leaves = match leaves.into_inner(&mut self.persisted_cids) {
+                        Ok(leaves) => leaves,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if has_failed_child(&leaves) {
+                        // a subdirectory failed earlier and the iteration was continued, which
+                        // makes this directory unbuildable as well, see `drive_lenient`
+                        continue;
+                    }
+
+                    if let Err(e) = self.check_node_count() {
+                        return Some(Err(e));
+                    }
+
+                    #[cfg(feature = "tracing")]
+                    let span = self.render_span(leaves.len(), true);
+                    #[cfg(feature = "tracing")]
+                    let _entered = span.enter();
+
+                    let started = self.render_started();
+                    let buffer = &mut self.block_buffer;
+
+                    let rendered =
+                        Self::render_bucket(&leaves, &bitfield, &metadata, buffer, &self.opts);
+                    let leaf = match rendered {
+                        Ok(leaf) => leaf,
+                        Err(e) => return Some(Err(e)),
+                    };
+
+                    if let Err(e) = self.opts.check_total_size(leaf.total_size) {
+                        return Some(Err(e));
+                    }
+
+                    self.cid = Some(leaf.link.clone());
+                    self.total_size = leaf.total_size;
+                    self.record_rendered(leaves.len(), true, started);
+
+                    let completed = match target {
+                        ShardTarget::Root => Ok(()),
+                        ShardTarget::Directory {
+                            parent_id,
+                            name,
+                            index,
+                        }
+                        | ShardTarget::Bucket {
+                            parent_id,
+                            index,
+                            name,
+                        } => self.complete_child(
+                            parent_id,
+                            index,
+                            NamedLeaf(name, leaf.link, leaf.total_size),
+                        ),
+                    };
+
+                    if let Err(e) = completed {
+                        return Some(Err(e));
+                    }
+
+                    return Some(Ok(TreeNode {
+                        path: self.full_path.as_str(),
+                        cid: self.cid.as_ref().unwrap(),
+                        total_size: self.total_size,
+                        block: &self.block_buffer,
+                        is_root: self.is_root_next(),
+                    }));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for PostOrderIterator {
+    type Item = Result<OwnedTreeNode, TreeConstructionFailed>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_owned()
+    }
+}
+
+/// Iterator of the nodes at a single depth, see `PostOrderIterator::only_depth`.
+pub struct OnlyDepth {
+    iter: PostOrderIterator,
+    depth: usize,
+}
+
+impl Iterator for OnlyDepth {
+    type Item = Result<OwnedTreeNode, TreeConstructionFailed>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // the depth can only be read once the borrowed node has been dropped
+            let is_root = match self.iter.next_borrowed()? {
+                Ok(node) => node.is_root,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if self.iter.current_depth() == self.depth {
+                let node = TreeNode {
+                    path: &self.iter.full_path,
+                    cid: self.iter.cid.as_ref().expect("a node was just yielded"),
+                    total_size: self.iter.total_size,
+                    block: &self.iter.block_buffer,
+                    is_root,
+                };
+                return Some(Ok(node.into_owned()));
+            }
+        }
+    }
+}
 
 /// Borrowed representation of a node in the tree.
 pub struct TreeNode<'a> {
@@ -314,10 +1182,13 @@ pub struct TreeNode<'a> {
     pub path: &'a str,
     /// The Cid of the document.
     pub cid: &'a Cid,
-    /// Cumulative total size of the subtree in bytes.
+    /// Cumulative total size of the subtree in bytes: the size of this block and the total sizes of
+    /// all of the links, see `TreeOptions::content_only_total_size`.
     pub total_size: u64,
     /// Raw dag-pb document.
     pub block: &'a [u8],
+    /// True for the root of the tree, which is the last node.
+    pub is_root: bool,
 }
 
 impl<'a> fmt::Debug for TreeNode<'a> {
@@ -327,10 +1198,28 @@ impl<'a> fmt::Debug for TreeNode<'a> {
             .field("cid", &format_args!("{}", self.cid))
             .field("total_size", &self.total_size)
             .field("size", &self.block.len())
+            .field("is_root", &self.is_root)
             .finish()
     }
 }
 
+/// Single line summary of the node for logging: the path, the `Cid`, the length of the block and
+/// the total size, for example `a/b (Qm..., 52 bytes, total 1052)`. The empty path of the root is
+/// shown as `.`.
+impl fmt::Display for TreeNode<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "." } else { self.path };
+        write!(
+            fmt,
+            "{} ({}, {} bytes, total {})",
+            path,
+            self.cid,
+            self.block.len(),
+            self.total_size
+        )
+    }
+}
+
 impl TreeNode<'_> {
     /// Convert to an owned and detached representation.
     pub fn into_owned(self) -> OwnedTreeNode {
@@ -339,31 +1228,180 @@ impl TreeNode<'_> {
             cid: self.cid.to_owned(),
             total_size: self.total_size,
             block: self.block.into(),
+            is_root: self.is_root,
         }
     }
 }
 
+/// Returns `true` for the codecs of the UnixFs nodes, which are `dag-pb` for the files and the
+/// directories and `raw` for the raw leaves, see `TreeOptions::allow_non_unixfs_links`.
+fn is_unixfs_codec(codec: cid::Codec) -> bool {
+    matches!(codec, cid::Codec::DagProtobuf | cid::Codec::Raw)
+}
+
+/// Returns the `PBNode::Data` of a plain directory with the mode and the mtime of the
+/// `metadata`, see `TreeOptions::omit_directory_data`.
+fn directory_data(opts: &TreeOptions, metadata: &Metadata) -> NodeData<'static> {
+    if opts.omit_directory_data {
+        return NodeData::Absent;
+    }
+
+    NodeData::UnixFs(UnixFs {
+        Type: UnixFsType::Directory,
+        mode: metadata.mode(),
+        mtime: unix_time(metadata),
+        ..Default::default()
+    })
+}
+
+pub(super) fn unix_time(metadata: &Metadata) -> Option<UnixTime> {
+    metadata.mtime().map(|(secs, nanos)| UnixTime {
+        Seconds: secs,
+        // the zero nanoseconds are left out
+        FractionalNanoseconds: Some(nanos).filter(|&nanos| nanos != 0),
+    })
+}
+
+/// The most recently rendered blocks, see `TreeOptions::block_cache_size`.
+#[derive(Default)]
+struct BlockCache {
+    blocks: HashMap<Cid, Box<[u8]>>,
+    // least recently rendered first
+    order: VecDeque<Cid>,
+}
+
+impl BlockCache {
+    fn insert(&mut self, capacity: usize, cid: &Cid, block: &[u8]) {
+        if capacity == 0 {
+            return;
+        }
+
+        // identical directories are rendered into the same block, which is kept only once but
+        // becomes the most recently rendered again
+        if self.blocks.contains_key(cid) {
+            let pos = self
+                .order
+                .iter()
+                .position(|cached| cached == cid)
+                .expect("the cached blocks are in the order");
+            let cid = self.order.remove(pos).expect("the position was just found");
+            self.order.push_back(cid);
+            return;
+        }
+
+        while self.order.len() >= capacity {
+            let oldest = self.order.pop_front().expect("the cache is not empty");
+            self.blocks.remove(&oldest);
+        }
+
+        self.order.push_back(cid.to_owned());
+        self.blocks.insert(cid.to_owned(), block.into());
+    }
+}
+
+/// `std::time::Instant::now` panics on `wasm32-unknown-unknown`, where no time is measured for
+/// the `TreeOptions::render_observer` and every render is reported as taking zero time.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Clone, Copy)]
+pub(super) struct Instant;
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Instant {
+    pub(super) fn now() -> Self {
+        Instant
+    }
+
+    pub(super) fn elapsed(&self) -> core::time::Duration {
+        core::time::Duration::from_secs(0)
+    }
+}
+
+/// Checks that the rendered block has the size computed before writing it; a difference would be
+/// a bug in the sizing of the custom protobuf types, and the block would not be what was intended.
+fn check_written(expected: usize, written: usize) -> Result<(), TreeConstructionFailed> {
+    if expected != written {
+        return Err(TreeConstructionFailed::SizeMismatch { expected, written });
+    }
+    Ok(())
+}
+
+/// Forwards the written bytes to `inner` while hashing them.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Box<dyn multihash::MultihashDigest<multihash::Code>>,
+    written: usize,
+}
+
+impl<W: std::io::Write> std::io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// Owned representation of a node in the tree.
+///
+/// With the `serde` feature the node is serialized as a struct with the fields `path`, `cid` as
+/// its canonical string form (base58btc for CIDv0, base32 for CIDv1), `total_size`, `block` as
+/// bytes and `is_root`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OwnedTreeNode {
     /// Full path to the node.
     pub path: String,
     /// The Cid of the document.
+    #[cfg_attr(feature = "serde", serde(with = "super::checkpoint::cid_string"))]
     pub cid: Cid,
-    /// Cumulative total size of the subtree in bytes.
+    /// Cumulative total size of the subtree in bytes: the size of this block and the total sizes of
+    /// all of the links, see `TreeOptions::content_only_total_size`.
     pub total_size: u64,
     /// Raw dag-pb document.
+    #[cfg_attr(feature = "serde", serde(with = "super::checkpoint::boxed_bytes"))]
     pub block: Box<[u8]>,
+    /// True for the root of the tree, which is the last node.
+    pub is_root: bool,
+}
+
+/// Same as the `Display` of `TreeNode`.
+impl fmt::Display for OwnedTreeNode {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node = TreeNode {
+            path: &self.path,
+            cid: &self.cid,
+            total_size: self.total_size,
+            block: &self.block,
+            is_root: self.is_root,
+        };
+        fmt::Display::fmt(&node, fmt)
+    }
 }
 
+/// Updates the `full_path` for the given `name` at `depth`. The first `prefix_len` bytes of the
+/// `full_path` are the `TreeOptions::path_prefix` which is never modified.
 fn update_full_path(
     (full_path, old_depth): (&mut String, &mut usize),
+    prefix_len: usize,
     name: Option<&str>,
     depth: usize,
 ) {
+    // the nodes are visited in the post order, so the next node is at most a child of the
+    // previous one; the popping below relies on the segments being there
+    debug_assert!(
+        depth <= old_depth.saturating_add(1),
+        "descended from depth {} to {}",
+        old_depth,
+        depth
+    );
+
     if depth < 2 {
         // initially thought it might be a good idea to add a slash to all components; removing it made
         // it impossible to get back down to empty string, so fixing this for depths 0 and 1.
-        full_path.clear();
+        full_path.truncate(prefix_len);
         *old_depth = 0;
     } else {
         while *old_depth >= depth && *old_depth > 0 {
@@ -379,11 +1417,11 @@ fn update_full_path(
                 full_path.truncate(slash_at);
                 *old_depth -= 1;
             } else {
-                todo!(
+                // old_depth >= 2 means there are at least two segments after the prefix, and the
+                // names never contain slashes, so the last one is always preceded by a slash
+                unreachable!(
                     "no last slash_at in {:?} yet {} >= {}",
-                    full_path,
-                    old_depth,
-                    depth
+                    full_path, old_depth, depth
                 );
             }
         }
@@ -399,55 +1437,199 @@ fn update_full_path(
         *old_depth += 1;
     }
 
-    assert_eq!(*old_depth, depth);
+    debug_assert_eq!(*old_depth, depth);
+}
+
+/// Returns the entries in the order they will be linked; `BTreeMap` order unless a custom
+/// `LinkOrder` was configured.
+fn ordered_entries(
+    nodes: BTreeMap<String, Entry>,
+    order: Option<&LinkOrder>,
+) -> impl Iterator<Item = (String, Entry)> {
+    use either::Either;
+
+    match order {
+        None => Either::Left(nodes.into_iter()),
+        Some(order) => {
+            let mut entries = nodes.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| order.compare(a, b));
+            Either::Right(entries.into_iter())
+        }
+    }
+}
+
+/// Applies the `filter` to the entries of the directory at `dir_path`.
+fn filter_entries(
+    nodes: BTreeMap<String, Entry>,
+    dir_path: &str,
+    filter: Option<&EntryFilter>,
+) -> Result<BTreeMap<String, Entry>, TreeConstructionFailed> {
+    use super::FilterDecision::*;
+
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Ok(nodes),
+    };
+
+    let mut path = String::with_capacity(dir_path.len() + 1);
+    let mut filtered = BTreeMap::new();
+
+    for (name, entry) in nodes {
+        path.clear();
+        if !dir_path.is_empty() {
+            path.push_str(dir_path);
+            path.push('/');
+        }
+        path.push_str(&name);
+
+        let name = match filter.decide(&path, &entry.kind()) {
+            Keep => name,
+            Skip => continue,
+            Rename(name)
+                if name.is_empty() || name == "." || name == ".." || name.contains('/') =>
+            {
+                return Err(TreeConstructionFailed::InvalidRename { path, name })
+            }
+            Rename(name) => name,
+        };
+
+        if filtered.contains_key(&name) {
+            return Err(TreeConstructionFailed::DuplicateName(name));
+        }
+        filtered.insert(name, entry);
+    }
+
+    Ok(filtered)
+}
+
+/// Applies the `policy` to the entries of a directory with names which are the same when compared
+/// case-insensitively.
+fn resolve_case_collisions(
+    nodes: BTreeMap<String, Entry>,
+    policy: CaseCollisionPolicy,
+) -> Result<BTreeMap<String, Entry>, TreeConstructionFailed> {
+    use CaseCollisionPolicy::*;
+
+    if policy == Allow {
+        return Ok(nodes);
+    }
+
+    // lowercase forms to the first names with them, in the byte order of the names
+    let mut seen: HashMap<String, String> = HashMap::with_capacity(nodes.len());
+    let mut resolved = BTreeMap::new();
+
+    for (name, entry) in nodes {
+        match seen.get(&name.to_lowercase()) {
+            Some(first) if policy == Error => {
+                return Err(TreeConstructionFailed::CaseCollision(first.clone(), name))
+            }
+            Some(_) => continue,
+            None => {
+                seen.insert(name.to_lowercase(), name.clone());
+                resolved.insert(name, entry);
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Fails if any of the leaves of the directory at `dir_path` is still waiting for its size.
+fn check_deferred_sizes(
+    nodes: &BTreeMap<String, Entry>,
+    dir_path: &str,
+) -> Result<(), TreeConstructionFailed> {
+    use TreeConstructionFailed::UnfilledLeafSize;
+
+    match nodes
+        .iter()
+        .find(|(_, entry)| matches!(entry, Entry::Deferred(_)))
+    {
+        Some((name, _)) if dir_path.is_empty() => Err(UnfilledLeafSize(name.clone())),
+        Some((name, _)) => Err(UnfilledLeafSize(format!("{}/{}", dir_path, name))),
+        None => Ok(()),
+    }
 }
 
 /// Returns a Vec of the links in order with only the leaves, the given `children` will contain yet
-/// incomplete nodes of the tree.
+/// incomplete nodes of the tree. The leaves are rewritten with the `rewriter`, if any. Fails if the
+/// subdirectories would be deeper than the `depth` can count.
 fn partition_children_leaves(
     depth: usize,
     it: impl Iterator<Item = (String, Entry)>,
     children: &mut Vec<Visited>,
-) -> Leaves {
+    rewriter: Option<&LinkRewriter>,
+) -> Result<Leaves, TreeConstructionFailed> {
     let mut leaves = Vec::new();
+    // the children are reused between the directories, and none of the children of a failed
+    // directory must be left behind to be descended into
+    let children_len = children.len();
 
     for (i, (k, v)) in it.enumerate() {
         match v {
             Entry::Directory(node) => {
+                let depth = match depth.checked_add(1) {
+                    Some(depth) => depth,
+                    None => {
+                        children.truncate(children_len);
+                        return Err(TreeConstructionFailed::DepthOverflow);
+                    }
+                };
+
                 children.push(Visited::Descent {
                     node,
                     // this needs to be pushed down to update the full_path
                     name: k,
-                    depth: depth + 1,
+                    depth,
                     index: i,
                 });
 
                 // this will be overwritten later, but the order is fixed
                 leaves.push(None);
             }
-            Entry::Leaf(leaf) => leaves.push(Some(NamedLeaf(k, leaf.link, leaf.total_size))),
+            Entry::Deferred(_) => unreachable!("sizes are checked before the partitioning"),
+            Entry::Leaf(leaf) => {
+                let link = match rewriter {
+                    Some(rewriter) => rewriter.rewrite(&leaf.link),
+                    None => leaf.link,
+                };
+                leaves.push(Some(NamedLeaf(k, link, leaf.total_size)))
+            }
         }
     }
 
-    leaves
+    Ok(leaves)
+}
+
+/// Returns true if any of the subdirectories of a directory about to be rendered is missing,
+/// which happens only when the iteration is continued after a failure.
+fn has_failed_child(leaves: &[Option<NamedLeaf>]) -> bool {
+    leaves.iter().any(Option::is_none)
+}
+
+/// Counts the already completed links, which are the leaves until the directories have been
+/// rendered.
+fn count_leaves(leaves: &[Option<NamedLeaf>]) -> u64 {
+    leaves.iter().filter(|leaf| leaf.is_some()).count() as u64
 }
 
 #[derive(Debug)]
-enum LeafStorage {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(super) enum LeafStorage {
     Direct(Leaves),
     Stashed(u64),
 }
 
 impl LeafStorage {
-    fn into_inner(self, stash: &mut HashMap<u64, Leaves>) -> Leaves {
+    fn into_inner(self, stash: &mut LeafStash) -> Result<Leaves, TreeConstructionFailed> {
         use LeafStorage::*;
 
         match self {
-            Direct(leaves) => leaves,
-            Stashed(id) => stash
-                .remove(&id)
+            Direct(leaves) => Ok(leaves),
+            Stashed(id) => Ok(stash
+                .remove(id)?
                 .ok_or(id)
-                .expect("leaves are either stashed or direct, must able to find with id"),
+                .expect("leaves are either stashed or direct, must able to find with id")),
         }
     }
 }
@@ -463,3 +1645,674 @@ impl From<Leaves> for LeafStorage {
         LeafStorage::Direct(leaves)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PostOrderIterator, TreeOptions};
+    use crate::dir::builder::NamedLeaf;
+    use crate::dir::hamt::Bitfield;
+    use crate::pb::FlatUnixFs;
+    use crate::test_support::some_cid;
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use hex_literal::hex;
+
+    proptest::proptest! {
+        #[test]
+        fn paths_are_joined_ancestor_names(
+            paths in proptest::collection::vec(
+                proptest::collection::vec(proptest::sample::select(&["a", "b", "c", "dd"][..]), 1..6),
+                1..20,
+            ),
+            wrap in proptest::bool::ANY,
+            prefix in proptest::option::of(proptest::sample::select(&["p", "p/q"][..])),
+        ) {
+            assert_paths_are_joined_ancestor_names(&paths, wrap, prefix);
+        }
+
+        #[test]
+        fn insertion_order_does_not_change_the_tree(
+            order in proptest::strategy::Strategy::prop_shuffle(
+                proptest::strategy::Just((0..ORDERED_ENTRIES.len()).collect::<Vec<_>>())
+            ),
+            sharded in proptest::bool::ANY,
+        ) {
+            let expected = build_in_order(&(0..ORDERED_ENTRIES.len()).collect::<Vec<_>>(), sharded);
+            assert_eq!(build_in_order(&order, sharded), expected);
+        }
+    }
+
+    /// Directories and files used to check that the order of adding them does not change the
+    /// built tree. `None` is a directory created with `set_metadata`.
+    const ORDERED_ENTRIES: &[(&str, Option<usize>)] = &[
+        ("a/1.txt", Some(0)),
+        ("a/2.txt", Some(1)),
+        ("a/b/3.txt", Some(2)),
+        ("a/b/c/4.txt", Some(3)),
+        ("a/b/c/5.txt", Some(4)),
+        ("a/d", None),
+        ("a/d/6.txt", Some(5)),
+        ("e", None),
+        ("f/7.txt", Some(6)),
+        ("f/g/h/i", None),
+        ("f/g/8.txt", Some(7)),
+        ("j/9.txt", Some(8)),
+        ("j/10.txt", Some(9)),
+        ("j/11.txt", Some(10)),
+        ("j/12.txt", Some(11)),
+        ("j/k/13.txt", Some(12)),
+        ("14.txt", Some(13)),
+    ];
+
+    /// Builds the `ORDERED_ENTRIES` in the given order, returning the paths and cids of the
+    /// directories in the order they were yielded, ending with the root.
+    fn build_in_order(order: &[usize], sharded: bool) -> Vec<(String, Cid)> {
+        use super::super::BufferingTreeBuilder;
+        use crate::Metadata;
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        if sharded {
+            // low enough to shard the widest directories but not the narrow ones
+            opts.sharding_threshold(Some(200));
+        }
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        for &index in order {
+            match ORDERED_ENTRIES[index] {
+                (path, Some(n)) => builder.put_link(path, some_cid(n), n as u64).unwrap(),
+                (path, None) => builder.set_metadata(path, Metadata::default()).unwrap(),
+            }
+        }
+
+        let mut iter = builder.build();
+        let nodes = iter
+            .by_ref()
+            .map(|res| res.map(|node| (node.path, node.cid)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        // the sharded half would test nothing if none of the directories were sharded
+        assert_eq!(iter.summary().hamt_buckets > 0, sharded);
+        nodes
+    }
+
+    #[test]
+    fn paths_back_to_shallower_depths() {
+        let paths = [
+            &["a", "b", "c", "d", "e"][..],
+            &["a", "f"],
+            &["a", "b", "g", "h"],
+            &["a", "i", "j"],
+        ];
+
+        let paths = paths
+            .iter()
+            .map(|segments| segments.to_vec())
+            .collect::<Vec<_>>();
+
+        for &wrap in &[false, true] {
+            for &prefix in &[None, Some("p"), Some("p/q")] {
+                assert_paths_are_joined_ancestor_names(&paths, wrap, prefix);
+            }
+        }
+    }
+
+    /// Builds a tree out of the paths which can be added, and asserts that exactly the directories
+    /// are yielded in the post order with their paths as the prefixed joins of their ancestors.
+    fn assert_paths_are_joined_ancestor_names(
+        paths: &[Vec<&str>],
+        wrap: bool,
+        prefix: Option<&str>,
+    ) {
+        use super::super::BufferingTreeBuilder;
+        use std::collections::BTreeSet;
+
+        let mut opts = TreeOptions::default();
+        if wrap {
+            opts.wrap_with_directory();
+        }
+        opts.path_prefix(prefix.map(String::from));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        let mut expected = BTreeSet::new();
+
+        for (i, segments) in paths.iter().enumerate() {
+            if builder
+                .put_link(&segments.join("/"), some_cid(i), 1)
+                .is_ok()
+            {
+                expected.extend((1..segments.len()).map(|depth| segments[..depth].join("/")));
+            }
+        }
+
+        if wrap {
+            expected.insert(String::new());
+        }
+
+        let prefixed = |path: &str| match (prefix, path) {
+            (None, path) => path.to_owned(),
+            (Some(prefix), "") => prefix.to_owned(),
+            (Some(prefix), path) => format!("{}/{}", prefix, path),
+        };
+
+        let expected = expected
+            .iter()
+            .map(|path| prefixed(path))
+            .collect::<BTreeSet<_>>();
+
+        let actual = builder
+            .build()
+            .map(|res| res.map(|node| node.path))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(actual.iter().cloned().collect::<BTreeSet<_>>(), expected);
+        assert_eq!(
+            actual.len(),
+            expected.len(),
+            "duplicate paths: {:?}",
+            actual
+        );
+
+        // every directory is yielded after all of its descendants
+        for (i, path) in actual.iter().enumerate() {
+            let is_descendant = |other: &String| {
+                other.len() > path.len()
+                    && other.starts_with(path.as_str())
+                    && (path.is_empty() || other.as_bytes()[path.len()] == b'/')
+            };
+
+            assert!(
+                !actual[i + 1..].iter().any(is_descendant),
+                "{:?} yielded before its descendants: {:?}",
+                path,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn degenerate_chains() {
+        let deep = vec!["d"; 500];
+        let mut deep_with_sibling = deep.clone();
+        deep_with_sibling[499] = "e";
+
+        let paths = vec![
+            deep.clone(),
+            deep_with_sibling,
+            deep[..2].to_vec(),
+            vec!["f"],
+            vec!["g", "h"],
+        ];
+
+        for &wrap in &[false, true] {
+            assert_paths_are_joined_ancestor_names(&paths, wrap, None);
+        }
+        assert_paths_are_joined_ancestor_names(&paths[..1], false, Some("p/q"));
+    }
+
+    #[test]
+    fn path_depth_returns_to_zero() {
+        use super::update_full_path;
+
+        let mut path = String::from("p");
+        let mut depth = 0;
+
+        for d in 1..=100 {
+            update_full_path((&mut path, &mut depth), 1, Some("d"), d);
+        }
+        assert_eq!(depth, 100);
+
+        // the post of the directories, from the deepest
+        for d in (0..=100).rev() {
+            let name = if d == 0 { None } else { Some("d") };
+            update_full_path((&mut path, &mut depth), 1, name, d);
+            assert_eq!(depth, d);
+        }
+        assert_eq!(path, "p");
+    }
+
+    #[test]
+    fn child_depth_overflow_fails() {
+        use super::{partition_children_leaves, Entry, Visited};
+        use crate::dir::builder::{DirBuilder, TreeConstructionFailed};
+
+        let entries = vec![
+            ("a".to_owned(), Entry::Directory(DirBuilder::new(0, 1))),
+            ("b".to_owned(), Entry::Directory(DirBuilder::new(0, 2))),
+        ];
+
+        // the children of an earlier directory, yet to be descended into
+        let mut children = vec![Visited::DescentRoot(DirBuilder::root(3))];
+
+        match partition_children_leaves(usize::MAX, entries.into_iter(), &mut children, None) {
+            Err(TreeConstructionFailed::DepthOverflow) => {}
+            x => unreachable!("{:?}", x.map(|leaves| leaves.len())),
+        }
+
+        assert_eq!(children.len(), 1);
+    }
+
+    #[test]
+    fn links_are_held_only_for_ancestors() {
+        use crate::dir::builder::BufferingTreeBuilder;
+
+        let children = 10_000;
+
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(None);
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        // wide directory with subdirectories, each with a nested subdirectory
+        for i in 0..children {
+            builder
+                .put_link(&format!("a/{}/b/c.txt", i), some_cid(0), 1)
+                .unwrap();
+        }
+
+        let mut iter = builder.build();
+        let mut nodes = 0;
+
+        while let Some(node) = iter.next_borrowed() {
+            node.unwrap();
+            nodes += 1;
+
+            // only the root, "a" and "a/{i}" await their children when "a/{i}/b" is rendered
+            let held = iter.persisted_cids.len();
+            assert!(held <= 3, "{}", held);
+        }
+
+        assert!(iter.persisted_cids.is_empty());
+        assert_eq!(nodes, 2 * children + 1);
+    }
+
+    #[test]
+    #[cfg(feature = "spill")]
+    fn spilled_links_render_the_same_tree() {
+        use crate::test_support::builder_with;
+
+        // nested directories, some wide enough to be sharded
+        let paths = (0..300)
+            .map(|i| format!("{}/{}/{}/f{}.txt", i % 3, i % 7, i % 11, i))
+            .collect::<Vec<_>>();
+
+        let build = |budget| {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            opts.sharding_threshold(Some(10));
+            opts.spill_budget(budget);
+
+            let mut iter = builder_with(opts, &paths).build();
+            let mut nodes = Vec::new();
+            let mut spilled = 0;
+
+            while let Some(node) = iter.next_borrowed() {
+                let node = node.unwrap();
+                nodes.push((
+                    node.path.to_owned(),
+                    node.cid.to_owned(),
+                    node.block.to_vec(),
+                ));
+                spilled = spilled.max(iter.persisted_cids.spilled_len());
+            }
+
+            assert!(iter.persisted_cids.is_empty());
+            (nodes, spilled)
+        };
+
+        let (expected, spilled) = build(None);
+        assert_eq!(spilled, 0);
+
+        for &budget in &[0, 1024, 1024 * 1024] {
+            let (nodes, spilled) = build(Some(budget));
+            assert_eq!(nodes, expected, "budget {}", budget);
+            assert_eq!(spilled > 0, budget < 1024 * 1024, "budget {}", budget);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn tracing_spans() {
+        use crate::dir::builder::BufferingTreeBuilder;
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        #[derive(Default)]
+        struct Recorded {
+            // the name and the fields of the spans in the order of creation
+            spans: Vec<(&'static str, Vec<(&'static str, String)>)>,
+            // the number of times each span was entered
+            entered: Vec<usize>,
+        }
+
+        struct Fields<'a>(&'a mut Vec<(&'static str, String)>);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+                self.0.push((field.name(), format!("{:?}", value)));
+            }
+
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.push((field.name(), value.to_owned()));
+            }
+        }
+
+        struct Recorder(Arc<Mutex<Recorded>>);
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut recorded = self.0.lock().unwrap();
+                let mut fields = Vec::new();
+                span.record(&mut Fields(&mut fields));
+                recorded.spans.push((span.metadata().name(), fields));
+                recorded.entered.push(0);
+                Id::from_u64(recorded.spans.len() as u64)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, _: &Event<'_>) {}
+
+            fn enter(&self, span: &Id) {
+                self.0.lock().unwrap().entered[span.into_u64() as usize - 1] += 1;
+            }
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorded = Arc::new(Mutex::new(Recorded::default()));
+
+        tracing::subscriber::with_default(Recorder(Arc::clone(&recorded)), || {
+            let mut opts = TreeOptions::default();
+            opts.wrap_with_directory();
+            let mut builder = BufferingTreeBuilder::new(opts);
+            builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+            builder.put_link("c/d.txt", some_cid(1), 1).unwrap();
+            builder.put_link("e.txt", some_cid(2), 1).unwrap();
+
+            assert_eq!(builder.build().count(), 3);
+        });
+
+        let recorded = recorded.lock().unwrap();
+        let field = |fields: &[(&str, String)], name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        };
+
+        let (name, fields) = &recorded.spans[0];
+        assert_eq!(*name, "build_tree");
+        assert_eq!(field(fields, "prefix"), "");
+        // entered for every call to next, including the last one returning None
+        assert_eq!(recorded.entered[0], 4);
+
+        let renders = recorded.spans[1..]
+            .iter()
+            .map(|(name, fields)| {
+                assert_eq!(*name, "render_directory");
+                (field(fields, "path"), field(fields, "link_count"))
+            })
+            .collect::<Vec<_>>();
+
+        let expected = [("c", "1"), ("a", "1"), ("", "3")];
+        let expected = expected
+            .iter()
+            .map(|(path, links)| (path.to_string(), links.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(renders, expected);
+        assert!(recorded.entered[1..].iter().all(|entered| *entered == 1));
+    }
+
+    #[test]
+    fn golden_directory_bytes() {
+        // the exact bytes are pinned, independent of the cid, so that a change in how
+        // quick-protobuf frames or orders the fields is caught even if the cids of the other
+        // tests were updated along with it
+        let links = [
+            Some(NamedLeaf(
+                "a".into(),
+                Cid::try_from("QmfLJN6HLyREnWr7QQNmgmuNziUhcbwUopkHQ8gD3pMfp6").unwrap(),
+                7,
+            )),
+            Some(NamedLeaf(
+                "b".into(),
+                Cid::try_from("QmNYVgoDXh3dqC1jjCuYqQ9w4XfiocehPZjEPiQiCVYv33").unwrap(),
+                12,
+            )),
+        ];
+
+        let mut buffer = Vec::new();
+        let metadata = crate::Metadata::default();
+        let opts = TreeOptions::default();
+        PostOrderIterator::render_directory(&links, &metadata, &mut buffer, &opts).unwrap();
+
+        // assembled by hand: a PBLink per link, then the PBNode.Data with the UnixFs Data of
+        // Type Directory
+        let expected = [
+            // the link "a": the tag and length of the PBLink, then the Hash, the Name and the Tsize
+            &hex!("1229 0a22 1220fc7fac69ddb44e39686ecfd1ecc6c52ab653f4227e533ee74a2e238f8b2143d3 120161 1807")[..],
+            &hex!("1229 0a22 12200308c49252eb61966f802baf45074e074f3b3b90619766e0589c1445261a1a22 120162 180c"),
+            // the tag and length of the Data, then the Type
+            &hex!("0a02 0801"),
+        ]
+        .concat();
+
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn owned_and_borrowed_nodes() {
+        use crate::dir::builder::BufferingTreeBuilder;
+
+        let build = || {
+            let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+            builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+            builder.put_link("a/d.txt", some_cid(1), 1).unwrap();
+            builder.build()
+        };
+
+        let mut owned = build();
+        let mut borrowed = build();
+
+        while let Some(node) = borrowed.next_borrowed() {
+            let node = node.unwrap();
+            let copy = owned.next_owned().unwrap().unwrap();
+            assert_eq!(copy.path, node.path);
+            assert_eq!(&copy.cid, node.cid);
+            assert_eq!(&*copy.block, node.block);
+            assert_eq!(copy.is_root, node.is_root);
+        }
+
+        assert!(owned.next_owned().is_none());
+        assert_eq!(owned.root(), borrowed.root());
+    }
+
+    #[test]
+    fn buffer_stays_within_block_size_limit() {
+        use crate::dir::builder::BufferingTreeBuilder;
+
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(Some(1000));
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        // "b" of about 600 bytes is rendered before "a" of about 900 bytes
+        for i in 0..20 {
+            builder
+                .put_link(&format!("r/a/{}", i), some_cid(i), 1)
+                .unwrap();
+        }
+        for i in 0..13 {
+            builder
+                .put_link(&format!("r/b/{}", i), some_cid(i), 1)
+                .unwrap();
+        }
+
+        let mut iter = builder.build();
+        let mut largest = 0;
+        while let Some(node) = iter.next_borrowed() {
+            largest = largest.max(node.unwrap().block.len());
+        }
+
+        assert!(largest > 800, "{}", largest);
+        let capacity = iter.into_buffer().capacity();
+        assert!(capacity <= 1000, "{}", capacity);
+    }
+
+    #[test]
+    fn buffer_hard_cap() {
+        use crate::dir::builder::{BufferingTreeBuilder, TreeConstructionFailed};
+
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(None);
+        opts.buffer_hard_cap(Some(100));
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        for i in 0..5 {
+            builder
+                .put_link(&format!("a/{}", i), some_cid(i), 1)
+                .unwrap();
+        }
+
+        let mut iter = builder.build();
+        match iter.next() {
+            Some(Err(TreeConstructionFailed::BufferCapExceeded { size, cap: 100 })) => {
+                assert!(size > 100, "{}", size)
+            }
+            x => unreachable!("{:?}", x.map(|res| res.map(|node| node.path))),
+        }
+
+        // nothing was allocated for the block
+        assert!(iter.into_buffer().capacity() <= 100);
+    }
+
+    #[test]
+    fn interleaved_siblings_fill_their_own_parents() {
+        use crate::dir::builder::{parse_directory_block, BufferingTreeBuilder};
+        use std::collections::{BTreeMap, BTreeSet};
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        let mut builder = BufferingTreeBuilder::new(opts);
+
+        // the directory structure implied by the paths, with the leaf links
+        let mut children = BTreeMap::<String, BTreeSet<String>>::new();
+        let mut leaves = BTreeMap::new();
+
+        // siblings of mixed leaves and directories at every level, added in an interleaved order
+        let mut n = 0;
+        for i in 0..5 {
+            for j in 0..4 {
+                for k in 0..3 {
+                    let paths = [
+                        format!("d{}/d{}/d{}/f{}", j, i, k, n),
+                        format!("d{}/f{}", k, n),
+                        format!("d{}/d{}/f{}", i, k, n),
+                        format!("f{}", n),
+                    ];
+
+                    for path in paths.iter() {
+                        builder.put_link(path, some_cid(n), n as u64).unwrap();
+                        leaves.insert(path.clone(), some_cid(n));
+
+                        let segments = path.split('/').collect::<Vec<_>>();
+                        for depth in 0..segments.len() {
+                            children
+                                .entry(segments[..depth].join("/"))
+                                .or_default()
+                                .insert(segments[depth].to_owned());
+                        }
+                        n += 1;
+                    }
+                }
+            }
+        }
+
+        let mut iter = builder.build();
+        let mut directories = BTreeMap::new();
+
+        while let Some(node) = iter.next_borrowed() {
+            let node = node.unwrap();
+            let links = parse_directory_block(node.block).unwrap();
+
+            let names = links.keys().cloned().collect::<BTreeSet<_>>();
+            assert_eq!(Some(&names), children.get(node.path), "{}", node.path);
+
+            for (name, leaf) in links {
+                let path = if node.path.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", node.path, name)
+                };
+
+                let expected = leaves.get(&path).or_else(|| directories.get(&path));
+                assert_eq!(expected, Some(leaf.cid()), "{}", path);
+            }
+
+            directories.insert(node.path.to_owned(), node.cid.to_owned());
+        }
+
+        assert!(iter.persisted_cids.is_empty());
+        assert_eq!(directories.len(), children.len());
+    }
+
+    #[test]
+    fn written_size_is_checked() {
+        use super::check_written;
+        use crate::dir::builder::TreeConstructionFailed;
+
+        check_written(10, 10).unwrap();
+
+        match check_written(10, 9) {
+            Err(TreeConstructionFailed::SizeMismatch {
+                expected: 10,
+                written: 9,
+            }) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn render_go_ipfs_bucket() {
+        // the root bucket of the HAMT sharded directory fixture in `sharded_lookup`
+        let expected = hex!("122e0a2212204baf5104fe53d495223f8e2ba95375a31fda6b18e926cb54edd61f30b5f1de6512053641646f6318b535122c0a221220fd9f545068048e647d5d0b275ed171596e0c1c04b8fed09dc13bee7607e75bc7120242391883c00312330a2212208a4a68f6b88594ce373419586c12d24bde2d519ab636b1d2dcc986eb6265b7a3120a43444d616b6566696c65189601122f0a2212201ededc99d23a7ef43a8f17e6dd8b89934993245ef39e18936a37e412e536ed681205463562696e18c5ad030a280805121f200000000020000200000000000000000004000000000000000000000000002822308002");
+
+        let parsed = FlatUnixFs::try_parse(&expected).unwrap();
+        let mut bitfield = Bitfield::default();
+
+        let links = parsed
+            .links
+            .into_iter()
+            .map(|link| {
+                let name = link.Name.unwrap().into_owned();
+                bitfield.set(u8::from_str_radix(&name[..2], 16).unwrap());
+                let cid = Cid::try_from(&*link.Hash.unwrap()).unwrap();
+                Some(NamedLeaf(name, cid, link.Tsize.unwrap()))
+            })
+            .collect::<Vec<_>>();
+
+        let mut buffer = Vec::new();
+        let opts = TreeOptions::default();
+        PostOrderIterator::render_bucket(
+            &links,
+            &bitfield,
+            &crate::Metadata::default(),
+            &mut buffer,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+}
@@ -0,0 +1,117 @@
+use super::{Leaf, TreeConstructionFailed, TreeOptions};
+use crate::pb::{unixfs, FlatUnixFs, PBLink, UnixFs, UnixFsType};
+use alloc::borrow::Cow;
+use quick_protobuf::{MessageWrite, Writer};
+
+/// Renders a legacy UnixFs `Metadata` node carrying the `mime_type` of the `leaf`, returning the
+/// link to the node and its block. The node has a single link named `file` to the `leaf`, as
+/// written by the early versions of go-ipfs.
+///
+/// The `Metadata` nodes are deprecated and not understood by most of the implementations, this
+/// crate included, so they should only be created to faithfully archive the old datasets using
+/// them. The returned `Leaf` can be linked into a tree with `BufferingTreeBuilder::put_link`. The
+/// `Cid` is created with the `Cid` version and the hash of the `opts`; the other options are not
+/// used. Available with the `legacy-metadata` feature.
+pub fn wrap_with_mime_type(
+    leaf: &Leaf,
+    mime_type: &str,
+    opts: &TreeOptions,
+) -> Result<(Leaf, Vec<u8>), TreeConstructionFailed> {
+    let metadata = unixfs::Metadata {
+        MimeType: Some(Cow::Borrowed(mime_type)),
+    };
+
+    let mut data = Vec::with_capacity(metadata.get_size());
+    metadata
+        .write_message(&mut Writer::new(&mut data))
+        .map_err(TreeConstructionFailed::Protobuf)?;
+
+    let link = leaf.link.to_bytes();
+
+    let node = FlatUnixFs {
+        links: vec![PBLink {
+            Hash: Some(Cow::Borrowed(&link)),
+            Name: Some(Cow::Borrowed("file")),
+            Tsize: Some(leaf.total_size),
+        }],
+        data: UnixFs {
+            Type: UnixFsType::Metadata,
+            Data: Some(Cow::Borrowed(&data)),
+            // written by go-ipfs even though there is no content
+            filesize: Some(0),
+            ..Default::default()
+        },
+    };
+
+    let mut block = Vec::with_capacity(node.get_size());
+    node.write_message(&mut Writer::new(&mut block))
+        .map_err(TreeConstructionFailed::Protobuf)?;
+
+    let cid = opts.cid_for(&block)?;
+    let total_size = leaf
+        .total_size
+        .checked_add(block.len() as u64)
+        .ok_or(TreeConstructionFailed::SizeOverflow)?;
+
+    Ok((Leaf::new(cid, total_size), block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wrap_with_mime_type;
+    use crate::dir::builder::{BufferingTreeBuilder, Leaf, TreeOptions};
+    use crate::pb::{FlatUnixFs, UnixFsType};
+    use crate::test_support::some_cid;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn metadata_node() {
+        let file = some_cid(0);
+        let (leaf, block) = wrap_with_mime_type(
+            &Leaf::new(file.clone(), 13),
+            "text/plain",
+            &TreeOptions::default(),
+        )
+        .unwrap();
+
+        // no node from go-ipfs was available, so the expected block has been assembled by hand
+        // after go-ipfs' unixfs.BytesForMetadata and coreunix.AddMetadataTo
+        let mut expected = vec![0x12, 0x2c, 0x0a, 0x22];
+        expected.extend_from_slice(&file.to_bytes());
+        expected.extend_from_slice(&[0x12, 0x04]);
+        expected.extend_from_slice(b"file");
+        expected.extend_from_slice(&[0x18, 0x0d, 0x0a, 0x12, 0x08, 0x03, 0x12, 0x0c, 0x0a, 0x0a]);
+        expected.extend_from_slice(b"text/plain");
+        expected.extend_from_slice(&[0x18, 0x00]);
+        assert_eq!(block, expected);
+
+        assert_eq!(leaf.total_size(), 13 + block.len() as u64);
+        assert_eq!(leaf.cid().version(), cid::Version::V0);
+
+        let flat = FlatUnixFs::try_from(&block[..]).unwrap();
+        assert_eq!(flat.data.Type, UnixFsType::Metadata);
+        assert_eq!(flat.links.len(), 1);
+        assert_eq!(flat.links[0].Name.as_deref(), Some("file"));
+
+        let mut opts = TreeOptions::default();
+        opts.cid_version(cid::Version::V1);
+        let (v1, _) = wrap_with_mime_type(&Leaf::new(file, 13), "text/plain", &opts).unwrap();
+        assert_eq!(v1.cid().hash(), leaf.cid().hash());
+        assert_eq!(v1.cid().version(), cid::Version::V1);
+    }
+
+    #[test]
+    fn linked_into_a_tree() {
+        let file = some_cid(0);
+        let (leaf, _) =
+            wrap_with_mime_type(&Leaf::new(file, 5), "image/png", &TreeOptions::default()).unwrap();
+
+        let mut builder = BufferingTreeBuilder::default();
+        builder
+            .put_link("a/b.png", leaf.cid().to_owned(), leaf.total_size())
+            .unwrap();
+
+        let root = builder.build().last().unwrap().unwrap();
+        assert_eq!(root.total_size, leaf.total_size() + root.block.len() as u64);
+    }
+}
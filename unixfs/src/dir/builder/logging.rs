@@ -0,0 +1,226 @@
+use super::{BlockSink, TreeNode};
+use cid::Cid;
+use core::fmt;
+use std::io::{self, Write};
+
+/// `BlockSink` decorator which writes a line of JSON for every stored block into the `log`,
+/// giving a replayable record of what was built and when. The blocks are stored into the wrapped
+/// sink first, so only the blocks which were stored are logged.
+///
+/// Each line is an object with the fields `path`, `cid`, `size` and `timestamp`, for example:
+///
+/// ```text
+/// {"path":"a/b","cid":"QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n","size":4,"timestamp":1602000000.123}
+/// ```
+///
+/// The `path` is that of the directory, including any `TreeOptions::path_prefix`, or `null` for
+/// the blocks given to `BlockSink::put` without a path, like the blocks of files. The `size` is
+/// the length of the block, and the `timestamp` is the wall time of storing the block in seconds
+/// since the unix epoch, with millisecond precision. On `wasm32-unknown-unknown` there is no
+/// clock and the `timestamp` is always `null`.
+///
+/// The log is not flushed; wrap it in a `BufWriter` as needed and flush it after the build, see
+/// `LoggingSink::into_inner`.
+#[derive(Debug)]
+pub struct LoggingSink<S, W> {
+    sink: S,
+    log: W,
+}
+
+impl<S: BlockSink, W: Write> LoggingSink<S, W> {
+    /// Wraps the `sink`, logging the stored blocks into `log`.
+    pub fn new(sink: S, log: W) -> Self {
+        LoggingSink { sink, log }
+    }
+
+    /// Returns the wrapped sink and the log.
+    pub fn into_inner(self) -> (S, W) {
+        (self.sink, self.log)
+    }
+
+    fn write_line(&mut self, path: Option<&str>, cid: &Cid, size: usize) -> io::Result<()> {
+        let mut line = String::with_capacity(128);
+
+        line.push_str("{\"path\":");
+        match path {
+            Some(path) => push_json_string(&mut line, path),
+            None => line.push_str("null"),
+        }
+
+        line.push_str(",\"cid\":\"");
+        line.push_str(&cid.to_string());
+        line.push_str("\",\"size\":");
+        line.push_str(&size.to_string());
+        line.push_str(",\"timestamp\":");
+
+        match timestamp_millis() {
+            Some(millis) => line.push_str(&format!("{}.{:03}", millis / 1000, millis % 1000)),
+            None => line.push_str("null"),
+        }
+
+        line.push_str("}\n");
+        self.log.write_all(line.as_bytes())
+    }
+}
+
+impl<S: BlockSink, W: Write> BlockSink for LoggingSink<S, W> {
+    type Error = LoggingFailed<S::Error>;
+
+    fn put(&mut self, cid: &Cid, block: &[u8]) -> Result<(), Self::Error> {
+        self.sink.put(cid, block).map_err(LoggingFailed::Sink)?;
+        self.write_line(None, cid, block.len())
+            .map_err(LoggingFailed::Log)
+    }
+
+    fn put_node(&mut self, node: &TreeNode<'_>) -> Result<(), Self::Error> {
+        self.sink.put_node(node).map_err(LoggingFailed::Sink)?;
+        self.write_line(Some(node.path), node.cid, node.block.len())
+            .map_err(LoggingFailed::Log)
+    }
+
+    fn deduplicate(&self) -> bool {
+        self.sink.deduplicate()
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn timestamp_millis() -> Option<u128> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|elapsed| elapsed.as_millis())
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn timestamp_millis() -> Option<u128> {
+    None
+}
+
+/// Appends the `s` as a quoted JSON string, escaping the quotes, the backslashes and the control
+/// characters.
+fn push_json_string(out: &mut String, s: &str) {
+    use core::fmt::Write as _;
+
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", ch as u32).expect("writing to a String cannot fail")
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+/// Failure cases for `LoggingSink`.
+#[derive(Debug)]
+pub enum LoggingFailed<E> {
+    /// Storing the block into the wrapped sink failed.
+    Sink(E),
+    /// Writing the line into the log failed.
+    Log(io::Error),
+}
+
+impl<E: fmt::Display> fmt::Display for LoggingFailed<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LoggingFailed::*;
+        match self {
+            Sink(e) => write!(fmt, "{}", e),
+            Log(e) => write!(fmt, "failed to write the log: {}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for LoggingFailed<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use LoggingFailed::*;
+        match self {
+            Sink(e) => Some(e),
+            Log(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_json_string, LoggingFailed, LoggingSink};
+    use crate::dir::builder::{BlockSink, BufferingTreeBuilder, DriveFailed, TreeOptions};
+    use crate::test_support::some_cid;
+    use std::collections::HashMap;
+
+    #[test]
+    fn logged_lines() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+        builder.put_link("c\"d/e.txt", some_cid(1), 1).unwrap();
+
+        let mut sink = LoggingSink::new(HashMap::new(), Vec::new());
+        sink.put(&some_cid(2), b"foo").unwrap();
+        let root = builder.build().drive(&mut sink).unwrap();
+
+        let (blocks, log) = sink.into_inner();
+        assert_eq!(blocks.len(), 4);
+
+        let lines = String::from_utf8(log).unwrap();
+        let lines = lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        let paths = lines
+            .iter()
+            .map(|line| line["path"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &[None, Some("c\"d"), Some("a"), Some("")]);
+
+        let root_line = lines.last().unwrap();
+        assert_eq!(root_line["cid"].as_str(), Some(root.to_string().as_str()));
+        assert_eq!(root_line["size"].as_u64(), Some(blocks[&root].len() as u64));
+        assert!(root_line["timestamp"].as_f64().unwrap() > 1.5e9);
+    }
+
+    #[test]
+    fn failed_log() {
+        struct Broken;
+
+        impl std::io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+        let mut sink = LoggingSink::new(Vec::new(), Broken);
+        match builder.build().drive(&mut sink) {
+            Err(DriveFailed::Sink(LoggingFailed::Log(e))) => assert_eq!(e.to_string(), "broken"),
+            x => unreachable!("{:?}", x),
+        }
+
+        // the block was stored before the log failed
+        assert_eq!(sink.into_inner().0.len(), 1);
+    }
+
+    #[test]
+    fn json_strings() {
+        let mut out = String::new();
+        push_json_string(&mut out, "a\"b\\c\nd\u{1}\u{e9}");
+        assert_eq!(out, "\"a\\\"b\\\\c\\nd\\u0001\u{e9}\"");
+    }
+}
@@ -0,0 +1,353 @@
+use super::{
+    CaseCollisionPolicy, EntryFilter, LinkOrder, LinkRewriter, RenderObserver, RootData,
+    TreeConstructionFailed, TreeOptions, WhitespacePolicy,
+};
+use core::fmt;
+
+/// Convenience type for configuring `TreeOptions` in a single expression while checking the
+/// combinations of the options before any building is attempted, see `TreeOptions::builder`.
+///
+/// The methods are the same as the setters of `TreeOptions`.
+#[derive(Debug, Default, Clone)]
+pub struct TreeOptionsBuilder {
+    opts: TreeOptions,
+}
+
+impl TreeOptions {
+    /// Returns a `TreeOptionsBuilder` for creating validated non-default options.
+    pub fn builder() -> TreeOptionsBuilder {
+        TreeOptionsBuilder::default()
+    }
+
+    /// Checks that the options can be used together, which is otherwise only found out when
+    /// rendering the first directory. The sharding options are only checked when sharding has
+    /// been enabled with `TreeOptions::sharding_threshold`.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        self.validate_cid_options()
+            .map_err(OptionsError::from_failed)?;
+
+        if self.sharding_threshold.is_some() {
+            self.shard_layout().map_err(OptionsError::from_failed)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TreeOptionsBuilder {
+    /// See `TreeOptions::block_size_limit`.
+    pub fn block_size_limit(mut self, limit: Option<u64>) -> Self {
+        self.opts.block_size_limit(limit);
+        self
+    }
+
+    /// See `TreeOptions::with_protocol_max`.
+    pub fn with_protocol_max(mut self) -> Self {
+        self.opts.with_protocol_max();
+        self
+    }
+
+    /// See `TreeOptions::buffer_hard_cap`.
+    pub fn buffer_hard_cap(mut self, cap: Option<u64>) -> Self {
+        self.opts.buffer_hard_cap(cap);
+        self
+    }
+
+    /// See `TreeOptions::wrap_with_directory`.
+    pub fn wrap_with_directory(mut self) -> Self {
+        self.opts.wrap_with_directory();
+        self
+    }
+
+    /// See `TreeOptions::path_prefix`.
+    pub fn path_prefix(mut self, prefix: Option<String>) -> Self {
+        self.opts.path_prefix(prefix);
+        self
+    }
+
+    /// See `TreeOptions::cid_version`.
+    pub fn cid_version(mut self, version: cid::Version) -> Self {
+        self.opts.cid_version(version);
+        self
+    }
+
+    /// See `TreeOptions::hash`.
+    pub fn hash(mut self, code: multihash::Code, digest_len: Option<usize>) -> Self {
+        self.opts.hash(code, digest_len);
+        self
+    }
+
+    /// See `TreeOptions::link_order`.
+    pub fn link_order(mut self, order: Option<LinkOrder>) -> Self {
+        self.opts.link_order(order);
+        self
+    }
+
+    /// See `TreeOptions::sharding_threshold`.
+    pub fn sharding_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.opts.sharding_threshold(threshold);
+        self
+    }
+
+    /// See `TreeOptions::whitespace_policy`.
+    pub fn whitespace_policy(mut self, policy: WhitespacePolicy) -> Self {
+        self.opts.whitespace_policy(policy);
+        self
+    }
+
+    /// See `TreeOptions::case_insensitive_collisions`.
+    pub fn case_insensitive_collisions(mut self, policy: CaseCollisionPolicy) -> Self {
+        self.opts.case_insensitive_collisions(policy);
+        self
+    }
+
+    /// See `TreeOptions::name_normalization`.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn name_normalization(
+        mut self,
+        normalization: Option<super::UnicodeNormalization>,
+    ) -> Self {
+        self.opts.name_normalization(normalization);
+        self
+    }
+
+    /// See `TreeOptions::link_rewriter`.
+    pub fn link_rewriter(mut self, rewriter: Option<LinkRewriter>) -> Self {
+        self.opts.link_rewriter(rewriter);
+        self
+    }
+
+    /// See `TreeOptions::entry_filter`.
+    pub fn entry_filter(mut self, filter: Option<EntryFilter>) -> Self {
+        self.opts.entry_filter(filter);
+        self
+    }
+
+    /// See `TreeOptions::omit_tsize`.
+    pub fn omit_tsize(mut self) -> Self {
+        self.opts.omit_tsize();
+        self
+    }
+
+    /// See `TreeOptions::content_only_total_size`.
+    pub fn content_only_total_size(mut self) -> Self {
+        self.opts.content_only_total_size();
+        self
+    }
+
+    /// See `TreeOptions::max_depth`.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.opts.max_depth(max_depth);
+        self
+    }
+
+    /// See `TreeOptions::max_name_len`.
+    pub fn max_name_len(mut self, limit: Option<usize>) -> Self {
+        self.opts.max_name_len(limit);
+        self
+    }
+
+    /// See `TreeOptions::max_total_size`.
+    pub fn max_total_size(mut self, limit: Option<u64>) -> Self {
+        self.opts.max_total_size(limit);
+        self
+    }
+
+    /// See `TreeOptions::max_total_nodes`.
+    pub fn max_total_nodes(mut self, limit: Option<usize>) -> Self {
+        self.opts.max_total_nodes(limit);
+        self
+    }
+
+    /// See `TreeOptions::block_cache_size`.
+    pub fn block_cache_size(mut self, blocks: usize) -> Self {
+        self.opts.block_cache_size(blocks);
+        self
+    }
+
+    /// See `TreeOptions::spill_budget`.
+    #[cfg(feature = "spill")]
+    pub fn spill_budget(mut self, bytes: Option<usize>) -> Self {
+        self.opts.spill_budget(bytes);
+        self
+    }
+
+    /// See `TreeOptions::reject_suspicious_names`.
+    pub fn reject_suspicious_names(mut self) -> Self {
+        self.opts.reject_suspicious_names();
+        self
+    }
+
+    /// See `TreeOptions::render_observer`.
+    pub fn render_observer(mut self, observer: Option<RenderObserver>) -> Self {
+        self.opts.render_observer(observer);
+        self
+    }
+
+    /// See `TreeOptions::data_first`.
+    pub fn data_first(mut self) -> Self {
+        self.opts.data_first();
+        self
+    }
+
+    /// See `TreeOptions::prefix_buckets`.
+    pub fn prefix_buckets(mut self, max_links: Option<usize>) -> Self {
+        self.opts.prefix_buckets(max_links);
+        self
+    }
+
+    /// See `TreeOptions::root_data`.
+    pub fn root_data(mut self, data: RootData) -> Self {
+        self.opts.root_data(data);
+        self
+    }
+
+    /// See `TreeOptions::omit_directory_data`.
+    pub fn omit_directory_data(mut self) -> Self {
+        self.opts.omit_directory_data();
+        self
+    }
+
+    /// See `TreeOptions::allow_non_unixfs_links`.
+    pub fn allow_non_unixfs_links(mut self) -> Self {
+        self.opts.allow_non_unixfs_links();
+        self
+    }
+
+    /// See `TreeOptions::shard_fanout`.
+    pub fn shard_fanout(mut self, fanout: u64) -> Self {
+        self.opts.shard_fanout(fanout);
+        self
+    }
+
+    /// See `TreeOptions::shard_hash_type`.
+    pub fn shard_hash_type(mut self, hash_type: u64) -> Self {
+        self.opts.shard_hash_type(hash_type);
+        self
+    }
+
+    /// Returns the options if they can be used together, see `TreeOptions::validate`.
+    pub fn build(self) -> Result<TreeOptions, OptionsError> {
+        self.opts.validate()?;
+        Ok(self.opts)
+    }
+}
+
+/// Failure cases for `TreeOptionsBuilder::build` and `TreeOptions::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionsError {
+    /// The configured hash and `Cid` version cannot be used together.
+    InvalidCidOptions(&'static str),
+    /// The HAMT sharding options cannot be used.
+    InvalidShardingOptions(&'static str),
+}
+
+impl OptionsError {
+    fn from_failed(e: TreeConstructionFailed) -> Self {
+        match e {
+            TreeConstructionFailed::InvalidCidOptions(reason) => {
+                OptionsError::InvalidCidOptions(reason)
+            }
+            TreeConstructionFailed::InvalidShardingOptions(reason) => {
+                OptionsError::InvalidShardingOptions(reason)
+            }
+            e => unreachable!("only options are validated: {:?}", e),
+        }
+    }
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use OptionsError::*;
+        match self {
+            InvalidCidOptions(reason) => write!(fmt, "invalid cid options: {}", reason),
+            InvalidShardingOptions(reason) => write!(fmt, "invalid sharding options: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::OptionsError;
+    use crate::dir::builder::TreeOptions;
+    use multihash::Code;
+
+    #[test]
+    fn valid_options() {
+        let opts = TreeOptions::builder()
+            .wrap_with_directory()
+            .cid_version(cid::Version::V1)
+            .hash(Code::Blake2b256, Some(20))
+            .sharding_threshold(Some(1024))
+            .shard_fanout(16)
+            .build()
+            .unwrap();
+
+        assert!(opts.wrap_with_directory);
+        assert_eq!(opts.shard_fanout, 16);
+
+        TreeOptions::builder().build().unwrap();
+    }
+
+    #[test]
+    fn invalid_cid_options() {
+        let invalid = [
+            (
+                TreeOptions::builder().hash(Code::Blake2b256, None),
+                "CIDv0 requires sha2-256 multihash",
+            ),
+            (
+                TreeOptions::builder().hash(Code::Sha2_256, Some(20)),
+                "CIDv0 cannot have a truncated digest",
+            ),
+            (
+                TreeOptions::builder()
+                    .cid_version(cid::Version::V1)
+                    .hash(Code::Sha2_256, Some(0)),
+                "digest cannot be truncated to zero length",
+            ),
+        ];
+
+        for (builder, reason) in invalid.iter().cloned() {
+            match builder.build() {
+                Err(OptionsError::InvalidCidOptions(r)) if r == reason => {}
+                Err(e) => unreachable!("{:?}", e),
+                Ok(_) => unreachable!("{} was accepted", reason),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_sharding_options() {
+        let sharded = || TreeOptions::builder().sharding_threshold(Some(1024));
+
+        let invalid = [
+            (
+                sharded().shard_fanout(100),
+                "fanout must be a power of two between 2 and 256",
+            ),
+            (
+                sharded().shard_fanout(512),
+                "fanout must be a power of two between 2 and 256",
+            ),
+            (
+                sharded().shard_hash_type(0x12),
+                "only murmur3-x64-64 hash is supported",
+            ),
+        ];
+
+        for (builder, reason) in invalid.iter().cloned() {
+            let e = builder.build().map(|_| ()).unwrap_err();
+            assert_eq!(e, OptionsError::InvalidShardingOptions(reason));
+            assert_eq!(
+                e.to_string(),
+                format!("invalid sharding options: {}", reason)
+            );
+        }
+
+        // without sharding the options are not used
+        TreeOptions::builder().shard_fanout(100).build().unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use super::Leaf;
+use crate::pb::{FlatUnixFs, ParsingFailed, UnixFsType};
+use crate::{InvalidCidInLink, UnexpectedNodeType};
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use cid::Cid;
+use core::convert::TryFrom;
+use core::fmt;
+
+/// Parses the links of a rendered plain UnixFs directory block back into `(name, Leaf)` pairs,
+/// which is the inverse of rendering the directory. The links are not checked to be pointing at
+/// any particular kind of nodes.
+///
+/// HAMT sharded directories cannot be read from a single block and are reported as
+/// `DirectoryParsingFailed::Sharded`.
+pub fn parse_directory_block(
+    block: &[u8],
+) -> Result<BTreeMap<String, Leaf>, DirectoryParsingFailed> {
+    let flat = match FlatUnixFs::try_parse(block) {
+        Ok(flat) if flat.data.Type == UnixFsType::Directory => flat,
+        Ok(flat) if flat.data.Type == UnixFsType::HAMTShard => {
+            return Err(DirectoryParsingFailed::Sharded)
+        }
+        Ok(other) => {
+            return Err(DirectoryParsingFailed::UnexpectedType(
+                other.data.Type.into(),
+            ))
+        }
+        Err(ParsingFailed::InvalidDagPb(e)) | Err(ParsingFailed::InvalidUnixFs(e, _)) => {
+            return Err(DirectoryParsingFailed::Read(Some(e)))
+        }
+        Err(ParsingFailed::NoData(_)) => return Err(DirectoryParsingFailed::Read(None)),
+    };
+
+    let mut entries = BTreeMap::new();
+
+    for (nth, link) in flat.links.into_iter().enumerate() {
+        let name = match link.Name.as_deref() {
+            Some(name) if !name.is_empty() => name.to_owned(),
+            _ => return Err(DirectoryParsingFailed::MissingName(nth)),
+        };
+
+        let total_size = match link.Tsize {
+            Some(total_size) => total_size,
+            None => return Err(DirectoryParsingFailed::MissingSize(nth)),
+        };
+
+        let hash = link.Hash.as_deref().unwrap_or_default();
+        let cid = match Cid::try_from(hash) {
+            Ok(cid) => cid,
+            Err(e) => return Err(InvalidCidInLink::from((nth, link, e)).into()),
+        };
+
+        match entries.entry(name) {
+            Entry::Vacant(ve) => {
+                ve.insert(Leaf {
+                    link: cid,
+                    total_size,
+                });
+            }
+            Entry::Occupied(oe) => {
+                return Err(DirectoryParsingFailed::DuplicateName(oe.key().clone()))
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Failure cases for `parse_directory_block`.
+#[derive(Debug)]
+pub enum DirectoryParsingFailed {
+    /// The block could not be read as dag-pb, or the inner UnixFs message was invalid or missing
+    /// (`None`).
+    Read(Option<quick_protobuf::Error>),
+    /// The block was a HAMT sharded directory, and the links cannot be read from a single block.
+    Sharded,
+    /// The block was not a UnixFs directory.
+    UnexpectedType(UnexpectedNodeType),
+    /// The link at the index had no name or an empty name.
+    MissingName(usize),
+    /// The link at the index had no `Tsize`.
+    MissingSize(usize),
+    /// The name was used by multiple links.
+    DuplicateName(String),
+    /// The link could not be converted into a `Cid`.
+    InvalidCid(InvalidCidInLink),
+}
+
+impl From<InvalidCidInLink> for DirectoryParsingFailed {
+    fn from(e: InvalidCidInLink) -> Self {
+        DirectoryParsingFailed::InvalidCid(e)
+    }
+}
+
+impl fmt::Display for DirectoryParsingFailed {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DirectoryParsingFailed::*;
+        match self {
+            Read(Some(e)) => write!(fmt, "failed to read the block: {}", e),
+            Read(None) => write!(fmt, "failed to read the block: missing UnixFs data"),
+            Sharded => write!(
+                fmt,
+                "cannot parse a HAMT sharded directory from a single block"
+            ),
+            UnexpectedType(ut) => write!(fmt, "unexpected type for UnixFs: {:?}", ut),
+            MissingName(nth) => write!(fmt, "link #{} has no name", nth),
+            MissingSize(nth) => write!(fmt, "link #{} has no Tsize", nth),
+            DuplicateName(name) => write!(fmt, "multiple links named {:?}", name),
+            InvalidCid(e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DirectoryParsingFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use DirectoryParsingFailed::*;
+        match self {
+            Read(Some(e)) => Some(e),
+            InvalidCid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_directory_block, DirectoryParsingFailed};
+    use crate::dir::builder::{BufferingTreeBuilder, TreeOptions};
+    use crate::test_support::some_cid;
+    use hex_literal::hex;
+
+    #[test]
+    fn parse_rendered_directory() {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/b.txt", some_cid(0), 10).unwrap();
+        builder.put_link("a/c/d.txt", some_cid(1), 20).unwrap();
+
+        let nodes = builder.build().collect::<Result<Vec<_>, _>>().unwrap();
+        let (c, a) = (&nodes[0], &nodes[1]);
+
+        let parsed = parse_directory_block(&a.block).unwrap();
+        let parsed = parsed
+            .iter()
+            .map(|(name, leaf)| (name.as_str(), leaf.cid().clone(), leaf.total_size()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            parsed,
+            &[
+                ("b.txt", some_cid(0), 10),
+                ("c", c.cid.clone(), c.total_size)
+            ]
+        );
+    }
+
+    #[test]
+    fn sharded_is_flagged() {
+        // the root bucket from the sharded_lookup tests
+        let block = hex!("122e0a2212204baf5104fe53d495223f8e2ba95375a31fda6b18e926cb54edd61f30b5f1de6512053641646f6318b535122c0a221220fd9f545068048e647d5d0b275ed171596e0c1c04b8fed09dc13bee7607e75bc7120242391883c00312330a2212208a4a68f6b88594ce373419586c12d24bde2d519ab636b1d2dcc986eb6265b7a3120a43444d616b6566696c65189601122f0a2212201ededc99d23a7ef43a8f17e6dd8b89934993245ef39e18936a37e412e536ed681205463562696e18c5ad030a280805121f200000000020000200000000000000000004000000000000000000000000002822308002");
+
+        match parse_directory_block(&block) {
+            Err(DirectoryParsingFailed::Sharded) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn missing_name_and_size() {
+        let hash = some_cid(0).to_bytes();
+
+        // links without the Name and the Tsize fields, followed by Data { Type: Directory }
+        let mut block = vec![0x12, 2 + hash.len() as u8, 0x0a, hash.len() as u8];
+        block.extend_from_slice(&hash);
+        block.extend_from_slice(&hex!("0a020801"));
+
+        match parse_directory_block(&block) {
+            Err(DirectoryParsingFailed::MissingName(0)) => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        let mut block = vec![0x12, 5 + hash.len() as u8, 0x0a, hash.len() as u8];
+        block.extend_from_slice(&hash);
+        block.extend_from_slice(&hex!("120161"));
+        block.extend_from_slice(&hex!("0a020801"));
+
+        match parse_directory_block(&block) {
+            Err(DirectoryParsingFailed::MissingSize(0)) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use super::{OwnedTreeNode, PostOrderIterator, TreeConstructionFailed};
+
+impl PostOrderIterator {
+    /// Renders the whole tree and returns the nodes in the depth-first pre-order, with every
+    /// directory (or HAMT bucket) before its children and the children in the order of the links.
+    /// This is useful for stores which want to receive the parents before the children.
+    ///
+    /// As the `Cid` of a directory depends on the `Cid`s of its children, the nodes cannot be
+    /// rendered in this order, and all of the nodes along with their blocks are buffered in memory
+    /// before any of them can be returned. For large trees prefer storing the blocks as they are
+    /// yielded in the post order.
+    pub fn into_pre_order(self) -> Result<Vec<OwnedTreeNode>, TreeConstructionFailed> {
+        let mut nodes = self.collect::<Result<Vec<_>, _>>()?;
+
+        // the children are visited in the reverse order of the links, which makes the reversed
+        // post order the pre order with the children in the order of the links
+        nodes.reverse();
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dir::builder::{BufferingTreeBuilder, OwnedTreeNode, TreeOptions};
+    use crate::pb::PBNode;
+    use crate::test_support::some_cid;
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parents_before_children() {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/d/e.txt", some_cid(1), 1).unwrap();
+        builder.put_link("f/g.txt", some_cid(2), 1).unwrap();
+        // two identical directories with the same cid
+        builder.put_link("h/1/i.txt", some_cid(3), 1).unwrap();
+        builder.put_link("h/2/i.txt", some_cid(3), 1).unwrap();
+
+        let nodes = builder.build().into_pre_order().unwrap();
+        let paths = nodes
+            .iter()
+            .map(|node| node.path.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(paths, &["", "a", "a/b", "a/d", "f", "h", "h/1", "h/2"]);
+        assert!(nodes[0].is_root);
+        assert!(nodes[1..].iter().all(|node| !node.is_root));
+    }
+
+    #[test]
+    fn sharded_buckets_before_their_children() {
+        let mut opts = TreeOptions::default();
+        opts.sharding_threshold(Some(1024));
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        for i in 0..2000 {
+            builder
+                .put_link(&format!("a/{}.txt", i), some_cid(i), 1)
+                .unwrap();
+        }
+
+        let post_order = builder
+            .clone()
+            .build()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let pre_order = builder.build().into_pre_order().unwrap();
+
+        assert_eq!(post_order.len(), pre_order.len());
+        assert!(pre_order.len() > 2, "{}", pre_order.len());
+        assert!(pre_order[0].is_root);
+
+        assert_parents_first(&pre_order);
+    }
+
+    /// Asserts that every node comes before the nodes it links to, which are in the link order.
+    fn assert_parents_first(nodes: &[OwnedTreeNode]) {
+        let positions = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.cid.clone(), i))
+            .collect::<HashMap<_, _>>();
+
+        for (i, node) in nodes.iter().enumerate() {
+            let children = PBNode::try_from(&node.block[..])
+                .unwrap()
+                .Links
+                .iter()
+                .map(|link| Cid::try_from(link.Hash.as_deref().unwrap()).unwrap())
+                .filter_map(|cid| positions.get(&cid).copied())
+                .collect::<Vec<_>>();
+
+            assert!(children.iter().all(|&j| i < j), "{:?}", children);
+            assert!(children.windows(2).all(|w| w[0] < w[1]), "{:?}", children);
+        }
+    }
+}
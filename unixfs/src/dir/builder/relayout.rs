@@ -0,0 +1,323 @@
+use super::{
+    parse_directory_block, BufferingTreeBuilder, DirectoryParsingFailed, Leaf, PostOrderIterator,
+    TreeBuildingFailed, TreeOptions,
+};
+use crate::dir::hamt::Layout;
+use crate::pb::{FlatUnixFs, ParsingFailed, UnixFsType};
+use crate::{InvalidCidInLink, Metadata};
+use cid::Cid;
+use core::convert::TryFrom;
+use core::fmt;
+
+/// Reads the existing directory tree starting at `root` and returns a `PostOrderIterator` for
+/// rendering the same tree again with the `opts`, for example to change the HAMT sharding or the
+/// `Cid` version of the directories without importing the content again. The links to the files
+/// and all other leaves are kept as they are; only the directories are rendered again.
+///
+/// The blocks are requested from `fetch`. Both plain and HAMT sharded directories are read, and
+/// every `dag-pb` link is fetched to find out if it is a directory, which includes the root
+/// blocks of the files but none of their other blocks. The links to the other codecs are never
+/// fetched. Anything which is not a UnixFs directory is linked to as a leaf.
+///
+/// The root is always the wrapping directory of the new tree, so
+/// `TreeOptions::wrap_with_directory` is enabled. As with `parse_directory_block`, the metadata
+/// of the directories is not kept.
+pub fn relayout<F, E>(
+    root: &Cid,
+    mut fetch: F,
+    mut opts: TreeOptions,
+) -> Result<PostOrderIterator, RelayoutFailed<E>>
+where
+    F: FnMut(&Cid) -> Result<Vec<u8>, E>,
+{
+    opts.wrap_with_directory();
+    let mut builder = BufferingTreeBuilder::new(opts);
+
+    let block = fetch(root).map_err(RelayoutFailed::Fetch)?;
+    if !is_directory(&block) {
+        return Err(RelayoutFailed::NotADirectory(root.to_owned()));
+    }
+
+    // the directories read but not yet visited, along with their blocks
+    let mut pending = vec![(String::new(), block)];
+
+    while let Some((path, block)) = pending.pop() {
+        for (name, leaf) in read_directory(&block, &path, &mut fetch)? {
+            if name.contains('/') {
+                return Err(RelayoutFailed::InvalidName(path, name));
+            }
+
+            let entry_path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+
+            let block = if leaf.link.codec() == cid::Codec::DagProtobuf {
+                Some(fetch(&leaf.link).map_err(RelayoutFailed::Fetch)?)
+            } else {
+                None
+            };
+
+            match block {
+                Some(block) if is_directory(&block) => {
+                    // creates the directory even when it is empty
+                    builder
+                        .set_metadata(&entry_path, Metadata::default())
+                        .map_err(RelayoutFailed::Building)?;
+                    pending.push((entry_path, block));
+                }
+                _ => builder
+                    .put_link(&entry_path, leaf.link, leaf.total_size)
+                    .map_err(RelayoutFailed::Building)?,
+            }
+        }
+    }
+
+    Ok(builder.build())
+}
+
+pub(super) fn is_directory(block: &[u8]) -> bool {
+    match FlatUnixFs::try_parse(block) {
+        Ok(flat) => matches!(
+            flat.data.Type,
+            UnixFsType::Directory | UnixFsType::HAMTShard
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Returns the entries of the plain or HAMT sharded directory, fetching the buckets of a sharded
+/// directory.
+pub(super) fn read_directory<F, E>(
+    block: &[u8],
+    path: &str,
+    fetch: &mut F,
+) -> Result<Vec<(String, Leaf)>, RelayoutFailed<E>>
+where
+    F: FnMut(&Cid) -> Result<Vec<u8>, E>,
+{
+    let parse_failed = |e| RelayoutFailed::Parse(path.to_owned(), e);
+
+    match parse_directory_block(block) {
+        Ok(entries) => return Ok(entries.into_iter().collect()),
+        Err(DirectoryParsingFailed::Sharded) => {}
+        Err(e) => return Err(parse_failed(e)),
+    }
+
+    let mut entries = Vec::new();
+    let mut buckets = vec![block.to_vec()];
+
+    while let Some(bucket) = buckets.pop() {
+        let flat = match FlatUnixFs::try_parse(&bucket) {
+            Ok(flat) if flat.data.Type == UnixFsType::HAMTShard => flat,
+            Ok(other) => {
+                return Err(parse_failed(DirectoryParsingFailed::UnexpectedType(
+                    other.data.Type.into(),
+                )))
+            }
+            Err(ParsingFailed::InvalidDagPb(e)) | Err(ParsingFailed::InvalidUnixFs(e, _)) => {
+                return Err(parse_failed(DirectoryParsingFailed::Read(Some(e))))
+            }
+            Err(ParsingFailed::NoData(_)) => {
+                return Err(parse_failed(DirectoryParsingFailed::Read(None)))
+            }
+        };
+
+        let fanout = flat.data.fanout;
+        let padding = match fanout.and_then(Layout::new) {
+            Some(layout) => layout.bucket_name(0).len(),
+            None => return Err(RelayoutFailed::UnsupportedFanout(path.to_owned(), fanout)),
+        };
+
+        for (nth, link) in flat.links.into_iter().enumerate() {
+            let name = link.Name.as_deref().unwrap_or_default();
+
+            // the name of the bucket is only the prefix, the entries have their name after it
+            let entry_name = match name.get(padding..) {
+                Some(rest) if name.len() >= padding => rest.to_owned(),
+                _ => return Err(parse_failed(DirectoryParsingFailed::MissingName(nth))),
+            };
+
+            let total_size = match link.Tsize {
+                Some(total_size) => total_size,
+                None => return Err(parse_failed(DirectoryParsingFailed::MissingSize(nth))),
+            };
+
+            let hash = link.Hash.as_deref().unwrap_or_default();
+            let cid = match Cid::try_from(hash) {
+                Ok(cid) => cid,
+                Err(e) => return Err(parse_failed(InvalidCidInLink::from((nth, link, e)).into())),
+            };
+
+            if entry_name.is_empty() {
+                buckets.push(fetch(&cid).map_err(RelayoutFailed::Fetch)?);
+            } else {
+                entries.push((entry_name, Leaf::new(cid, total_size)));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Failure cases for `relayout` and `append_entries`.
+#[derive(Debug)]
+pub enum RelayoutFailed<E> {
+    /// Fetching a block failed.
+    Fetch(E),
+    /// The root was not a UnixFs directory.
+    NotADirectory(Cid),
+    /// The block of the directory at the path, or one of its HAMT shard buckets, could not be
+    /// read.
+    Parse(String, DirectoryParsingFailed),
+    /// The HAMT sharded directory at the path had an unsupported or missing fanout.
+    UnsupportedFanout(String, Option<u64>),
+    /// The directory at the path had an entry with a name which cannot be added, as it contains
+    /// a slash.
+    InvalidName(String, String),
+    /// Adding the entries to the new tree failed.
+    Building(TreeBuildingFailed),
+}
+
+impl<E: fmt::Display> fmt::Display for RelayoutFailed<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RelayoutFailed::*;
+        match self {
+            Fetch(e) => write!(fmt, "failed to fetch a block: {}", e),
+            NotADirectory(cid) => write!(fmt, "root {} is not a UnixFs directory", cid),
+            Parse(path, e) => write!(fmt, "directory at {:?} could not be read: {}", path, e),
+            UnsupportedFanout(path, fanout) => write!(
+                fmt,
+                "sharded directory at {:?} has unsupported fanout {:?}",
+                path, fanout
+            ),
+            InvalidName(path, name) => {
+                write!(
+                    fmt,
+                    "directory at {:?} has an invalid name {:?}",
+                    path, name
+                )
+            }
+            Building(e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for RelayoutFailed<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use RelayoutFailed::*;
+        match self {
+            Fetch(e) => Some(e),
+            Parse(_, e) => Some(e),
+            Building(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{relayout, RelayoutFailed};
+    use crate::dir::builder::{BufferingTreeBuilder, TreeOptions};
+    use crate::file::adder::FileAdder;
+    use crate::test_support::raw_cid;
+    use crate::Metadata;
+    use cid::Cid;
+    use std::collections::HashMap;
+
+    /// Builds the same tree with the `opts`, storing all of the blocks into `blocks` and returning
+    /// the root.
+    fn build(opts: TreeOptions, blocks: &mut HashMap<Cid, Vec<u8>>) -> Cid {
+        let mut adder = FileAdder::default();
+        let (pushed, consumed) = adder.push(b"foobar\n");
+        assert_eq!((pushed.count(), consumed), (0, 7));
+        let file = adder.finish().collect::<Vec<_>>();
+        let (file_cid, file_block) = file.last().unwrap().clone();
+        let file_size = file_block.len() as u64;
+        blocks.insert(file_cid.clone(), file_block);
+
+        let mut opts = opts;
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", raw_cid(0), 1).unwrap();
+        builder.put_link("a/c/d.txt", raw_cid(1), 1).unwrap();
+        builder.put_link("a/c/e.txt", raw_cid(2), 1).unwrap();
+        builder.put_link("f.txt", file_cid, file_size).unwrap();
+        builder.set_metadata("g", Metadata::default()).unwrap();
+
+        let mut root = None;
+        for node in builder.build() {
+            let node = node.unwrap();
+            root = Some(node.cid.clone());
+            blocks.insert(node.cid, node.block.into_vec());
+        }
+        root.unwrap()
+    }
+
+    fn sharded() -> TreeOptions {
+        let mut opts = TreeOptions::default();
+        opts.sharding_threshold(Some(0));
+        opts.shard_fanout(16);
+        opts
+    }
+
+    fn relayout_root<F>(root: &Cid, opts: TreeOptions, fetch: F) -> Cid
+    where
+        F: FnMut(&Cid) -> Result<Vec<u8>, &'static str>,
+    {
+        let mut iter = relayout(root, fetch, opts).unwrap();
+        while let Some(node) = iter.next_borrowed() {
+            node.unwrap();
+        }
+        iter.root().unwrap().0.to_owned()
+    }
+
+    #[test]
+    fn sharded_to_plain_and_back() {
+        let mut blocks = HashMap::new();
+        let sharded_root = build(sharded(), &mut blocks);
+        let plain_root = build(TreeOptions::default(), &mut blocks);
+        assert_ne!(sharded_root, plain_root);
+
+        let mut fetched = 0;
+        let fetch = |cid: &Cid| {
+            fetched += 1;
+            blocks.get(cid).cloned().ok_or("missing")
+        };
+
+        assert_eq!(
+            relayout_root(&sharded_root, TreeOptions::default(), fetch),
+            plain_root
+        );
+        assert!(fetched > 5);
+
+        let fetch = |cid: &Cid| blocks.get(cid).cloned().ok_or("missing");
+        assert_eq!(relayout_root(&plain_root, sharded(), fetch), sharded_root);
+    }
+
+    #[test]
+    fn root_must_be_a_directory() {
+        let mut blocks = HashMap::new();
+        build(TreeOptions::default(), &mut blocks);
+
+        // the only file block
+        let (file, _) = blocks
+            .iter()
+            .find(|(_, block)| block.ends_with(b"foobar\n\x18\x07"))
+            .unwrap();
+
+        let fetch = |cid: &Cid| blocks.get(cid).cloned().ok_or("missing");
+        match relayout(file, fetch, TreeOptions::default()) {
+            Err(RelayoutFailed::NotADirectory(cid)) if &cid == file => {}
+            x => unreachable!("{:?}", x.map(|_| ())),
+        }
+
+        let fetch = |_: &Cid| Err::<Vec<u8>, _>("missing");
+        match relayout(file, fetch, TreeOptions::default()) {
+            Err(RelayoutFailed::Fetch("missing")) => {}
+            x => unreachable!("{:?}", x.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use super::{
+    BufferingTreeBuilder, Leaf, OwnedTreeNode, TreeBuildingFailed, TreeConstructionFailed,
+    TreeOptions,
+};
+use core::fmt;
+
+/// Builds the chain of directories leading to the single `leaf` at `path`, without any other
+/// entries, for example to create a minimal proof of the path or a minimal mount point. Every
+/// directory has exactly one link, to the next directory or finally to the `leaf`.
+///
+/// There is one directory per path component: the root is always a wrapping directory, so
+/// `TreeOptions::wrap_with_directory` is enabled, and the `path` "a/b/c.txt" yields the
+/// directories "a/b", "a" and "" in this order, with the root as the last node. The path is
+/// validated and the directories are rendered as with `BufferingTreeBuilder`.
+pub fn single_path_tree(
+    path: &str,
+    leaf: Leaf,
+    mut opts: TreeOptions,
+) -> Result<impl Iterator<Item = OwnedTreeNode>, SinglePathFailed> {
+    opts.wrap_with_directory();
+    let mut builder = BufferingTreeBuilder::new(opts);
+
+    builder
+        .put_link(path, leaf.link, leaf.total_size)
+        .map_err(SinglePathFailed::Building)?;
+
+    let nodes = builder
+        .build()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(SinglePathFailed::Construction)?;
+
+    Ok(nodes.into_iter())
+}
+
+/// Failure cases for `single_path_tree`.
+#[derive(Debug)]
+pub enum SinglePathFailed {
+    /// The path could not be added.
+    Building(TreeBuildingFailed),
+    /// Rendering one of the directories failed.
+    Construction(TreeConstructionFailed),
+}
+
+impl fmt::Display for SinglePathFailed {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SinglePathFailed::*;
+        match self {
+            Building(e) => write!(fmt, "{}", e),
+            Construction(e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SinglePathFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use SinglePathFailed::*;
+        match self {
+            Building(e) => Some(e),
+            Construction(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{single_path_tree, SinglePathFailed};
+    use crate::dir::builder::{parse_directory_block, Leaf, TreeBuildingFailed, TreeOptions};
+    use crate::test_support::some_cid;
+
+    #[test]
+    fn three_component_chain() {
+        let nodes = single_path_tree(
+            "a/b/c.txt",
+            Leaf::new(some_cid(0), 5),
+            TreeOptions::default(),
+        )
+        .unwrap()
+        .collect::<Vec<_>>();
+
+        let paths = nodes
+            .iter()
+            .map(|node| node.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &["a/b", "a", ""]);
+        assert!(nodes.last().unwrap().is_root);
+
+        // every directory links only to the previous one, starting from the leaf
+        let mut expected = (String::from("c.txt"), some_cid(0), 5);
+
+        for node in &nodes {
+            let links = parse_directory_block(&node.block).unwrap();
+            assert_eq!(links.len(), 1);
+
+            let (name, leaf) = links.into_iter().next().unwrap();
+            assert_eq!((name, leaf.cid().to_owned(), leaf.total_size()), expected);
+
+            let name = node.path.rsplit('/').next().unwrap().to_owned();
+            expected = (name, node.cid.clone(), node.total_size);
+        }
+    }
+
+    #[test]
+    fn invalid_path() {
+        match single_path_tree("a//b", Leaf::new(some_cid(0), 1), TreeOptions::default()) {
+            Err(SinglePathFailed::Building(TreeBuildingFailed::RepeatSlashesInPath(_))) => {}
+            Err(e) => unreachable!("{:?}", e),
+            Ok(_) => unreachable!("invalid path was accepted"),
+        }
+    }
+}
@@ -0,0 +1,789 @@
+use super::iter::Instant;
+use super::{BuildSummary, PostOrderIterator, TreeConstructionFailed, TreeNode};
+use cid::Cid;
+use core::convert::Infallible;
+use core::fmt;
+use core::ops::ControlFlow;
+use core::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Storage for the rendered blocks, see `PostOrderIterator::drive`.
+pub trait BlockSink {
+    /// Error type for the failed stores.
+    type Error;
+
+    /// Stores the block, returning an error to stop the building.
+    fn put(&mut self, cid: &Cid, block: &[u8]) -> Result<(), Self::Error>;
+
+    /// Stores the block of the rendered directory `node`, which is called by
+    /// `PostOrderIterator::drive` instead of `BlockSink::put` to give the path of the directory
+    /// as well. Defaults to `BlockSink::put`.
+    fn put_node(&mut self, node: &TreeNode<'_>) -> Result<(), Self::Error> {
+        self.put(node.cid, node.block)
+    }
+
+    /// Returns true if the sink wants to receive each distinct block only once. When true,
+    /// `PostOrderIterator::drive` keeps track of the stored `Cid`s and skips the blocks which
+    /// have already been stored, counting them in `DriveStats::duplicates`.
+    ///
+    /// Defaults to false, in which case every block is stored in the post order.
+    fn deduplicate(&self) -> bool {
+        false
+    }
+}
+
+/// Statistics on the stored blocks, see `PostOrderIterator::drive_with_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DriveStats {
+    /// Number of blocks given to the `BlockSink::put`.
+    pub stored: u64,
+    /// Number of blocks which were not given to the sink as a block with the same `Cid` had
+    /// already been stored. Always zero unless `BlockSink::deduplicate` returns true.
+    pub duplicates: u64,
+}
+
+/// Summary of a whole build, see `PostOrderIterator::drive_with_report`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriveReport {
+    /// The statistics on the stored blocks.
+    pub stats: DriveStats,
+    /// The shape of the built tree, see `PostOrderIterator::summary`.
+    pub summary: BuildSummary,
+    /// Total length of the blocks given to the `BlockSink::put`.
+    pub bytes: u64,
+    /// Wall time of the whole build, including the time spent in the sink. Always zero on
+    /// `wasm32-unknown-unknown`, where there is no clock.
+    pub elapsed: Duration,
+}
+
+impl DriveReport {
+    /// Returns the rate of the stored bytes in MiB/s, or `None` if no time was measured.
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            Some(self.bytes as f64 / (1024.0 * 1024.0) / secs)
+        } else {
+            None
+        }
+    }
+}
+
+/// In-memory sink which collects the blocks in the post order.
+impl BlockSink for Vec<(Cid, Box<[u8]>)> {
+    type Error = Infallible;
+
+    fn put(&mut self, cid: &Cid, block: &[u8]) -> Result<(), Self::Error> {
+        self.push((cid.to_owned(), block.into()));
+        Ok(())
+    }
+}
+
+/// In-memory sink which keeps a single copy of the identical blocks.
+impl BlockSink for HashMap<Cid, Box<[u8]>> {
+    type Error = Infallible;
+
+    fn put(&mut self, cid: &Cid, block: &[u8]) -> Result<(), Self::Error> {
+        if !self.contains_key(cid) {
+            self.insert(cid.to_owned(), block.into());
+        }
+        Ok(())
+    }
+
+    fn deduplicate(&self) -> bool {
+        true
+    }
+}
+
+impl PostOrderIterator {
+    /// Renders the whole tree, storing all of the blocks into the `sink`. Returns the `Cid` of
+    /// the root on success, see `PostOrderIterator::root`.
+    pub fn drive<S: BlockSink>(self, sink: &mut S) -> Result<Cid, DriveFailed<S::Error>> {
+        self.drive_with_stats(sink).map(|(root, _)| root)
+    }
+
+    /// Same as `PostOrderIterator::drive` but also returns the statistics on the stored blocks.
+    ///
+    /// Identical subtrees render into identical blocks, and for a sink which opts into
+    /// `BlockSink::deduplicate` the repeated blocks are counted in `DriveStats::duplicates`
+    /// instead of being stored again. The seen `Cid`s are kept in memory for the duration of the
+    /// build.
+    pub fn drive_with_stats<S: BlockSink>(
+        self,
+        sink: &mut S,
+    ) -> Result<(Cid, DriveStats), DriveFailed<S::Error>> {
+        self.drive_with_report(sink)
+            .map(|(root, report)| (root, report.stats))
+    }
+
+    /// Same as `PostOrderIterator::drive_with_stats` but also measures the build, returning a
+    /// `DriveReport` with the number of the stored bytes and the wall time of the build, for
+    /// reporting the throughput of an import. For the time spent rendering each directory see
+    /// `TreeOptions::render_observer`.
+    pub fn drive_with_report<S: BlockSink>(
+        mut self,
+        sink: &mut S,
+    ) -> Result<(Cid, DriveReport), DriveFailed<S::Error>> {
+        let started = Instant::now();
+        let mut bytes = 0;
+        let mut stats = DriveStats::default();
+        let mut seen = if sink.deduplicate() {
+            Some(HashSet::new())
+        } else {
+            None
+        };
+
+        while let Some(node) = self.next_borrowed() {
+            let node = node.map_err(DriveFailed::Construction)?;
+
+            if let Some(seen) = seen.as_mut() {
+                if seen.contains(node.cid) {
+                    stats.duplicates += 1;
+                    continue;
+                }
+                seen.insert(node.cid.to_owned());
+            }
+
+            sink.put_node(&node).map_err(DriveFailed::Sink)?;
+            stats.stored += 1;
+            bytes += node.block.len() as u64;
+        }
+
+        let report = DriveReport {
+            stats,
+            summary: self.summary(),
+            bytes,
+            elapsed: started.elapsed(),
+        };
+
+        self.root()
+            .map(|(cid, _)| (cid.to_owned(), report))
+            .ok_or(DriveFailed::NoRoot)
+    }
+
+    /// Same as `PostOrderIterator::drive` but continues rendering after a directory has failed
+    /// to render, for example with `TreeConstructionFailed::TooLargeBlock`, storing the blocks
+    /// of all of the other directories. Returns the paths and `Cid`s of the topmost rendered
+    /// directories, in the order they were rendered, along with the path and the error of each
+    /// directory which failed.
+    ///
+    /// A directory which cannot be rendered makes all of its ancestors unbuildable as well, as
+    /// their blocks would need to link to it; these are skipped and not reported as failures by
+    /// themselves. The partial roots are thus the siblings of the failed directories and of
+    /// their ancestors, or only the root of the tree when nothing failed. The buckets of a
+    /// sharded directory are reported under the path of the directory. Only an error from the
+    /// `sink` stops the rendering.
+    ///
+    /// For a single top level file without `TreeOptions::wrap_with_directory` there are no
+    /// blocks and the partial roots are empty, see `PostOrderIterator::root`.
+    #[allow(clippy::type_complexity)]
+    pub fn drive_lenient<S: BlockSink>(
+        mut self,
+        sink: &mut S,
+    ) -> Result<(Vec<(String, Cid)>, Vec<(String, TreeConstructionFailed)>), S::Error> {
+        let mut roots: Vec<(String, Cid)> = Vec::new();
+        let mut failures = Vec::new();
+
+        while let Some(node) = self.next_borrowed() {
+            let node = match node {
+                Ok(node) => node,
+                Err(e) => {
+                    failures.push((self.current_path().to_owned(), e));
+                    continue;
+                }
+            };
+
+            sink.put_node(&node)?;
+
+            // in the post order the rendered descendants of this node are the latest roots
+            while let Some((path, _)) = roots.last() {
+                let descendant = node.path.is_empty()
+                    || path == node.path
+                    || (path.starts_with(node.path) && path[node.path.len()..].starts_with('/'));
+
+                if !descendant {
+                    break;
+                }
+                roots.pop();
+            }
+
+            roots.push((node.path.to_owned(), node.cid.to_owned()));
+        }
+
+        Ok((roots, failures))
+    }
+
+    /// Renders the tree, calling `f` with each of the nodes until it returns
+    /// `ControlFlow::Break`. Returns the `Cid` of the root once all of the nodes have been
+    /// visited, or `None` if `f` stopped the rendering early or there was no root, see
+    /// `PostOrderIterator::root`. The first failure to render a node is returned as is.
+    pub fn for_each_node<F>(mut self, mut f: F) -> Result<Option<Cid>, TreeConstructionFailed>
+    where
+        F: FnMut(&TreeNode<'_>) -> ControlFlow<()>,
+    {
+        while let Some(node) = self.next_borrowed() {
+            if let ControlFlow::Break(()) = f(&node?) {
+                return Ok(None);
+            }
+        }
+
+        Ok(self.root().map(|(cid, _)| cid.to_owned()))
+    }
+
+    /// Renders the whole tree, returning the `Cid` of the root and all of the blocks by their
+    /// `Cid`s. Identical directories have the same `Cid` and are stored only once.
+    ///
+    /// For a single top level file without `TreeOptions::wrap_with_directory` the root is the
+    /// file itself, and the map is empty.
+    #[allow(clippy::type_complexity)]
+    pub fn into_block_map(self) -> Result<(Cid, HashMap<Cid, Box<[u8]>>), TreeConstructionFailed> {
+        let mut blocks = HashMap::new();
+
+        match self.drive(&mut blocks) {
+            Ok(root) => Ok((root, blocks)),
+            Err(DriveFailed::Construction(e)) => Err(e),
+            Err(DriveFailed::Sink(e)) => match e {},
+            Err(DriveFailed::NoRoot) => Err(TreeConstructionFailed::EmptyTree),
+        }
+    }
+
+    /// Renders the whole tree, writing all of the blocks in the post order into `w`. Returns the
+    /// `Cid` of each block along with the offset of its frame in the written bytes, making the
+    /// last entry the root.
+    ///
+    /// Each block is written as a frame of the block length as an unsigned LEB128 varint (the
+    /// same as the multiformats unsigned-varint), immediately followed by the block bytes. The
+    /// frames follow each other without any header or padding, so the output can be read back by
+    /// reading a varint `len`, then `len` bytes of the block, until the end of the input. The
+    /// `Cid` is not written and can be recovered from the returned index or by hashing the block.
+    ///
+    /// Failing to render the tree is returned as an `io::Error` of kind `InvalidData`.
+    pub fn write_blocks<W: io::Write>(self, w: W) -> io::Result<Vec<(Cid, u64)>> {
+        let mut len_buffer = [0u8; 10];
+
+        let index = self
+            .write_indexed_blocks(w)?
+            .into_iter()
+            .map(|(cid, offset, len)| {
+                let frame_offset = offset - encode_varint(len, &mut len_buffer).len() as u64;
+                (cid, frame_offset)
+            })
+            .collect();
+
+        Ok(index)
+    }
+
+    /// Same as `PostOrderIterator::write_blocks` but returns an index of the `Cid`, offset and
+    /// length of each block, which can be used to build an index for the written blocks without
+    /// reading them back.
+    ///
+    /// The offset is the position of the first byte of the block data in the written bytes,
+    /// after the varint length prefix of the frame, and the length is the length of the block
+    /// data alone. The frame of the block starts at the offset minus the length of the varint
+    /// encoded length.
+    pub fn write_indexed_blocks<W: io::Write>(
+        mut self,
+        mut w: W,
+    ) -> io::Result<Vec<(Cid, u64, u64)>> {
+        let mut index = Vec::new();
+        let mut offset = 0u64;
+        let mut len_buffer = [0u8; 10];
+
+        while let Some(node) = self.next_borrowed() {
+            let node = node.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let block_len = node.block.len() as u64;
+
+            let len = encode_varint(block_len, &mut len_buffer);
+            w.write_all(len)?;
+            w.write_all(node.block)?;
+
+            offset += len.len() as u64;
+            index.push((node.cid.to_owned(), offset, block_len));
+            offset += block_len;
+        }
+
+        Ok(index)
+    }
+
+    /// Same as `PostOrderIterator::write_indexed_blocks` but each block is passed through
+    /// `encode` before being framed and written, for example to compress the blocks for a local
+    /// cache. The `encode` is given the block and an empty buffer to write the encoded bytes into;
+    /// a header to identify the encoding can be written into the buffer as well.
+    ///
+    /// The `Cid`s are always those of the unencoded blocks, so reading the blocks back requires
+    /// decoding them before they can be verified or used. The returned offsets and lengths are
+    /// those of the encoded bytes.
+    ///
+    /// Note: the output is not a standard format and cannot be read as a CAR file or by any other
+    /// implementation.
+    pub fn write_encoded_blocks<W, F>(
+        mut self,
+        mut w: W,
+        mut encode: F,
+    ) -> io::Result<Vec<(Cid, u64, u64)>>
+    where
+        W: io::Write,
+        F: FnMut(&[u8], &mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut index = Vec::new();
+        let mut offset = 0u64;
+        let mut len_buffer = [0u8; 10];
+        let mut encoded = Vec::new();
+
+        while let Some(node) = self.next_borrowed() {
+            let node = node.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            encoded.clear();
+            encode(node.block, &mut encoded)?;
+            let encoded_len = encoded.len() as u64;
+
+            let len = encode_varint(encoded_len, &mut len_buffer);
+            w.write_all(len)?;
+            w.write_all(&encoded)?;
+
+            offset += len.len() as u64;
+            index.push((node.cid.to_owned(), offset, encoded_len));
+            offset += encoded_len;
+        }
+
+        Ok(index)
+    }
+}
+
+/// Encodes `value` as an unsigned LEB128 varint into the `buffer`, returning the written part.
+pub(super) fn encode_varint(mut value: u64, buffer: &mut [u8; 10]) -> &[u8] {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer[len] = byte;
+            len += 1;
+            return &buffer[..len];
+        }
+        buffer[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+/// Failure cases for `PostOrderIterator::drive`.
+#[derive(Debug)]
+pub enum DriveFailed<E> {
+    /// Rendering the tree failed.
+    Construction(TreeConstructionFailed),
+    /// Storing the block failed.
+    Sink(E),
+    /// There was no root, which happens when nothing was added and
+    /// `TreeOptions::wrap_with_directory` was not enabled.
+    NoRoot,
+}
+
+impl<E: fmt::Display> fmt::Display for DriveFailed<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DriveFailed::*;
+        match self {
+            Construction(e) => write!(fmt, "{}", e),
+            Sink(e) => write!(fmt, "failed to store a block: {}", e),
+            NoRoot => write!(fmt, "the tree was empty"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for DriveFailed<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use DriveFailed::*;
+        match self {
+            Construction(e) => Some(e),
+            Sink(e) => Some(e),
+            NoRoot => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_varint, BlockSink, DriveFailed, DriveReport, DriveStats};
+    use crate::dir::builder::{BufferingTreeBuilder, TreeConstructionFailed, TreeOptions};
+    use crate::test_support::{small_tree, some_cid};
+    use cid::Cid;
+
+    #[test]
+    fn for_each_node() {
+        use core::ops::ControlFlow;
+
+        let mut paths = Vec::new();
+        let root = small_tree(TreeOptions::default())
+            .build()
+            .for_each_node(|node| {
+                paths.push(node.path.to_owned());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(paths, &["a/c", "a"]);
+        assert_eq!(
+            root,
+            Some(
+                small_tree(TreeOptions::default())
+                    .build()
+                    .drive(&mut Vec::new())
+                    .unwrap()
+            )
+        );
+
+        let mut visited = 0;
+        let root = small_tree(TreeOptions::default())
+            .build()
+            .for_each_node(|_| {
+                visited += 1;
+                ControlFlow::Break(())
+            })
+            .unwrap();
+
+        assert_eq!((visited, root), (1, None));
+
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(Some(1));
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b.txt", some_cid(0), 1).unwrap();
+
+        match builder.build().for_each_node(|_| ControlFlow::Continue(())) {
+            Err(TreeConstructionFailed::TooLargeBlock(_)) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn drive_into_vec() {
+        let expected = small_tree(TreeOptions::default())
+            .build()
+            .map(|res| res.map(|node| (node.cid, node.block)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut blocks = Vec::new();
+        let root = small_tree(TreeOptions::default())
+            .build()
+            .drive(&mut blocks)
+            .unwrap();
+
+        assert_eq!(blocks, expected);
+        assert_eq!(Some(&root), expected.last().map(|(cid, _)| cid));
+    }
+
+    #[test]
+    fn sink_error_stops() {
+        struct Full(usize);
+
+        impl BlockSink for Full {
+            type Error = &'static str;
+
+            fn put(&mut self, _: &Cid, _: &[u8]) -> Result<(), Self::Error> {
+                self.0 = self.0.checked_sub(1).ok_or("full")?;
+                Ok(())
+            }
+        }
+
+        let mut sink = Full(1);
+
+        match small_tree(TreeOptions::default()).build().drive(&mut sink) {
+            Err(DriveFailed::Sink("full")) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn identical_blocks_are_stored_once() {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/d/c.txt", some_cid(0), 1).unwrap();
+
+        let nodes = builder
+            .clone()
+            .build()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let (root, blocks) = builder.build().into_block_map().unwrap();
+
+        // a/b and a/d are identical
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].cid, nodes[1].cid);
+        assert_eq!(blocks.len(), 2);
+
+        for node in &nodes {
+            assert_eq!(blocks[&node.cid], node.block);
+        }
+        assert_eq!(root, nodes[2].cid);
+    }
+
+    #[test]
+    fn deduplicated_drive_stats() {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/d/c.txt", some_cid(0), 1).unwrap();
+        builder.put_link("a/e/c.txt", some_cid(0), 1).unwrap();
+
+        let mut all = Vec::new();
+        let (root, stats) = builder.clone().build().drive_with_stats(&mut all).unwrap();
+
+        assert_eq!(all.len(), 4);
+        assert_eq!(
+            stats,
+            DriveStats {
+                stored: 4,
+                duplicates: 0
+            }
+        );
+
+        let mut deduplicated = std::collections::HashMap::new();
+        let (same_root, stats) = builder.build().drive_with_stats(&mut deduplicated).unwrap();
+
+        // a/b, a/d and a/e are identical
+        assert_eq!(same_root, root);
+        assert_eq!(deduplicated.len(), 2);
+        assert_eq!(
+            stats,
+            DriveStats {
+                stored: 2,
+                duplicates: 2
+            }
+        );
+    }
+
+    #[test]
+    fn lenient_drive_skips_failed_ancestors() {
+        let mut opts = TreeOptions::default();
+        opts.block_size_limit(Some(200));
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        builder.put_link("a/b/c.txt", some_cid(0), 1).unwrap();
+        for i in 0..10 {
+            builder
+                .put_link(&format!("a/big/{}.txt", i), some_cid(i), 1)
+                .unwrap();
+        }
+        builder.put_link("a/d/e/f.txt", some_cid(1), 1).unwrap();
+        builder.put_link("g/h.txt", some_cid(2), 1).unwrap();
+
+        let mut blocks = Vec::new();
+        let (roots, failures) = builder.build().drive_lenient(&mut blocks).unwrap();
+
+        let paths = roots
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &["g", "a/d", "a/b"]);
+
+        match failures.as_slice() {
+            [(path, TreeConstructionFailed::TooLargeBlock(_))] if path == "a/big" => {}
+            x => unreachable!("{:?}", x),
+        }
+
+        // a/b, a/d/e, a/d and g but not a or the root
+        assert_eq!(blocks.len(), 4);
+        for (_, cid) in &roots {
+            assert!(blocks.iter().any(|(stored, _)| stored == cid));
+        }
+    }
+
+    #[test]
+    fn drive_report() {
+        let mut blocks = Vec::new();
+        let (root, report) = small_tree(TreeOptions::default())
+            .build()
+            .drive_with_report(&mut blocks)
+            .unwrap();
+
+        assert_eq!(blocks.last().map(|(cid, _)| cid), Some(&root));
+        assert_eq!(
+            report.stats,
+            DriveStats {
+                stored: 2,
+                duplicates: 0
+            }
+        );
+        assert_eq!(report.summary.directories, 2);
+        assert_eq!(report.summary.leaf_links, 2);
+        assert_eq!(
+            report.bytes,
+            blocks
+                .iter()
+                .map(|(_, block)| block.len() as u64)
+                .sum::<u64>()
+        );
+
+        match report.throughput() {
+            Some(rate) => assert!(rate > 0.0 && rate.is_finite()),
+            None => assert_eq!(report.elapsed, Default::default()),
+        }
+
+        let idle = DriveReport {
+            elapsed: Default::default(),
+            ..report
+        };
+        assert_eq!(idle.throughput(), None);
+    }
+
+    #[test]
+    fn empty_block_map() {
+        let builder = BufferingTreeBuilder::new(TreeOptions::default());
+
+        match builder.build().into_block_map() {
+            Err(TreeConstructionFailed::EmptyTree) => {}
+            Ok((root, _)) => unreachable!("{}", root),
+            Err(e) => unreachable!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn single_file_root() {
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a.txt", some_cid(0), 1).unwrap();
+
+        let mut blocks = Vec::new();
+        let root = builder.build().drive(&mut blocks).unwrap();
+
+        assert_eq!(root, some_cid(0));
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn no_root() {
+        let builder = BufferingTreeBuilder::new(TreeOptions::default());
+
+        let mut blocks = Vec::new();
+
+        match builder.build().drive(&mut blocks) {
+            Err(DriveFailed::NoRoot) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn write_framed_blocks() {
+        let expected = small_tree(TreeOptions::default())
+            .build()
+            .map(|res| res.map(|node| (node.cid, node.block)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut out = Vec::new();
+        let index = small_tree(TreeOptions::default())
+            .build()
+            .write_blocks(&mut out)
+            .unwrap();
+
+        assert_eq!(index.len(), expected.len());
+
+        // read the frames back
+        let mut remaining = &out[..];
+        for ((cid, offset), (expected_cid, expected_block)) in index.iter().zip(expected.iter()) {
+            assert_eq!(cid, expected_cid);
+            assert_eq!(*offset as usize, out.len() - remaining.len());
+
+            // all of the blocks are shorter than 128 bytes
+            let len = remaining[0] as usize;
+            assert_eq!(&remaining[1..][..len], &expected_block[..]);
+            remaining = &remaining[1 + len..];
+        }
+
+        assert!(remaining.is_empty());
+    }
+
+    #[test]
+    fn indexed_blocks() {
+        // a block longer than 127 bytes needs two bytes for the length
+        let mut large = BufferingTreeBuilder::new(TreeOptions::default());
+        for i in 0..10 {
+            large
+                .put_link(&format!("a/{}.txt", i), some_cid(i), 1)
+                .unwrap();
+        }
+
+        for builder in [small_tree(TreeOptions::default()), large].iter() {
+            let mut out = Vec::new();
+            let index = builder
+                .clone()
+                .build()
+                .write_indexed_blocks(&mut out)
+                .unwrap();
+
+            let frames = builder.clone().build().write_blocks(Vec::new()).unwrap();
+            assert_eq!(index.len(), frames.len());
+
+            let mut end = 0;
+            for ((cid, offset, len), (frame_cid, frame_offset)) in index.iter().zip(frames.iter()) {
+                assert_eq!(cid, frame_cid);
+                assert_eq!(*frame_offset, end);
+
+                let block = &out[*offset as usize..][..*len as usize];
+                crate::dir::builder::verify_block(cid, block).unwrap();
+
+                end = offset + len;
+            }
+
+            assert_eq!(end as usize, out.len());
+        }
+    }
+
+    #[test]
+    fn encoded_blocks() {
+        // stand-in for a compressor: a one byte header and the bytes reversed
+        let encode = |block: &[u8], out: &mut Vec<u8>| {
+            out.push(0xEE);
+            out.extend(block.iter().rev());
+            Ok(())
+        };
+
+        let mut out = Vec::new();
+        let index = small_tree(TreeOptions::default())
+            .build()
+            .write_encoded_blocks(&mut out, encode)
+            .unwrap();
+
+        let expected = small_tree(TreeOptions::default())
+            .build()
+            .map(|res| res.map(|node| (node.cid, node.block)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(index.len(), expected.len());
+
+        for ((cid, offset, len), (expected_cid, expected_block)) in
+            index.iter().zip(expected.iter())
+        {
+            let encoded = &out[*offset as usize..][..*len as usize];
+            assert_eq!(encoded[0], 0xEE);
+
+            let decoded = encoded[1..].iter().rev().copied().collect::<Vec<_>>();
+            assert_eq!(&decoded[..], &expected_block[..]);
+
+            // the cids are of the unencoded blocks
+            assert_eq!(cid, expected_cid);
+            crate::dir::builder::verify_block(cid, &decoded).unwrap();
+        }
+
+        let failing = small_tree(TreeOptions::default())
+            .build()
+            .write_encoded_blocks(Vec::new(), |_, _| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "encoding failed",
+                ))
+            })
+            .unwrap_err();
+
+        assert_eq!(failing.to_string(), "encoding failed");
+    }
+
+    #[test]
+    fn varints() {
+        let mut buffer = [0u8; 10];
+        assert_eq!(encode_varint(0, &mut buffer), &[0x00]);
+        assert_eq!(encode_varint(127, &mut buffer), &[0x7f]);
+        assert_eq!(encode_varint(128, &mut buffer), &[0x80, 0x01]);
+        assert_eq!(encode_varint(300, &mut buffer), &[0xac, 0x02]);
+        assert_eq!(encode_varint(u64::MAX, &mut buffer).len(), 10);
+    }
+}
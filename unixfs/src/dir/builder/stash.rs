@@ -0,0 +1,426 @@
+use super::iter::Leaves;
+use super::{NamedLeaf, TreeConstructionFailed, TreeOptions};
+use std::collections::HashMap;
+
+/// The leaves of the directories and HAMT buckets waiting for their children to be rendered,
+/// keyed by the id of the directory or bucket, see `PostOrderIterator::persisted_cids`.
+///
+/// With the `spill` feature and `TreeOptions::spill_budget`, the least recently stashed leaves
+/// are written to a temporary file whenever the stashed leaves in memory go over the budget, and
+/// read back when they are needed again.
+pub(super) struct LeafStash {
+    in_memory: HashMap<u64, Leaves>,
+    #[cfg(feature = "spill")]
+    spill: Option<spill::Spill>,
+}
+
+impl LeafStash {
+    pub(super) fn new(opts: &TreeOptions) -> Self {
+        #[cfg(not(feature = "spill"))]
+        let _ = opts;
+
+        LeafStash {
+            in_memory: HashMap::new(),
+            #[cfg(feature = "spill")]
+            spill: opts.spill_budget.map(spill::Spill::new),
+        }
+    }
+
+    /// Restores the stashed leaves of a checkpoint, all of which are kept in memory until the
+    /// next leaves are stashed.
+    #[cfg(feature = "serde")]
+    pub(super) fn restore(&mut self, leaves: HashMap<u64, Leaves>) {
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_mut() {
+            for (id, leaves) in &leaves {
+                spill.stashed(*id, leaves);
+            }
+        }
+
+        self.in_memory = leaves;
+    }
+
+    /// Returns all of the stashed leaves, reading back the spilled ones.
+    #[cfg(feature = "serde")]
+    pub(super) fn into_map(self) -> Result<HashMap<u64, Leaves>, TreeConstructionFailed> {
+        #[cfg_attr(not(feature = "spill"), allow(unused_mut))]
+        let mut in_memory = self.in_memory;
+
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill {
+            for (id, leaves) in spill.into_spilled()? {
+                in_memory.insert(id, leaves);
+            }
+        }
+
+        Ok(in_memory)
+    }
+
+    pub(super) fn insert(&mut self, id: u64, leaves: Leaves) -> Result<(), TreeConstructionFailed> {
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_mut() {
+            spill.stashed(id, &leaves);
+            self.in_memory.insert(id, leaves);
+            return spill.spill_over_budget(&mut self.in_memory);
+        }
+
+        self.in_memory.insert(id, leaves);
+        Ok(())
+    }
+
+    pub(super) fn contains_key(&self, id: u64) -> bool {
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_ref() {
+            if spill.is_spilled(id) {
+                return true;
+            }
+        }
+
+        self.in_memory.contains_key(&id)
+    }
+
+    /// Fills the slot at `index` of the stashed leaves with `child`, returning `false` if there
+    /// are no leaves stashed for the `id`.
+    pub(super) fn fill(
+        &mut self,
+        id: u64,
+        index: usize,
+        child: NamedLeaf,
+    ) -> Result<bool, TreeConstructionFailed> {
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_mut() {
+            spill.unspill(id, &mut self.in_memory)?;
+        }
+
+        let leaves = match self.in_memory.get_mut(&id) {
+            Some(leaves) => leaves,
+            None => return Ok(false),
+        };
+
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_mut() {
+            spill.filled(id, &child);
+        }
+
+        let cell = &mut leaves[index];
+        // each slot is filled exactly once by the only child at `index`
+        debug_assert!(cell.is_none(), "slot {} already filled", index);
+        *cell = Some(child);
+
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_mut() {
+            spill.spill_over_budget(&mut self.in_memory)?;
+        }
+
+        Ok(true)
+    }
+
+    pub(super) fn remove(&mut self, id: u64) -> Result<Option<Leaves>, TreeConstructionFailed> {
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_mut() {
+            spill.unspill(id, &mut self.in_memory)?;
+            let leaves = self.in_memory.remove(&id);
+            if let Some(leaves) = &leaves {
+                spill.removed(id, leaves);
+            }
+            return Ok(leaves);
+        }
+
+        Ok(self.in_memory.remove(&id))
+    }
+
+    /// The number of the directories and buckets with stashed leaves.
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        #[cfg(feature = "spill")]
+        if let Some(spill) = self.spill.as_ref() {
+            return self.in_memory.len() + spill.spilled_len();
+        }
+
+        self.in_memory.len()
+    }
+
+    #[cfg(test)]
+    pub(super) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of the directories and buckets with spilled leaves.
+    #[cfg(all(test, feature = "spill"))]
+    pub(super) fn spilled_len(&self) -> usize {
+        self.spill
+            .as_ref()
+            .map(|spill| spill.spilled_len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(feature = "spill")]
+mod spill {
+    use super::{Leaves, NamedLeaf, TreeConstructionFailed};
+    use cid::Cid;
+    use core::convert::TryFrom;
+    use std::collections::{HashMap, VecDeque};
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    /// The spilled leaves in a temporary file, which is removed when this is dropped.
+    pub(super) struct Spill {
+        budget: usize,
+        // the estimated size of the leaves in memory
+        in_memory_bytes: usize,
+        // the ids of the leaves in memory, the least recently stashed or used first
+        order: VecDeque<u64>,
+        // created once the first leaves are spilled
+        file: Option<File>,
+        // the end of the written part of the file; the space of the read back leaves is not
+        // reused
+        end: u64,
+        // the offset and the length of the spilled leaves in the file
+        spilled: HashMap<u64, (u64, usize)>,
+        buffer: Vec<u8>,
+    }
+
+    impl Spill {
+        pub(super) fn new(budget: usize) -> Self {
+            Spill {
+                budget,
+                in_memory_bytes: 0,
+                order: VecDeque::new(),
+                file: None,
+                end: 0,
+                spilled: HashMap::new(),
+                buffer: Vec::new(),
+            }
+        }
+
+        pub(super) fn is_spilled(&self, id: u64) -> bool {
+            self.spilled.contains_key(&id)
+        }
+
+        #[cfg(test)]
+        pub(super) fn spilled_len(&self) -> usize {
+            self.spilled.len()
+        }
+
+        /// Accounts for the leaves stashed into memory.
+        pub(super) fn stashed(&mut self, id: u64, leaves: &Leaves) {
+            self.in_memory_bytes += estimated_size(leaves);
+            self.order.push_back(id);
+        }
+
+        /// Accounts for the child filled into the slot of the leaves in memory.
+        pub(super) fn filled(&mut self, id: u64, child: &NamedLeaf) {
+            self.in_memory_bytes += estimated_leaf_size(child);
+            self.touch(id);
+        }
+
+        /// Accounts for the leaves removed from memory.
+        pub(super) fn removed(&mut self, id: u64, leaves: &Leaves) {
+            self.in_memory_bytes -= estimated_size(leaves);
+            if let Some(pos) = self.order.iter().rposition(|&other| other == id) {
+                self.order.remove(pos);
+            }
+        }
+
+        /// Marks the leaves as the most recently used. The leaves are used in the reverse of the
+        /// order of stashing, so the leaves are almost always found at the end.
+        fn touch(&mut self, id: u64) {
+            if self.order.back() == Some(&id) {
+                return;
+            }
+            if let Some(pos) = self.order.iter().rposition(|&other| other == id) {
+                self.order.remove(pos);
+            }
+            self.order.push_back(id);
+        }
+
+        /// Writes the least recently used leaves to the file until the rest fit the budget. The
+        /// most recently used leaves are always kept in memory.
+        pub(super) fn spill_over_budget(
+            &mut self,
+            in_memory: &mut HashMap<u64, Leaves>,
+        ) -> Result<(), TreeConstructionFailed> {
+            while self.in_memory_bytes > self.budget && self.order.len() > 1 {
+                let id = self
+                    .order
+                    .pop_front()
+                    .expect("more than one leaves in memory");
+                let leaves = in_memory
+                    .remove(&id)
+                    .expect("the ids in the order are in memory");
+
+                self.in_memory_bytes -= estimated_size(&leaves);
+                self.write(id, &leaves)
+                    .map_err(TreeConstructionFailed::Spill)?;
+            }
+            Ok(())
+        }
+
+        /// Reads the leaves back into memory if they have been spilled.
+        pub(super) fn unspill(
+            &mut self,
+            id: u64,
+            in_memory: &mut HashMap<u64, Leaves>,
+        ) -> Result<(), TreeConstructionFailed> {
+            if let Some((offset, len)) = self.spilled.remove(&id) {
+                let leaves = self
+                    .read(offset, len)
+                    .map_err(TreeConstructionFailed::Spill)?;
+                self.stashed(id, &leaves);
+                in_memory.insert(id, leaves);
+            }
+            Ok(())
+        }
+
+        /// Reads back all of the spilled leaves.
+        #[cfg(feature = "serde")]
+        pub(super) fn into_spilled(mut self) -> Result<Vec<(u64, Leaves)>, TreeConstructionFailed> {
+            let spilled = core::mem::take(&mut self.spilled);
+            spilled
+                .into_iter()
+                .map(|(id, (offset, len))| {
+                    let leaves = self.read(offset, len)?;
+                    Ok((id, leaves))
+                })
+                .collect::<io::Result<_>>()
+                .map_err(TreeConstructionFailed::Spill)
+        }
+
+        fn write(&mut self, id: u64, leaves: &Leaves) -> io::Result<()> {
+            if self.file.is_none() {
+                self.file = Some(tempfile::tempfile()?);
+            }
+            let file = self.file.as_mut().expect("the file was just created");
+
+            self.buffer.clear();
+            encode_leaves(leaves, &mut self.buffer);
+
+            file.seek(SeekFrom::Start(self.end))?;
+            file.write_all(&self.buffer)?;
+
+            self.spilled.insert(id, (self.end, self.buffer.len()));
+            self.end += self.buffer.len() as u64;
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u64, len: usize) -> io::Result<Leaves> {
+            let file = self
+                .file
+                .as_mut()
+                .expect("the file is created before spilling any leaves");
+
+            self.buffer.clear();
+            self.buffer.resize(len, 0);
+
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut self.buffer)?;
+
+            decode_leaves(&self.buffer)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt spilled leaves"))
+        }
+    }
+
+    /// The estimated size of the leaves in memory, which is good enough for keeping the memory
+    /// use near the budget.
+    fn estimated_size(leaves: &Leaves) -> usize {
+        let slots = leaves.len() * core::mem::size_of::<Option<NamedLeaf>>();
+        slots
+            + leaves
+                .iter()
+                .flatten()
+                .map(estimated_leaf_size)
+                .sum::<usize>()
+    }
+
+    fn estimated_leaf_size(leaf: &NamedLeaf) -> usize {
+        leaf.0.len() + leaf.1.hash().as_bytes().len()
+    }
+
+    /// Encodes the leaves as a count followed by every slot: a zero for an empty slot, or a one
+    /// followed by the length prefixed name and `Cid` and the total size.
+    fn encode_leaves(leaves: &Leaves, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(leaves.len() as u64).to_le_bytes());
+        for slot in leaves {
+            match slot {
+                None => out.push(0),
+                Some(NamedLeaf(name, cid, total_size)) => {
+                    out.push(1);
+                    out.extend_from_slice(&(name.len() as u64).to_le_bytes());
+                    out.extend_from_slice(name.as_bytes());
+                    let cid = cid.to_bytes();
+                    out.extend_from_slice(&(cid.len() as u64).to_le_bytes());
+                    out.extend_from_slice(&cid);
+                    out.extend_from_slice(&total_size.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    fn decode_leaves(mut bytes: &[u8]) -> Option<Leaves> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if bytes.len() < len {
+                return None;
+            }
+            let (taken, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Some(taken)
+        }
+
+        fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+            let mut le = [0u8; 8];
+            le.copy_from_slice(take(bytes, 8)?);
+            Some(u64::from_le_bytes(le))
+        }
+
+        let count = take_u64(&mut bytes)? as usize;
+        let mut leaves = Vec::with_capacity(count.min(bytes.len()));
+
+        for _ in 0..count {
+            match take(&mut bytes, 1)?[0] {
+                0 => leaves.push(None),
+                1 => {
+                    let len = take_u64(&mut bytes)? as usize;
+                    let name = String::from_utf8(take(&mut bytes, len)?.to_vec()).ok()?;
+                    let len = take_u64(&mut bytes)? as usize;
+                    let cid = Cid::try_from(take(&mut bytes, len)?).ok()?;
+                    let total_size = take_u64(&mut bytes)?;
+                    leaves.push(Some(NamedLeaf(name, cid, total_size)));
+                }
+                _ => return None,
+            }
+        }
+
+        if bytes.is_empty() {
+            Some(leaves)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{decode_leaves, encode_leaves};
+        use crate::dir::builder::NamedLeaf;
+        use crate::test_support::some_cid;
+
+        #[test]
+        fn leaves_round_trip() {
+            let leaves = vec![
+                Some(NamedLeaf("a".into(), some_cid(0), 1)),
+                None,
+                Some(NamedLeaf(String::new(), some_cid(1), u64::MAX)),
+            ];
+
+            let mut encoded = Vec::new();
+            encode_leaves(&leaves, &mut encoded);
+
+            let decoded = decode_leaves(&encoded).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", leaves));
+
+            assert!(decode_leaves(&encoded[..encoded.len() - 1]).is_none());
+            encoded.push(0);
+            assert!(decode_leaves(&encoded).is_none());
+        }
+    }
+}
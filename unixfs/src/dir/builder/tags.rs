@@ -0,0 +1,45 @@
+//! The protobuf tags of the dag-pb fields written when rendering the directories, for the
+//! external parsers and verifiers to use the same values.
+//!
+//! A tag is the field number shifted left by three bits, combined with the wire type in the low
+//! three bits. All of the fields are length delimited (wire type 2), except for the `Tsize`,
+//! which is a varint (wire type 0). The field numbers are those of the
+//! [dag-pb specification](https://ipld.io/specs/codecs/dag-pb/spec/).
+
+/// The tag of `PBNode::Data`, field number 1.
+pub const PBNODE_DATA_TAG: u32 = 10;
+
+/// The tag of the repeated `PBNode::Links`, field number 2.
+pub const PBNODE_LINKS_TAG: u32 = 18;
+
+/// The tag of `PBLink::Hash`, field number 1.
+pub const PBLINK_HASH_TAG: u32 = 10;
+
+/// The tag of `PBLink::Name`, field number 2.
+pub const PBLINK_NAME_TAG: u32 = 18;
+
+/// The tag of `PBLink::Tsize`, field number 3.
+pub const PBLINK_TSIZE_TAG: u32 = 24;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_match_field_numbers() {
+        const LENGTH_DELIMITED: u32 = 2;
+        const VARINT: u32 = 0;
+
+        let tags = [
+            (PBNODE_DATA_TAG, 1, LENGTH_DELIMITED),
+            (PBNODE_LINKS_TAG, 2, LENGTH_DELIMITED),
+            (PBLINK_HASH_TAG, 1, LENGTH_DELIMITED),
+            (PBLINK_NAME_TAG, 2, LENGTH_DELIMITED),
+            (PBLINK_TSIZE_TAG, 3, VARINT),
+        ];
+
+        for &(tag, field, wire_type) in &tags {
+            assert_eq!(tag, field << 3 | wire_type);
+        }
+    }
+}
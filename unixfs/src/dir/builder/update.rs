@@ -0,0 +1,341 @@
+use super::relayout::is_directory;
+use super::{
+    encode_directory_to, parse_directory_block, DirectoryParsingFailed, Leaf, OwnedTreeNode,
+    TreeConstructionFailed, TreeOptions,
+};
+use alloc::collections::BTreeMap;
+use cid::Cid;
+use core::fmt;
+
+/// Replaces or adds the leaf at `path` in the existing tree starting at `root`, re-rendering only
+/// the directories along the path. The blocks of these directories are requested from `fetch`,
+/// which is called once per directory from the root downwards; the rest of the tree is not
+/// needed. Missing directories along the path are created.
+///
+/// An existing `dag-pb` link at `path` is fetched as well, and replacing it fails with
+/// `UpdateFailed::NotALeaf` if it is a directory, as the whole subtree would be lost.
+///
+/// Returns the new directory nodes in the post order, starting from the parent of the leaf, with
+/// the new root as the last node. The paths of the nodes start with the `TreeOptions::path_prefix`
+/// as with `PostOrderIterator`. The links of the directories are rendered again with the
+/// `opts`, as with `encode_directory`, so the `Cid`s of the untouched siblings are passed to the
+/// `TreeOptions::link_rewriter` again.
+///
+/// Only plain directories can be updated, as the HAMT sharded directories cannot be read from a
+/// single block; these are reported as `DirectoryParsingFailed::Sharded`. The metadata of the
+/// directories is not read from the blocks, so a directory with metadata along the path loses
+/// it.
+pub fn update_leaf<F, E>(
+    root: &Cid,
+    path: &str,
+    new_leaf: Leaf,
+    mut fetch: F,
+    opts: &TreeOptions,
+) -> Result<Vec<OwnedTreeNode>, UpdateFailed<E>>
+where
+    F: FnMut(&Cid) -> Result<Vec<u8>, E>,
+{
+    if path.is_empty() || path.split('/').any(str::is_empty) {
+        return Err(UpdateFailed::InvalidPath(path.to_owned()));
+    }
+
+    let mut segments = path.split('/').collect::<Vec<_>>();
+    let leaf_name = segments.pop().expect("split always returns a segment");
+
+    // the links of the directories along the path, starting from the root
+    let mut dirs = Vec::with_capacity(segments.len() + 1);
+    dirs.push(read_directory(root, "", &mut fetch)?);
+
+    for (depth, name) in segments.iter().enumerate() {
+        let dir_path = segments[..=depth].join("/");
+
+        let links = match dirs[depth].get(*name) {
+            Some(leaf) => read_directory(&leaf.link, &dir_path, &mut fetch)?,
+            None => BTreeMap::new(),
+        };
+
+        dirs.push(links);
+    }
+
+    let parent = dirs.last().expect("the root is always read");
+    if let Some(existing) = parent.get(leaf_name) {
+        if existing.link.codec() == cid::Codec::DagProtobuf {
+            let block = fetch(&existing.link).map_err(UpdateFailed::Fetch)?;
+            if is_directory(&block) {
+                return Err(UpdateFailed::NotALeaf(path.to_owned()));
+            }
+        }
+    }
+
+    let prefix = opts.path_prefix.as_deref().unwrap_or_default();
+    let mut nodes = Vec::with_capacity(dirs.len());
+    let (mut name, mut child) = (leaf_name, new_leaf);
+
+    while let Some(mut links) = dirs.pop() {
+        let depth = dirs.len();
+        links.insert(name.to_owned(), child);
+
+        let entries = links
+            .into_iter()
+            .map(|(name, leaf)| (name, leaf.link, leaf.total_size));
+
+        let mut block = Vec::new();
+        let (cid, total_size) =
+            encode_directory_to(entries, opts, &mut block).map_err(UpdateFailed::Construction)?;
+
+        child = Leaf::new(cid.clone(), total_size);
+        if depth > 0 {
+            name = segments[depth - 1];
+        }
+
+        let dir_path = segments[..depth].join("/");
+        let path = match (prefix, dir_path.as_str()) {
+            ("", dir_path) => dir_path.to_owned(),
+            (prefix, "") => prefix.to_owned(),
+            (prefix, dir_path) => format!("{}/{}", prefix, dir_path),
+        };
+
+        nodes.push(OwnedTreeNode {
+            path,
+            cid,
+            total_size,
+            block: block.into_boxed_slice(),
+            is_root: depth == 0,
+        });
+    }
+
+    Ok(nodes)
+}
+
+fn read_directory<F, E>(
+    cid: &Cid,
+    path: &str,
+    fetch: &mut F,
+) -> Result<BTreeMap<String, Leaf>, UpdateFailed<E>>
+where
+    F: FnMut(&Cid) -> Result<Vec<u8>, E>,
+{
+    let block = fetch(cid).map_err(UpdateFailed::Fetch)?;
+    parse_directory_block(&block).map_err(|e| UpdateFailed::Parse(path.to_owned(), e))
+}
+
+/// Failure cases for `update_leaf`.
+#[derive(Debug)]
+pub enum UpdateFailed<E> {
+    /// The path was empty, or it had an empty segment.
+    InvalidPath(String),
+    /// Fetching a directory block failed.
+    Fetch(E),
+    /// The block of the directory at the path could not be read as a plain directory.
+    Parse(String, DirectoryParsingFailed),
+    /// Rendering an updated directory failed.
+    Construction(TreeConstructionFailed),
+    /// The existing link at the path was a directory, which cannot be replaced by a leaf.
+    NotALeaf(String),
+}
+
+impl<E: fmt::Display> fmt::Display for UpdateFailed<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use UpdateFailed::*;
+        match self {
+            InvalidPath(path) => write!(fmt, "invalid path: {:?}", path),
+            Fetch(e) => write!(fmt, "failed to fetch a directory block: {}", e),
+            Parse(path, e) => write!(fmt, "directory at {:?} could not be read: {}", path, e),
+            Construction(e) => write!(fmt, "{}", e),
+            NotALeaf(path) => write!(fmt, "existing link at {:?} is a directory", path),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for UpdateFailed<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use UpdateFailed::*;
+        match self {
+            InvalidPath(_) | NotALeaf(_) => None,
+            Fetch(e) => Some(e),
+            Parse(_, e) => Some(e),
+            Construction(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_leaf, UpdateFailed};
+    use crate::dir::builder::{
+        BufferingTreeBuilder, DirectoryParsingFailed, Leaf, OwnedTreeNode, TreeOptions,
+    };
+    use crate::test_support::some_cid;
+    use cid::Cid;
+    use std::collections::HashMap;
+
+    fn build(links: &[(&str, usize)]) -> Vec<OwnedTreeNode> {
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        for (path, n) in links {
+            builder.put_link(path, some_cid(*n), 1).unwrap();
+        }
+        builder.build().collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    /// A block of an empty file.
+    fn file_block() -> Vec<u8> {
+        b"\x0a\x02\x08\x02".to_vec()
+    }
+
+    fn store(nodes: &[OwnedTreeNode]) -> HashMap<Cid, Vec<u8>> {
+        nodes
+            .iter()
+            .map(|node| (node.cid.clone(), node.block.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn updated_path_matches_rebuild() {
+        let old = build(&[("a/b/c.txt", 0), ("a/d.txt", 1), ("e/f.txt", 2)]);
+        let blocks = store(&old);
+
+        let mut fetched = Vec::new();
+        let nodes = update_leaf(
+            &old.last().unwrap().cid,
+            "a/b/c.txt",
+            Leaf::new(some_cid(3), 1),
+            |cid: &Cid| {
+                fetched.push(cid.clone());
+                // the replaced leaf is fetched as a file
+                Ok::<_, &str>(blocks.get(cid).cloned().unwrap_or_else(file_block))
+            },
+            &TreeOptions::default(),
+        )
+        .unwrap();
+
+        let rebuilt = build(&[("a/b/c.txt", 3), ("a/d.txt", 1), ("e/f.txt", 2)]);
+        let expected = rebuilt
+            .iter()
+            .filter(|node| ["a/b", "a", ""].contains(&node.path.as_str()))
+            .map(|node| (node.path.as_str(), &node.cid, node.total_size))
+            .collect::<Vec<_>>();
+
+        let actual = nodes
+            .iter()
+            .map(|node| (node.path.as_str(), &node.cid, node.total_size))
+            .collect::<Vec<_>>();
+
+        assert_eq!(actual, expected);
+        assert!(nodes.last().unwrap().is_root);
+        // the directories along the path and the replaced leaf; the e directory was never
+        // needed
+        assert_eq!(fetched.len(), 4);
+        assert_eq!(fetched[3], some_cid(0));
+    }
+
+    #[test]
+    fn replacing_directory_fails() {
+        let old = build(&[("a/b/c.txt", 0), ("a/d.txt", 1)]);
+        let blocks = store(&old);
+
+        match update_leaf(
+            &old.last().unwrap().cid,
+            "a/b",
+            Leaf::new(some_cid(2), 1),
+            |cid: &Cid| blocks.get(cid).cloned().ok_or("missing"),
+            &TreeOptions::default(),
+        ) {
+            Err(UpdateFailed::NotALeaf(path)) if path == "a/b" => {}
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+    }
+
+    #[test]
+    fn paths_have_the_prefix() {
+        let old = build(&[("a/b/c.txt", 0)]);
+        let blocks = store(&old);
+
+        let mut opts = TreeOptions::default();
+        opts.path_prefix(Some("prefix".to_owned()));
+
+        let nodes = update_leaf(
+            &old.last().unwrap().cid,
+            "a/b/d.txt",
+            Leaf::new(some_cid(1), 1),
+            |cid: &Cid| blocks.get(cid).cloned().ok_or("missing"),
+            &opts,
+        )
+        .unwrap();
+
+        let paths = nodes
+            .iter()
+            .map(|node| node.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, &["prefix/a/b", "prefix/a", "prefix"]);
+    }
+
+    #[test]
+    fn missing_directories_are_created() {
+        let old = build(&[("a/b.txt", 0)]);
+        let blocks = store(&old);
+
+        let nodes = update_leaf(
+            &old.last().unwrap().cid,
+            "a/c/d/e.txt",
+            Leaf::new(some_cid(1), 1),
+            |cid: &Cid| blocks.get(cid).cloned().ok_or("missing"),
+            &TreeOptions::default(),
+        )
+        .unwrap();
+
+        let rebuilt = build(&[("a/b.txt", 0), ("a/c/d/e.txt", 1)]);
+        assert_eq!(
+            nodes.iter().map(|node| &node.cid).collect::<Vec<_>>(),
+            rebuilt.iter().map(|node| &node.cid).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn leaf_as_directory_fails() {
+        let old = build(&[("a/b.txt", 0)]);
+        let blocks = store(&old);
+        let root = old.last().unwrap().cid.clone();
+
+        let fetch = |cid: &Cid| blocks.get(cid).cloned().ok_or("missing");
+
+        // the b.txt is not stored, as it would not be a directory anyway
+        match update_leaf(
+            &root,
+            "a/b.txt/c",
+            Leaf::new(some_cid(1), 1),
+            fetch,
+            &TreeOptions::default(),
+        ) {
+            Err(UpdateFailed::Fetch("missing")) => {}
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+
+        let fetch = |cid: &Cid| Ok::<_, &str>(blocks.get(cid).cloned().unwrap_or_else(file_block));
+
+        match update_leaf(
+            &root,
+            "a/b.txt/c",
+            Leaf::new(some_cid(1), 1),
+            fetch,
+            &TreeOptions::default(),
+        ) {
+            Err(UpdateFailed::Parse(path, DirectoryParsingFailed::UnexpectedType(_)))
+                if path == "a/b.txt" => {}
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+
+        match update_leaf(
+            &root,
+            "a//c",
+            Leaf::new(some_cid(1), 1),
+            fetch,
+            &TreeOptions::default(),
+        ) {
+            Err(UpdateFailed::InvalidPath(path)) if path == "a//c" => {}
+            x => unreachable!("{:?}", x.map(|nodes| nodes.len())),
+        }
+    }
+}
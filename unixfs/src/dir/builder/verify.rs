@@ -0,0 +1,108 @@
+use cid::Cid;
+use core::fmt;
+
+/// Checks that the `block` hashes to the multihash of the `cid`, recomputing the hash with the
+/// algorithm of the multihash. Truncated digests, see the `digest_len` of `TreeOptions::hash`,
+/// are compared to the same length prefix of the recomputed digest.
+///
+/// Useful for asserting that the blocks created by `PostOrderIterator` are self-consistent, or
+/// for checking blocks read back from a store.
+pub fn verify_block(cid: &Cid, block: &[u8]) -> Result<(), VerifyError> {
+    let expected = cid.hash();
+    let actual = expected.algorithm().digest(block);
+
+    let expected = expected.digest();
+    let actual = actual.digest();
+
+    if expected.is_empty() {
+        return Err(VerifyError::EmptyDigest);
+    }
+
+    if expected.len() > actual.len() {
+        return Err(VerifyError::TooLongDigest(expected.len()));
+    }
+
+    if expected != &actual[..expected.len()] {
+        return Err(VerifyError::Mismatch);
+    }
+
+    Ok(())
+}
+
+/// Failure cases for `verify_block`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The block hashed to a different digest than the one in the `Cid`.
+    Mismatch,
+    /// The digest in the `Cid` had the given length, which is longer than the output of the hash.
+    TooLongDigest(usize),
+    /// The digest in the `Cid` was empty, which any block would match.
+    EmptyDigest,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VerifyError::*;
+        match self {
+            Mismatch => write!(fmt, "block does not hash to the cid"),
+            TooLongDigest(len) => write!(
+                fmt,
+                "digest of {} bytes is longer than the hash output",
+                len
+            ),
+            EmptyDigest => write!(fmt, "digest of the cid is empty"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_block, VerifyError};
+    use crate::dir::builder::TreeOptions;
+    use crate::test_support::small_tree;
+    use cid::Cid;
+
+    fn blocks(opts: TreeOptions) -> Vec<(Cid, Vec<u8>)> {
+        small_tree(opts)
+            .build()
+            .map(|res| res.map(|node| (node.cid, node.block.into_vec())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn rendered_blocks_verify() {
+        let mut truncated = TreeOptions::default();
+        truncated.cid_version(cid::Version::V1);
+        truncated.hash(multihash::Code::Blake2b512, Some(20));
+
+        for opts in [TreeOptions::default(), truncated].iter() {
+            for (cid, block) in blocks(opts.clone()) {
+                verify_block(&cid, &block).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_block_fails() {
+        let (cid, mut block) = blocks(TreeOptions::default()).pop().unwrap();
+
+        let last = block.len() - 1;
+        block[last] ^= 1;
+
+        assert_eq!(verify_block(&cid, &block), Err(VerifyError::Mismatch));
+    }
+
+    #[test]
+    fn empty_digest_fails() {
+        let (_, mut block) = blocks(TreeOptions::default()).pop().unwrap();
+        block[0] ^= 1;
+
+        let mh = multihash::wrap(multihash::Code::Sha2_256, &[]);
+        let cid = Cid::new_v1(cid::Codec::DagProtobuf, mh);
+
+        assert_eq!(verify_block(&cid, &block), Err(VerifyError::EmptyDigest));
+    }
+}
@@ -0,0 +1,225 @@
+//! HAMT sharded directory support shared between the reading and the building side.
+
+/// The default fanout of HAMT sharded directories used by go-ipfs, and the only one supported
+/// when reading.
+pub(crate) const FANOUT: u64 = 256;
+
+/// The multicodec code of `murmur3-x64-64`, the only supported hash for HAMT sharded directories.
+pub(crate) const MURMUR3_X64_64: u64 = 0x22;
+
+/// The shape of the HAMT buckets derived from the fanout.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Layout {
+    /// Number of bits of the name hash consumed at every level.
+    bits: u32,
+    /// Number of hex digits in the name prefixes of the links.
+    padding: usize,
+}
+
+impl Layout {
+    /// Returns `None` unless the fanout is a power of two between 2 and 256, inclusive.
+    pub(crate) fn new(fanout: u64) -> Option<Self> {
+        if !fanout.is_power_of_two() || !(2..=FANOUT).contains(&fanout) {
+            return None;
+        }
+
+        Some(Layout {
+            bits: fanout.trailing_zeros(),
+            padding: format!("{:X}", fanout - 1).len(),
+        })
+    }
+
+    /// Maximum depth of the buckets before the 64-bit hash has been exhausted.
+    pub(crate) fn max_levels(&self) -> u32 {
+        64 / self.bits
+    }
+
+    /// Returns the bucket index for the given hash at the level, consuming the hash bits from the
+    /// most significant bit as go-ipfs does.
+    pub(crate) fn bucket_index(&self, hash: u64, level: u32) -> u8 {
+        debug_assert!(level < self.max_levels());
+        ((hash << (self.bits * level)) >> (64 - self.bits)) as u8
+    }
+
+    /// Returns the zero padded uppercase hex prefix for the links in the bucket.
+    pub(crate) fn bucket_name(&self, bucket: u8) -> String {
+        format!("{:0width$X}", bucket, width = self.padding)
+    }
+}
+
+/// Bitfield of the occupied buckets in a HAMT shard node, serialized as the `Data` of the UnixFs
+/// message.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Bitfield([u8; (FANOUT / 8) as usize]);
+
+impl Bitfield {
+    pub(crate) fn set(&mut self, index: u8) {
+        let len = self.0.len();
+        self.0[len - 1 - usize::from(index / 8)] |= 1 << (index % 8);
+    }
+
+    /// Returns the bytes in the big endian order with the leading zero bytes removed, matching
+    /// go-ipfs.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let first = self.0.iter().position(|&b| b != 0).unwrap_or(self.0.len());
+        &self.0[first..]
+    }
+}
+
+/// Returns the `murmur3-x64-64` hash of the given name, which is the first half of the 128-bit
+/// murmur3 (x64 variant) hash with zero seed.
+pub(crate) fn hash_name(name: &str) -> u64 {
+    murmur3_x64_128(name.as_bytes()).0
+}
+
+fn murmur3_x64_128(data: &[u8]) -> (u64, u64) {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+
+    let mut h1 = 0u64;
+    let mut h2 = 0u64;
+
+    let mut chunks = data.chunks_exact(16);
+
+    for chunk in &mut chunks {
+        let (k1, k2) = chunk.split_at(8);
+        let k1 = u64::from_le_bytes(copy_to_array(k1));
+        let k2 = u64::from_le_bytes(copy_to_array(k2));
+
+        h1 ^= mix_k1(k1, C1, C2);
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dc_e729);
+
+        h2 ^= mix_k2(k2, C1, C2);
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x3849_5ab5);
+    }
+
+    let tail = chunks.remainder();
+
+    if tail.len() > 8 {
+        let mut k2 = [0u8; 8];
+        k2[..tail.len() - 8].copy_from_slice(&tail[8..]);
+        h2 ^= mix_k2(u64::from_le_bytes(k2), C1, C2);
+    }
+
+    if !tail.is_empty() {
+        let mut k1 = [0u8; 8];
+        let len = tail.len().min(8);
+        k1[..len].copy_from_slice(&tail[..len]);
+        h1 ^= mix_k1(u64::from_le_bytes(k1), C1, C2);
+    }
+
+    h1 ^= data.len() as u64;
+    h2 ^= data.len() as u64;
+
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+
+    h1 = fmix64(h1);
+    h2 = fmix64(h2);
+
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+
+    (h1, h2)
+}
+
+fn copy_to_array(slice: &[u8]) -> [u8; 8] {
+    let mut arr = [0u8; 8];
+    arr.copy_from_slice(slice);
+    arr
+}
+
+fn mix_k1(k1: u64, c1: u64, c2: u64) -> u64 {
+    k1.wrapping_mul(c1).rotate_left(31).wrapping_mul(c2)
+}
+
+fn mix_k2(k2: u64, c1: u64, c2: u64) -> u64 {
+    k2.wrapping_mul(c2).rotate_left(33).wrapping_mul(c1)
+}
+
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^= k >> 33;
+    k
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_name, murmur3_x64_128, Bitfield, Layout, FANOUT};
+    use hex_literal::hex;
+
+    #[test]
+    fn murmur3_vectors() {
+        assert_eq!(murmur3_x64_128(b""), (0, 0));
+        assert_eq!(
+            murmur3_x64_128(b"hello"),
+            (0xcbd8_a7b3_41bd_9b02, 0x5b1e_906a_48ae_1d19)
+        );
+    }
+
+    #[test]
+    fn go_ipfs_bucket_indices() {
+        // the names and their bucket prefixes are from the HAMT sharded directory fixture in
+        // `sharded_lookup`, created by go-ipfs
+        for &(name, expected) in &[
+            ("doc", 0x6a),
+            ("Makefile", 0xcd),
+            ("bin", 0xf5),
+            ("formal", 0xb9),
+        ] {
+            let layout = Layout::new(FANOUT).unwrap();
+            assert_eq!(
+                layout.bucket_index(hash_name(name), 0),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn go_ipfs_bitfield() {
+        let mut bitfield = Bitfield::default();
+        assert!(bitfield.as_bytes().is_empty());
+
+        for &index in &[0x6a, 0xb9, 0xcd, 0xf5] {
+            bitfield.set(index);
+        }
+
+        assert_eq!(
+            bitfield.as_bytes(),
+            &hex!("20000000002000020000000000000000000400000000000000000000000000")[..]
+        );
+    }
+
+    #[test]
+    fn layouts() {
+        for &fanout in &[0, 1, 3, 100, 512] {
+            assert!(Layout::new(fanout).is_none(), "{}", fanout);
+        }
+
+        let layout = Layout::new(16).unwrap();
+        assert_eq!(layout.max_levels(), 16);
+        assert_eq!(layout.bucket_index(0xabcd_0000_0000_0000, 0), 0xa);
+        assert_eq!(layout.bucket_index(0xabcd_0000_0000_0000, 1), 0xb);
+        assert_eq!(layout.bucket_index(0x0000_0000_0000_000f, 15), 0xf);
+        assert_eq!(layout.bucket_name(0xa), "A");
+
+        let layout = Layout::new(FANOUT).unwrap();
+        assert_eq!(layout.max_levels(), 8);
+        assert_eq!(layout.bucket_index(0xabcd_0000_0000_0000, 1), 0xcd);
+        assert_eq!(layout.bucket_name(0x5), "05");
+    }
+}
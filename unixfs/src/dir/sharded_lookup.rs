@@ -1,3 +1,5 @@
+use super::builder::Leaf;
+use super::hamt;
 use super::{try_convert_cid, MaybeResolved, MultipleMatchingLinks, ResolveError};
 use crate::pb::{FlatUnixFs, PBLink, ParsingFailed, UnixFsType};
 use crate::{InvalidCidInLink, UnexpectedNodeType};
@@ -188,6 +190,152 @@ impl<'needle> ShardedLookup<'needle> {
     }
 }
 
+/// Resolves the `needle` in the HAMT sharded directory starting at the `block` by following the
+/// `murmur3-x64-64` hash of the needle, requesting only the buckets along the path from `fetch`.
+/// Returns `None` if there is no entry with the name.
+///
+/// The hash bits are consumed from the most significant bit, a level of buckets at a time, as
+/// go-ipfs does and as the `builder::TreeOptions::sharding_threshold` writes the buckets, so at
+/// most one block per level needs to be fetched. Unlike `ShardedLookup`, all of the fanouts
+/// supported by the `builder::TreeOptions::shard_fanout` can be read.
+pub fn lookup_by_hash<F, E>(
+    block: &[u8],
+    needle: &str,
+    mut fetch: F,
+) -> Result<Option<Leaf>, HashLookupFailed<E>>
+where
+    F: FnMut(&Cid) -> Result<Vec<u8>, E>,
+{
+    let hash = hamt::hash_name(needle);
+    let mut level = 0;
+    let mut step = hashed_step(block, needle, hash, level)?;
+
+    loop {
+        match step {
+            HashedStep::Found(leaf) => return Ok(Some(leaf)),
+            HashedStep::NotFound => return Ok(None),
+            HashedStep::Bucket(cid) => {
+                let block = fetch(&cid).map_err(HashLookupFailed::Fetch)?;
+                level += 1;
+                step = hashed_step(&block, needle, hash, level)?;
+            }
+        }
+    }
+}
+
+enum HashedStep {
+    Found(Leaf),
+    NotFound,
+    Bucket(Cid),
+}
+
+/// Looks up the bucket of the `hash` at the `level` in the single bucket `block`.
+fn hashed_step<E>(
+    block: &[u8],
+    needle: &str,
+    hash: u64,
+    level: u32,
+) -> Result<HashedStep, HashLookupFailed<E>> {
+    let mut hamt = match FlatUnixFs::try_from(block) {
+        Ok(hamt) if hamt.data.Type == UnixFsType::HAMTShard => hamt,
+        Ok(other) => return Err(LookupError::UnexpectedBucketType(other.data.Type.into()).into()),
+        Err(ParsingFailed::InvalidDagPb(e)) | Err(ParsingFailed::InvalidUnixFs(e, _)) => {
+            return Err(LookupError::Read(Some(e)).into())
+        }
+        Err(ParsingFailed::NoData(_)) => return Err(LookupError::Read(None).into()),
+    };
+
+    let layout = match hamt.data.fanout.and_then(hamt::Layout::new) {
+        Some(layout) if hamt.data.hashType == Some(hamt::MURMUR3_X64_64) => layout,
+        _ => {
+            return Err(LookupError::from(ShardError::UnsupportedProperties {
+                hash_type: hamt.data.hashType,
+                fanout: hamt.data.fanout,
+            })
+            .into())
+        }
+    };
+
+    if hamt.data.filesize.is_some() || !hamt.data.blocksizes.is_empty() {
+        return Err(LookupError::from(ShardError::UnexpectedProperties {
+            filesize: hamt.data.filesize,
+            blocksizes: core::mem::take(&mut hamt.data.blocksizes),
+        })
+        .into());
+    }
+
+    if level >= layout.max_levels() {
+        return Err(HashLookupFailed::HashExhausted);
+    }
+
+    let prefix = layout.bucket_name(layout.bucket_index(hash, level));
+
+    for (nth, link) in hamt.links.into_iter().enumerate() {
+        let name = link.Name.as_deref().unwrap_or_default();
+
+        match name.strip_prefix(prefix.as_str()) {
+            Some("") => return Ok(HashedStep::Bucket(try_convert_cid(nth, link)?)),
+            Some(rest) if rest == needle => {
+                let total_size = link.Tsize.ok_or(HashLookupFailed::MissingSize(nth))?;
+                let cid = try_convert_cid(nth, link)?;
+                return Ok(HashedStep::Found(Leaf::new(cid, total_size)));
+            }
+            // another name in the same slot, or a link of an other bucket
+            _ => {}
+        }
+    }
+
+    Ok(HashedStep::NotFound)
+}
+
+/// Failure cases for `lookup_by_hash`.
+#[derive(Debug)]
+pub enum HashLookupFailed<E> {
+    /// Fetching a bucket block failed.
+    Fetch(E),
+    /// A bucket could not be read or it had unsupported properties.
+    Lookup(LookupError),
+    /// The matching link at the index had no `Tsize`.
+    MissingSize(usize),
+    /// The buckets were nested deeper than the 64 bits of the hash allow.
+    HashExhausted,
+}
+
+impl<E> From<LookupError> for HashLookupFailed<E> {
+    fn from(e: LookupError) -> Self {
+        HashLookupFailed::Lookup(e)
+    }
+}
+
+impl<E> From<InvalidCidInLink> for HashLookupFailed<E> {
+    fn from(e: InvalidCidInLink) -> Self {
+        HashLookupFailed::Lookup(e.into())
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for HashLookupFailed<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use HashLookupFailed::*;
+        match self {
+            Fetch(e) => write!(fmt, "failed to fetch a bucket: {}", e),
+            Lookup(e) => write!(fmt, "{}", e),
+            MissingSize(nth) => write!(fmt, "link at index {} has no Tsize", nth),
+            HashExhausted => write!(fmt, "buckets nested deeper than the hash allows"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for HashLookupFailed<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use HashLookupFailed::*;
+        match self {
+            Fetch(e) => Some(e),
+            Lookup(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) enum PartitioningError {
     Multiple(MultipleMatchingLinks),
     InvalidCid(InvalidCidInLink),
@@ -337,6 +485,7 @@ impl std::error::Error for LookupError {
 mod tests {
     use super::{LookupError, MaybeResolved, ShardError, ShardedLookup};
     use crate::pb::FlatUnixFs;
+    use crate::test_support::some_cid;
     use core::convert::TryFrom;
     use hex_literal::hex;
 
@@ -394,6 +543,96 @@ mod tests {
         }
     }
 
+    #[test]
+    fn lookup_by_hash_in_go_ipfs_shard() {
+        use super::lookup_by_hash;
+        use crate::test_support::FakeBlockstore;
+        use cid::Cid;
+
+        let blocks = FakeBlockstore::with_fixtures();
+        let root = blocks.get_by_str("QmZbFPTnDBMWbQ6iBxQAhuhLz8Nu9XptYS96e7cuf5wvbk");
+        let file = Cid::try_from("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+
+        let indices = [38, 48, 50, 58, 9, 33, 4, 34, 17, 37, 40, 16, 41, 3, 25, 49];
+        for i in &indices {
+            let name = format!("long-named-file-{:03}", i);
+
+            let mut fetched = 0;
+            let fetch = |cid: &Cid| {
+                fetched += 1;
+                Ok::<_, ()>(blocks.get_by_cid(cid).to_vec())
+            };
+
+            let leaf = lookup_by_hash(root, &name, fetch).unwrap().unwrap();
+            assert_eq!(leaf.cid(), &file, "{}", name);
+            assert_eq!(leaf.total_size(), 6, "{}", name);
+            // the root only has buckets
+            assert_eq!(fetched, 1, "{}", name);
+        }
+
+        let fetch = |cid: &Cid| Ok::<_, ()>(blocks.get_by_cid(cid).to_vec());
+        assert!(lookup_by_hash(root, "long-named-file-000", fetch)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn lookup_by_hash_in_built_shard() {
+        use super::{lookup_by_hash, HashLookupFailed};
+        use crate::dir::builder::{BufferingTreeBuilder, TreeOptions};
+        use cid::Cid;
+        use std::collections::HashMap;
+
+        let mut opts = TreeOptions::default();
+        opts.wrap_with_directory();
+        opts.sharding_threshold(Some(0));
+        opts.shard_fanout(16);
+
+        let mut builder = BufferingTreeBuilder::new(opts);
+        for i in 0..100usize {
+            let name = format!("{:03}.txt", i);
+            builder.put_link(&name, some_cid(i), i as u64).unwrap();
+        }
+
+        let mut blocks = HashMap::new();
+        let mut root = None;
+        for node in builder.build() {
+            let node = node.unwrap();
+            root = Some(node.cid.clone());
+            blocks.insert(node.cid, node.block.into_vec());
+        }
+        let root = &blocks[&root.unwrap()];
+
+        let mut max_fetched = 0;
+        for i in 0..100usize {
+            let mut fetched = 0;
+            let fetch = |cid: &Cid| {
+                fetched += 1;
+                blocks.get(cid).cloned().ok_or("missing")
+            };
+
+            let leaf = lookup_by_hash(root, &format!("{:03}.txt", i), fetch)
+                .unwrap()
+                .unwrap();
+            assert_eq!(leaf.cid(), &some_cid(i));
+            assert_eq!(leaf.total_size(), i as u64);
+            max_fetched = max_fetched.max(fetched);
+        }
+
+        // 100 names do not fit into the 16 buckets of the root, but only the buckets along the
+        // path of the hash are fetched, which are at most three levels deep for these names
+        assert!(max_fetched > 1 && max_fetched <= 3, "{}", max_fetched);
+        assert!(blocks.len() > 16);
+
+        let fetch = |cid: &Cid| blocks.get(cid).cloned().ok_or("missing");
+        assert!(lookup_by_hash(root, "100.txt", fetch).unwrap().is_none());
+
+        match lookup_by_hash(root, "000.txt", |_: &Cid| Err("missing")) {
+            Err(HashLookupFailed::Fetch("missing")) => {}
+            x => unreachable!("{:?}", x),
+        }
+    }
+
     #[test]
     fn unsupported_hash_type_or_fanout() {
         use crate::pb::{FlatUnixFs, UnixFs, UnixFsType};
@@ -7,6 +7,19 @@
 //! The main entry point for extracting information and/or data out of UnixFs trees is
 //! `ipfs_unixfs::walk::Walker`. To resolve `IpfsPath` segments over dag-pb nodes,
 //! `ipfs_unixfs::resolve` should be used.
+//!
+//! The crate does no IO of its own, except for `dir::builder::import_dir` with the `fs` feature,
+//! and spawns no threads, so it can be used on
+//! `wasm32-unknown-unknown`. The only clock is read for the
+//! `dir::builder::TreeOptions::render_observer`, which measures nothing on WASM. The `filetime`
+//! feature is only useful with a filesystem, so for WASM the crate should be used with
+//! `default-features = false`.
+//!
+//! With the `tracing` feature, building a tree with `dir::builder::PostOrderIterator` is traced
+//! in a `build_tree` span entered for every step of the iteration, and every rendered directory
+//! and HAMT shard bucket in a nested `render_directory` span with the `path`, `link_count` and
+//! `bucket` fields, all at the debug level. Unlike `dir::builder::TreeOptions::render_observer`,
+//! the spans need no configuration, and without the feature nothing is compiled in.
 
 extern crate alloc;
 
@@ -32,6 +45,10 @@ pub mod dagpb;
 /// Support for walking over all UnixFs trees
 pub mod walk;
 
+/// In-memory block storage for testing the created DAGs
+#[cfg(feature = "testing")]
+pub mod testing;
+
 #[cfg(test)]
 pub(crate) mod test_support;
 
@@ -130,6 +147,7 @@ impl UnexpectedNodeType {
 
 /// A container for the UnixFs metadata, which can be present at the root of the file, directory, or symlink trees.
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Metadata {
     mode: Option<u32>,
     mtime: Option<(i64, u32)>,
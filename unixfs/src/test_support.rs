@@ -1,3 +1,4 @@
+use crate::dir::builder::{BufferingTreeBuilder, TreeOptions};
 use cid::Cid;
 use core::convert::TryFrom;
 use hex_literal::hex;
@@ -114,3 +115,34 @@ impl FakeBlockstore {
         this
     }
 }
+
+/// Returns a quick and dirty sha2-256 of the given number as a Cidv0
+pub fn some_cid(number: usize) -> Cid {
+    use multihash::Sha2_256;
+    let mh = Sha2_256::digest(&number.to_le_bytes());
+    Cid::new_v0(mh).unwrap()
+}
+
+/// Returns a quick and dirty sha2-256 of the given number as a Cidv1 of the raw codec, which is
+/// never read as a directory.
+pub fn raw_cid(number: usize) -> Cid {
+    use multihash::Sha2_256;
+    let mh = Sha2_256::digest(&number.to_le_bytes());
+    Cid::new_v1(cid::Codec::Raw, mh)
+}
+
+/// Returns a builder with a leaf of `some_cid(n)` and size 1 at the nth of the `paths`.
+pub fn builder_with<P: AsRef<str>>(opts: TreeOptions, paths: &[P]) -> BufferingTreeBuilder {
+    let mut builder = BufferingTreeBuilder::new(opts);
+
+    for (i, path) in paths.iter().enumerate() {
+        builder.put_link(path.as_ref(), some_cid(i), 1).unwrap();
+    }
+
+    builder
+}
+
+/// Returns a builder of the small two-level tree `a/b.txt` and `a/c/d.txt`.
+pub fn small_tree(opts: TreeOptions) -> BufferingTreeBuilder {
+    builder_with(opts, &["a/b.txt", "a/c/d.txt"])
+}
@@ -0,0 +1,229 @@
+//! In-memory block storage with assertions on the stored DAG, for writing tests against the
+//! blocks created by `crate::dir::builder` and `crate::file::adder`. Available with the
+//! `testing` feature.
+
+use crate::dir::builder::{verify_block, BlockSink, VerifyError};
+use crate::pb::PBNode;
+use cid::Cid;
+use core::convert::TryFrom;
+use std::collections::{HashMap, HashSet};
+
+/// Block store which keeps all of the stored blocks in memory and records every insertion. Every
+/// stored block is verified to hash to its `Cid`, see `verify_block`.
+///
+/// Can be used as the sink of `PostOrderIterator::drive`, and the blocks of the files can be
+/// added with `MemoryBlockStore::put`.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryBlockStore {
+    blocks: HashMap<Cid, Box<[u8]>>,
+    insertions: Vec<Cid>,
+    duplicates: Vec<Cid>,
+}
+
+impl MemoryBlockStore {
+    /// Returns the stored block, if any.
+    pub fn get(&self, cid: &Cid) -> Option<&[u8]> {
+        self.blocks.get(cid).map(|block| &block[..])
+    }
+
+    /// Returns the number of distinct stored blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns true if nothing has been stored.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the `Cid`s of all of the stored blocks in the order of the insertions, including
+    /// the duplicates.
+    pub fn insertions(&self) -> &[Cid] {
+        &self.insertions
+    }
+
+    /// Returns the `Cid`s which were stored more than once, once for every repeated insertion.
+    pub fn duplicates(&self) -> &[Cid] {
+        &self.duplicates
+    }
+
+    /// Returns the `Cid`s which are linked to from the DAG starting at `root` but have not been
+    /// stored, including the `root` itself. Only the `dag-pb` blocks are followed; the links of
+    /// other codecs are not known. Each missing `Cid` is reported once, in the order found
+    /// walking the DAG depth-first.
+    pub fn missing_links(&self, root: &Cid) -> Vec<Cid> {
+        let mut missing = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![root.to_owned()];
+
+        while let Some(cid) = stack.pop() {
+            if !seen.insert(cid.clone()) {
+                continue;
+            }
+
+            let block = match self.blocks.get(&cid) {
+                Some(block) => block,
+                None => {
+                    missing.push(cid);
+                    continue;
+                }
+            };
+
+            if cid.codec() != cid::Codec::DagProtobuf {
+                continue;
+            }
+
+            let node = PBNode::try_from(&block[..])
+                .unwrap_or_else(|e| panic!("stored dag-pb block {} is invalid: {}", cid, e));
+
+            let links = node
+                .Links
+                .iter()
+                .map(|link| {
+                    let hash = link.Hash.as_deref().unwrap_or_default();
+                    Cid::try_from(hash)
+                        .unwrap_or_else(|e| panic!("invalid link in dag-pb block {}: {}", cid, e))
+                })
+                .collect::<Vec<_>>();
+
+            // reversed to visit the links in their order
+            stack.extend(links.into_iter().rev());
+        }
+
+        missing
+    }
+
+    /// Panics if any of the blocks of the DAG starting at `root` are missing, see
+    /// `MemoryBlockStore::missing_links`.
+    pub fn assert_complete(&self, root: &Cid) {
+        let missing = self.missing_links(root);
+        assert!(
+            missing.is_empty(),
+            "{} blocks reachable from {} are missing: {:?}",
+            missing.len(),
+            root,
+            missing
+        );
+    }
+
+    /// Panics if any `Cid` has been stored more than once.
+    pub fn assert_no_duplicates(&self) {
+        assert!(
+            self.duplicates.is_empty(),
+            "blocks were stored more than once: {:?}",
+            self.duplicates
+        );
+    }
+}
+
+impl BlockSink for MemoryBlockStore {
+    type Error = VerifyError;
+
+    fn put(&mut self, cid: &Cid, block: &[u8]) -> Result<(), Self::Error> {
+        verify_block(cid, block)?;
+
+        self.insertions.push(cid.to_owned());
+
+        if self.blocks.contains_key(cid) {
+            self.duplicates.push(cid.to_owned());
+        } else {
+            self.blocks.insert(cid.to_owned(), block.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBlockStore;
+    use crate::dir::builder::{BlockSink, BufferingTreeBuilder, TreeOptions, VerifyError};
+    use crate::file::adder::FileAdder;
+    use crate::test_support::some_cid;
+    use cid::Cid;
+
+    /// Adds the content as a file into the `store`, returning the `Cid` and the total size.
+    fn add_file(store: &mut MemoryBlockStore, content: &[u8]) -> (Cid, u64) {
+        let mut adder = FileAdder::default();
+        let mut blocks = Vec::new();
+
+        let mut written = 0;
+        while written < content.len() {
+            let (iter, consumed) = adder.push(&content[written..]);
+            blocks.extend(iter);
+            written += consumed;
+        }
+        blocks.extend(adder.finish());
+
+        let total_size = blocks.iter().map(|(_, block)| block.len() as u64).sum();
+        for (cid, block) in &blocks {
+            store.put(cid, block).unwrap();
+        }
+
+        (blocks.last().unwrap().0.clone(), total_size)
+    }
+
+    #[test]
+    fn complete_tree() {
+        let mut store = MemoryBlockStore::default();
+
+        let (foo, foo_size) = add_file(&mut store, b"foo\n");
+        let (bar, bar_size) = add_file(&mut store, b"bar\n");
+
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/foo.txt", foo, foo_size).unwrap();
+        builder.put_link("a/b/bar.txt", bar, bar_size).unwrap();
+        let root = builder.build().drive(&mut store).unwrap();
+
+        store.assert_complete(&root);
+        store.assert_no_duplicates();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.insertions().len(), 4);
+    }
+
+    #[test]
+    fn missing_and_duplicate_blocks() {
+        let mut store = MemoryBlockStore::default();
+
+        let (foo, foo_size) = add_file(&mut store, b"foo\n");
+        let absent = some_cid(0);
+
+        // two identical directories
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder
+            .put_link("a/b/foo.txt", foo.clone(), foo_size)
+            .unwrap();
+        builder.put_link("a/c/foo.txt", foo, foo_size).unwrap();
+        builder
+            .put_link("a/d/absent.txt", absent.clone(), 1)
+            .unwrap();
+        let root = builder.build().drive(&mut store).unwrap();
+
+        assert_eq!(store.missing_links(&root), &[absent]);
+        assert_eq!(store.duplicates().len(), 1);
+        assert_eq!(store.missing_links(&some_cid(1)), &[some_cid(1)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "are missing")]
+    fn incomplete_tree_panics() {
+        let mut store = MemoryBlockStore::default();
+
+        let mut builder = BufferingTreeBuilder::new(TreeOptions::default());
+        builder.put_link("a/foo.txt", some_cid(0), 1).unwrap();
+        let root = builder.build().drive(&mut store).unwrap();
+
+        store.assert_complete(&root);
+    }
+
+    #[test]
+    fn unverified_block_is_rejected() {
+        let mut store = MemoryBlockStore::default();
+
+        assert_eq!(
+            store.put(&some_cid(0), b"not the block"),
+            Err(VerifyError::Mismatch)
+        );
+        assert!(store.is_empty());
+    }
+}